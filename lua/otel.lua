@@ -12,6 +12,18 @@
 --   OTEL_EXPORTER_OTLP_PROTOCOL: Protocol (default: "http/protobuf")
 --      Values: "grpc", "http/protobuf", "http/json"
 --   OTEL_EXPORTER_OTLP_TRACES_PROTOCOL: Signal-specific protocol override
+--   OTEL_EXPORTER_OTLP_COMPRESSION: Request compression (default: none)
+--      Values: "gzip", "zstd", "none" (read by the Rust module)
+--   OTEL_EXPORTER_OTLP_CERTIFICATE: PEM bundle of trusted CA certificates
+--      (read by the Rust module, see otlp.ca_file)
+--   OTEL_EXPORTER_OTLP_CLIENT_CERTIFICATE, OTEL_EXPORTER_OTLP_CLIENT_KEY: Client
+--      certificate and key for mutual TLS (read by the Rust module)
+--   OTEL_EXPORTER_OTLP_HEADERS, OTEL_EXPORTER_OTLP_TRACES_HEADERS: Export headers
+--      (key1=value1,key2=value2, read by the Rust module, see otlp.headers)
+--   OTEL_EXPORTER_OTLP_TIMEOUT: Export timeout in milliseconds (default: 10000,
+--      read by the Rust module, see otlp.timeout_ms)
+--   HTTPS_PROXY, HTTP_PROXY, NO_PROXY: Proxy of the HTTP exporters (read by the
+--      Rust module, see otlp.proxy_url)
 
 local opentelemetry = require("haproxy_otel_module")
 
@@ -20,28 +32,48 @@ local opentelemetry = require("haproxy_otel_module")
 local service_name = os.getenv("OTEL_SERVICE_NAME") or "haproxy-ingress"
 local endpoint = os.getenv("OTEL_EXPORTER_OTLP_TRACES_ENDPOINT")
                  or os.getenv("OTEL_EXPORTER_OTLP_ENDPOINT")
-local sampler = os.getenv("OTEL_TRACES_SAMPLER") or "ParentBased"
-local propagator = os.getenv("OTEL_PROPAGATORS") or "w3c"
 local protocol = os.getenv("OTEL_EXPORTER_OTLP_TRACES_PROTOCOL")
                  or os.getenv("OTEL_EXPORTER_OTLP_PROTOCOL")
 
 -- Normalize sampler names
 local sampler_map = {
     ["always_on"] = "AlwaysOn",
+    ["alwayson"] = "AlwaysOn",
+    ["silenton"] = "SilentOn",
     ["always_off"] = "AlwaysOff",
+    ["alwaysoff"] = "AlwaysOff",
     ["parentbased_always_on"] = "ParentBased",
     ["parentbased"] = "ParentBased",
 }
-sampler = sampler_map[string.lower(sampler)] or sampler
 
 -- Normalize propagator names
 local propagator_map = {
     ["tracecontext"] = "w3c",
+    ["w3c"] = "w3c",
     ["b3"] = "zipkin",
     ["b3multi"] = "zipkin",
+    ["zipkin"] = "zipkin",
     ["jaeger"] = "jaeger",
 }
-propagator = propagator_map[string.lower(propagator)] or propagator
+
+-- First supported entry of a comma-separated variable (OTEL_PROPAGATORS=tracecontext,baggage),
+-- the default with a warning if there is none (OTEL_TRACES_SAMPLER=parentbased_traceidratio)
+local function normalize(name, map, default)
+    local value = os.getenv(name)
+    if not value or value == "" then
+        return default
+    end
+    for entry in string.gmatch(value, "[^,%s]+") do
+        if map[string.lower(entry)] then
+            return map[string.lower(entry)]
+        end
+    end
+    core.Warning(name .. "=" .. value .. " is not supported, using " .. default)
+    return default
+end
+
+local sampler = normalize("OTEL_TRACES_SAMPLER", sampler_map, "ParentBased")
+local propagator = normalize("OTEL_PROPAGATORS", propagator_map, "w3c")
 
 opentelemetry.register({
     name = service_name,
@@ -63,10 +95,37 @@ core.register_service("otel_metrics", "http", function(applet)
     local size = otel.cache_size()
     local response = "# HELP haproxy_otel_cache_size Number of spans currently in the thread-local cache\\n" ..
                      "# TYPE haproxy_otel_cache_size gauge\\n" ..
-                     "haproxy_otel_cache_size " .. tostring(size) .. "\\n"
+                     "haproxy_otel_cache_size " .. tostring(size) .. "\\n" ..
+                     otel.metrics()
     applet:set_status(200)
     applet:add_header("content-length", string.len(response))
     applet:add_header("content-type", "text/plain")
     applet:start_response()
     applet:send(response)
 end)
+
+-- Incident capture: GET /?seconds=N samples all requests for N seconds (default 60)
+-- and copies the finished spans to a local JSON lines file
+core.register_service("otel_dump", "http", function(applet)
+    local otel = package.loaded["haproxy_otel_module"] or require("haproxy_otel_module")
+    local seconds = tonumber(string.match(applet.qs or "", "seconds=(%d+)")) or 60
+    local response = otel.dump(seconds) .. "\n"
+    applet:set_status(200)
+    applet:add_header("content-length", string.len(response))
+    applet:add_header("content-type", "text/plain")
+    applet:start_response()
+    applet:send(response)
+end)
+
+-- Context hand-off to components that don't see the HTTP headers (other Lua modules, TLVs):
+-- the module's lua.otel_context fetch returns the server span context as a compact string, and
+-- the lua.otel_valid_context converter keeps a stored string only when otel.deserialize_context
+-- accepts it, e.g. to continue the trace of a PROXY protocol TLV:
+--   http-request set-var(txn.otel_parent_context) fc_pp_tlv(0xE1),lua.otel_valid_context
+core.register_converters("otel_valid_context", function(context)
+    local otel = package.loaded["haproxy_otel_module"] or require("haproxy_otel_module")
+    if context and otel.deserialize_context(context) then
+        return context
+    end
+    return nil
+end)
@@ -4,6 +4,17 @@ use mlua::prelude::*;
 fn haproxy_otel_module(lua: &Lua) -> LuaResult<LuaTable> {
     let table = lua.create_table()?;
     table.set("register", lua.create_function(haproxy_otel::register)?)?;
+    table.set("httpclient", lua.create_function(haproxy_otel::httpclient)?)?;
     table.set("cache_size", lua.create_function(haproxy_otel::cache_size)?)?;
+    table.set("metrics", lua.create_function(haproxy_otel::metrics)?)?;
+    table.set("dump", lua.create_function(haproxy_otel::dump)?)?;
+    table.set(
+        "serialize_context",
+        lua.create_function(haproxy_otel::serialize_context)?,
+    )?;
+    table.set(
+        "deserialize_context",
+        lua.create_function(haproxy_otel::deserialize_context)?,
+    )?;
     Ok(table)
 }
@@ -1,76 +1,87 @@
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::OnceLock;
 
 use haproxy_api::Txn;
-use mlua::prelude::LuaString;
 use opentelemetry::trace::TraceContextExt as _;
 use opentelemetry::{Context, TraceId};
 
 // This is a global cache to store the context of the spans
 // It can be reused independently of http session in many listeners
-static TRACE_CACHE: OnceLock<quick_cache::sync::Cache<[u8; 16], Context>> = OnceLock::new();
+static TRACE_CACHE: OnceLock<quick_cache::sync::Cache<u64, Context>> = OnceLock::new();
 
-fn init_cache() -> quick_cache::sync::Cache<[u8; 16], Context> {
+// Source of the cache keys. A key is allocated per transaction rather than derived from the
+// trace id: concurrent requests can share a trace id (streams of one HTTP/2 connection whose
+// client reuses a `traceparent`, retried requests) and must not overwrite each other's context.
+static NEXT_KEY: AtomicU64 = AtomicU64::new(1);
+
+/// Private variable holding the cache key of the transaction
+const KEY_VAR: &str = "txn.__otel_context_key";
+
+fn init_cache() -> quick_cache::sync::Cache<u64, Context> {
     quick_cache::sync::Cache::new(100_000)
 }
 
+fn next_key() -> u64 {
+    NEXT_KEY.fetch_add(1, Ordering::Relaxed)
+}
+
+fn context_key(txn: &Txn) -> Option<u64> {
+    // Lua integers are signed, the key is stored with its bits unchanged
+    match txn.get_var::<Option<i64>>(KEY_VAR) {
+        Ok(key) => key.map(|key| key as u64),
+        Err(e) => {
+            crate::strict::fault("get txn.__otel_context_key", e);
+            None
+        }
+    }
+}
+
 // Get the context from the global cache
 pub(crate) fn get_context(txn: &Txn) -> Option<Context> {
-    let trace_id = match txn.get_var::<LuaString>("txn.otel_trace_id") {
-        Ok(t) => t,
-        Err(_) => {
-            crate::exporter::log_debug("get_context: no txn.otel_trace_id var");
-            return None;
-        }
-    };
-    let mut trace_bytes = [0u8; 16];
-    if let Err(e) = const_hex::decode_to_slice(trace_id.as_bytes(), &mut trace_bytes) {
-        crate::exporter::log_warn(&format!("get_context: decode hex failed: {}", e));
+    let Some(key) = context_key(txn) else {
+        crate::exporter::log_debug("get_context: no context key var");
         return None;
-    }
-    let res = TRACE_CACHE.get_or_init(init_cache).get(&trace_bytes);
+    };
+    let res = TRACE_CACHE.get_or_init(init_cache).get(&key);
     if res.is_none() {
-        crate::exporter::log_debug(&format!(
-            "get_context: not found in cache for {:?}",
-            trace_bytes
-        ));
+        crate::exporter::log_debug(&format!("get_context: not found in cache for {key}"));
     }
     res
 }
 
 // Store the context in the globally cache to share it between listeners/frontends
 pub(crate) fn store_context(txn: &Txn, trace_id: TraceId, context: Context) {
-    let trace_id_bytes = trace_id.to_bytes();
-    let trace_id_hex = const_hex::encode(trace_id_bytes);
+    let trace_id_hex = const_hex::encode(trace_id.to_bytes());
     let span_id_hex = const_hex::encode(context.span().span_context().span_id().to_bytes());
-    let _ = txn.set_var("txn.otel_trace_id", &*trace_id_hex);
-    let _ = txn.set_var("txn.otel_span_id", &*span_id_hex);
-    TRACE_CACHE
-        .get_or_init(init_cache)
-        .insert(trace_id_bytes, context);
+    if let Err(e) = txn.set_var("txn.otel_trace_id", &*trace_id_hex) {
+        crate::strict::fault("set txn.otel_trace_id", e);
+    }
+    if let Err(e) = txn.set_var("txn.otel_span_id", &*span_id_hex) {
+        crate::strict::fault("set txn.otel_span_id", e);
+    }
+    // A transaction storing a new context replaces its previous one
+    let key = context_key(txn).unwrap_or_else(|| {
+        let key = next_key();
+        // The context can't be found again, the span is never ended
+        if let Err(e) = txn.set_var(KEY_VAR, key as i64) {
+            crate::strict::fault("set txn.__otel_context_key", e);
+        }
+        key
+    });
+    TRACE_CACHE.get_or_init(init_cache).insert(key, context);
 }
 
 pub(crate) fn remove_context(txn: &Txn) -> Option<Context> {
-    let trace_id = match txn.get_var::<LuaString>("txn.otel_trace_id") {
-        Ok(t) => t,
-        Err(_) => {
-            crate::exporter::log_debug("remove_context: no txn.otel_trace_id var");
-            return None;
-        }
-    };
-    let mut trace_bytes = [0u8; 16];
-    if let Err(e) = const_hex::decode_to_slice(trace_id.as_bytes(), &mut trace_bytes) {
-        crate::exporter::log_warn(&format!("remove_context: decode hex failed: {}", e));
+    let Some(key) = context_key(txn) else {
+        crate::exporter::log_debug("remove_context: no context key var");
         return None;
-    }
+    };
     let res = TRACE_CACHE
         .get_or_init(init_cache)
-        .remove(&trace_bytes)
+        .remove(&key)
         .map(|(_, context)| context);
     if res.is_none() {
-        crate::exporter::log_debug(&format!(
-            "remove_context: not found in cache for {:?}",
-            trace_bytes
-        ));
+        crate::exporter::log_debug(&format!("remove_context: not found in cache for {key}"));
     }
     res
 }
@@ -78,3 +89,33 @@ pub(crate) fn remove_context(txn: &Txn) -> Option<Context> {
 pub(crate) fn get_size() -> usize {
     TRACE_CACHE.get().map(|c| c.len()).unwrap_or(0)
 }
+
+#[cfg(test)]
+mod tests {
+    use opentelemetry::trace::{SpanContext, SpanId, TraceFlags, TraceState};
+
+    use super::*;
+
+    #[test]
+    fn test_keys_per_transaction() {
+        let context = |span_id: u64| {
+            let span_context = SpanContext::new(
+                TraceId::from(0x4bf9_2f35_77b3_4da6_a3ce_929d_0e0e_4736u128),
+                SpanId::from(span_id),
+                TraceFlags::SAMPLED,
+                false,
+                TraceState::default(),
+            );
+            Context::new().with_remote_span_context(span_context)
+        };
+        // Two streams with the same trace id keep their own context
+        let cache = init_cache();
+        let (first, second) = (next_key(), next_key());
+        assert_ne!(first, second);
+        cache.insert(first, context(1));
+        cache.insert(second, context(2));
+        let span_id = |key| cache.get(&key).unwrap().span().span_context().span_id();
+        assert_eq!(span_id(first), SpanId::from(1));
+        assert_eq!(span_id(second), SpanId::from(2));
+    }
+}
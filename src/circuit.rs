@@ -0,0 +1,174 @@
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use opentelemetry_sdk::error::{OTelSdkError, OTelSdkResult};
+use opentelemetry_sdk::trace::{SpanData, SpanExporter};
+use opentelemetry_sdk::Resource;
+
+use crate::exporter::{log_info, log_warn};
+
+/// Consecutive failed exports opening the circuit (`breaker.open_after`)
+pub(crate) const DEFAULT_OPEN_AFTER: u32 = 5;
+
+/// Interval between probe exports while the circuit is open (`breaker.probe_interval_ms`)
+pub(crate) const DEFAULT_PROBE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Span exporter opening a circuit after `open_after` consecutive failed exports, so a dead
+/// collector doesn't keep every batch busy with connection attempts and retries.
+///
+/// While open, batches are dropped without being sent, except for one batch every
+/// `probe_interval` which is sent to check whether the collector is back. The first
+/// successful export closes the circuit. The dropped spans are logged once per probe rather
+/// than per batch. `open_after = 0` never opens the circuit.
+#[derive(Debug)]
+pub(crate) struct CircuitExporter<E> {
+    inner: E,
+    open_after: u32,
+    probe_interval: Duration,
+    failures: AtomicU32,
+    // When the circuit opened or was last probed, `None` while closed
+    opened: Mutex<Option<Instant>>,
+    // Spans dropped since the last log line
+    dropped: AtomicU64,
+}
+
+impl<E: SpanExporter> CircuitExporter<E> {
+    pub(crate) fn new(inner: E, open_after: u32, probe_interval: Duration) -> Self {
+        Self {
+            inner,
+            open_after,
+            probe_interval,
+            failures: AtomicU32::new(0),
+            opened: Mutex::new(None),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    /// Whether a batch is sent: always while closed, once per probe interval while open
+    fn admit(&self) -> bool {
+        let mut opened = self.opened.lock().unwrap_or_else(|e| e.into_inner());
+        match *opened {
+            Some(since) if since.elapsed() < self.probe_interval => false,
+            Some(_) => {
+                *opened = Some(Instant::now());
+                true
+            }
+            None => true,
+        }
+    }
+
+    fn record(&self, exported: bool) {
+        let mut opened = self.opened.lock().unwrap_or_else(|e| e.into_inner());
+        if exported {
+            self.failures.store(0, Ordering::Relaxed);
+            if opened.take().is_some() {
+                let dropped = self.dropped.swap(0, Ordering::Relaxed);
+                log_info(&format!(
+                    "export succeeded, closing the circuit after dropping {dropped} spans"
+                ));
+            }
+            return;
+        }
+        let failures = self.failures.fetch_add(1, Ordering::Relaxed) + 1;
+        let interval = self.probe_interval.as_secs_f64();
+        match *opened {
+            Some(_) => {
+                let dropped = self.dropped.swap(0, Ordering::Relaxed);
+                log_warn(&format!(
+                    "probe export failed, circuit stays open, dropped {dropped} spans in the \
+                     last {interval}s"
+                ));
+            }
+            None if self.open_after > 0 && failures >= self.open_after => {
+                *opened = Some(Instant::now());
+                log_warn(&format!(
+                    "{failures} consecutive exports failed, opening the circuit: spans are \
+                     dropped and an export is attempted every {interval}s"
+                ));
+            }
+            None => {}
+        }
+    }
+}
+
+impl<E: SpanExporter> SpanExporter for CircuitExporter<E> {
+    async fn export(&self, batch: Vec<SpanData>) -> OTelSdkResult {
+        if !self.admit() {
+            self.dropped
+                .fetch_add(batch.len() as u64, Ordering::Relaxed);
+            return Err(OTelSdkError::InternalFailure(
+                "circuit open, batch dropped".into(),
+            ));
+        }
+        let result = self.inner.export(batch).await;
+        self.record(result.is_ok());
+        result
+    }
+
+    fn shutdown_with_timeout(&mut self, timeout: Duration) -> OTelSdkResult {
+        self.inner.shutdown_with_timeout(timeout)
+    }
+
+    fn force_flush(&mut self) -> OTelSdkResult {
+        self.inner.force_flush()
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        self.inner.set_resource(resource);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{span, Collector};
+
+    #[test]
+    fn test_circuit_breaker() {
+        let collector = Collector::default();
+        let exporter = CircuitExporter::new(collector.clone(), 2, DEFAULT_PROBE_INTERVAL);
+        let export = || crate::exporter::get_otel_runtime().block_on(exporter.export(vec![span()]));
+
+        collector.set_down(true);
+        assert!(export().is_err());
+        assert!(exporter.opened.lock().unwrap().is_none());
+        assert!(export().is_err());
+        assert!(exporter.opened.lock().unwrap().is_some());
+
+        // Open: dropped without reaching the collector
+        assert!(export().is_err());
+        assert!(export().is_err());
+        assert_eq!(collector.attempts(), 2);
+        assert_eq!(exporter.dropped.load(Ordering::Relaxed), 2);
+
+        // The failed probe keeps it open and restarts the interval
+        *exporter.opened.lock().unwrap() = Some(Instant::now() - DEFAULT_PROBE_INTERVAL);
+        assert!(export().is_err());
+        assert_eq!(collector.attempts(), 3);
+        assert_eq!(exporter.dropped.load(Ordering::Relaxed), 0);
+        assert!(export().is_err());
+        assert_eq!(collector.attempts(), 3);
+
+        // A successful probe closes it
+        collector.set_down(false);
+        *exporter.opened.lock().unwrap() = Some(Instant::now() - DEFAULT_PROBE_INTERVAL);
+        assert!(export().is_ok());
+        assert!(exporter.opened.lock().unwrap().is_none());
+        assert!(export().is_ok());
+        assert_eq!(collector.attempts(), 5);
+    }
+
+    #[test]
+    fn test_circuit_breaker_disabled() {
+        let collector = Collector::default();
+        collector.set_down(true);
+        let exporter = CircuitExporter::new(collector.clone(), 0, DEFAULT_PROBE_INTERVAL);
+        for _ in 0..10 {
+            let result =
+                crate::exporter::get_otel_runtime().block_on(exporter.export(vec![span()]));
+            assert!(result.is_err());
+        }
+        assert_eq!(collector.attempts(), 10);
+    }
+}
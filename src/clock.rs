@@ -0,0 +1,122 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Attribute recording how far in the future a clamped start time was
+pub(crate) const CLOCK_SKEW_ATTRIBUTE: &str = "haproxy.clock_skew_ms";
+
+/// Allowed difference between the clock of HAProxy and the clocks behind extracted
+/// timestamps (`clock_skew` option)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct ClockSkew {
+    /// Timestamps up to this far in the future are clamped to now, later ones are ignored
+    pub(crate) tolerance: Duration,
+    /// Timestamps further in the past are considered garbage and ignored
+    pub(crate) max_age: Duration,
+}
+
+impl Default for ClockSkew {
+    fn default() -> Self {
+        ClockSkew {
+            tolerance: Duration::from_secs(5),
+            max_age: Duration::from_secs(3600),
+        }
+    }
+}
+
+/// Parses a request start timestamp such as the `X-Request-Start` header of an upstream LB.
+///
+/// Accepts an optional `t=` prefix and Unix timestamps in seconds (with fraction),
+/// milliseconds, microseconds or nanoseconds; the unit is guessed from the magnitude.
+pub(crate) fn parse_timestamp(value: &str) -> Option<SystemTime> {
+    let value = value.trim();
+    let value = value.strip_prefix("t=").unwrap_or(value);
+    let number = value.parse::<f64>().ok().filter(|n| *n > 0.0)?;
+    let secs = if number < 1e11 {
+        number
+    } else if number < 1e14 {
+        number / 1e3
+    } else if number < 1e17 {
+        number / 1e6
+    } else {
+        number / 1e9
+    };
+    Some(UNIX_EPOCH + Duration::from_secs_f64(secs))
+}
+
+/// Returns the start time override if it is plausible relative to `now`, and how far in
+/// the future it was.
+///
+/// Times in the future within the skew tolerance (unsynchronized clocks of the upstream LB)
+/// are clamped to `now` so derived durations are never negative.
+pub(crate) fn start_time_override(
+    value: &str,
+    now: SystemTime,
+    skew: &ClockSkew,
+) -> Option<(SystemTime, Duration)> {
+    let start_time = parse_timestamp(value)?;
+    match now.duration_since(start_time) {
+        Ok(age) if age > skew.max_age => None,
+        Ok(_) => Some((start_time, Duration::ZERO)),
+        Err(e) if e.duration() > skew.tolerance => None,
+        Err(e) => Some((now, e.duration())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_timestamp_units() {
+        let expected = UNIX_EPOCH + Duration::from_millis(1_700_000_000_123);
+        let close = |value: &str| {
+            let parsed = parse_timestamp(value).unwrap();
+            let diff = parsed
+                .duration_since(expected)
+                .unwrap_or_else(|e| e.duration());
+            diff < Duration::from_micros(1)
+        };
+        assert!(close("1700000000.123"));
+        assert!(close("1700000000123"));
+        assert!(close("t=1700000000123000"));
+        assert!(close("1700000000123000000"));
+        assert_eq!(parse_timestamp("t=abc"), None);
+        assert_eq!(parse_timestamp("-1"), None);
+    }
+
+    #[test]
+    fn test_start_time_override() {
+        let now = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let skew = ClockSkew::default();
+        assert_eq!(
+            start_time_override("1699999999.5", now, &skew),
+            Some((now - Duration::from_millis(500), Duration::ZERO))
+        );
+        // In the future: clamped
+        assert_eq!(
+            start_time_override("1700000005", now, &skew),
+            Some((now, Duration::from_secs(5)))
+        );
+        // Too old: ignored
+        assert_eq!(start_time_override("1600000000", now, &skew), None);
+    }
+
+    #[test]
+    fn test_clock_skew() {
+        let now = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let skew = ClockSkew {
+            tolerance: Duration::from_secs(2),
+            max_age: Duration::from_secs(10),
+        };
+        assert_eq!(
+            start_time_override("1700000001", now, &skew),
+            Some((now, Duration::from_secs(1)))
+        );
+        // Beyond the tolerance the timestamp is not trusted at all
+        assert_eq!(start_time_override("1700000003", now, &skew), None);
+        assert_eq!(
+            start_time_override("1699999990", now, &skew),
+            Some((now - Duration::from_secs(10), Duration::ZERO))
+        );
+        assert_eq!(start_time_override("1699999989", now, &skew), None);
+    }
+}
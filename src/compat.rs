@@ -0,0 +1,83 @@
+use haproxy_api::Core;
+use mlua::prelude::{LuaFunction, LuaTable};
+
+/// Oldest HAProxy release whose Lua API the request path is built against
+pub(crate) const MIN_HAPROXY_VERSION: (u32, u32) = (2, 8);
+
+/// What the running HAProxy supports, detected once by `register()`
+#[derive(Debug, PartialEq)]
+pub(crate) struct Compatibility {
+    // `Version` reported by `core.get_info()`, when available
+    pub(crate) version: Option<String>,
+    // HAProxy is older than `MIN_HAPROXY_VERSION`: no spans, trace context passes through
+    pub(crate) propagation_only: bool,
+    // `core.register_filter` exists
+    pub(crate) filters: bool,
+}
+
+impl Compatibility {
+    pub(crate) fn detect(core: &Core) -> Self {
+        let version = core
+            .get_info()
+            .ok()
+            .and_then(|info: LuaTable| info.get::<Option<String>>("Version").ok().flatten());
+        let propagation_only = version
+            .as_deref()
+            .and_then(parse_version)
+            .is_some_and(|version| version < MIN_HAPROXY_VERSION);
+        let filters = core
+            .get::<Option<LuaFunction>>("register_filter")
+            .ok()
+            .flatten()
+            .is_some();
+        Compatibility {
+            version,
+            propagation_only,
+            filters,
+        }
+    }
+
+    /// Explains the degradation, if any
+    pub(crate) fn warning(&self) -> Option<String> {
+        let version = self.version.as_deref().unwrap_or("unknown");
+        let (major, minor) = MIN_HAPROXY_VERSION;
+        if self.propagation_only {
+            return Some(format!(
+                "HAProxy {version} is older than {major}.{minor}, spans are disabled and the \
+                 trace context is only propagated"
+            ));
+        }
+        if !self.filters {
+            return Some(format!(
+                "HAProxy {version} has no Lua filter API, the opentelemetry-trace filter is not \
+                 registered (no client spans, server spans need lua.end_server_span)"
+            ));
+        }
+        None
+    }
+}
+
+/// Parses the major and minor version of a HAProxy version string (e.g. `2.8.3-1ubuntu1`)
+pub(crate) fn parse_version(version: &str) -> Option<(u32, u32)> {
+    let mut parts = version.trim().splitn(3, |c: char| !c.is_ascii_digit());
+    let major = parts.next()?.parse().ok()?;
+    let minor = parts.next()?.parse().ok()?;
+    Some((major, minor))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_version() {
+        assert_eq!(parse_version("2.8.3-1ubuntu1"), Some((2, 8)));
+        assert_eq!(parse_version("3.2.4"), Some((3, 2)));
+        assert_eq!(parse_version("2.6-dev5"), Some((2, 6)));
+        assert_eq!(parse_version("3.0"), Some((3, 0)));
+        assert_eq!(parse_version("dev"), None);
+        assert_eq!(parse_version("3"), None);
+        assert!(parse_version("2.6.9").unwrap() < MIN_HAPROXY_VERSION);
+        assert!(parse_version("2.10.0").unwrap() > MIN_HAPROXY_VERSION);
+    }
+}
@@ -0,0 +1,185 @@
+#[cfg(feature = "gzip")]
+use std::io::Write;
+
+#[cfg(feature = "gzip")]
+use flate2::write::GzEncoder;
+use opentelemetry_http::{Bytes, HttpClient, Request};
+
+use crate::exporter::SendFuture;
+
+/// Compression of OTLP export requests (`otlp.compression`)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Compression {
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    /// Parse compression from string (OTEL spec values), `none` disables compression
+    pub(crate) fn from_str(s: &str) -> Option<Option<Self>> {
+        match s.to_lowercase().as_str() {
+            "gzip" => Some(Some(Compression::Gzip)),
+            "zstd" => Some(Some(Compression::Zstd)),
+            "none" => Some(None),
+            _ => None,
+        }
+    }
+
+    /// Value of the `Content-Encoding` header
+    fn encoding(&self) -> &'static str {
+        match self {
+            Compression::Gzip => "gzip",
+            Compression::Zstd => "zstd",
+        }
+    }
+
+    #[cfg_attr(not(any(feature = "gzip", feature = "zstd")), allow(unused_variables))]
+    fn compress(&self, body: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            #[cfg(feature = "gzip")]
+            Compression::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(body)?;
+                encoder.finish()
+            }
+            // Rejected when the exporter is built
+            #[cfg(not(feature = "gzip"))]
+            Compression::Gzip => Err(std::io::Error::other("gzip is not compiled in")),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => zstd::encode_all(body, zstd::DEFAULT_COMPRESSION_LEVEL),
+            #[cfg(not(feature = "zstd"))]
+            Compression::Zstd => Err(std::io::Error::other("zstd is not compiled in")),
+        }
+    }
+}
+
+/// HTTP client of the OTLP HTTP exporters compressing request bodies.
+///
+/// The HTTP exporters of `opentelemetry-otlp` only support compression for gRPC.
+#[derive(Debug)]
+pub(crate) struct CompressingClient<C> {
+    inner: C,
+    compression: Compression,
+}
+
+impl<C: HttpClient> CompressingClient<C> {
+    pub(crate) fn new(inner: C, compression: Compression) -> Self {
+        Self { inner, compression }
+    }
+}
+
+impl<C: HttpClient> HttpClient for CompressingClient<C> {
+    fn send_bytes<'a, 'b>(&'a self, request: Request<Bytes>) -> SendFuture<'b>
+    where
+        'a: 'b,
+        Self: 'b,
+    {
+        Box::pin(async move {
+            let (mut parts, body) = request.into_parts();
+            let body = self.compression.compress(&body)?;
+            parts.headers.insert(
+                reqwest::header::CONTENT_ENCODING,
+                reqwest::header::HeaderValue::from_static(self.compression.encoding()),
+            );
+            self.inner
+                .send_bytes(Request::from_parts(parts, Bytes::from(body)))
+                .await
+        })
+    }
+}
+
+#[cfg(test)]
+#[cfg_attr(
+    not(any(feature = "gzip", feature = "zstd")),
+    allow(dead_code, unused_imports)
+)]
+mod tests {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+
+    #[cfg(feature = "gzip")]
+    use flate2::read::GzDecoder;
+
+    use super::*;
+
+    // Lowercased header lines and body of a received request
+    type Received = (Vec<String>, Vec<u8>);
+
+    /// Accepts a single request and returns its headers and body
+    fn mock_collector() -> (String, std::thread::JoinHandle<Received>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/v1/traces", listener.local_addr().unwrap());
+        let handle = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut headers = Vec::new();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let line = line.trim_end().to_ascii_lowercase();
+                if line.is_empty() {
+                    break;
+                }
+                headers.push(line);
+            }
+            let length = headers
+                .iter()
+                .find_map(|h| h.strip_prefix("content-length: ")?.parse().ok())
+                .unwrap_or(0);
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            let response = "HTTP/1.1 200 OK\r\ncontent-length: 0\r\nconnection: close\r\n\r\n";
+            reader.get_mut().write_all(response.as_bytes()).unwrap();
+            (headers, body)
+        });
+        (url, handle)
+    }
+
+    /// Sends a payload through the client, returns it as received by the collector
+    fn send(compression: Compression, payload: &[u8]) -> Received {
+        let (url, collector) = mock_collector();
+        let client = CompressingClient::new(reqwest::Client::new(), compression);
+        let request = Request::post(url)
+            .header("content-type", "application/x-protobuf")
+            .body(Bytes::copy_from_slice(payload))
+            .unwrap();
+        let response = crate::exporter::get_otel_runtime()
+            .block_on(client.send_bytes(request))
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        collector.join().unwrap()
+    }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn test_gzip_request() {
+        let payload = b"spans ".repeat(100);
+        let (headers, body) = send(Compression::Gzip, &payload);
+        assert!(headers.contains(&"content-encoding: gzip".to_string()));
+        assert!(headers.contains(&"content-type: application/x-protobuf".to_string()));
+        assert!(body.len() < payload.len());
+        let mut decompressed = Vec::new();
+        GzDecoder::new(body.as_slice())
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, payload);
+    }
+
+    #[test]
+    #[cfg(feature = "zstd")]
+    fn test_zstd_request() {
+        let payload = b"spans ".repeat(100);
+        let (headers, body) = send(Compression::Zstd, &payload);
+        assert!(headers.contains(&"content-encoding: zstd".to_string()));
+        assert!(body.len() < payload.len());
+        assert_eq!(zstd::decode_all(body.as_slice()).unwrap(), payload);
+    }
+
+    #[test]
+    fn test_from_str() {
+        assert_eq!(Compression::from_str("GZIP"), Some(Some(Compression::Gzip)));
+        assert_eq!(Compression::from_str("zstd"), Some(Some(Compression::Zstd)));
+        assert_eq!(Compression::from_str("none"), Some(None));
+        assert_eq!(Compression::from_str("brotli"), None);
+    }
+}
@@ -0,0 +1,293 @@
+use mlua::prelude::{FromLua, Lua, LuaError, LuaResult, LuaTable, LuaValue};
+
+/// Documented values of the enumerated options, listed in error messages
+pub(crate) const SAMPLERS: &[&str] = &["AlwaysOn", "SilentOn", "AlwaysOff", "ParentBased"];
+pub(crate) const PROPAGATORS: &[&str] = &["w3c", "zipkin", "jaeger"];
+pub(crate) const PROTOCOLS: &[&str] = &["grpc", "http/protobuf", "http/json"];
+pub(crate) const COMPRESSIONS: &[&str] = &["gzip", "zstd", "none"];
+pub(crate) const EXPORTERS: &[&str] = &[
+    "otlp", "redis", "file", "pipe", "zipkin", "datadog", "console", "direct",
+];
+pub(crate) const ATTRIBUTE_POLICIES: &[&str] = &["last_wins", "first_wins"];
+pub(crate) const SPAN_EVENTS: &[&str] = &["all", "errors"];
+pub(crate) const MODES: &[&str] = &["ingress", "egress"];
+pub(crate) const NO_SERVER_SPANS: &[&str] = &["mark", "drop"];
+pub(crate) const PROCESSORS: &[&str] = &["batch", "simple"];
+pub(crate) const OVERFLOW_POLICIES: &[&str] = &["drop_new", "buffer"];
+pub(crate) const STRICT_MODES: &[&str] = &["count", "log"];
+
+/// Typed access to a `register()` options table.
+///
+/// Unlike a plain `get().unwrap_or_default()`, a value of the wrong type is reported
+/// with its full option path (e.g. `otlp.http2`) and the expected type or values,
+/// so misconfigurations fail at startup instead of silently falling back to defaults.
+pub(crate) struct OptionTable<'a> {
+    lua: &'a Lua,
+    table: Option<LuaTable>,
+    prefix: String,
+}
+
+impl<'a> OptionTable<'a> {
+    pub(crate) fn new(lua: &'a Lua, table: LuaTable) -> Self {
+        Self {
+            lua,
+            table: Some(table),
+            prefix: String::new(),
+        }
+    }
+
+    /// Options table found under the free-form key `path` (e.g. `tenants.acme`)
+    pub(crate) fn nested(lua: &'a Lua, table: LuaTable, path: &str) -> Self {
+        Self {
+            lua,
+            table: Some(table),
+            prefix: format!("{path}."),
+        }
+    }
+
+    /// Full path of the option `key` (e.g. `otlp.retry.max_attempts`)
+    pub(crate) fn path(&self, key: &str) -> String {
+        format!("{}{key}", self.prefix)
+    }
+
+    fn raw(&self, key: &str) -> LuaResult<LuaValue> {
+        match self.table {
+            Some(ref table) => table.raw_get(key),
+            None => Ok(LuaValue::Nil),
+        }
+    }
+
+    /// Reads an optional value, a conversion failure is reported as "expected `expected`"
+    fn get<T: FromLua>(&self, key: &str, expected: &str) -> LuaResult<Option<T>> {
+        let value = self.raw(key)?;
+        if value.is_nil() {
+            return Ok(None);
+        }
+        let type_name = value.type_name();
+        T::from_lua(value, self.lua).map(Some).map_err(|_| {
+            invalid(
+                &self.path(key),
+                &format!("expected {expected}, got {type_name}"),
+            )
+        })
+    }
+
+    /// Nested options table (`otlp`, `batch`, ...), a missing table reads as empty
+    pub(crate) fn table(&self, key: &str) -> LuaResult<OptionTable<'a>> {
+        Ok(OptionTable {
+            lua: self.lua,
+            table: self.get::<LuaTable>(key, "a table")?,
+            prefix: format!("{}.", self.path(key)),
+        })
+    }
+
+    /// Nested options table or list of options tables (`otlp = { {...}, {...} }`), a missing
+    /// option reads as a single empty table
+    pub(crate) fn tables(&self, key: &str) -> LuaResult<Vec<OptionTable<'a>>> {
+        let Some(table) = self.get::<LuaTable>(key, "a table or a list of tables")? else {
+            return Ok(vec![self.table(key)?]);
+        };
+        if table.raw_len() == 0 {
+            return Ok(vec![self.table(key)?]);
+        }
+        (1..=table.raw_len())
+            .map(|index| {
+                let path = format!("{}[{index}]", self.path(key));
+                match table.raw_get::<LuaValue>(index)? {
+                    LuaValue::Table(table) => Ok(OptionTable::nested(self.lua, table, &path)),
+                    value => Err(invalid(
+                        &path,
+                        &format!("expected a table, got {}", value.type_name()),
+                    )),
+                }
+            })
+            .collect()
+    }
+
+    /// Raw nested table, for options with free-form keys (`tenants`, `end_attributes`)
+    pub(crate) fn raw_table(&self, key: &str) -> LuaResult<Option<LuaTable>> {
+        self.get::<LuaTable>(key, "a table")
+    }
+
+    pub(crate) fn string(&self, key: &str) -> LuaResult<Option<String>> {
+        self.get(key, "a string")
+    }
+
+    pub(crate) fn strings(&self, key: &str) -> LuaResult<Option<Vec<String>>> {
+        self.get(key, "a list of strings")
+    }
+
+    /// Reads a boolean, or its string form (`"true"`/`"false"`, e.g. from `os.getenv`)
+    pub(crate) fn boolean(&self, key: &str) -> LuaResult<Option<bool>> {
+        // Lua truthiness would turn any value (including the string "false") into true
+        match self.raw(key)? {
+            LuaValue::Nil => Ok(None),
+            LuaValue::Boolean(b) => Ok(Some(b)),
+            LuaValue::String(s) => {
+                let s = s.to_string_lossy();
+                parse_boolean(&s).map(Some).ok_or_else(|| {
+                    invalid(&self.path(key), &format!("expected a boolean, got '{s}'"))
+                })
+            }
+            value => Err(self.unexpected_type(key, "a boolean", &value)),
+        }
+    }
+
+    /// Reads a non-negative integer: an integer, a float without fractional part or a
+    /// numeric string (decimal or `0x` hexadecimal)
+    pub(crate) fn unsigned(&self, key: &str) -> LuaResult<Option<u64>> {
+        let expected = "a non-negative integer";
+        let value = match self.raw(key)? {
+            LuaValue::Nil => return Ok(None),
+            LuaValue::Integer(i) => u64::try_from(i).ok().ok_or(i.to_string()),
+            LuaValue::Number(n) => unsigned_number(n).ok_or(n.to_string()),
+            LuaValue::String(s) => {
+                let s = s.to_string_lossy();
+                parse_unsigned(&s).ok_or(format!("'{s}'"))
+            }
+            value => return Err(self.unexpected_type(key, expected, &value)),
+        };
+        value
+            .map(Some)
+            .map_err(|got| invalid(&self.path(key), &format!("expected {expected}, got {got}")))
+    }
+
+    /// Reads a number, or a numeric string
+    fn number(&self, key: &str) -> LuaResult<Option<f64>> {
+        match self.raw(key)? {
+            LuaValue::Nil => Ok(None),
+            LuaValue::Integer(i) => Ok(Some(i as f64)),
+            LuaValue::Number(n) => Ok(Some(n)),
+            LuaValue::String(s) => {
+                let s = s.to_string_lossy();
+                parse_number(&s).map(Some).ok_or_else(|| {
+                    invalid(&self.path(key), &format!("expected a number, got '{s}'"))
+                })
+            }
+            value => Err(self.unexpected_type(key, "a number", &value)),
+        }
+    }
+
+    /// Reads a number between 0.0 and 1.0
+    pub(crate) fn ratio(&self, key: &str) -> LuaResult<Option<f64>> {
+        let value = self.number(key)?;
+        match value {
+            Some(ratio) if !(0.0..=1.0).contains(&ratio) => Err(invalid(
+                &self.path(key),
+                &format!("expected a number between 0.0 and 1.0, got {ratio}"),
+            )),
+            _ => Ok(value),
+        }
+    }
+
+    fn unexpected_type(&self, key: &str, expected: &str, value: &LuaValue) -> LuaError {
+        invalid(
+            &self.path(key),
+            &format!("expected {expected}, got {}", value.type_name()),
+        )
+    }
+
+    /// Reads a string accepted by `accepts`, `allowed` lists the documented values
+    pub(crate) fn choice(
+        &self,
+        key: &str,
+        allowed: &[&str],
+        accepts: impl Fn(&str) -> bool,
+    ) -> LuaResult<Option<String>> {
+        let value = self.string(key)?;
+        match value {
+            Some(ref value) if !accepts(value) => {
+                Err(invalid(&self.path(key), &unexpected(value, allowed)))
+            }
+            _ => Ok(value),
+        }
+    }
+}
+
+/// Error for an invalid option, `path` is the dotted option path
+pub(crate) fn invalid(path: &str, reason: &str) -> LuaError {
+    LuaError::RuntimeError(format!("haproxy-otel: invalid option '{path}': {reason}"))
+}
+
+fn parse_boolean(value: &str) -> Option<bool> {
+    match value.trim().to_ascii_lowercase().as_str() {
+        "true" => Some(true),
+        "false" => Some(false),
+        _ => None,
+    }
+}
+
+fn unsigned_number(value: f64) -> Option<u64> {
+    // Above 2^53 floats are no longer exact integers
+    (value >= 0.0 && value.fract() == 0.0 && value <= (1u64 << 53) as f64).then_some(value as u64)
+}
+
+fn parse_unsigned(value: &str) -> Option<u64> {
+    let value = value.trim();
+    match value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+    {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => value
+            .parse()
+            .ok()
+            .or_else(|| parse_number(value).and_then(unsigned_number)),
+    }
+}
+
+fn parse_number(value: &str) -> Option<f64> {
+    value.trim().parse::<f64>().ok().filter(|n| n.is_finite())
+}
+
+/// Describes an unexpected value of an enumerated option
+fn unexpected(value: &str, allowed: &[&str]) -> String {
+    let allowed = allowed
+        .iter()
+        .map(|a| format!("'{a}'"))
+        .collect::<Vec<_>>()
+        .join(", ");
+    format!("expected one of {allowed}, got '{value}'")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unexpected() {
+        assert_eq!(
+            unexpected("b3", PROPAGATORS),
+            "expected one of 'w3c', 'zipkin', 'jaeger', got 'b3'"
+        );
+    }
+
+    #[test]
+    fn test_parse_boolean() {
+        assert_eq!(parse_boolean("true"), Some(true));
+        assert_eq!(parse_boolean(" FALSE "), Some(false));
+        assert_eq!(parse_boolean("yes"), None);
+        assert_eq!(parse_boolean(""), None);
+    }
+
+    #[test]
+    fn test_parse_unsigned() {
+        assert_eq!(parse_unsigned("8192"), Some(8192));
+        assert_eq!(parse_unsigned("100.0"), Some(100));
+        assert_eq!(parse_unsigned("1e3"), Some(1000));
+        assert_eq!(parse_unsigned("0xE0"), Some(0xe0));
+        assert_eq!(parse_unsigned("0.5"), None);
+        assert_eq!(parse_unsigned("-1"), None);
+        assert_eq!(parse_unsigned("8k"), None);
+        assert_eq!(unsigned_number(2.0), Some(2));
+        assert_eq!(unsigned_number(f64::INFINITY), None);
+    }
+
+    #[test]
+    fn test_parse_number() {
+        assert_eq!(parse_number("0.1"), Some(0.1));
+        assert_eq!(parse_number(" 1 "), Some(1.0));
+        assert_eq!(parse_number("NaN"), None);
+        assert_eq!(parse_number("ten"), None);
+    }
+}
@@ -0,0 +1,121 @@
+use std::hash::Hasher;
+
+use mlua::prelude::LuaValue;
+
+/// Resource attribute identifying the configuration a trace was recorded with
+pub(crate) const CONFIG_HASH_ATTRIBUTE: &str = "haproxy.config.hash";
+
+/// Nested tables deeper than this are hashed by their type only (guards against cycles)
+const MAX_DEPTH: usize = 16;
+
+/// 64-bit FNV-1a, stable across builds and platforms unlike `DefaultHasher`
+struct Fnv1a(u64);
+
+impl Default for Fnv1a {
+    fn default() -> Self {
+        Fnv1a(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for Fnv1a {
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= u64::from(*byte);
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// Hash of the `register()` options and of the HAProxy configuration files, when readable.
+///
+/// Tables are hashed with their keys sorted, so the hash only changes with the configuration
+/// itself and not with the iteration order of Lua tables.
+pub(crate) fn config_hash(options: &LuaValue) -> String {
+    let mut canonical = Vec::new();
+    write_canonical(options, &mut canonical, 0);
+    hash(&canonical, &crate::vars::config_files())
+}
+
+fn hash(canonical_options: &[u8], files: &[(String, Vec<u8>)]) -> String {
+    let mut hasher = Fnv1a::default();
+    hasher.write(canonical_options);
+    for (path, contents) in files {
+        hasher.write(path.as_bytes());
+        hasher.write(contents);
+    }
+    format!("{:016x}", hasher.finish())
+}
+
+/// Serializes a Lua value with sorted table keys
+fn write_canonical(value: &LuaValue, out: &mut Vec<u8>, depth: usize) {
+    match value {
+        LuaValue::Nil => out.extend(b"nil"),
+        LuaValue::Boolean(b) => out.extend(if *b { &b"true"[..] } else { &b"false"[..] }),
+        LuaValue::Integer(i) => out.extend(i.to_string().as_bytes()),
+        LuaValue::Number(n) => out.extend(n.to_string().as_bytes()),
+        LuaValue::String(s) => {
+            out.push(b'"');
+            out.extend(&*s.as_bytes());
+            out.push(b'"');
+        }
+        LuaValue::Table(table) if depth < MAX_DEPTH => {
+            let mut entries = table
+                .pairs::<LuaValue, LuaValue>()
+                .filter_map(Result::ok)
+                .map(|(key, value)| {
+                    let mut entry = Vec::new();
+                    write_canonical(&key, &mut entry, depth + 1);
+                    entry.push(b'=');
+                    write_canonical(&value, &mut entry, depth + 1);
+                    entry
+                })
+                .collect::<Vec<_>>();
+            entries.sort();
+            out.push(b'{');
+            for entry in entries {
+                out.extend(entry);
+                out.push(b',');
+            }
+            out.push(b'}');
+        }
+        // Functions and userdata have no stable representation
+        other => out.extend(other.type_name().as_bytes()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fnv1a(bytes: &[u8]) -> u64 {
+        let mut hasher = Fnv1a::default();
+        hasher.write(bytes);
+        hasher.finish()
+    }
+
+    #[test]
+    fn test_fnv1a() {
+        // Reference values of the FNV-1a 64-bit test suite
+        assert_eq!(fnv1a(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(fnv1a(b"a"), 0xaf63_dc4c_8601_ec8c);
+        assert_eq!(fnv1a(b"foobar"), 0x8594_4171_f739_67e8);
+    }
+
+    #[test]
+    fn test_hash() {
+        let options = b"{\"name\"=\"edge\",}";
+        let options_only = hash(options, &[]);
+        assert_eq!(options_only, format!("{:016x}", fnv1a(options)));
+        let cfg = [("/etc/haproxy/haproxy.cfg".to_string(), b"global\n".to_vec())];
+        assert_ne!(hash(options, &cfg), options_only);
+        let changed = [(
+            "/etc/haproxy/haproxy.cfg".to_string(),
+            b"global\n\n".to_vec(),
+        )];
+        assert_ne!(hash(options, &cfg), hash(options, &changed));
+    }
+}
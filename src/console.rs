@@ -0,0 +1,92 @@
+use std::io::Write;
+use std::sync::Mutex;
+
+use opentelemetry_sdk::error::{OTelSdkError, OTelSdkResult};
+use opentelemetry_sdk::trace::{SpanData, SpanExporter};
+
+/// Prefix of the span lines, to tell them apart from the other lines of the HAProxy logs
+const LINE_PREFIX: &str = "haproxy-otel span: ";
+
+/// Span exporter printing every span as a JSON line to stderr (`exporter = "console"`), to
+/// check span content locally without a collector.
+///
+/// Spans are printed after sampling, filtering and redaction, as they would be exported.
+#[derive(Debug)]
+pub(crate) struct ConsoleExporter<W> {
+    service_name: String,
+    out: Mutex<W>,
+}
+
+impl ConsoleExporter<std::io::Stderr> {
+    pub(crate) fn new(service_name: &str) -> Self {
+        Self::with_writer(service_name, std::io::stderr())
+    }
+}
+
+impl<W: Write> ConsoleExporter<W> {
+    fn with_writer(service_name: &str, out: W) -> Self {
+        Self {
+            service_name: service_name.to_string(),
+            out: Mutex::new(out),
+        }
+    }
+}
+
+impl<W: Write + Send + std::fmt::Debug> SpanExporter for ConsoleExporter<W> {
+    async fn export(&self, batch: Vec<SpanData>) -> OTelSdkResult {
+        let mut lines = String::new();
+        for span in &batch {
+            let json = crate::json::span_to_json(span, &self.service_name);
+            lines.push_str(&format!("{LINE_PREFIX}{json}\n"));
+        }
+        // One write per batch, so lines of concurrent batches don't interleave
+        let mut out = self.out.lock().unwrap_or_else(|e| e.into_inner());
+        out.write_all(lines.as_bytes())
+            .and_then(|_| out.flush())
+            .map_err(|e| OTelSdkError::InternalFailure(format!("console export failed: {e}")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use opentelemetry::KeyValue;
+
+    use super::*;
+    use crate::fixtures::{self, span_context, TRACE_ID};
+
+    fn span(span_id: u64) -> SpanData {
+        SpanData {
+            span_context: span_context(TRACE_ID, span_id),
+            end_time: UNIX_EPOCH + Duration::from_millis(12),
+            attributes: vec![KeyValue::new("http.response.status_code", 200)],
+            ..fixtures::span()
+        }
+    }
+
+    #[test]
+    fn test_console_export() {
+        let exporter = ConsoleExporter::with_writer("haproxy-ingress", Vec::new());
+        crate::exporter::get_otel_runtime()
+            .block_on(exporter.export(vec![span(1), span(2)]))
+            .unwrap();
+
+        let out = exporter.out.into_inner().unwrap();
+        let lines = String::from_utf8(out).unwrap();
+        let spans = lines
+            .lines()
+            .map(|line| {
+                let json = line.strip_prefix(LINE_PREFIX).expect(line);
+                serde_json::from_str::<serde_json::Value>(json).unwrap()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(spans.len(), 2);
+        assert_eq!(spans[0]["service"], "haproxy-ingress");
+        assert_eq!(spans[0]["trace_id"], "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(spans[0]["span_id"], "0000000000000001");
+        assert_eq!(spans[1]["span_id"], "0000000000000002");
+        assert_eq!(spans[0]["duration_ms"], 12.0);
+        assert_eq!(spans[0]["attributes"]["http.response.status_code"], 200);
+    }
+}
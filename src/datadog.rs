@@ -0,0 +1,327 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::time::UNIX_EPOCH;
+
+use opentelemetry::trace::{SpanId, SpanKind, Status};
+use opentelemetry::Value as OtelValue;
+use opentelemetry_http::{Bytes, HttpClient, Request};
+use opentelemetry_sdk::error::{OTelSdkError, OTelSdkResult};
+use opentelemetry_sdk::trace::{SpanData, SpanExporter};
+use opentelemetry_sdk::Resource;
+use serde_json::{json, Map, Value};
+
+/// Trace intake of the agent, accepting JSON as well as msgpack
+const TRACES_PATH: &str = "/v0.4/traces";
+
+const DEFAULT_AGENT_HOST: &str = "127.0.0.1";
+const DEFAULT_AGENT_PORT: &str = "8126";
+
+/// OpenTelemetry attributes renamed to the Datadog tags the APM UI knows
+const TAG_NAMES: &[(&str, &str)] = &[
+    ("http.request.method", "http.method"),
+    ("http.response.status_code", "http.status_code"),
+    ("url.template", "http.route"),
+    ("client.address", "network.client.ip"),
+    ("server.address", "out.host"),
+    ("server.port", "network.destination.port"),
+];
+
+/// Datadog tag of an attribute. The peer of a client span is the server it calls, of the
+/// other spans the client of HAProxy.
+fn tag_name<'a>(key: &'a str, kind: &SpanKind) -> &'a str {
+    match (key, kind) {
+        ("network.peer.address", SpanKind::Client) => "peer.hostname",
+        ("network.peer.address", _) => "network.client.ip",
+        _ => TAG_NAMES
+            .iter()
+            .find(|(name, _)| *name == key)
+            .map_or(key, |(_, tag)| tag),
+    }
+}
+
+/// Tags kept as strings although their values are numbers
+const STRING_TAGS: &[&str] = &["http.status_code"];
+
+/// Base URL of the agent from the `datadog.agent_url` option, `DD_TRACE_AGENT_URL`, or
+/// `DD_AGENT_HOST` and `DD_TRACE_AGENT_PORT`
+pub(crate) fn agent_url(option: Option<&str>, env: impl Fn(&str) -> Option<String>) -> String {
+    let env = |name: &str| env(name).filter(|value| !value.is_empty());
+    if let Some(url) = option
+        .map(str::to_string)
+        .or_else(|| env("DD_TRACE_AGENT_URL"))
+    {
+        return url;
+    }
+    let host = env("DD_AGENT_HOST").unwrap_or(DEFAULT_AGENT_HOST.to_string());
+    let port = env("DD_TRACE_AGENT_PORT").unwrap_or(DEFAULT_AGENT_PORT.to_string());
+    format!("http://{host}:{port}")
+}
+
+/// Socket path (`unix:///var/run/datadog/apm.socket`) and request URL of the trace intake
+pub(crate) fn traces_endpoint(agent_url: &str) -> (Option<PathBuf>, String) {
+    match agent_url.strip_prefix(crate::exporter::UNIX_SCHEME) {
+        Some(socket) => (
+            Some(PathBuf::from(socket)),
+            format!("http://localhost{TRACES_PATH}"),
+        ),
+        None => (
+            None,
+            format!("{}{TRACES_PATH}", agent_url.trim_end_matches('/')),
+        ),
+    }
+}
+
+fn unix_nanos(time: std::time::SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as i64
+}
+
+/// Datadog span (v0.4 JSON) of a finished span
+fn encode_span(span: &SpanData, service_name: &str, resource: &Map<String, Value>) -> Value {
+    let mut meta = resource.clone();
+    let mut metrics = Map::new();
+    for kv in &span.attributes {
+        let key = tag_name(kv.key.as_str(), &span.span_kind);
+        match &kv.value {
+            OtelValue::I64(i) if !STRING_TAGS.contains(&key) => {
+                metrics.insert(key.to_string(), json!(*i as f64));
+            }
+            OtelValue::F64(f) => {
+                metrics.insert(key.to_string(), json!(f));
+            }
+            value => {
+                meta.insert(key.to_string(), value.to_string().into());
+            }
+        }
+    }
+    let (kind, span_type) = match span.span_kind {
+        SpanKind::Server => ("server", "web"),
+        SpanKind::Client => ("client", "http"),
+        SpanKind::Producer => ("producer", "custom"),
+        SpanKind::Consumer => ("consumer", "custom"),
+        SpanKind::Internal => ("internal", "custom"),
+    };
+    meta.insert("span.kind".to_string(), kind.into());
+    let error = match &span.status {
+        Status::Error { description } => {
+            meta.insert("error.message".to_string(), description.to_string().into());
+            1
+        }
+        _ => 0,
+    };
+    // 128-bit trace ids are split, the agent joins the high bits back from `_dd.p.tid`
+    let trace_id = u128::from_be_bytes(span.span_context.trace_id().to_bytes());
+    if trace_id >> 64 != 0 {
+        let high = format!("{:016x}", (trace_id >> 64) as u64);
+        meta.insert("_dd.p.tid".to_string(), high.into());
+    }
+    // Spans reaching the exporter are sampled, the agent must keep them
+    metrics.insert("_sampling_priority_v1".to_string(), json!(1.0));
+    let span_id = |id: SpanId| u64::from_be_bytes(id.to_bytes());
+    json!({
+        "trace_id": trace_id as u64,
+        "span_id": span_id(span.span_context.span_id()),
+        "parent_id": span_id(span.parent_span_id),
+        "name": format!("{}.{kind}", span.instrumentation_scope.name()),
+        "resource": span.name,
+        "service": service_name,
+        "type": span_type,
+        "start": unix_nanos(span.start_time),
+        "duration": (unix_nanos(span.end_time) - unix_nanos(span.start_time)).max(0),
+        "error": error,
+        "meta": meta,
+        "metrics": metrics,
+    })
+}
+
+/// Request body of the trace intake, a list of traces holding their spans, and the number
+/// of traces
+fn encode(
+    batch: &[SpanData],
+    service_name: &str,
+    resource: &Map<String, Value>,
+) -> (Vec<u8>, usize) {
+    let mut traces = BTreeMap::<_, Vec<_>>::new();
+    for span in batch {
+        let trace_id = u128::from_be_bytes(span.span_context.trace_id().to_bytes());
+        traces
+            .entry(trace_id)
+            .or_default()
+            .push(encode_span(span, service_name, resource));
+    }
+    let count = traces.len();
+    let traces = traces.into_values().collect::<Vec<_>>();
+    (serde_json::to_vec(&traces).unwrap_or_default(), count)
+}
+
+/// Span exporter sending the spans to the trace intake of a Datadog agent
+/// (`exporter = "datadog"`), for setups without an OpenTelemetry collector.
+///
+/// Attributes become tags (`meta`, numbers in `metrics`), the common HTTP ones renamed to
+/// their Datadog names. Span events are not sent, the v0.4 intake has no place for them.
+#[derive(Debug)]
+pub(crate) struct DatadogExporter<C> {
+    client: C,
+    endpoint: String,
+    service_name: String,
+    // Resource attributes, added as tags to every span
+    resource: Map<String, Value>,
+}
+
+impl<C: HttpClient> DatadogExporter<C> {
+    pub(crate) fn new(client: C, endpoint: String, service_name: &str) -> Self {
+        Self {
+            client,
+            endpoint,
+            service_name: service_name.to_string(),
+            resource: Map::new(),
+        }
+    }
+}
+
+impl<C: HttpClient> SpanExporter for DatadogExporter<C> {
+    async fn export(&self, batch: Vec<SpanData>) -> OTelSdkResult {
+        let (body, traces) = encode(&batch, &self.service_name, &self.resource);
+        let request = Request::builder()
+            .method("POST")
+            .uri(&self.endpoint)
+            .header("content-type", "application/json")
+            .header("x-datadog-trace-count", traces)
+            .header("datadog-meta-lang", "rust")
+            .header("datadog-meta-tracer-version", env!("CARGO_PKG_VERSION"))
+            .body(Bytes::from(body))
+            .map_err(|e| OTelSdkError::InternalFailure(e.to_string()))?;
+        let response = (self.client.send_bytes(request).await)
+            .map_err(|e| OTelSdkError::InternalFailure(format!("datadog export failed: {e}")))?;
+        match response.status().is_success() {
+            true => Ok(()),
+            false => Err(OTelSdkError::InternalFailure(format!(
+                "datadog agent returned {}",
+                response.status()
+            ))),
+        }
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        self.resource = resource
+            .iter()
+            .filter(|(key, _)| key.as_str() != "service.name")
+            .map(|(key, value)| (key.to_string(), value.to_string().into()))
+            .collect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use opentelemetry::KeyValue;
+
+    use super::*;
+    use crate::fixtures::{self, span_context, TRACE_ID};
+
+    fn span(span_id: u64, parent_span_id: u64, kind: SpanKind) -> SpanData {
+        SpanData {
+            span_context: span_context(TRACE_ID, span_id),
+            parent_span_id: SpanId::from(parent_span_id),
+            span_kind: kind,
+            start_time: UNIX_EPOCH + Duration::from_secs(1),
+            end_time: UNIX_EPOCH + Duration::from_millis(1012),
+            attributes: vec![
+                KeyValue::new("http.request.method", "GET"),
+                KeyValue::new("http.response.status_code", 502),
+                KeyValue::new("haproxy.backend.name", "app"),
+                KeyValue::new("haproxy.retries", 2),
+                KeyValue::new("network.peer.address", "10.0.0.1"),
+            ],
+            status: Status::error("5xx status code"),
+            instrumentation_scope: opentelemetry::InstrumentationScope::builder("haproxy-otel")
+                .build(),
+            ..fixtures::span()
+        }
+    }
+
+    #[test]
+    fn test_encode() {
+        let resource = Map::from_iter([("haproxy.role".to_string(), "edge".into())]);
+        let batch = [span(1, 0, SpanKind::Server), span(2, 1, SpanKind::Client)];
+        let (body, traces) = encode(&batch, "haproxy-ingress", &resource);
+        assert_eq!(traces, 1);
+        let body = serde_json::from_slice::<Value>(&body).unwrap();
+        let server = &body[0][0];
+        assert_eq!(server["trace_id"], 0xa3ce_929d_0e0e_4736u64);
+        assert_eq!(server["meta"]["_dd.p.tid"], "4bf92f3577b34da6");
+        assert_eq!(server["span_id"], 1);
+        assert_eq!(server["parent_id"], 0);
+        assert_eq!(server["name"], "haproxy-otel.server");
+        assert_eq!(server["resource"], "GET example.com");
+        assert_eq!(server["service"], "haproxy-ingress");
+        assert_eq!(server["type"], "web");
+        assert_eq!(server["start"], 1_000_000_000);
+        assert_eq!(server["duration"], 12_000_000);
+        assert_eq!(server["error"], 1);
+        assert_eq!(server["meta"]["error.message"], "5xx status code");
+        assert_eq!(server["meta"]["http.method"], "GET");
+        assert_eq!(server["meta"]["http.status_code"], "502");
+        assert_eq!(server["meta"]["haproxy.backend.name"], "app");
+        assert_eq!(server["meta"]["haproxy.role"], "edge");
+        assert_eq!(server["meta"]["network.client.ip"], "10.0.0.1");
+        assert_eq!(server["metrics"]["haproxy.retries"], 2.0);
+        assert_eq!(server["metrics"]["_sampling_priority_v1"], 1.0);
+        let client = &body[0][1];
+        assert_eq!(client["parent_id"], 1);
+        assert_eq!(client["type"], "http");
+        assert_eq!(client["meta"]["span.kind"], "client");
+        assert_eq!(client["meta"]["peer.hostname"], "10.0.0.1");
+        assert!(client["meta"].get("network.client.ip").is_none());
+    }
+
+    #[test]
+    fn test_agent_url() {
+        let env = |vars: &'static [(&'static str, &'static str)]| {
+            move |name: &str| {
+                let var = vars.iter().find(|(key, _)| *key == name);
+                var.map(|(_, value)| value.to_string())
+            }
+        };
+        assert_eq!(agent_url(None, env(&[])), "http://127.0.0.1:8126");
+        assert_eq!(
+            agent_url(
+                None,
+                env(&[
+                    ("DD_AGENT_HOST", "datadog"),
+                    ("DD_TRACE_AGENT_PORT", "9126")
+                ])
+            ),
+            "http://datadog:9126"
+        );
+        assert_eq!(
+            agent_url(
+                None,
+                env(&[("DD_TRACE_AGENT_URL", "unix:///var/run/datadog/apm.socket")])
+            ),
+            "unix:///var/run/datadog/apm.socket"
+        );
+        let option = Some("http://agent:8126");
+        assert_eq!(
+            agent_url(option, env(&[("DD_AGENT_HOST", "datadog")])),
+            "http://agent:8126"
+        );
+    }
+
+    #[test]
+    fn test_traces_endpoint() {
+        assert_eq!(
+            traces_endpoint("http://datadog:8126/"),
+            (None, "http://datadog:8126/v0.4/traces".to_string())
+        );
+        assert_eq!(
+            traces_endpoint("unix:///var/run/datadog/apm.socket"),
+            (
+                Some(PathBuf::from("/var/run/datadog/apm.socket")),
+                "http://localhost/v0.4/traces".to_string()
+            )
+        );
+    }
+}
@@ -0,0 +1,380 @@
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use opentelemetry::Context;
+use opentelemetry_proto::tonic::common::v1::InstrumentationScope;
+use opentelemetry_proto::tonic::resource::v1::Resource as ProtoResource;
+use opentelemetry_proto::tonic::trace::v1::{ScopeSpans, Span as ProtoSpan};
+use opentelemetry_proto::transform::common::tonic::ResourceAttributesWithSchema;
+use opentelemetry_sdk::error::{OTelSdkError, OTelSdkResult};
+use opentelemetry_sdk::trace::{Span, SpanData, SpanProcessor};
+use opentelemetry_sdk::Resource;
+use prost::encoding::{encode_key, encode_varint, WireType};
+use prost::Message as _;
+
+use crate::token::TokenFile;
+
+/// Spans waiting for the writer thread, further spans are dropped (and counted)
+const QUEUE_SIZE: usize = 2048;
+
+/// Size of the pending request body that triggers an export before the flush interval
+const MAX_BODY_SIZE: usize = 1 << 20;
+
+/// Parsed `http://` traces endpoint
+#[derive(Debug, PartialEq)]
+struct HttpEndpoint {
+    // host:port, also sent as the Host header
+    authority: String,
+    path: String,
+}
+
+impl HttpEndpoint {
+    fn parse(url: &str) -> Result<Self, String> {
+        let rest = url.strip_prefix("http://").ok_or_else(|| {
+            format!("unsupported endpoint '{url}' for exporter 'direct' (expected http://)")
+        })?;
+        let (host, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/"),
+        };
+        let authority = match host {
+            "" => return Err(format!("missing host in endpoint '{url}'")),
+            host => crate::exporter::with_default_port(host, 80),
+        };
+        Ok(HttpEndpoint {
+            authority,
+            path: path.to_string(),
+        })
+    }
+}
+
+enum Message {
+    // One encoded `ScopeSpans` holding a single span
+    Span(Vec<u8>),
+    // Export everything queued so far and report the outcome
+    Flush(mpsc::Sender<Result<(), String>>),
+}
+
+/// Experimental export path without the batch processor and the tokio runtime.
+///
+/// Spans are encoded to OTLP protobuf on the HAProxy thread that ends them and handed to a
+/// single writer thread over a bounded channel. The writer concatenates them into one
+/// `ExportTraceServiceRequest` and POSTs it with a blocking HTTP/1.1 request every flush
+/// interval (or once the body reaches `MAX_BODY_SIZE`). When the writer falls behind, spans
+/// are dropped rather than blocking HAProxy.
+#[derive(Debug)]
+pub(crate) struct DirectProcessor {
+    sender: SyncSender<Message>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl DirectProcessor {
+    pub(crate) fn new(
+        endpoint: &str,
+        headers: &[(String, String)],
+        token: Option<Arc<TokenFile>>,
+        resource: &Resource,
+        flush_interval: Duration,
+        timeout: Duration,
+    ) -> Result<Self, String> {
+        let endpoint = HttpEndpoint::parse(endpoint)?;
+        let (sender, receiver) = mpsc::sync_channel(QUEUE_SIZE);
+        let dropped = Arc::new(AtomicU64::new(0));
+        let writer = Writer {
+            endpoint,
+            headers: headers.to_vec(),
+            token,
+            resource: encode_resource(resource),
+            timeout,
+            dropped: Arc::clone(&dropped),
+        };
+        std::thread::Builder::new()
+            .name("haproxy-otel-direct".to_string())
+            .spawn(move || writer.run(receiver, flush_interval))
+            .map_err(|e| format!("failed to start the export thread: {e}"))?;
+        Ok(DirectProcessor { sender, dropped })
+    }
+
+    fn flush(&self, timeout: Duration) -> OTelSdkResult {
+        let (reply, outcome) = mpsc::channel();
+        self.sender
+            .send(Message::Flush(reply))
+            .map_err(|_| OTelSdkError::AlreadyShutdown)?;
+        match outcome.recv_timeout(timeout) {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(e)) => Err(OTelSdkError::InternalFailure(e)),
+            Err(_) => Err(OTelSdkError::Timeout(timeout)),
+        }
+    }
+}
+
+impl SpanProcessor for DirectProcessor {
+    fn on_start(&self, _span: &mut Span, _cx: &Context) {}
+
+    fn on_end(&self, span: SpanData) {
+        if !span.span_context.is_sampled() {
+            return;
+        }
+        match self.sender.try_send(Message::Span(encode_span(span))) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) | Err(TrySendError::Disconnected(_)) => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    fn force_flush(&self) -> OTelSdkResult {
+        self.flush(Duration::from_secs(30))
+    }
+
+    fn shutdown_with_timeout(&self, timeout: Duration) -> OTelSdkResult {
+        self.flush(timeout)
+    }
+}
+
+/// Encodes a span as a `ScopeSpans` message, the unit concatenated by the writer
+fn encode_span(span: SpanData) -> Vec<u8> {
+    let scope = InstrumentationScope::from((&span.instrumentation_scope, None));
+    ScopeSpans {
+        scope: Some(scope),
+        spans: vec![ProtoSpan::from(span)],
+        schema_url: String::new(),
+    }
+    .encode_to_vec()
+}
+
+/// Encodes the resource as the `resource` field of a `ResourceSpans` message
+fn encode_resource(resource: &Resource) -> Vec<u8> {
+    let attributes = ResourceAttributesWithSchema::from(resource).attributes.0;
+    let resource = ProtoResource {
+        attributes,
+        ..Default::default()
+    };
+    let mut buf = Vec::new();
+    encode_key(1, WireType::LengthDelimited, &mut buf);
+    encode_varint(resource.encoded_len() as u64, &mut buf);
+    resource.encode_raw(&mut buf);
+    buf
+}
+
+/// Pending `ResourceSpans` message: the resource followed by one `ScopeSpans` per span
+struct Body {
+    resource_spans: Vec<u8>,
+    spans: usize,
+}
+
+impl Body {
+    fn new(resource: &[u8]) -> Self {
+        Body {
+            resource_spans: resource.to_vec(),
+            spans: 0,
+        }
+    }
+
+    fn push(&mut self, scope_spans: &[u8]) {
+        encode_key(2, WireType::LengthDelimited, &mut self.resource_spans);
+        encode_varint(scope_spans.len() as u64, &mut self.resource_spans);
+        self.resource_spans.extend_from_slice(scope_spans);
+        self.spans += 1;
+    }
+
+    /// Wraps the `ResourceSpans` into an `ExportTraceServiceRequest`
+    fn request(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(self.resource_spans.len() + 8);
+        encode_key(1, WireType::LengthDelimited, &mut buf);
+        encode_varint(self.resource_spans.len() as u64, &mut buf);
+        buf.extend_from_slice(&self.resource_spans);
+        buf
+    }
+}
+
+struct Writer {
+    endpoint: HttpEndpoint,
+    // Additional request headers (`otlp.headers`)
+    headers: Vec<(String, String)>,
+    // Bearer token file (`otlp.bearer_token_file`), read before every export
+    token: Option<Arc<TokenFile>>,
+    resource: Vec<u8>,
+    timeout: Duration,
+    dropped: Arc<AtomicU64>,
+}
+
+impl Writer {
+    fn run(self, receiver: Receiver<Message>, flush_interval: Duration) {
+        let mut body = Body::new(&self.resource);
+        let mut deadline = Instant::now() + flush_interval;
+        loop {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            match receiver.recv_timeout(timeout) {
+                Ok(Message::Span(scope_spans)) => {
+                    body.push(&scope_spans);
+                    if body.resource_spans.len() < MAX_BODY_SIZE {
+                        continue;
+                    }
+                    let _ = self.export(&mut body);
+                }
+                Ok(Message::Flush(reply)) => {
+                    // Spans queued before the flush request are already in the body
+                    let _ = reply.send(self.export(&mut body));
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    let _ = self.export(&mut body);
+                    deadline = Instant::now() + flush_interval;
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    let _ = self.export(&mut body);
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Exports and resets the pending body, failures are logged and the spans discarded
+    fn export(&self, body: &mut Body) -> Result<(), String> {
+        let dropped = self.dropped.swap(0, Ordering::Relaxed);
+        if dropped > 0 {
+            crate::exporter::log_warn(&format!(
+                "direct exporter queue full, dropped {dropped} spans"
+            ));
+        }
+        if body.spans == 0 {
+            return Ok(());
+        }
+        let request = body.request();
+        let spans = body.spans;
+        *body = Body::new(&self.resource);
+        let result = self.post(&request);
+        if let Err(ref e) = result {
+            crate::exporter::log_error(&format!("direct export of {spans} spans failed: {e}"));
+        }
+        result
+    }
+
+    fn post(&self, request: &[u8]) -> Result<(), String> {
+        let mut headers = self.headers.clone();
+        if let Some(ref token) = self.token {
+            headers.push(("Authorization".to_string(), token.authorization()));
+        }
+        let addr = self
+            .endpoint
+            .authority
+            .to_socket_addrs()
+            .map_err(|e| e.to_string())?
+            .next()
+            .ok_or_else(|| format!("could not resolve '{}'", self.endpoint.authority))?;
+        let mut stream =
+            TcpStream::connect_timeout(&addr, self.timeout).map_err(|e| e.to_string())?;
+        stream
+            .set_read_timeout(Some(self.timeout))
+            .and_then(|_| stream.set_write_timeout(Some(self.timeout)))
+            .map_err(|e| e.to_string())?;
+        stream
+            .write_all(&request_head(&self.endpoint, &headers, request.len()))
+            .and_then(|_| stream.write_all(request))
+            .map_err(|e| e.to_string())?;
+        let mut status = String::new();
+        BufReader::new(stream)
+            .read_line(&mut status)
+            .map_err(|e| e.to_string())?;
+        match status.split_whitespace().nth(1) {
+            Some(code) if code.starts_with('2') => Ok(()),
+            _ => Err(format!("unexpected response '{}'", status.trim_end())),
+        }
+    }
+}
+
+/// HTTP/1.1 request line and headers of an export request
+fn request_head(
+    endpoint: &HttpEndpoint,
+    headers: &[(String, String)],
+    content_length: usize,
+) -> Vec<u8> {
+    let mut head = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/x-protobuf\r\n",
+        endpoint.path, endpoint.authority
+    );
+    for (name, value) in headers {
+        head += &format!("{name}: {value}\r\n");
+    }
+    head += &format!("Content-Length: {content_length}\r\nConnection: close\r\n\r\n");
+    head.into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use opentelemetry::KeyValue;
+    use opentelemetry_proto::tonic::collector::trace::v1::ExportTraceServiceRequest;
+
+    use super::*;
+    use crate::fixtures::{self, span_context};
+
+    fn span(id: u64) -> SpanData {
+        SpanData {
+            span_context: span_context(1u128, id),
+            attributes: vec![KeyValue::new("http.request.method", "GET")],
+            ..fixtures::span()
+        }
+    }
+
+    #[test]
+    fn test_concatenated_request() {
+        let resource = Resource::builder().with_service_name("haproxy").build();
+        let mut body = Body::new(&encode_resource(&resource));
+        body.push(&encode_span(span(1)));
+        body.push(&encode_span(span(2)));
+
+        let request = ExportTraceServiceRequest::decode(body.request().as_slice()).unwrap();
+        assert_eq!(request.resource_spans.len(), 1);
+        let resource_spans = &request.resource_spans[0];
+        let attributes = &resource_spans.resource.as_ref().unwrap().attributes;
+        assert!(attributes.iter().any(|kv| kv.key == "service.name"));
+        let spans = resource_spans
+            .scope_spans
+            .iter()
+            .flat_map(|scope_spans| &scope_spans.spans)
+            .map(|span| span.span_id.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            spans,
+            vec![1u64.to_be_bytes().to_vec(), 2u64.to_be_bytes().to_vec()]
+        );
+        assert_eq!(
+            resource_spans.scope_spans[0].scope.as_ref().unwrap().name,
+            "test"
+        );
+    }
+
+    #[test]
+    fn test_parse_endpoint() {
+        assert_eq!(
+            HttpEndpoint::parse("http://collector:4318/v1/traces").unwrap(),
+            HttpEndpoint {
+                authority: "collector:4318".to_string(),
+                path: "/v1/traces".to_string(),
+            }
+        );
+        assert_eq!(
+            HttpEndpoint::parse("http://collector").unwrap(),
+            HttpEndpoint {
+                authority: "collector:80".to_string(),
+                path: "/".to_string(),
+            }
+        );
+        let authority = |url| HttpEndpoint::parse(url).unwrap().authority;
+        assert_eq!(authority("http://[::1]/v1/traces"), "[::1]:80");
+        assert_eq!(authority("http://[::1]:4318/v1/traces"), "[::1]:4318");
+        assert!(HttpEndpoint::parse("https://collector:4318/v1/traces").is_err());
+        assert!(HttpEndpoint::parse("http:///v1/traces").is_err());
+        let endpoint = HttpEndpoint::parse("http://c:4318/v1/traces").unwrap();
+        let head = request_head(&endpoint, &[], 42);
+        assert!(head.starts_with(b"POST /v1/traces HTTP/1.1\r\nHost: c:4318\r\n"));
+        assert!(head.ends_with(b"Content-Length: 42\r\nConnection: close\r\n\r\n"));
+        let headers = [("x-api-key".to_string(), "secret".to_string())];
+        let head = String::from_utf8(request_head(&endpoint, &headers, 42)).unwrap();
+        assert!(head.contains("\r\nx-api-key: secret\r\nContent-Length: 42\r\n"));
+    }
+}
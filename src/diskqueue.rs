@@ -0,0 +1,606 @@
+use std::borrow::Cow;
+use std::path::{Path, PathBuf};
+use std::str::FromStr as _;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Once;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use opentelemetry::trace::{
+    Event, Link, SpanContext, SpanId, SpanKind, Status, TraceFlags, TraceId, TraceState,
+};
+use opentelemetry::{Array, InstrumentationScope, KeyValue, StringValue, Value};
+use opentelemetry_proto::tonic::collector::trace::v1::ExportTraceServiceRequest;
+use opentelemetry_proto::tonic::common::v1::any_value;
+use opentelemetry_proto::tonic::common::v1::KeyValue as ProtoKeyValue;
+use opentelemetry_proto::tonic::trace::v1::{span, status, ScopeSpans, Span as ProtoSpan};
+use opentelemetry_proto::transform::common::tonic::ResourceAttributesWithSchema;
+use opentelemetry_proto::transform::trace::tonic::group_spans_by_resource_and_scope;
+use opentelemetry_sdk::error::OTelSdkResult;
+use opentelemetry_sdk::trace::{SpanData, SpanEvents, SpanExporter, SpanLinks};
+use opentelemetry_sdk::Resource;
+use prost::Message as _;
+
+use crate::exporter::{log_debug, log_info, log_warn, DiskQueueOptions};
+use crate::retry::is_retryable_error;
+
+const DEFAULT_MAX_SIZE_MB: u64 = 100;
+
+/// Extension of the queued batch files
+const EXTENSION: &str = "otlp";
+
+/// Queued batches exported after a successful export, so draining doesn't hold up the
+/// batches of the running process for long
+const DRAIN_BATCHES: usize = 16;
+
+/// Time the pipelines have to write their in-memory batches to the queue when the process exits
+const EXIT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Set when the process exits, batches are then only written to the queue
+static EXITING: AtomicBool = AtomicBool::new(false);
+
+static AT_EXIT: Once = Once::new();
+
+extern "C" {
+    fn atexit(callback: extern "C" fn()) -> std::ffi::c_int;
+}
+
+/// Writes the spans still in the batch queues of the pipelines to the disk queue when the
+/// worker exits, the next worker exports them
+extern "C" fn on_exit() {
+    EXITING.store(true, Ordering::Relaxed);
+    crate::exporter::shutdown_disk_queues(EXIT_TIMEOUT);
+}
+
+/// Span exporter writing every batch to a directory before exporting it (`disk_queue.path`),
+/// so the batches of a worker stopped by a reload, or that failed to export, are not lost.
+///
+/// Each batch is a file holding its `ExportTraceServiceRequest` in the protobuf encoding,
+/// removed once exported or rejected by the collector. The files left behind by failed
+/// exports, and by processes that exited, are exported again after the next successful
+/// export, oldest first. Batches are exported without being written once the files reach
+/// `disk_queue.max_size_mb`. When the process exits, the batches still in memory are only
+/// written. Without `disk_queue.path` the batches are only exported.
+#[derive(Debug)]
+pub(crate) struct DiskQueueExporter<E> {
+    inner: E,
+    // Directory of the queued batches, `None` when disabled
+    dir: Option<PathBuf>,
+    // Prefix of the files of the registration, `<registration>-`
+    prefix: String,
+    max_size: u64,
+    // Bytes of the registration's queued files
+    size: AtomicU64,
+    next: AtomicU64,
+    resource: ResourceAttributesWithSchema,
+}
+
+impl<E: SpanExporter> DiskQueueExporter<E> {
+    pub(crate) fn new(inner: E, options: &DiskQueueOptions, registration: &str) -> Self {
+        let dir = options.path.as_deref().map(PathBuf::from);
+        let prefix = format!("{}-", registration.replace('/', "_"));
+        let mut size = 0;
+        if let Some(dir) = &dir {
+            // SAFETY: registers a function without arguments, run once when the process exits
+            AT_EXIT.call_once(|| unsafe {
+                atexit(on_exit);
+            });
+            if let Err(e) = std::fs::create_dir_all(dir) {
+                log_warn(&format!("failed to create {}: {e}", dir.display()));
+            }
+            let queued = queued_files(dir, &prefix);
+            size = queued.iter().map(|(_, _, len)| len).sum();
+            if !queued.is_empty() {
+                log_info(&format!(
+                    "{} batches queued in {}, exported after the next successful export",
+                    queued.len(),
+                    dir.display()
+                ));
+            }
+        }
+        Self {
+            inner,
+            dir,
+            prefix,
+            max_size: options.max_size_mb.unwrap_or(DEFAULT_MAX_SIZE_MB) << 20,
+            size: AtomicU64::new(size),
+            next: AtomicU64::new(0),
+            resource: ResourceAttributesWithSchema::default(),
+        }
+    }
+
+    /// Writes the batch to the queue, `None` when the queue is full or the write failed
+    async fn write(&self, dir: &Path, batch: &[SpanData]) -> Option<PathBuf> {
+        let request = ExportTraceServiceRequest {
+            resource_spans: group_spans_by_resource_and_scope(batch.to_vec(), &self.resource),
+        };
+        let body = request.encode_to_vec();
+        let len = body.len() as u64;
+        if self.size.load(Ordering::Relaxed) + len > self.max_size {
+            log_debug("disk queue full, batch exported without being queued");
+            return None;
+        }
+        let name = format!(
+            "{}{}-{:016}.{EXTENSION}",
+            self.prefix,
+            std::process::id(),
+            self.next.fetch_add(1, Ordering::Relaxed)
+        );
+        let path = dir.join(name);
+        if let Err(e) = blocking({
+            let path = path.clone();
+            move || write_file(&path, &body)
+        })
+        .await
+        {
+            log_warn(&format!(
+                "failed to queue a batch in {}: {e}",
+                dir.display()
+            ));
+            return None;
+        }
+        self.size.fetch_add(len, Ordering::Relaxed);
+        Some(path)
+    }
+
+    async fn remove(&self, path: PathBuf) {
+        let removed = blocking(move || {
+            let len = std::fs::metadata(&path)
+                .map(|m| m.len())
+                .unwrap_or_default();
+            std::fs::remove_file(&path).map(|_| len)
+        });
+        if let Ok(len) = removed.await {
+            let _ = (self.size).fetch_update(Ordering::Relaxed, Ordering::Relaxed, |size| {
+                Some(size.saturating_sub(len))
+            });
+        }
+    }
+
+    /// Exports the queued batches of failed exports and of exited processes, until one fails.
+    /// The batches the collector rejects are dropped, they would be rejected again.
+    async fn drain(&self, dir: &Path) {
+        let (dir, prefix) = (dir.to_path_buf(), self.prefix.clone());
+        let drainable = blocking(move || {
+            let pid = std::process::id();
+            // Batches of another running process (the old worker during a reload) are its own
+            let drainable = (queued_files(&dir, &prefix).into_iter()).filter(|(_, owner, _)| {
+                *owner == pid || !Path::new(&format!("/proc/{owner}")).exists()
+            });
+            Ok(drainable.map(|(path, _, _)| path).collect::<Vec<_>>())
+        });
+        for path in drainable
+            .await
+            .unwrap_or_default()
+            .into_iter()
+            .take(DRAIN_BATCHES)
+        {
+            let batch = blocking({
+                let path = path.clone();
+                move || std::fs::read(path)
+            })
+            .await
+            .map_err(|e| e.to_string())
+            .and_then(|body| {
+                ExportTraceServiceRequest::decode(body.as_slice()).map_err(|e| e.to_string())
+            });
+            let batch = match batch {
+                Ok(request) => decode_spans(request),
+                Err(e) => {
+                    log_warn(&format!("dropping unreadable {}: {e}", path.display()));
+                    self.remove(path).await;
+                    continue;
+                }
+            };
+            match self.inner.export(batch).await {
+                Ok(()) => {}
+                Err(e) if !is_retryable_error(&e) => {
+                    log_warn(&format!("dropping {}, rejected: {e}", path.display()));
+                }
+                Err(_) => return,
+            }
+            self.remove(path).await;
+        }
+    }
+}
+
+/// Runs file system calls on the blocking threads rather than the exporters' runtime thread
+async fn blocking<T: Send + 'static>(
+    f: impl FnOnce() -> std::io::Result<T> + Send + 'static,
+) -> std::io::Result<T> {
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(std::io::Error::other)?
+}
+
+/// Writes a queued batch aside and renames it, a process stopped mid-write leaves no partial
+/// batch
+fn write_file(path: &Path, body: &[u8]) -> std::io::Result<()> {
+    let partial = path.with_extension("partial");
+    let written = std::fs::write(&partial, body).and_then(|_| std::fs::rename(&partial, path));
+    if written.is_err() {
+        let _ = std::fs::remove_file(&partial);
+    }
+    written
+}
+
+/// Queued files with the prefix, oldest first, with the process that wrote them and their size
+fn queued_files(dir: &Path, prefix: &str) -> Vec<(PathBuf, u32, u64)> {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let mut files = entries
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            let path = entry.path();
+            if path.extension()? != EXTENSION {
+                return None;
+            }
+            let name = path.file_name()?.to_str()?;
+            let owner = name.strip_prefix(prefix)?.split('-').next()?.parse().ok()?;
+            let metadata = entry.metadata().ok()?;
+            let modified = metadata.modified().unwrap_or(UNIX_EPOCH);
+            Some((modified, path, owner, metadata.len()))
+        })
+        .collect::<Vec<_>>();
+    files.sort();
+    (files.into_iter())
+        .map(|(_, path, owner, len)| (path, owner, len))
+        .collect()
+}
+
+impl<E: SpanExporter> SpanExporter for DiskQueueExporter<E> {
+    async fn export(&self, batch: Vec<SpanData>) -> OTelSdkResult {
+        let Some(dir) = &self.dir else {
+            return self.inner.export(batch).await;
+        };
+        let queued = self.write(dir, &batch).await;
+        // Exported by the next worker
+        if queued.is_some() && EXITING.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        let result = self.inner.export(batch).await;
+        match (&result, queued) {
+            (Ok(()), queued) => {
+                if let Some(path) = queued {
+                    self.remove(path).await;
+                }
+                self.drain(dir).await;
+            }
+            (Err(e), Some(path)) if !is_retryable_error(e) => self.remove(path).await,
+            (Err(_), _) => {}
+        }
+        result
+    }
+
+    fn shutdown_with_timeout(&mut self, timeout: Duration) -> OTelSdkResult {
+        self.inner.shutdown_with_timeout(timeout)
+    }
+
+    fn force_flush(&mut self) -> OTelSdkResult {
+        self.inner.force_flush()
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        self.resource = resource.into();
+        self.inner.set_resource(resource);
+    }
+}
+
+/// Spans of a queued request, the resource is the exporter's own
+fn decode_spans(request: ExportTraceServiceRequest) -> Vec<SpanData> {
+    (request.resource_spans.into_iter())
+        .flat_map(|resource_spans| resource_spans.scope_spans)
+        .flat_map(|scope_spans| {
+            let ScopeSpans {
+                scope,
+                spans,
+                schema_url,
+            } = scope_spans;
+            let scope = scope.unwrap_or_default();
+            let mut builder = InstrumentationScope::builder(scope.name)
+                .with_attributes(key_values(scope.attributes));
+            if !scope.version.is_empty() {
+                builder = builder.with_version(scope.version);
+            }
+            if !schema_url.is_empty() {
+                builder = builder.with_schema_url(schema_url);
+            }
+            let scope = builder.build();
+            spans
+                .into_iter()
+                .map(move |span| decode_span(span, scope.clone()))
+        })
+        .collect()
+}
+
+fn decode_span(span: ProtoSpan, instrumentation_scope: InstrumentationScope) -> SpanData {
+    let span_kind = match span::SpanKind::try_from(span.kind) {
+        Ok(span::SpanKind::Server) => SpanKind::Server,
+        Ok(span::SpanKind::Client) => SpanKind::Client,
+        Ok(span::SpanKind::Producer) => SpanKind::Producer,
+        Ok(span::SpanKind::Consumer) => SpanKind::Consumer,
+        _ => SpanKind::Internal,
+    };
+    let status = match span.status {
+        Some(s) if s.code == status::StatusCode::Error as i32 => Status::error(s.message),
+        Some(s) if s.code == status::StatusCode::Ok as i32 => Status::Ok,
+        _ => Status::Unset,
+    };
+    let mut events = SpanEvents::default();
+    events.events = (span.events.into_iter())
+        .map(|event| {
+            let attributes = key_values(event.attributes);
+            let timestamp = time(event.time_unix_nano);
+            Event::new(
+                event.name,
+                timestamp,
+                attributes,
+                event.dropped_attributes_count,
+            )
+        })
+        .collect();
+    events.dropped_count = span.dropped_events_count;
+    let mut links = SpanLinks::default();
+    links.links = (span.links.into_iter())
+        .map(|link| {
+            let context =
+                span_context(&link.trace_id, &link.span_id, link.flags, &link.trace_state);
+            Link::new(
+                context,
+                key_values(link.attributes),
+                link.dropped_attributes_count,
+            )
+        })
+        .collect();
+    links.dropped_count = span.dropped_links_count;
+    SpanData {
+        span_context: span_context(&span.trace_id, &span.span_id, span.flags, &span.trace_state),
+        parent_span_id: (span.parent_span_id.try_into())
+            .map(SpanId::from_bytes)
+            .unwrap_or(SpanId::INVALID),
+        span_kind,
+        name: Cow::Owned(span.name),
+        start_time: time(span.start_time_unix_nano),
+        end_time: time(span.end_time_unix_nano),
+        attributes: key_values(span.attributes),
+        dropped_attributes_count: span.dropped_attributes_count,
+        events,
+        links,
+        status,
+        instrumentation_scope,
+    }
+}
+
+fn span_context(trace_id: &[u8], span_id: &[u8], flags: u32, trace_state: &str) -> SpanContext {
+    SpanContext::new(
+        (trace_id.try_into()).map_or(TraceId::INVALID, TraceId::from_bytes),
+        (span_id.try_into()).map_or(SpanId::INVALID, SpanId::from_bytes),
+        TraceFlags::new(flags as u8),
+        false,
+        TraceState::from_str(trace_state).unwrap_or_default(),
+    )
+}
+
+fn time(unix_nanos: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_nanos(unix_nanos)
+}
+
+fn key_values(attributes: Vec<ProtoKeyValue>) -> Vec<KeyValue> {
+    (attributes.into_iter())
+        .filter_map(|kv| Some(KeyValue::new(kv.key, attribute_value(kv.value?.value?))))
+        .collect()
+}
+
+/// Attribute value of an OTLP value, the values the SDK doesn't have become strings
+fn attribute_value(value: any_value::Value) -> Value {
+    match value {
+        any_value::Value::StringValue(s) => Value::String(s.into()),
+        any_value::Value::BoolValue(b) => Value::Bool(b),
+        any_value::Value::IntValue(i) => Value::I64(i),
+        any_value::Value::DoubleValue(d) => Value::F64(d),
+        any_value::Value::ArrayValue(array) => {
+            let values = array
+                .values
+                .into_iter()
+                .filter_map(|v| v.value.map(attribute_value));
+            Value::Array(array_value(values.collect()))
+        }
+        any_value::Value::KvlistValue(list) => Value::String(format!("{list:?}").into()),
+        any_value::Value::BytesValue(bytes) => Value::String(const_hex::encode(bytes).into()),
+    }
+}
+
+/// Array of the type of its first value
+fn array_value(values: Vec<Value>) -> Array {
+    match values.first() {
+        Some(Value::Bool(_)) => Array::Bool(
+            (values.into_iter())
+                .filter_map(|v| {
+                    if let Value::Bool(b) = v {
+                        Some(b)
+                    } else {
+                        None
+                    }
+                })
+                .collect(),
+        ),
+        Some(Value::I64(_)) => Array::I64(
+            (values.into_iter())
+                .filter_map(|v| if let Value::I64(i) = v { Some(i) } else { None })
+                .collect(),
+        ),
+        Some(Value::F64(_)) => Array::F64(
+            (values.into_iter())
+                .filter_map(|v| if let Value::F64(d) = v { Some(d) } else { None })
+                .collect(),
+        ),
+        _ => Array::String(
+            values
+                .iter()
+                .map(|v| StringValue::from(v.to_string()))
+                .collect(),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{self, Collector, SPAN_ID, TRACE_ID};
+
+    fn span() -> SpanData {
+        let mut events = SpanEvents::default();
+        let attributes = vec![KeyValue::new("n", 1i64)];
+        events.events = vec![Event::new("retry", UNIX_EPOCH, attributes, 0)];
+        events.dropped_count = 2;
+        SpanData {
+            span_context: SpanContext::new(
+                TraceId::from(TRACE_ID),
+                SpanId::from(SPAN_ID),
+                TraceFlags::SAMPLED,
+                false,
+                TraceState::from_str("vendor=value").unwrap(),
+            ),
+            parent_span_id: SpanId::from(0x0102_0304_0506_0708u64),
+            start_time: UNIX_EPOCH + Duration::from_millis(1500),
+            end_time: UNIX_EPOCH + Duration::from_millis(1750),
+            attributes: vec![
+                KeyValue::new("http.request.method", "GET"),
+                KeyValue::new("http.response.status_code", 200i64),
+                KeyValue::new("haproxy.retried", true),
+                KeyValue::new("haproxy.ratio", 0.5),
+                KeyValue::new(
+                    "haproxy.servers",
+                    Value::Array(vec![StringValue::from("web1")].into()),
+                ),
+            ],
+            dropped_attributes_count: 1,
+            events,
+            status: Status::error("bad gateway"),
+            instrumentation_scope: InstrumentationScope::builder("haproxy-otel")
+                .with_version("1.0")
+                .build(),
+            ..fixtures::span()
+        }
+    }
+
+    fn queue_dir(name: &str) -> (PathBuf, DiskQueueOptions) {
+        let dir = std::env::temp_dir().join(format!("haproxy-otel-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let options = DiskQueueOptions {
+            path: dir.to_str().map(str::to_string),
+            max_size_mb: None,
+        };
+        (dir, options)
+    }
+
+    #[test]
+    fn test_decode_spans() {
+        let request = ExportTraceServiceRequest {
+            resource_spans: group_spans_by_resource_and_scope(
+                vec![span()],
+                &ResourceAttributesWithSchema::default(),
+            ),
+        };
+        let body = request.encode_to_vec();
+        let spans = decode_spans(ExportTraceServiceRequest::decode(body.as_slice()).unwrap());
+        let (decoded, span) = (&spans[0], span());
+        assert_eq!(decoded.span_context, span.span_context);
+        assert_eq!(decoded.parent_span_id, span.parent_span_id);
+        assert_eq!(decoded.span_kind, span.span_kind);
+        assert_eq!(decoded.name, span.name);
+        assert_eq!(decoded.start_time, span.start_time);
+        assert_eq!(decoded.end_time, span.end_time);
+        assert_eq!(decoded.attributes, span.attributes);
+        assert_eq!(decoded.dropped_attributes_count, 1);
+        assert_eq!(decoded.events.events[0].name, "retry");
+        assert_eq!(decoded.events.dropped_count, 2);
+        assert_eq!(decoded.status, span.status);
+        assert_eq!(decoded.instrumentation_scope, span.instrumentation_scope);
+    }
+
+    #[test]
+    fn test_disk_queue() {
+        let (dir, options) = queue_dir("disk-queue");
+        let collector = Collector::default();
+        let exporter = DiskQueueExporter::new(collector.clone(), &options, "default");
+        let runtime = crate::exporter::get_otel_runtime();
+
+        // Exported batches leave nothing behind
+        runtime.block_on(exporter.export(vec![span()])).unwrap();
+        assert!(queued_files(&dir, "default-").is_empty());
+
+        // Failed batches stay queued
+        collector.set_down(true);
+        assert!(runtime.block_on(exporter.export(vec![span()])).is_err());
+        assert!(runtime
+            .block_on(exporter.export(vec![span(), span()]))
+            .is_err());
+        assert_eq!(queued_files(&dir, "default-").len(), 2);
+        assert!(exporter.size.load(Ordering::Relaxed) > 0);
+
+        // A new worker's exporter drains them after its first successful export
+        collector.set_down(false);
+        drop(exporter);
+        let exporter = DiskQueueExporter::new(collector.clone(), &options, "default");
+        assert!(exporter.size.load(Ordering::Relaxed) > 0);
+        runtime.block_on(exporter.export(vec![span()])).unwrap();
+        assert!(queued_files(&dir, "default-").is_empty());
+        assert_eq!(exporter.size.load(Ordering::Relaxed), 0);
+        assert_eq!(collector.attempts(), 6);
+        assert_eq!(collector.names().len(), 5);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_disk_queue_rejected() {
+        let (dir, options) = queue_dir("disk-queue-rejected");
+        let collector = Collector::default();
+        let exporter = DiskQueueExporter::new(collector.clone(), &options, "default");
+        let runtime = crate::exporter::get_otel_runtime();
+        let rejected = SpanData {
+            name: Cow::Borrowed("rejected"),
+            ..span()
+        };
+
+        // A rejected batch isn't kept
+        assert!(runtime
+            .block_on(exporter.export(vec![rejected.clone()]))
+            .is_err());
+        assert!(queued_files(&dir, "default-").is_empty());
+
+        // Nor does it stop the batches queued after it from being drained
+        collector.set_down(true);
+        assert!(runtime.block_on(exporter.export(vec![rejected])).is_err());
+        assert!(runtime.block_on(exporter.export(vec![span()])).is_err());
+        assert_eq!(queued_files(&dir, "default-").len(), 2);
+        collector.set_down(false);
+        runtime.block_on(exporter.export(vec![span()])).unwrap();
+        assert!(queued_files(&dir, "default-").is_empty());
+        assert_eq!(collector.names().len(), 2);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_disk_queue_full() {
+        let (dir, mut options) = queue_dir("disk-queue-full");
+        options.max_size_mb = Some(0);
+        let collector = Collector::default();
+        collector.set_down(true);
+        let exporter = DiskQueueExporter::new(collector.clone(), &options, "default");
+        let runtime = crate::exporter::get_otel_runtime();
+        assert!(runtime.block_on(exporter.export(vec![span()])).is_err());
+        assert!(queued_files(&dir, "default-").is_empty());
+
+        // Unreadable batches are dropped rather than blocking the queue
+        std::fs::write(
+            dir.join(format!("default-{}-x.otlp", std::process::id())),
+            b"\xff",
+        )
+        .unwrap();
+        collector.set_down(false);
+        runtime.block_on(exporter.export(vec![span()])).unwrap();
+        assert!(queued_files(&dir, "default-").is_empty());
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,244 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use opentelemetry::Context;
+use opentelemetry_sdk::error::OTelSdkResult;
+use opentelemetry_sdk::trace::{Span, SpanData, SpanProcessor};
+use opentelemetry_sdk::Resource;
+
+/// Upper bound of a dump window, so a typo can't turn an incident capture into a full disk
+pub(crate) const MAX_DUMP_DURATION: Duration = Duration::from_secs(3600);
+
+/// Spans waiting for the writer thread, more are dropped instead of blocking HAProxy
+const QUEUE_SIZE: usize = 4096;
+
+/// End of the running dump in milliseconds since `EPOCH`, 0 when no dump is running.
+/// Checked by the sampler on every span, so it is lock-free.
+static DEADLINE: AtomicU64 = AtomicU64::new(0);
+
+/// Spans not dumped because the writer thread fell behind
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+static EPOCH: OnceLock<Instant> = OnceLock::new();
+
+static WRITER: OnceLock<SyncSender<Message>> = OnceLock::new();
+
+enum Message {
+    /// Opens `path` (or keeps the current file when `None`), replies with the file written to
+    Open(Option<PathBuf>, mpsc::Sender<std::io::Result<PathBuf>>),
+    Span(Box<SpanData>, Arc<str>),
+    /// Flushes and closes the current file, replies with its path
+    Close(mpsc::Sender<Option<PathBuf>>),
+}
+
+/// Milliseconds since `EPOCH`, never 0
+fn now() -> u64 {
+    let epoch = EPOCH.get_or_init(Instant::now);
+    epoch.elapsed().as_millis() as u64 + 1
+}
+
+/// Returns true while a dump forces all requests to be sampled
+pub(crate) fn is_active() -> bool {
+    now() < DEADLINE.load(Ordering::Relaxed)
+}
+
+/// Starts (or extends) a dump for `duration`, returns the file written to
+pub(crate) fn start(duration: Duration, path: Option<PathBuf>) -> std::io::Result<PathBuf> {
+    let duration = duration.min(MAX_DUMP_DURATION);
+    let (reply, response) = mpsc::channel();
+    let _ = writer().send(Message::Open(path, reply));
+    let path = response
+        .recv()
+        .map_err(|_| std::io::Error::other("dump writer stopped"))??;
+    // A shorter window doesn't cut the running one short
+    DEADLINE.fetch_max(now() + duration.as_millis() as u64, Ordering::Relaxed);
+    Ok(path)
+}
+
+/// Ends the dump once its window has passed, returns the path of the finished file
+pub(crate) fn stop_expired() -> Option<PathBuf> {
+    let deadline = DEADLINE.load(Ordering::Relaxed);
+    if deadline == 0 || deadline > now() {
+        return None;
+    }
+    // Lost against a concurrent stop or extension
+    DEADLINE
+        .compare_exchange(deadline, 0, Ordering::Relaxed, Ordering::Relaxed)
+        .ok()?;
+    let (reply, response) = mpsc::channel();
+    let _ = writer().send(Message::Close(reply));
+    let path = response.recv().ok()??;
+    let dropped = DROPPED.swap(0, Ordering::Relaxed);
+    if dropped > 0 {
+        crate::exporter::log_warn(&format!(
+            "dump to {} is missing {} spans, the writer fell behind",
+            path.display(),
+            dropped
+        ));
+    }
+    Some(path)
+}
+
+fn default_path() -> PathBuf {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    std::env::temp_dir().join(format!("haproxy-otel-dump-{}.jsonl", now.as_secs()))
+}
+
+/// Queue of the writer thread, started with the first dump
+fn writer() -> &'static SyncSender<Message> {
+    WRITER.get_or_init(|| {
+        let (sender, receiver) = mpsc::sync_channel(QUEUE_SIZE);
+        std::thread::Builder::new()
+            .name("otel-dump".to_string())
+            .spawn(move || write_dumps(receiver))
+            .expect("failed to spawn the dump writer thread");
+        sender
+    })
+}
+
+/// Writer thread: owns the dump file, so HAProxy threads never wait on the disk
+fn write_dumps(receiver: Receiver<Message>) {
+    let mut dump: Option<(PathBuf, BufWriter<File>)> = None;
+    for message in receiver {
+        match message {
+            Message::Open(path, reply) => {
+                let result = match (dump.take(), path) {
+                    // Same file: keep appending, only the window is extended
+                    (Some(current), None) => {
+                        let path = current.0.clone();
+                        dump = Some(current);
+                        Ok(path)
+                    }
+                    (current, path) => {
+                        if let Some((_, mut file)) = current {
+                            let _ = file.flush();
+                        }
+                        let path = path.unwrap_or_else(default_path);
+                        File::options()
+                            .create(true)
+                            .append(true)
+                            .open(&path)
+                            .map(|file| {
+                                dump = Some((path.clone(), BufWriter::new(file)));
+                                path
+                            })
+                    }
+                };
+                let _ = reply.send(result);
+            }
+            Message::Span(span, service_name) => {
+                if let Some((path, file)) = dump.as_mut() {
+                    let json = crate::json::span_to_json(&span, &service_name);
+                    if let Err(e) = writeln!(file, "{json}") {
+                        crate::exporter::log_warn(&format!(
+                            "dump to {} failed: {}",
+                            path.display(),
+                            e
+                        ));
+                    }
+                }
+            }
+            Message::Close(reply) => {
+                let path = dump.take().map(|(path, mut file)| {
+                    let _ = file.flush();
+                    path
+                });
+                let _ = reply.send(path);
+            }
+        }
+    }
+}
+
+/// Hands a finished span to the writer thread while the dump window is open
+fn record(span: &SpanData, service_name: &Arc<str>) {
+    if !is_active() {
+        return;
+    }
+    let message = Message::Span(Box::new(span.clone()), service_name.clone());
+    if let Err(TrySendError::Full(_)) = writer().try_send(message) {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Span processor wrapper copying finished spans to the running dump.
+///
+/// Placed next to the exporter, so dumped spans went through the same attribute
+/// de-duplication and tenant redaction as exported ones.
+#[derive(Debug)]
+pub(crate) struct DumpProcessor<P> {
+    inner: P,
+    service_name: Arc<str>,
+}
+
+impl<P> DumpProcessor<P> {
+    pub(crate) fn new(inner: P, service_name: String) -> Self {
+        Self {
+            inner,
+            service_name: service_name.into(),
+        }
+    }
+}
+
+impl<P: SpanProcessor> SpanProcessor for DumpProcessor<P> {
+    fn on_start(&self, span: &mut Span, cx: &Context) {
+        self.inner.on_start(span, cx);
+    }
+
+    fn on_end(&self, span: SpanData) {
+        record(&span, &self.service_name);
+        self.inner.on_end(span);
+    }
+
+    fn force_flush(&self) -> OTelSdkResult {
+        self.inner.force_flush()
+    }
+
+    fn shutdown_with_timeout(&self, timeout: Duration) -> OTelSdkResult {
+        self.inner.shutdown_with_timeout(timeout)
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        self.inner.set_resource(resource);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{self, span_context};
+
+    #[test]
+    fn test_dump_window() {
+        let path = std::env::temp_dir().join(format!("haproxy-otel-test-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        // Zero-length windows only, other tests rely on the sampler not being forced
+        assert_eq!(start(Duration::ZERO, Some(path.clone())).unwrap(), path);
+        assert!(!is_active());
+        // Extending keeps the current file
+        assert_eq!(start(Duration::ZERO, None).unwrap(), path);
+
+        let span = SpanData {
+            span_context: span_context(1u128, 1u64),
+            ..fixtures::span()
+        };
+        // Past the window the file is still open until stopped, but takes no more spans
+        record(&span, &Arc::from("haproxy"));
+        let message = Message::Span(Box::new(span.clone()), Arc::from("haproxy"));
+        writer().send(message).unwrap();
+        assert_eq!(stop_expired(), Some(path.clone()));
+        assert_eq!(stop_expired(), None);
+        record(&span, &Arc::from("haproxy"));
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(content.lines().count(), 1);
+        assert!(content.contains("\"name\":\"GET example.com\""));
+    }
+}
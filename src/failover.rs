@@ -0,0 +1,183 @@
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use opentelemetry_sdk::error::OTelSdkResult;
+use opentelemetry_sdk::trace::{SpanData, SpanExporter};
+use opentelemetry_sdk::Resource;
+
+use crate::exporter::{log_info, log_warn};
+
+/// Consecutive failed exports before switching to the next endpoint (`otlp.failover_after`)
+pub(crate) const DEFAULT_FAILOVER_AFTER: u32 = 3;
+
+/// Interval between attempts to return to the primary endpoint
+const PRIMARY_PROBE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Span exporter sending to the primary endpoint and failing over to the secondaries
+/// (`otlp.failover_endpoints`) after consecutive failed exports.
+///
+/// While failed over, a batch is sent to the primary every `PRIMARY_PROBE_INTERVAL`; once it
+/// succeeds the exporter stays on the primary again. The batch whose failure triggers a
+/// switch is sent again to the next endpoint.
+#[derive(Debug)]
+pub(crate) struct FailoverExporter<E> {
+    endpoints: Vec<(String, E)>,
+    failover_after: u32,
+    // Index of the endpoint currently exported to
+    active: AtomicUsize,
+    // Consecutive failed exports to the active endpoint
+    failures: AtomicU32,
+    last_probe: Mutex<Instant>,
+}
+
+impl<E: SpanExporter> FailoverExporter<E> {
+    /// `endpoints` holds the primary first, each exporter with its endpoint for the logs
+    pub(crate) fn new(endpoints: Vec<(String, E)>, failover_after: u32) -> Self {
+        Self {
+            endpoints,
+            failover_after: failover_after.max(1),
+            active: AtomicUsize::new(0),
+            failures: AtomicU32::new(0),
+            last_probe: Mutex::new(Instant::now()),
+        }
+    }
+
+    fn switch(&self, from: usize, to: usize) {
+        self.active.store(to, Ordering::Relaxed);
+        self.failures.store(0, Ordering::Relaxed);
+        *self.last_probe.lock().unwrap_or_else(|e| e.into_inner()) = Instant::now();
+        let (from, to_endpoint) = (&self.endpoints[from].0, &self.endpoints[to].0);
+        match to {
+            0 => log_info(&format!(
+                "primary endpoint {to_endpoint} recovered, leaving {from}"
+            )),
+            _ => log_warn(&format!(
+                "{} consecutive exports to {from} failed, failing over to {to_endpoint}",
+                self.failover_after
+            )),
+        }
+    }
+
+    /// Whether a batch should be sent to the primary to check whether it recovered
+    fn probe_primary(&self) -> bool {
+        let mut last_probe = self.last_probe.lock().unwrap_or_else(|e| e.into_inner());
+        if last_probe.elapsed() < PRIMARY_PROBE_INTERVAL {
+            return false;
+        }
+        *last_probe = Instant::now();
+        true
+    }
+}
+
+impl<E: SpanExporter> SpanExporter for FailoverExporter<E> {
+    async fn export(&self, batch: Vec<SpanData>) -> OTelSdkResult {
+        let mut active = self.active.load(Ordering::Relaxed);
+        if active != 0
+            && self.probe_primary()
+            && self.endpoints[0].1.export(batch.clone()).await.is_ok()
+        {
+            self.switch(active, 0);
+            return Ok(());
+        }
+        let mut batch = Some(batch);
+        loop {
+            let failures = self.failures.load(Ordering::Relaxed) + 1;
+            let fail_over = failures >= self.failover_after && active + 1 < self.endpoints.len();
+            // Keep a copy for the next endpoint only when a failure would switch to it
+            let spans = match fail_over {
+                true => batch.clone().unwrap_or_default(),
+                false => batch.take().unwrap_or_default(),
+            };
+            let result = self.endpoints[active].1.export(spans).await;
+            if result.is_ok() {
+                self.failures.store(0, Ordering::Relaxed);
+                return result;
+            }
+            if !fail_over {
+                self.failures.store(failures, Ordering::Relaxed);
+                return result;
+            }
+            self.switch(active, active + 1);
+            active += 1;
+        }
+    }
+
+    fn shutdown_with_timeout(&mut self, timeout: Duration) -> OTelSdkResult {
+        let mut result = Ok(());
+        for (_, exporter) in &mut self.endpoints {
+            result = result.and(exporter.shutdown_with_timeout(timeout));
+        }
+        result
+    }
+
+    fn force_flush(&mut self) -> OTelSdkResult {
+        let mut result = Ok(());
+        for (_, exporter) in &mut self.endpoints {
+            result = result.and(exporter.force_flush());
+        }
+        result
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        for (_, exporter) in &mut self.endpoints {
+            exporter.set_resource(resource);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{span, Collector};
+
+    #[test]
+    fn test_failover() {
+        let (primary, secondary) = (Collector::default(), Collector::default());
+        let exporter = FailoverExporter::new(
+            vec![
+                ("http://primary".to_string(), primary.clone()),
+                ("http://secondary".to_string(), secondary.clone()),
+            ],
+            2,
+        );
+        let export = || crate::exporter::get_otel_runtime().block_on(exporter.export(vec![span()]));
+
+        assert!(export().is_ok());
+        assert_eq!(primary.names().len(), 1);
+
+        // The first failure is returned, the second switches and resends the batch
+        primary.set_down(true);
+        assert!(export().is_err());
+        assert!(export().is_ok());
+        assert_eq!(exporter.active.load(Ordering::Relaxed), 1);
+        assert_eq!(secondary.names().len(), 1);
+        assert!(export().is_ok());
+        assert_eq!(secondary.names().len(), 2);
+
+        // Still down at the probe: the batch goes to the secondary
+        *exporter.last_probe.lock().unwrap() -= PRIMARY_PROBE_INTERVAL;
+        assert!(export().is_ok());
+        assert_eq!(exporter.active.load(Ordering::Relaxed), 1);
+        assert_eq!(secondary.names().len(), 3);
+
+        // Recovered: the probe returns to the primary
+        primary.set_down(false);
+        *exporter.last_probe.lock().unwrap() -= PRIMARY_PROBE_INTERVAL;
+        assert!(export().is_ok());
+        assert_eq!(exporter.active.load(Ordering::Relaxed), 0);
+        assert_eq!(primary.names().len(), 2);
+        assert_eq!(secondary.names().len(), 3);
+    }
+
+    #[test]
+    fn test_failover_last_endpoint() {
+        let collector = Collector::default();
+        collector.set_down(true);
+        let exporter = FailoverExporter::new(vec![("http://only".to_string(), collector)], 1);
+        // Nothing to fail over to
+        let result = crate::exporter::get_otel_runtime().block_on(exporter.export(vec![span()]));
+        assert!(result.is_err());
+        assert_eq!(exporter.active.load(Ordering::Relaxed), 0);
+    }
+}
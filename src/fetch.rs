@@ -0,0 +1,67 @@
+/// A span attribute recorded from a sample fetch once the response has been processed
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct EndAttribute {
+    // Attribute key chosen by the user
+    pub(crate) key: String,
+    // Lua name of the fetch (`txn.conn_retries` becomes `txn_conn_retries`)
+    pub(crate) fetch: String,
+    pub(crate) args: Vec<String>,
+}
+
+impl EndAttribute {
+    /// Parses a fetch expression such as `txn.conn_retries` or `res.hdr(content-type)`
+    pub(crate) fn parse(key: &str, expression: &str) -> Result<Self, String> {
+        let invalid = || format!("invalid fetch expression '{expression}' for '{key}'");
+        let expression = expression.trim();
+        let (name, args) = match expression.split_once('(') {
+            Some((name, args)) => {
+                let args = args.strip_suffix(')').ok_or_else(invalid)?;
+                let args = args.split(',').map(|arg| arg.trim().to_string());
+                (name, args.filter(|arg| !arg.is_empty()).collect())
+            }
+            None => (expression, Vec::new()),
+        };
+        if key.is_empty() || name.is_empty() {
+            return Err(invalid());
+        }
+        // HAProxy registers fetches in Lua with non-alphanumeric characters replaced
+        let fetch = name
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect();
+        Ok(EndAttribute {
+            key: key.to_string(),
+            fetch,
+            args,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_end_attribute() {
+        assert_eq!(
+            EndAttribute::parse("haproxy.conn_retries", "txn.conn_retries").unwrap(),
+            EndAttribute {
+                key: "haproxy.conn_retries".to_string(),
+                fetch: "txn_conn_retries".to_string(),
+                args: vec![],
+            }
+        );
+        assert_eq!(
+            EndAttribute::parse("http.response.content_type", "res.hdr( content-type , -1)")
+                .unwrap(),
+            EndAttribute {
+                key: "http.response.content_type".to_string(),
+                fetch: "res_hdr".to_string(),
+                args: vec!["content-type".to_string(), "-1".to_string()],
+            }
+        );
+        assert!(EndAttribute::parse("a", "res.hdr(x").is_err());
+        assert!(EndAttribute::parse("a", "").is_err());
+        assert!(EndAttribute::parse("", "bc_http_major").is_err());
+    }
+}
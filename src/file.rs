@@ -0,0 +1,205 @@
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use opentelemetry_proto::tonic::collector::trace::v1::ExportTraceServiceRequest;
+use opentelemetry_proto::transform::common::tonic::ResourceAttributesWithSchema;
+use opentelemetry_proto::transform::trace::tonic::group_spans_by_resource_and_scope;
+use opentelemetry_sdk::error::{OTelSdkError, OTelSdkResult};
+use opentelemetry_sdk::trace::{SpanData, SpanExporter};
+use opentelemetry_sdk::Resource;
+
+use crate::exporter::FileOptions;
+
+const DEFAULT_MAX_SIZE_MB: u64 = 100;
+const DEFAULT_MAX_FILES: u64 = 5;
+
+/// Open file and the bytes written to it
+#[derive(Debug)]
+struct Output {
+    file: File,
+    size: u64,
+}
+
+/// Span exporter appending the spans to a file in the OTLP file format (`exporter = "file"`):
+/// one `ExportTraceServiceRequest` in the OTLP/JSON encoding per line, a line per batch.
+///
+/// Meant for air-gapped environments where the files are shipped later, e.g. replayed into a
+/// collector with its `otlpjsonfile` receiver. The file is rotated once it reaches
+/// `file.max_size_mb`.
+#[derive(Debug)]
+pub(crate) struct FileExporter {
+    path: PathBuf,
+    max_size: u64,
+    max_files: u64,
+    resource: ResourceAttributesWithSchema,
+    output: Mutex<Output>,
+}
+
+impl FileExporter {
+    pub(crate) fn new(options: &FileOptions) -> Result<Self, String> {
+        let path = match options.path.as_deref() {
+            Some(path) => PathBuf::from(path),
+            None => return Err("exporter 'file' requires file.path".to_string()),
+        };
+        let output = open(&path)?;
+        Ok(FileExporter {
+            path,
+            max_size: options.max_size_mb.unwrap_or(DEFAULT_MAX_SIZE_MB) << 20,
+            max_files: options.max_files.unwrap_or(DEFAULT_MAX_FILES),
+            resource: ResourceAttributesWithSchema::default(),
+            output: Mutex::new(output),
+        })
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Moves `<path>` to `<path>.1`, shifting the older files and dropping the oldest
+    fn rotate(&self) -> Result<Output, String> {
+        let rotated = |n: u64| PathBuf::from(format!("{}.{n}", self.path.display()));
+        let _ = std::fs::remove_file(rotated(self.max_files));
+        for n in (1..self.max_files).rev() {
+            let _ = std::fs::rename(rotated(n), rotated(n + 1));
+        }
+        let result = match self.max_files {
+            0 => std::fs::remove_file(&self.path),
+            _ => std::fs::rename(&self.path, rotated(1)),
+        };
+        result.map_err(|e| format!("failed to rotate {}: {e}", self.path.display()))?;
+        open(&self.path)
+    }
+}
+
+fn open(path: &Path) -> Result<Output, String> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| format!("failed to open {}: {e}", path.display()))?;
+    let size = file.metadata().map(|m| m.len()).unwrap_or_default();
+    Ok(Output { file, size })
+}
+
+impl SpanExporter for FileExporter {
+    async fn export(&self, batch: Vec<SpanData>) -> OTelSdkResult {
+        let request = ExportTraceServiceRequest {
+            resource_spans: group_spans_by_resource_and_scope(batch, &self.resource),
+        };
+        let mut line = serde_json::to_vec(&request)
+            .map_err(|e| OTelSdkError::InternalFailure(format!("encoding failed: {e}")))?;
+        line.push(b'\n');
+
+        let mut output = self.output.lock().unwrap_or_else(|e| e.into_inner());
+        // A line larger than the limit still gets a file of its own
+        if output.size > 0 && output.size + line.len() as u64 > self.max_size {
+            *output = self.rotate().map_err(OTelSdkError::InternalFailure)?;
+        }
+        output.file.write_all(&line).map_err(|e| {
+            OTelSdkError::InternalFailure(format!("write to {} failed: {e}", self.path.display()))
+        })?;
+        output.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        self.resource = resource.into();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use opentelemetry::KeyValue;
+
+    use super::*;
+    use crate::fixtures::{self, span_context, SPAN_ID, TRACE_ID};
+
+    fn span() -> SpanData {
+        SpanData {
+            span_context: span_context(TRACE_ID, SPAN_ID),
+            attributes: vec![KeyValue::new("url.path", "/login")],
+            ..fixtures::span()
+        }
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("haproxy-otel-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_file_export() {
+        let dir = temp_dir("file-export");
+        let path = dir.join("spans.jsonl");
+        let mut exporter = FileExporter::new(&FileOptions {
+            path: Some(path.display().to_string()),
+            ..Default::default()
+        })
+        .unwrap();
+        exporter.set_resource(&Resource::builder_empty().with_service_name("edge").build());
+        let runtime = crate::exporter::get_otel_runtime();
+        runtime.block_on(exporter.export(vec![span()])).unwrap();
+        runtime
+            .block_on(exporter.export(vec![span(), span()]))
+            .unwrap();
+
+        let lines = std::fs::read_to_string(&path).unwrap();
+        let requests = lines
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(requests.len(), 2);
+        let resource_spans = &requests[0]["resourceSpans"][0];
+        let service = &resource_spans["resource"]["attributes"][0];
+        assert_eq!(service["key"], "service.name");
+        assert_eq!(service["value"]["stringValue"], "edge");
+        let span = &resource_spans["scopeSpans"][0]["spans"][0];
+        assert_eq!(span["traceId"], "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(span["spanId"], "00f067aa0ba902b7");
+        assert_eq!(span["name"], "GET example.com");
+        let spans = &requests[1]["resourceSpans"][0]["scopeSpans"][0]["spans"];
+        assert_eq!(spans.as_array().unwrap().len(), 2);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_file_rotation() {
+        let dir = temp_dir("file-rotation");
+        let path = dir.join("spans.jsonl");
+        let mut exporter = FileExporter::new(&FileOptions {
+            path: Some(path.display().to_string()),
+            max_files: Some(2),
+            ..Default::default()
+        })
+        .unwrap();
+        // Every line exceeds the limit, each one rotates the previous file
+        exporter.max_size = 1;
+        let runtime = crate::exporter::get_otel_runtime();
+        for _ in 0..4 {
+            runtime.block_on(exporter.export(vec![span()])).unwrap();
+        }
+        let mut files = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        files.sort();
+        assert_eq!(files, ["spans.jsonl", "spans.jsonl.1", "spans.jsonl.2"]);
+        let line_count = |name: &str| {
+            std::fs::read_to_string(dir.join(name))
+                .unwrap()
+                .lines()
+                .count()
+        };
+        assert!(files.iter().all(|name| line_count(name) == 1));
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_file_requires_path() {
+        assert!(FileExporter::new(&FileOptions::default()).is_err());
+    }
+}
@@ -1,5 +1,9 @@
-use haproxy_api::{Channel, FilterMethod, FilterResult, HttpMessage, Txn, UserFilter};
-use mlua::prelude::{Lua, LuaResult, LuaTable};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+use haproxy_api::{Channel, Core, FilterMethod, FilterResult, HttpMessage, Txn, UserFilter};
+use mlua::prelude::{Lua, LuaError, LuaResult, LuaTable};
 use opentelemetry::propagation::Injector;
 use opentelemetry::trace::{self, TraceContextExt, Tracer};
 use opentelemetry::{Context, KeyValue};
@@ -7,114 +11,373 @@ use opentelemetry_semantic_conventions::trace::{
     HTTP_REQUEST_METHOD, HTTP_RESPONSE_STATUS_CODE, URL_PATH, URL_QUERY,
 };
 
+use crate::exporter::Pipeline;
+use crate::metrics::DurationMetric;
+use crate::span::BestEffort;
 use crate::{get_context, remove_context};
 
+/// Callbacks of `opentelemetry-trace-inject`: header injection of the server span context,
+/// without client spans. The server span is ended by the `end_server_span` action.
+const INJECT_METHODS: u8 = FilterMethod::HTTP_HEADERS;
+
+/// Callbacks of `opentelemetry-trace-lite`: the client span, header injection and the end of
+/// the server span
+const LITE_METHODS: u8 = INJECT_METHODS | FilterMethod::END_ANALYZE;
+
+/// Callbacks of `opentelemetry-trace`, also applying the `tracer` and `omit_url_query`
+/// arguments before the http-request rules
+const DEFAULT_METHODS: u8 = LITE_METHODS | FilterMethod::START_ANALYZE;
+
+/// Callbacks of `opentelemetry-trace-payload`, also following the response body for the
+/// `payload_events` option
+const PAYLOAD_METHODS: u8 = DEFAULT_METHODS | FilterMethod::HTTP_PAYLOAD | FilterMethod::HTTP_END;
+
+static WARNED_PAYLOAD: AtomicBool = AtomicBool::new(false);
+
+/// Registers the filter once per set of callbacks. HAProxy calls every callback a filter
+/// registers for each request, so frontends trade features for per-request overhead by name.
+pub(crate) fn register(core: &Core) -> LuaResult<()> {
+    core.register_filter::<TraceFilter<DEFAULT_METHODS>>("opentelemetry-trace")?;
+    core.register_filter::<TraceFilter<PAYLOAD_METHODS>>("opentelemetry-trace-payload")?;
+    core.register_filter::<TraceFilter<LITE_METHODS>>("opentelemetry-trace-lite")?;
+    core.register_filter::<TraceFilter<INJECT_METHODS>>("opentelemetry-trace-inject")?;
+    Ok(())
+}
+
+/// Filter name of the callbacks `M`
+fn filter_name(methods: u8) -> &'static str {
+    match methods {
+        INJECT_METHODS => "opentelemetry-trace-inject",
+        LITE_METHODS => "opentelemetry-trace-lite",
+        PAYLOAD_METHODS => "opentelemetry-trace-payload",
+        _ => "opentelemetry-trace",
+    }
+}
+
+/// Error of a filter argument or option needing callbacks the filter doesn't register
+fn unsupported(methods: u8, what: &str, needs: &str) -> LuaError {
+    LuaError::RuntimeError(format!(
+        "haproxy-otel: {what} is not supported by filter lua.{}, use lua.{needs}",
+        filter_name(methods)
+    ))
+}
+
+/// Logs once per process that the `payload_events` option needs other callbacks, for
+/// frontends whose registration is only known per request
+fn warn_once(warned: &AtomicBool, message: &str) {
+    if !warned.swap(true, Ordering::Relaxed) {
+        crate::exporter::log_warn(message);
+    }
+}
+
+/// Filter of the frontends, registering the `FilterMethod` callbacks of `M`
 #[derive(Default)]
-pub(crate) struct TraceFilter {
+pub(crate) struct TraceFilter<const M: u8> {
     start_client_span: Option<bool>,
-    silent_on: bool,
+    inject_upstream: Option<bool>,
+    // Overrides the `omit_url_query` option for the frontend
+    omit_url_query: Option<bool>,
+    // Named tracer of the `tracers` option (`tracer=<name>`)
+    tracer: Option<String>,
+    // Pipeline of the transaction, resolved by the first callback needing it
+    pipeline: Option<Arc<Pipeline>>,
+    // Set when duration metrics are enabled
+    upstream_start: Option<Instant>,
+    // Set once the response body is followed (`payload_events`)
+    payload_events: bool,
+    // Set once the first response payload has been seen (`payload_events`)
+    first_byte_seen: bool,
+    // Set once a response from the server has been analyzed, HAProxy's own replies skip it
+    response_seen: bool,
     context: Context,
 }
 
-impl TraceFilter {
+impl<const M: u8> TraceFilter<M> {
+    /// Client spans are ended by `end_analyze`, without it the server span context is injected
+    const CLIENT_SPANS: bool = M & FilterMethod::END_ANALYZE != 0;
+
+    fn starts_client_span(&self) -> bool {
+        self.start_client_span != Some(false)
+    }
+
+    /// Pipeline of the registration that started the server span, looked up once per stream
+    fn pipeline(&mut self, lua: &Lua, txn: &Txn) -> Option<Arc<Pipeline>> {
+        if self.pipeline.is_none() {
+            self.pipeline = crate::txn_pipeline(lua, txn);
+        }
+        self.pipeline.clone()
+    }
+
     // This method is called before proxying the request to the server (upstream)
     fn on_request_headers(
         &mut self,
-        _lua: &Lua,
+        lua: &Lua,
         txn: Txn,
         msg: HttpMessage,
     ) -> LuaResult<FilterResult> {
-        let tracer = opentelemetry::global::tracer("haproxy-otel");
-
         // Find parent context (if any)
         let parent_context = match get_context(&txn) {
             Some(cx) => cx,
             None => return Ok(FilterResult::Continue),
         };
 
+        // Use the pipeline of the registration that started the server span
+        let pipeline = match self.pipeline(lua, &txn) {
+            Some(pipeline) => pipeline,
+            None => return Ok(FilterResult::Continue),
+        };
+
+        // The incoming tracing headers are forwarded as they are
+        if pipeline.options.propagation_only {
+            return Ok(FilterResult::Continue);
+        }
+
+        // All http-request rules and content switching are done at this point
+        if pipeline.options.diagnostics {
+            let be_name = txn.f.get_str("be_name", ())?;
+            parent_context.span().add_event(
+                "backend selected",
+                vec![KeyValue::new("haproxy.backend.name", be_name)],
+            );
+        }
+
         // Skip client span creation if this option is disabled
-        if self.start_client_span == Some(false) {
+        if !self.starts_client_span() {
+            return Ok(FilterResult::Continue);
+        }
+
+        // Responses served by the HAProxy cache never reach a server
+        if crate::span::is_cache_hit(&txn) {
             return Ok(FilterResult::Continue);
         }
+        let silent_on = pipeline.silent_on;
+
+        // In egress mode the request span already is the client span to the destination,
+        // the upstream headers carry its context
+        let context = match pipeline.options.egress || !Self::CLIENT_SPANS {
+            true => parent_context,
+            false => {
+                self.context = self.start_upstream_span(&txn, &pipeline, &parent_context)?;
+                self.context.clone()
+            }
+        };
+        if pipeline.options.duration_metrics && Self::CLIENT_SPANS {
+            self.upstream_start = Some(Instant::now());
+        }
+
+        // Inject tracing headers, unless the original headers must be forwarded untouched
+        let inject_upstream = self
+            .inject_upstream
+            .unwrap_or(pipeline.options.injects_upstream());
+        // Backends (e.g. third parties) that must not receive internal trace ids, and
+        // requests to `internal_frontends` which are never propagated
+        let inject_upstream = inject_upstream
+            && !crate::span::var_is_true(&txn, crate::span::INTERNAL_VAR)
+            && (pipeline.options.no_inject_backends.is_empty() || {
+                let be_name = txn.f.get_str("be_name", ())?;
+                !pipeline.options.no_inject_backends.contains(&be_name)
+            });
+        if inject_upstream {
+            pipeline
+                .propagator
+                .inject_context(&context, &mut HeaderInjector::new(&msg, silent_on));
+            // For backends deriving metrics from their spans, whatever the propagator
+            let span_context = context.span().span_context().clone();
+            if pipeline.options.propagate_sampling_rate && !silent_on && span_context.is_sampled() {
+                if let Some(rate) = crate::sampler::sampling_rate(span_context.trace_state()) {
+                    msg.set_header(crate::sampler::SAMPLING_RATE_HEADER, rate)?;
+                }
+            }
+            if pipeline.options.proxy_protocol_tlv.is_some() {
+                let encoded = crate::handoff::encode(&span_context);
+                txn.set_var(crate::handoff::PP2_CONTEXT_VAR, encoded)?;
+            }
+        } else if pipeline.options.proxy_protocol_tlv.is_some() {
+            txn.unset_var(crate::handoff::PP2_CONTEXT_VAR)?;
+        }
 
+        Ok(FilterResult::Continue)
+    }
+
+    // Client span of the request to the server, child of the server span
+    fn start_upstream_span(
+        &self,
+        txn: &Txn,
+        pipeline: &Pipeline,
+        parent_context: &Context,
+    ) -> LuaResult<Context> {
+        let (tracer, default_attributes) = pipeline.named_tracer(self.tracer.as_deref());
         let method = txn.f.get_str("method", ())?;
         let uri = txn.f.get_str("pathq", ())?;
         let (path, query) = uri.split_once('?').unwrap_or((&uri, ""));
 
+        let mut attributes = vec![
+            KeyValue::new(HTTP_REQUEST_METHOD, method),
+            KeyValue::new(URL_PATH, path.to_string()),
+        ];
+        if !crate::span::omits_url_query(txn, &pipeline.options) {
+            attributes.push(KeyValue::new(URL_QUERY, query.to_string()));
+        }
+        if let Ok(Some(tenant)) = txn.get_var::<Option<String>>("txn.__otel_tenant") {
+            attributes.push(KeyValue::new(crate::tenant::TENANT, tenant));
+        }
+        if let Ok(Some(class)) = txn.get_var::<Option<String>>("txn.otel_priority") {
+            if !class.is_empty() {
+                attributes.push(KeyValue::new(crate::sampler::REQUEST_CLASS, class));
+            }
+        }
+        crate::tracer::add_defaults(&mut attributes, default_attributes);
         let span_builder = tracer
             .span_builder("upstream")
             .with_kind(trace::SpanKind::Client)
-            .with_attributes([
-                KeyValue::new(HTTP_REQUEST_METHOD, method),
-                KeyValue::new(URL_PATH, path.to_string()),
-                KeyValue::new(URL_QUERY, query.to_string()),
-            ]);
-        let span = tracer.build_with_context(span_builder, &parent_context);
-        self.context = parent_context.with_span(span);
-
-        // Inject tracing headers
-        opentelemetry::global::get_text_map_propagator(|injector| {
-            injector.inject_context(
-                &self.context,
-                &mut HeaderInjector::new(&msg, self.silent_on),
-            );
-        });
-
-        Ok(FilterResult::Continue)
+            .with_attributes(attributes);
+        let span = tracer.build_with_context(span_builder, parent_context);
+        Ok(parent_context.with_span(span))
     }
 
     // This method is called after receiving the response from the server (upstream)
     fn on_response_headers(
         &mut self,
-        _lua: &Lua,
+        lua: &Lua,
         txn: Txn,
         msg: HttpMessage,
     ) -> LuaResult<FilterResult> {
+        self.response_seen = true;
+        self.echo_trace_id(lua, &txn, &msg)?;
+
+        // The payload callbacks only run for channels with a data filter
+        let payload_events = get_context(&txn).is_some()
+            && self
+                .pipeline(lua, &txn)
+                .is_some_and(|pipeline| pipeline.options.payload_events);
+        if payload_events && M & FilterMethod::HTTP_PAYLOAD == 0 {
+            let error = unsupported(M, "payload_events", filter_name(PAYLOAD_METHODS));
+            warn_once(&WARNED_PAYLOAD, &error.to_string());
+        } else if payload_events {
+            Self::register_data_filter(lua, txn.clone(), msg.channel()?)?;
+            self.payload_events = true;
+        }
+
         // Skip this logic if client span creation is disabled
-        if self.start_client_span == Some(false) {
+        if !self.starts_client_span() || !Self::CLIENT_SPANS {
             return Ok(FilterResult::Continue);
         }
 
         let span = self.context.span();
         span.add_event("received response headers", vec![]);
 
+        let mut fetches = BestEffort::new(&txn);
         let stline = msg.get_stline()?;
-        let status = stline.raw_get::<i64>("code").unwrap_or_default();
-        span.set_attribute(KeyValue::new(HTTP_RESPONSE_STATUS_CODE, status));
-        if status < 500 {
-            span.set_status(trace::Status::Ok);
-        } else {
-            span.set_status(trace::Status::error(stline.raw_get::<String>("reason")?));
+        match stline.raw_get::<Option<i64>>("code").ok().flatten() {
+            Some(status) => {
+                span.set_attribute(KeyValue::new(HTTP_RESPONSE_STATUS_CODE, status));
+                if status < 500 {
+                    span.set_status(trace::Status::Ok);
+                } else {
+                    let reason = stline.raw_get::<Option<String>>("reason").ok().flatten();
+                    let reason = reason.unwrap_or_else(|| "5xx status code".to_string());
+                    span.set_status(trace::Status::error(reason));
+                }
+            }
+            None => fetches.mark_partial(),
         }
 
-        let srv_name = txn.f.get_str("srv_name", ())?;
-        span.set_attribute(KeyValue::new("haproxy.server.name", srv_name));
+        // The cache-use decision may be known only once the response is there
+        if crate::span::is_cache_hit(&txn) {
+            span.set_attribute(KeyValue::new("haproxy.cache.hit", true));
+        } else if let Some(srv_name) = fetches.get_str("srv_name") {
+            span.set_attribute(KeyValue::new("haproxy.server.name", srv_name));
+        }
+        fetches.finish(&span);
 
         Ok(FilterResult::Continue)
     }
+
+    // First chunk of the response body from the server, recorded next to the response
+    // headers event (on the server span without a client span)
+    fn on_response_payload(&mut self, txn: &Txn) {
+        if self.first_byte_seen {
+            return;
+        }
+        self.first_byte_seen = true;
+        let context = match self.context.has_active_span() {
+            true => Some(self.context.clone()),
+            false => get_context(txn),
+        };
+        if let Some(context) = context {
+            context
+                .span()
+                .add_event("first response byte received", vec![]);
+        }
+    }
+
+    // Exposes the trace id of sampled requests to the client on server errors, see
+    // `span::error_trace_id`
+    fn echo_trace_id(&mut self, lua: &Lua, txn: &Txn, msg: &HttpMessage) -> LuaResult<()> {
+        let pipeline = match self.pipeline(lua, txn) {
+            Some(pipeline) => pipeline,
+            None => return Ok(()),
+        };
+        let header = match pipeline.options.error_trace_header {
+            Some(ref header) => header,
+            None => return Ok(()),
+        };
+        let status = msg.get_stline()?.raw_get::<Option<i64>>("code")?;
+        let trace_id = get_context(txn).and_then(|cx| crate::span::error_trace_id(&cx, status));
+        if let Some(trace_id) = trace_id {
+            msg.set_header(header, trace_id)?;
+        }
+        Ok(())
+    }
 }
 
-impl UserFilter for TraceFilter {
-    const METHODS: u8 = FilterMethod::END_ANALYZE | FilterMethod::HTTP_HEADERS;
+impl<const M: u8> UserFilter for TraceFilter<M> {
+    const METHODS: u8 = M;
 
     fn new(lua: &Lua, args: LuaTable) -> LuaResult<Self> {
-        let silent_on = lua
-            .app_data_ref::<crate::exporter::Options>()
-            .map(|c| c.sampler.as_deref() == Some("SilentOn"))
-            .unwrap_or_default();
-
-        let mut this = Self {
-            silent_on,
-            ..Default::default()
-        };
+        let mut this = Self::default();
         if let Ok(args) = args.get::<String>(1) {
             for arg in args.split(';') {
                 let (name, value) = arg.split_once('=').unwrap_or_default();
-                if name == "start_client_span" {
-                    this.start_client_span = Some(value.parse().unwrap_or(true));
+                match name {
+                    "start_client_span" => {
+                        this.start_client_span = Some(value.parse().unwrap_or(true))
+                    }
+                    "inject_upstream" => this.inject_upstream = Some(value.parse().unwrap_or(true)),
+                    "omit_url_query" => this.omit_url_query = Some(value.parse().unwrap_or(true)),
+                    "tracer" if !value.is_empty() => this.tracer = Some(value.to_string()),
+                    _ => {}
                 }
             }
         }
+        // Rejected rather than ignored, so the frontend doesn't silently lose the setting
+        if M & FilterMethod::START_ANALYZE == 0 {
+            if this.tracer.is_some() {
+                return Err(unsupported(M, "tracer=", filter_name(DEFAULT_METHODS)));
+            }
+            if this.omit_url_query.is_some() {
+                return Err(unsupported(
+                    M,
+                    "omit_url_query=",
+                    filter_name(DEFAULT_METHODS),
+                ));
+            }
+        }
+        if !Self::CLIENT_SPANS && this.start_client_span == Some(true) {
+            return Err(unsupported(
+                M,
+                "start_client_span=true",
+                filter_name(LITE_METHODS),
+            ));
+        }
+        let payload_events = crate::sole_registration(lua, |options| options.payload_events);
+        if M & FilterMethod::HTTP_PAYLOAD == 0 && payload_events == Some(true) {
+            return Err(unsupported(
+                M,
+                "payload_events",
+                filter_name(PAYLOAD_METHODS),
+            ));
+        }
         Ok(this)
     }
 
@@ -126,13 +389,63 @@ impl UserFilter for TraceFilter {
         }
     }
 
-    fn end_analyze(&mut self, _lua: &Lua, txn: Txn, chn: Channel) -> LuaResult<FilterResult> {
+    // Only called with `payload_events`, for the response
+    fn http_payload(&mut self, _lua: &Lua, txn: Txn, msg: HttpMessage) -> LuaResult<Option<usize>> {
+        if msg.is_resp()? {
+            self.on_response_payload(&txn);
+        }
+        Ok(Some(msg.input()?))
+    }
+
+    fn http_end(&mut self, _lua: &Lua, txn: Txn, msg: HttpMessage) -> LuaResult<FilterResult> {
+        if self.payload_events && msg.is_resp()? {
+            if let Some(context) = get_context(&txn) {
+                context
+                    .span()
+                    .add_event("last response byte forwarded", vec![]);
+            }
+        }
+        Ok(FilterResult::Continue)
+    }
+
+    // Runs before the http-request rules, so `start_server_span` sees the selected tracer
+    // and the frontend's `omit_url_query`
+    fn start_analyze(&mut self, _lua: &Lua, txn: Txn, chn: Channel) -> LuaResult<FilterResult> {
+        if chn.is_resp()? {
+            return Ok(FilterResult::Continue);
+        }
+        if let Some(ref tracer) = self.tracer {
+            txn.set_var(crate::tracer::TRACER_VAR, tracer.as_str())?;
+        }
+        if let Some(omit) = self.omit_url_query {
+            txn.set_var(crate::span::OMIT_QUERY_VAR, omit)?;
+        }
+        Ok(FilterResult::Continue)
+    }
+
+    fn end_analyze(&mut self, lua: &Lua, txn: Txn, chn: Channel) -> LuaResult<FilterResult> {
         if chn.is_resp()? {
             // Finish client span
             if self.start_client_span.unwrap_or(true) {
+                let span = self.context.span();
+                // The 503 of a backend without a usable server has no response to record
+                if !self.response_seen && span.is_recording() && crate::span::txn_is_no_server(&txn)
+                {
+                    span.set_attribute(KeyValue::new(crate::span::NO_SERVER, true));
+                    span.set_status(trace::Status::error("no server available"));
+                }
+                crate::span::set_connection_error_attributes(
+                    &txn,
+                    &span,
+                    "bc",
+                    "haproxy.backend.error",
+                );
                 let _guard = crate::exporter::get_otel_runtime().enter();
                 self.context.span().end();
             }
+            if let Some(start) = self.upstream_start.take() {
+                crate::metrics::observe_txn(DurationMetric::Upstream, &txn, start.elapsed());
+            }
 
             // Finish server span when all filters are done
             if !txn
@@ -151,27 +464,19 @@ impl UserFilter for TraceFilter {
                 }
             };
             let span = parent_context.span();
-            let status = (txn.f.get::<Option<i64>>("txn_status", ())?).unwrap_or_default();
-            span.set_attribute(KeyValue::new(HTTP_RESPONSE_STATUS_CODE, status));
-            if status < 500 {
-                span.set_status(trace::Status::Ok);
-            } else {
-                span.set_status(trace::Status::error("5xx status code"));
+            let pipeline = self.pipeline(lua, &txn);
+            if pipeline
+                .as_ref()
+                .is_some_and(|p| p.options.propagation_only)
+            {
+                self.context = Context::default();
+                return Ok(FilterResult::Continue);
             }
-
-            let fe_name = txn.f.get_str("fe_name", ())?;
-            span.set_attribute(KeyValue::new("haproxy.frontend.name", fe_name));
-            let be_name = txn.f.get_str("be_name", ())?;
-            span.set_attribute(KeyValue::new("haproxy.backend.name", be_name));
-            let termination_state =
-                (txn.f.get::<Option<String>>("txn_sess_term_state", ()))?.unwrap_or_default();
-            span.set_attribute(KeyValue::new(
-                "haproxy.termination_state",
-                termination_state,
-            ));
-
-            let _guard = crate::exporter::get_otel_runtime().enter();
-            span.end();
+            let options = pipeline.as_ref().map(|p| &p.options);
+            crate::span::set_server_span_attributes(&txn, &span, options)?;
+            crate::span::set_compression_attributes(&txn, &span, true);
+            crate::metrics::observe_request(&txn, &parent_context);
+            crate::span::end_span(&txn, &parent_context);
 
             // Explicitly clear the context to free memory instantly.
             // Otherwise, the memory is held until the Lua garbage collector
@@ -205,6 +510,32 @@ impl Injector for HeaderInjector<'_> {
         if value.is_empty() {
             return;
         }
-        let _ = self.msg.set_header(key, value);
+        // The upstream request goes on without the trace context
+        if let Err(e) = self.msg.set_header(key, value) {
+            crate::strict::fault("inject upstream headers", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_methods() {
+        // Only the payload filter follows the response body
+        let payload = FilterMethod::HTTP_PAYLOAD | FilterMethod::HTTP_END;
+        assert_eq!(TraceFilter::<DEFAULT_METHODS>::METHODS & payload, 0);
+        assert_eq!(PAYLOAD_METHODS, 0b11111);
+        assert_eq!(LITE_METHODS & FilterMethod::START_ANALYZE, 0);
+        assert_eq!(INJECT_METHODS & FilterMethod::END_ANALYZE, 0);
+        assert_eq!(filter_name(INJECT_METHODS), "opentelemetry-trace-inject");
+
+        let error = unsupported(LITE_METHODS, "tracer=", filter_name(DEFAULT_METHODS));
+        assert_eq!(
+            error.to_string(),
+            "runtime error: haproxy-otel: tracer= is not supported by filter \
+             lua.opentelemetry-trace-lite, use lua.opentelemetry-trace"
+        );
     }
 }
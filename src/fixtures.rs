@@ -0,0 +1,137 @@
+//! Spans and collectors shared by the unit tests
+use std::borrow::Cow;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::UNIX_EPOCH;
+
+use opentelemetry::trace::{SpanContext, SpanId, SpanKind, Status, TraceFlags, TraceId};
+use opentelemetry::InstrumentationScope;
+use opentelemetry_http::{Bytes, HttpClient, HttpError, Request, Response};
+use opentelemetry_sdk::error::{OTelSdkError, OTelSdkResult};
+use opentelemetry_sdk::trace::{SpanData, SpanEvents, SpanExporter, SpanLinks};
+
+use crate::exporter::SendFuture;
+
+/// Trace id of the W3C Trace Context examples
+pub(crate) const TRACE_ID: u128 = 0x4bf9_2f35_77b3_4da6_a3ce_929d_0e0e_4736;
+
+/// Span id of the W3C Trace Context examples
+#[cfg_attr(not(feature = "direct"), allow(dead_code))]
+pub(crate) const SPAN_ID: u64 = 0x00f0_67aa_0ba9_02b7;
+
+/// Sampled context of a local span
+pub(crate) fn span_context(
+    trace_id: impl Into<TraceId>,
+    span_id: impl Into<SpanId>,
+) -> SpanContext {
+    let (trace_id, span_id) = (trace_id.into(), span_id.into());
+    SpanContext::new(
+        trace_id,
+        span_id,
+        TraceFlags::SAMPLED,
+        false,
+        Default::default(),
+    )
+}
+
+/// Ended server span `GET example.com` of an empty context, without attributes
+pub(crate) fn span() -> SpanData {
+    SpanData {
+        span_context: SpanContext::empty_context(),
+        parent_span_id: SpanId::INVALID,
+        span_kind: SpanKind::Server,
+        name: Cow::Borrowed("GET example.com"),
+        start_time: UNIX_EPOCH,
+        end_time: UNIX_EPOCH,
+        attributes: Vec::new(),
+        dropped_attributes_count: 0,
+        events: SpanEvents::default(),
+        links: SpanLinks::default(),
+        status: Status::Unset,
+        instrumentation_scope: InstrumentationScope::builder("test").build(),
+    }
+}
+
+/// Collector that can be taken down, counts the export attempts and keeps the spans it
+/// accepted. Batches with a span named `rejected` fail as an HTTP 400 response.
+///
+/// Clones share the collector, to look into it once it is moved into an exporter.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct Collector {
+    pub(crate) down: Arc<AtomicBool>,
+    pub(crate) attempts: Arc<AtomicUsize>,
+    pub(crate) spans: Arc<Mutex<Vec<SpanData>>>,
+}
+
+impl Collector {
+    pub(crate) fn set_down(&self, down: bool) {
+        self.down.store(down, Ordering::Relaxed);
+    }
+
+    pub(crate) fn attempts(&self) -> usize {
+        self.attempts.load(Ordering::Relaxed)
+    }
+
+    /// Names of the spans accepted so far
+    pub(crate) fn names(&self) -> Vec<String> {
+        let spans = self.spans.lock().unwrap();
+        spans.iter().map(|span| span.name.to_string()).collect()
+    }
+}
+
+impl SpanExporter for Collector {
+    async fn export(&self, batch: Vec<SpanData>) -> OTelSdkResult {
+        self.attempts.fetch_add(1, Ordering::Relaxed);
+        if self.down.load(Ordering::Relaxed) {
+            return Err(OTelSdkError::InternalFailure("connection refused".into()));
+        }
+        if batch.iter().any(|span| span.name == "rejected") {
+            return Err(OTelSdkError::InternalFailure(
+                "OpenTelemetry trace export failed. Url: 400, Status Code: \
+                 http://collector:4318/v1/traces, Response: b\"\""
+                    .into(),
+            ));
+        }
+        self.spans.lock().unwrap().extend(batch);
+        Ok(())
+    }
+}
+
+type Respond = dyn Fn(&Request<Bytes>) -> Result<Response<Bytes>, HttpError> + Send + Sync;
+
+/// Collector of the HTTP exporters, answering every request with the response of `respond`
+pub(crate) struct HttpCollector(Box<Respond>);
+
+impl HttpCollector {
+    pub(crate) fn new(
+        respond: impl Fn(&Request<Bytes>) -> Result<Response<Bytes>, HttpError> + Send + Sync + 'static,
+    ) -> Self {
+        HttpCollector(Box::new(respond))
+    }
+
+    /// Collector answering every request with `status`
+    pub(crate) fn status(status: u16) -> Self {
+        HttpCollector::new(move |_| response(status))
+    }
+}
+
+impl std::fmt::Debug for HttpCollector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("HttpCollector").finish_non_exhaustive()
+    }
+}
+
+impl HttpClient for HttpCollector {
+    fn send_bytes<'a, 'b>(&'a self, request: Request<Bytes>) -> SendFuture<'b>
+    where
+        'a: 'b,
+        Self: 'b,
+    {
+        Box::pin(async move { (self.0)(&request) })
+    }
+}
+
+/// Empty response of `status`
+pub(crate) fn response(status: u16) -> Result<Response<Bytes>, HttpError> {
+    Ok(Response::builder().status(status).body(Bytes::new())?)
+}
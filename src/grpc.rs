@@ -0,0 +1,199 @@
+use std::error::Error as StdError;
+use std::time::Duration;
+
+use opentelemetry_proto::tonic::collector::trace::v1::trace_service_client::TraceServiceClient;
+use opentelemetry_proto::tonic::collector::trace::v1::ExportTraceServiceRequest;
+use opentelemetry_proto::transform::common::tonic::ResourceAttributesWithSchema;
+use opentelemetry_proto::transform::trace::tonic::group_spans_by_resource_and_scope;
+use opentelemetry_sdk::error::{OTelSdkError, OTelSdkResult};
+use opentelemetry_sdk::trace::{SpanData, SpanExporter};
+use opentelemetry_sdk::Resource;
+use tonic::codec::CompressionEncoding;
+use tonic::service::Interceptor;
+use tonic::transport::{Channel, ClientTlsConfig, Identity};
+
+use crate::compression::Compression;
+use crate::headers::HeadersInterceptor;
+use crate::partial::{self, PartialSuccess};
+use crate::tls::{ClientIdentity, ReloadingClient};
+
+type TracesClient = TraceServiceClient<Channel>;
+
+/// Client of the exporter, rebuilt when the files of the client certificate change
+#[derive(Debug)]
+enum Client {
+    Fixed(TracesClient),
+    Reloading(ReloadingClient<TracesClient>),
+}
+
+/// Span exporter of the OTLP/gRPC protocol (`otlp.protocol = "grpc"`).
+///
+/// Sends the export requests like the tonic exporter of `opentelemetry-otlp`, which drops the
+/// responses: the `partial_success` of the collector is reported as for the HTTP exporters.
+#[derive(Debug)]
+pub(crate) struct GrpcExporter {
+    client: Client,
+    interceptor: HeadersInterceptor,
+    resource: ResourceAttributesWithSchema,
+}
+
+impl GrpcExporter {
+    /// Exporter to `endpoint`, presenting the client certificate of `identity` if any
+    pub(crate) fn new(
+        endpoint: &str,
+        timeout: Duration,
+        tls_config: Option<ClientTlsConfig>,
+        identity: Option<ClientIdentity>,
+        compression: Option<Compression>,
+        interceptor: HeadersInterceptor,
+    ) -> Result<Self, Box<dyn StdError + Send + Sync>> {
+        let endpoint = endpoint.to_string();
+        let connect = move |identity: Option<Identity>| {
+            let channel = Channel::from_shared(endpoint.clone())
+                .map_err(|e| format!("invalid grpc endpoint '{endpoint}': {e}"))?;
+            let tls_config = match identity {
+                Some(identity) => Some(tls_config.clone().unwrap_or_default().identity(identity)),
+                None => tls_config.clone(),
+            };
+            let channel = match tls_config {
+                Some(tls_config) => channel.tls_config(tls_config)?,
+                None => channel,
+            };
+            let mut client = TraceServiceClient::new(channel.timeout(timeout).connect_lazy());
+            if let Some(compression) = compression {
+                let encoding = match compression {
+                    Compression::Gzip => CompressionEncoding::Gzip,
+                    Compression::Zstd => CompressionEncoding::Zstd,
+                };
+                client = client.send_compressed(encoding).accept_compressed(encoding);
+            }
+            Ok::<_, Box<dyn StdError + Send + Sync>>(client)
+        };
+        let client = match identity {
+            Some(identity) => {
+                Client::Reloading(ReloadingClient::with_identity(identity, move |identity| {
+                    let (cert, key) = identity.read()?;
+                    connect(Some(Identity::from_pem(cert, key)))
+                })?)
+            }
+            None => Client::Fixed(connect(None)?),
+        };
+        Ok(GrpcExporter {
+            client,
+            interceptor,
+            resource: ResourceAttributesWithSchema::default(),
+        })
+    }
+
+    fn client(&self) -> TracesClient {
+        match &self.client {
+            Client::Fixed(client) => client.clone(),
+            Client::Reloading(client) => client.client(),
+        }
+    }
+}
+
+impl SpanExporter for GrpcExporter {
+    async fn export(&self, batch: Vec<SpanData>) -> OTelSdkResult {
+        let (metadata, extensions, _) = (self.interceptor.clone())
+            .call(tonic::Request::new(()))
+            .map_err(|e| OTelSdkError::InternalFailure(format!("error: {e:?}")))?
+            .into_parts();
+        let request = ExportTraceServiceRequest {
+            resource_spans: group_spans_by_resource_and_scope(batch, &self.resource),
+        };
+        let response = (self.client())
+            .export(tonic::Request::from_parts(metadata, extensions, request))
+            .await
+            // As the SDK exporter, `RetryExporter` reads the status from the message
+            .map_err(|e| OTelSdkError::InternalFailure(e.to_string()))?;
+        if let Some(partial_success) = response.into_inner().partial_success {
+            partial::report(PartialSuccess {
+                rejected_spans: partial_success.rejected_spans.max(0) as u64,
+                error_message: partial_success.error_message,
+            });
+        }
+        Ok(())
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        self.resource = resource.into();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use super::*;
+    use crate::fixtures::span;
+    use crate::headers::ExportHeaders;
+
+    #[test]
+    fn test_unreachable_collector() {
+        // A port nothing listens on
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let runtime = crate::exporter::get_otel_runtime();
+        let _guard = runtime.enter();
+        let headers = ExportHeaders::from_env(Default::default());
+        let exporter = GrpcExporter::new(
+            &format!("http://127.0.0.1:{port}"),
+            Duration::from_secs(1),
+            None,
+            None,
+            None,
+            HeadersInterceptor(headers.clone(), None),
+        )
+        .unwrap();
+        // The formatted status `RetryExporter` reads the code from
+        match runtime.block_on(exporter.export(vec![span()])) {
+            Err(OTelSdkError::InternalFailure(e)) => {
+                assert!(e.starts_with("status: Unavailable,"), "{e}")
+            }
+            result => panic!("unexpected {result:?}"),
+        }
+        let interceptor = HeadersInterceptor(headers, None);
+        let invalid = GrpcExporter::new(
+            "not a uri",
+            Duration::from_secs(1),
+            None,
+            None,
+            None,
+            interceptor,
+        );
+        assert!(invalid.is_err());
+    }
+
+    #[test]
+    fn test_client_identity() {
+        let dir = std::env::temp_dir().join(format!("haproxy-otel-grpc-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let identity = ClientIdentity {
+            cert_file: dir.join("client.pem").display().to_string(),
+            key_file: dir.join("client.key").display().to_string(),
+        };
+        let _guard = crate::exporter::get_otel_runtime().enter();
+        let new = |identity: &ClientIdentity| {
+            let headers = ExportHeaders::from_env(Default::default());
+            GrpcExporter::new(
+                "https://collector:4317",
+                Duration::from_secs(1),
+                None,
+                Some(identity.clone()),
+                None,
+                HeadersInterceptor(headers, None),
+            )
+        };
+        assert!(new(&identity).is_err());
+
+        std::fs::write(&identity.cert_file, include_bytes!("testdata/client.pem")).unwrap();
+        std::fs::write(&identity.key_file, include_bytes!("testdata/client.key")).unwrap();
+        let exporter = new(&identity).unwrap();
+        assert!(matches!(exporter.client, Client::Reloading(_)));
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,102 @@
+use std::ops::RangeInclusive;
+
+use opentelemetry::trace::{SpanContext, SpanId, TraceFlags, TraceId, TraceState};
+
+/// TLV types reserved for custom use by the PROXY protocol v2 specification
+pub(crate) const PP2_CUSTOM_TLVS: RangeInclusive<u64> = 0xE0..=0xEF;
+
+/// Variable holding the encoded context to send in the PROXY protocol TLV
+/// (`set-proxy-v2-tlv-fmt(<type>) %[var(txn.otel_pp2_context)]`)
+pub(crate) const PP2_CONTEXT_VAR: &str = "txn.otel_pp2_context";
+
+/// Encodes a span context as `<trace_id>-<span_id>-<flags>[;<tracestate>]`.
+///
+/// Unlike a `traceparent` header this fits in a single HAProxy variable or stick-table
+/// string and keeps the trace state, so other Lua modules can hand the context over
+/// between proxies or transactions.
+pub(crate) fn encode(span_context: &SpanContext) -> String {
+    let mut encoded = format!(
+        "{}-{}-{:02x}",
+        span_context.trace_id(),
+        span_context.span_id(),
+        span_context.trace_flags().to_u8()
+    );
+    let trace_state = span_context.trace_state().header();
+    if !trace_state.is_empty() {
+        encoded.push(';');
+        encoded.push_str(&trace_state);
+    }
+    encoded
+}
+
+/// Decodes a span context produced by [`encode`], as a remote context
+pub(crate) fn decode(encoded: &str) -> Option<SpanContext> {
+    let (ids, trace_state) = match encoded.split_once(';') {
+        Some((ids, trace_state)) => (ids, trace_state.parse::<TraceState>().ok()?),
+        None => (encoded, TraceState::default()),
+    };
+    let mut parts = ids.trim().split('-');
+    let trace_id = parts.next().filter(|id| id.len() == 32)?;
+    let span_id = parts.next().filter(|id| id.len() == 16)?;
+    let flags = parts.next().filter(|flags| flags.len() == 2)?;
+    if parts.next().is_some() {
+        return None;
+    }
+    let span_context = SpanContext::new(
+        TraceId::from_hex(trace_id).ok()?,
+        SpanId::from_hex(span_id).ok()?,
+        TraceFlags::new(u8::from_str_radix(flags, 16).ok()?),
+        true,
+        trace_state,
+    );
+    span_context.is_valid().then_some(span_context)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() {
+        let span_context = SpanContext::new(
+            TraceId::from_hex("4bf92f3577b34da6a3ce929d0e0e4736").unwrap(),
+            SpanId::from_hex("00f067aa0ba902b7").unwrap(),
+            TraceFlags::SAMPLED,
+            true,
+            "vendor=value,other=1".parse().unwrap(),
+        );
+        let encoded = encode(&span_context);
+        assert_eq!(
+            encoded,
+            "4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01;vendor=value,other=1"
+        );
+        assert_eq!(decode(&encoded), Some(span_context));
+
+        let unsampled = decode("4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-00").unwrap();
+        assert!(!unsampled.is_sampled());
+        assert!(unsampled.is_remote());
+        assert_eq!(unsampled.trace_state().header(), "");
+    }
+
+    #[test]
+    fn test_decode_invalid() {
+        assert_eq!(decode(""), None);
+        assert_eq!(
+            decode("4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7"),
+            None
+        );
+        assert_eq!(
+            decode("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"),
+            None
+        );
+        // All-zero ids are invalid
+        assert_eq!(
+            decode("00000000000000000000000000000000-00f067aa0ba902b7-01"),
+            None
+        );
+        assert_eq!(
+            decode("4bf92f3577b34da6a3ce929d0e0e473g-00f067aa0ba902b7-01"),
+            None
+        );
+    }
+}
@@ -0,0 +1,128 @@
+use std::env;
+
+use opentelemetry_http::{Bytes, HttpClient, Request};
+use reqwest::header::{HeaderMap, HeaderName};
+
+use crate::exporter::SendFuture;
+
+/// Headers of the OTLP export requests.
+///
+/// The SDK exporters add the `OTEL_EXPORTER_OTLP_TRACES_HEADERS` or `OTEL_EXPORTER_OTLP_HEADERS`
+/// themselves, over the configured ones. They are removed again before the resolved headers
+/// are set, so `otlp.headers` win and further destinations don't get them at all.
+#[derive(Clone, Debug)]
+pub(crate) struct ExportHeaders {
+    // Headers the SDK took from the environment
+    env: Vec<HeaderName>,
+    headers: HeaderMap,
+}
+
+impl ExportHeaders {
+    /// Resolved headers, replacing the ones the SDK reads from the environment
+    pub(crate) fn from_env(headers: HeaderMap) -> Self {
+        let env = [
+            "OTEL_EXPORTER_OTLP_TRACES_HEADERS",
+            "OTEL_EXPORTER_OTLP_HEADERS",
+        ]
+        .iter()
+        .find_map(|name| env::var(name).ok());
+        Self::new(headers, env.as_deref().unwrap_or_default())
+    }
+
+    /// Resolved headers, replacing the `key1=value1,key2=value2` list the SDK adds
+    fn new(headers: HeaderMap, env: &str) -> Self {
+        let env = env
+            .split(',')
+            .filter_map(|entry| {
+                HeaderName::from_bytes(entry.split('=').next()?.trim().as_bytes()).ok()
+            })
+            .collect();
+        ExportHeaders { env, headers }
+    }
+
+    fn apply(&self, headers: &mut HeaderMap) {
+        for name in &self.env {
+            headers.remove(name);
+        }
+        for (name, value) in &self.headers {
+            headers.insert(name, value.clone());
+        }
+    }
+}
+
+/// HTTP client of the OTLP HTTP exporters setting the export headers
+#[derive(Debug)]
+pub(crate) struct HeadersClient<C> {
+    inner: C,
+    headers: ExportHeaders,
+}
+
+impl<C: HttpClient> HeadersClient<C> {
+    pub(crate) fn new(inner: C, headers: ExportHeaders) -> Self {
+        Self { inner, headers }
+    }
+}
+
+impl<C: HttpClient> HttpClient for HeadersClient<C> {
+    fn send_bytes<'a, 'b>(&'a self, mut request: Request<Bytes>) -> SendFuture<'b>
+    where
+        'a: 'b,
+        Self: 'b,
+    {
+        Box::pin(async move {
+            self.headers.apply(request.headers_mut());
+            self.inner.send_bytes(request).await
+        })
+    }
+}
+
+/// Interceptor of the OTLP gRPC exporter setting the export headers as metadata, then the
+/// bearer token if one is configured
+#[cfg(feature = "grpc")]
+#[derive(Clone, Debug)]
+pub(crate) struct HeadersInterceptor(
+    pub(crate) ExportHeaders,
+    pub(crate) Option<crate::token::BearerTokenInterceptor>,
+);
+
+#[cfg(feature = "grpc")]
+impl tonic::service::Interceptor for HeadersInterceptor {
+    fn call(
+        &mut self,
+        mut request: tonic::Request<()>,
+    ) -> Result<tonic::Request<()>, tonic::Status> {
+        let mut headers = std::mem::take(request.metadata_mut()).into_headers();
+        self.0.apply(&mut headers);
+        *request.metadata_mut() = tonic::metadata::MetadataMap::from_headers(headers);
+        match &mut self.1 {
+            Some(token) => token.call(request),
+            None => Ok(request),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    #[test]
+    fn test_apply() {
+        let mut configured = HeaderMap::new();
+        configured.insert("api-key", HeaderValue::from_static("lua"));
+        let headers = ExportHeaders::new(configured, "API-Key=env, x-org=nais");
+
+        // As the SDK sends them: the environment headers over the configured ones
+        let mut request = HeaderMap::new();
+        request.insert(
+            "content-type",
+            HeaderValue::from_static("application/x-protobuf"),
+        );
+        request.insert("api-key", HeaderValue::from_static("env"));
+        request.insert("x-org", HeaderValue::from_static("nais"));
+        headers.apply(&mut request);
+        assert_eq!(request.len(), 2);
+        assert_eq!(request["content-type"], "application/x-protobuf");
+        assert_eq!(request["api-key"], "lua");
+    }
+}
@@ -0,0 +1,93 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use haproxy_api::Core;
+use mlua::prelude::{Lua, LuaFunction, LuaResult, LuaTable, LuaValue};
+use opentelemetry::trace::{self, Span, Tracer};
+use opentelemetry::KeyValue;
+
+/// Subscribes to HAProxy `SERVER_CHECK` events and records one health check span
+/// out of every `sample_every` checks.
+///
+/// Requires HAProxy >= 2.9 (event subscriptions). The subscription is made from a
+/// single Lua state to avoid recording the same check once per thread.
+pub(crate) fn subscribe(
+    lua: &Lua,
+    core: &Core,
+    registration: String,
+    sample_every: u64,
+) -> LuaResult<()> {
+    if sample_every == 0 || core.thread()? > 1 {
+        return Ok(());
+    }
+
+    let counter = Arc::new(AtomicU64::new(0));
+    let handler = lua.create_function(move |lua, (_event, data): (String, LuaTable)| {
+        if !counter
+            .fetch_add(1, Ordering::Relaxed)
+            .is_multiple_of(sample_every)
+        {
+            return Ok(());
+        }
+        record_check(lua, &registration, data)
+    })?;
+
+    let event_sub = core.get::<LuaFunction>("event_sub")?;
+    if let Err(e) = event_sub.call::<LuaValue>((["SERVER_CHECK"], handler)) {
+        crate::exporter::log_warn(&format!(
+            "health check tracing is not available (requires HAProxy >= 2.9): {e}"
+        ));
+    }
+    Ok(())
+}
+
+fn record_check(lua: &Lua, registration: &str, data: LuaTable) -> LuaResult<()> {
+    let pipeline = match crate::pipeline(lua, registration) {
+        Some(pipeline) => pipeline,
+        None => return Ok(()),
+    };
+    let check = data.get::<LuaTable>("check")?;
+
+    let server = data.get::<Option<String>>("name")?.unwrap_or_default();
+    let backend = data
+        .get::<Option<String>>("proxy_name")?
+        .unwrap_or_default();
+    let result = check.get::<Option<String>>("result")?.unwrap_or_default();
+    let duration = check.get::<Option<u64>>("duration")?.unwrap_or_default();
+    let agent = check.get::<Option<bool>>("agent")?.unwrap_or_default();
+    let reason = check
+        .get::<Option<LuaTable>>("reason")?
+        .map(|reason| reason.get::<Option<String>>("desc"))
+        .transpose()?
+        .flatten();
+
+    let end_time = SystemTime::now();
+    let start_time = end_time - Duration::from_millis(duration);
+    let mut attributes = vec![
+        KeyValue::new("haproxy.healthcheck", true),
+        KeyValue::new("haproxy.healthcheck.result", result.clone()),
+        KeyValue::new("haproxy.healthcheck.agent", agent),
+        KeyValue::new("haproxy.backend.name", backend.clone()),
+        KeyValue::new("haproxy.server.name", server.clone()),
+    ];
+    if let Some(ref reason) = reason {
+        attributes.push(KeyValue::new("haproxy.healthcheck.reason", reason.clone()));
+    }
+
+    let _guard = crate::exporter::get_otel_runtime().enter();
+    let tracer = &pipeline.tracer;
+    let mut span = tracer
+        .span_builder(format!("health check {backend}/{server}"))
+        .with_kind(trace::SpanKind::Client)
+        .with_start_time(start_time)
+        .with_attributes(attributes)
+        .start(tracer);
+    if result == "FAILED" {
+        span.set_status(trace::Status::error(reason.unwrap_or(result)));
+    } else {
+        span.set_status(trace::Status::Ok);
+    }
+    span.end_with_timestamp(end_time);
+    Ok(())
+}
@@ -0,0 +1,172 @@
+use haproxy_api::Txn;
+use mlua::prelude::{Lua, LuaError, LuaObjectLike, LuaResult, LuaTable, LuaValue};
+use opentelemetry::propagation::Injector;
+use opentelemetry::trace::{self, TraceContextExt, Tracer};
+use opentelemetry::KeyValue;
+use opentelemetry_semantic_conventions::trace::{
+    HTTP_REQUEST_METHOD, HTTP_RESPONSE_STATUS_CODE, SERVER_ADDRESS, URL_FULL,
+};
+
+use crate::get_context;
+
+/// Performs a `core.httpclient` request as a child client span of the current transaction.
+///
+/// Usage from Lua: `otel.httpclient(txn, "post", { url = ..., headers = ..., body = ... })`.
+/// The trace context is injected into the request headers and the httpclient response
+/// is returned unchanged. Without an active server span the request is sent untraced.
+pub fn httpclient(
+    lua: &Lua,
+    (txn, method, request): (Txn, String, LuaTable),
+) -> LuaResult<LuaValue> {
+    let method = method.to_ascii_lowercase();
+    if !matches!(method.as_str(), "get" | "head" | "put" | "post" | "delete") {
+        return Err(LuaError::RuntimeError(format!(
+            "haproxy-otel: unsupported httpclient method '{method}'"
+        )));
+    }
+
+    let client = lua
+        .globals()
+        .get::<LuaTable>("core")?
+        .call_function::<LuaTable>("httpclient", ())?;
+
+    let parent_context = get_context(&txn);
+    let pipeline = parent_context
+        .as_ref()
+        .and_then(|_| crate::txn_pipeline(lua, &txn));
+    let (parent_context, pipeline) = match (parent_context, pipeline) {
+        (Some(parent_context), Some(pipeline)) => (parent_context, pipeline),
+        _ => return client.call_method(&method, request),
+    };
+    let tracer = &pipeline.tracer;
+    let silent_on = pipeline.silent_on;
+
+    let url = (request.get::<Option<String>>("url")?).unwrap_or_default();
+    let host = url_host(&url).to_string();
+    // Same policy as the `url.query` of the server span
+    let url = match crate::span::omits_url_query(&txn, &pipeline.options) {
+        true => without_query(&url).to_string(),
+        false => url,
+    };
+    let http_method = method.to_ascii_uppercase();
+    let span_name = format!("{http_method} {host}");
+    let mut attributes = vec![
+        KeyValue::new(HTTP_REQUEST_METHOD, http_method),
+        KeyValue::new(URL_FULL, url),
+        KeyValue::new(SERVER_ADDRESS, host),
+    ];
+    if let Ok(Some(tenant)) = txn.get_var::<Option<String>>("txn.__otel_tenant") {
+        attributes.push(KeyValue::new(crate::tenant::TENANT, tenant));
+    }
+    let span_builder = tracer
+        .span_builder(span_name)
+        .with_kind(trace::SpanKind::Client)
+        .with_attributes(attributes);
+    let span = tracer.build_with_context(span_builder, &parent_context);
+    let context = parent_context.with_span(span);
+
+    let headers = match request.get::<Option<LuaTable>>("headers")? {
+        Some(headers) => headers,
+        None => {
+            let headers = lua.create_table()?;
+            request.set("headers", &headers)?;
+            headers
+        }
+    };
+    pipeline
+        .propagator
+        .inject_context(&context, &mut TableInjector::new(&headers, silent_on));
+
+    let response = client.call_method::<LuaValue>(&method, request);
+
+    let span = context.span();
+    match &response {
+        Ok(LuaValue::Table(response)) => {
+            let status = response.get::<i64>("status").unwrap_or_default();
+            if status > 0 {
+                span.set_attribute(KeyValue::new(HTTP_RESPONSE_STATUS_CODE, status));
+            }
+            if status == 0 {
+                span.set_status(trace::Status::error("request failed"));
+            } else if status >= 500 {
+                span.set_status(trace::Status::error("5xx status code"));
+            } else {
+                span.set_status(trace::Status::Ok);
+            }
+        }
+        Ok(_) => span.set_status(trace::Status::error("request failed")),
+        Err(e) => span.set_status(trace::Status::error(e.to_string())),
+    }
+
+    let _guard = crate::exporter::get_otel_runtime().enter();
+    span.end();
+    response
+}
+
+/// Returns the `host[:port]` part of an absolute URL
+fn url_host(url: &str) -> &str {
+    let rest = url.split_once("://").map_or(url, |(_, rest)| rest);
+    let end = rest.find(['/', '?', '#']).unwrap_or(rest.len());
+    let authority = &rest[..end];
+    authority
+        .rsplit_once('@')
+        .map_or(authority, |(_, host)| host)
+}
+
+/// Returns the URL up to its query string
+fn without_query(url: &str) -> &str {
+    url.split_once('?').map_or(url, |(url, _)| url)
+}
+
+/// Injects headers into an httpclient request table (`{ name = { value } }`)
+struct TableInjector<'a> {
+    headers: &'a LuaTable,
+    silent_on: bool,
+}
+
+impl<'a> TableInjector<'a> {
+    fn new(headers: &'a LuaTable, silent_on: bool) -> Self {
+        Self { headers, silent_on }
+    }
+}
+
+impl Injector for TableInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if self.silent_on && key.eq_ignore_ascii_case("x-b3-sampled") {
+            return;
+        }
+        // Same as the upstream request: never send empty header values
+        if value.is_empty() {
+            return;
+        }
+        let _ = self.headers.set(key, [value]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_url_host() {
+        assert_eq!(
+            url_host("http://auth.local:8080/check?x=1"),
+            "auth.local:8080"
+        );
+        assert_eq!(url_host("https://user:pw@example.com"), "example.com");
+        assert_eq!(url_host("http://example.com?q"), "example.com");
+        assert_eq!(url_host("example.com/path"), "example.com");
+    }
+
+    #[test]
+    fn test_without_query() {
+        assert_eq!(
+            without_query("http://auth.local/check?token=secret"),
+            "http://auth.local/check"
+        );
+        assert_eq!(
+            without_query("http://auth.local/check"),
+            "http://auth.local/check"
+        );
+    }
+}
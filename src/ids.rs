@@ -0,0 +1,121 @@
+use std::cell::Cell;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use opentelemetry::trace::{SpanId, TraceId};
+use opentelemetry_sdk::trace::IdGenerator;
+
+thread_local! {
+    // Transaction handled by this thread, and the number of ids its spans took
+    static TRANSACTION: Cell<Option<(u64, u64)>> = const { Cell::new(None) };
+}
+
+/// Derives the ids of the spans this thread starts next from the request (`id_seed`).
+///
+/// A transaction runs on a single thread, so its ids don't depend on how the requests are
+/// spread over the threads or interleaved.
+pub(crate) fn begin_transaction(request: &[&str]) {
+    let mut hasher = DefaultHasher::new();
+    request.hash(&mut hasher);
+    TRANSACTION.set(Some((hasher.finish(), 0)));
+}
+
+/// Deterministic id generator for tests (`id_seed` option).
+///
+/// Ids are derived from the seed and the request of the transaction (method, host, path and
+/// query), so a given request always gets the same ids whatever the number of threads.
+/// Spans started outside a transaction use a shared counter. Ratio-based sampling only
+/// depends on the trace id, which makes the sampling decisions reproducible as well. Not
+/// meant for production: ids are predictable.
+#[derive(Debug)]
+pub(crate) struct SeededIdGenerator {
+    seed: u64,
+    counter: AtomicU64,
+}
+
+impl SeededIdGenerator {
+    pub(crate) fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            counter: AtomicU64::new(0),
+        }
+    }
+
+    fn next(&self) -> u64 {
+        loop {
+            let value = match TRANSACTION.get() {
+                Some((key, n)) => {
+                    TRANSACTION.set(Some((key, n + 1)));
+                    splitmix64(splitmix64(self.seed ^ key).wrapping_add(n))
+                }
+                None => {
+                    let n = self.counter.fetch_add(1, Ordering::Relaxed);
+                    splitmix64(self.seed.wrapping_add(n))
+                }
+            };
+            // All-zero ids are invalid
+            if value != 0 {
+                return value;
+            }
+        }
+    }
+}
+
+impl IdGenerator for SeededIdGenerator {
+    fn new_trace_id(&self) -> TraceId {
+        let high = self.next() as u128;
+        let low = self.next() as u128;
+        TraceId::from((high << 64) | low)
+    }
+
+    fn new_span_id(&self) -> SpanId {
+        SpanId::from(self.next())
+    }
+}
+
+/// SplitMix64 finalizer, spreads consecutive inputs over the whole id space
+fn splitmix64(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seeded_ids_are_deterministic() {
+        let a = SeededIdGenerator::new(42);
+        let b = SeededIdGenerator::new(42);
+        let ids = |g: &SeededIdGenerator| (g.new_trace_id(), g.new_span_id(), g.new_span_id());
+        let first = ids(&a);
+        assert_eq!(first, ids(&b));
+        assert_ne!(first, ids(&a));
+        assert_ne!(first.1, first.2);
+        assert_ne!(ids(&SeededIdGenerator::new(43)), first);
+        assert_ne!(first.0, TraceId::INVALID);
+    }
+
+    #[test]
+    fn test_transaction_ids() {
+        let generator = SeededIdGenerator::new(42);
+        let ids = |request: &[&str]| {
+            begin_transaction(request);
+            (generator.new_trace_id(), generator.new_span_id())
+        };
+        let first = ids(&["GET", "example.com", "/a", ""]);
+        let other = ids(&["GET", "example.com", "/b", ""]);
+        assert_ne!(first, other);
+        // Whatever the transactions handled before, or by other threads
+        let on_thread = std::thread::spawn(move || {
+            begin_transaction(&["GET", "example.com", "/a", ""]);
+            let generator = SeededIdGenerator::new(42);
+            (generator.new_trace_id(), generator.new_span_id())
+        });
+        assert_eq!(on_thread.join().unwrap(), first);
+        assert_eq!(ids(&["GET", "example.com", "/a", ""]), first);
+        TRANSACTION.set(None);
+    }
+}
@@ -0,0 +1,90 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use opentelemetry::trace::{SpanId, Status};
+use opentelemetry::{Array, KeyValue, Value};
+use opentelemetry_sdk::trace::SpanData;
+use serde_json::{json, Map};
+
+/// Serializes a finished span to a flat JSON object for the debugging exporters
+pub(crate) fn span_to_json(span: &SpanData, service_name: &str) -> serde_json::Value {
+    let parent_span_id =
+        (span.parent_span_id != SpanId::INVALID).then(|| span.parent_span_id.to_string());
+    let status = match &span.status {
+        Status::Unset => json!({ "code": "unset" }),
+        Status::Ok => json!({ "code": "ok" }),
+        Status::Error { description } => json!({ "code": "error", "message": description }),
+    };
+    let duration_ms = span
+        .end_time
+        .duration_since(span.start_time)
+        .unwrap_or_default()
+        .as_secs_f64()
+        * 1000.0;
+    let events = span
+        .events
+        .iter()
+        .map(|event| {
+            json!({
+                "name": event.name,
+                "time_unix_nano": unix_nanos(event.timestamp),
+                "attributes": attributes_to_json(&event.attributes),
+            })
+        })
+        .collect::<Vec<_>>();
+    let links = span
+        .links
+        .iter()
+        .map(|link| {
+            json!({
+                "trace_id": link.span_context.trace_id().to_string(),
+                "span_id": link.span_context.span_id().to_string(),
+            })
+        })
+        .collect::<Vec<_>>();
+
+    json!({
+        "service": service_name,
+        "trace_id": span.span_context.trace_id().to_string(),
+        "span_id": span.span_context.span_id().to_string(),
+        "parent_span_id": parent_span_id,
+        "name": span.name,
+        "kind": format!("{:?}", span.span_kind),
+        "start_time_unix_nano": unix_nanos(span.start_time),
+        "end_time_unix_nano": unix_nanos(span.end_time),
+        "duration_ms": duration_ms,
+        "status": status,
+        "attributes": attributes_to_json(&span.attributes),
+        "events": events,
+        "links": links,
+    })
+}
+
+fn attributes_to_json(attributes: &[KeyValue]) -> serde_json::Value {
+    let map = attributes
+        .iter()
+        .map(|kv| (kv.key.to_string(), value_to_json(&kv.value)))
+        .collect::<Map<_, _>>();
+    serde_json::Value::Object(map)
+}
+
+fn value_to_json(value: &Value) -> serde_json::Value {
+    match value {
+        Value::Bool(b) => json!(b),
+        Value::I64(i) => json!(i),
+        Value::F64(f) => json!(f),
+        Value::String(s) => json!(s.as_str()),
+        Value::Array(Array::Bool(values)) => json!(values),
+        Value::Array(Array::I64(values)) => json!(values),
+        Value::Array(Array::F64(values)) => json!(values),
+        Value::Array(Array::String(values)) => {
+            json!(values.iter().map(|s| s.as_str()).collect::<Vec<_>>())
+        }
+        value => json!(value.to_string()),
+    }
+}
+
+fn unix_nanos(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or_default()
+}
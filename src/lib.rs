@@ -1,53 +1,779 @@
-use haproxy_api::{Action, Core};
-use mlua::prelude::{Lua, LuaResult, LuaTable};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use haproxy_api::{Action, Core, Txn};
+use mlua::prelude::{Lua, LuaError, LuaResult, LuaTable, LuaValue};
+use opentelemetry::trace::TraceContextExt as _;
 
 pub(crate) use cache::{get_context, remove_context, store_context};
+pub use httpclient::httpclient;
 
 pub fn cache_size(_lua: &Lua, _: ()) -> LuaResult<usize> {
     Ok(cache::get_size())
 }
 
+/// Duration histograms in the Prometheus text format (empty unless `duration_metrics` is enabled)
+pub fn metrics(_lua: &Lua, _: ()) -> LuaResult<String> {
+    Ok(metrics::render())
+}
+
+/// Forces 100% sampling for `seconds` and copies finished spans to a local JSON lines file
+/// (`path`, defaults to a file in the temp directory). Returns the path of the dump file.
+pub fn dump(_lua: &Lua, (seconds, path): (u64, Option<String>)) -> LuaResult<String> {
+    let duration = Duration::from_secs(seconds).min(dump::MAX_DUMP_DURATION);
+    let path = dump::start(duration, path.map(PathBuf::from))
+        .map_err(|e| LuaError::RuntimeError(format!("haproxy-otel: cannot start dump: {e}")))?;
+    exporter::log_info(&format!(
+        "dump to {} started for {}s, sampling all requests",
+        path.display(),
+        duration.as_secs()
+    ));
+    // Revert automatically, even without traffic
+    exporter::get_otel_runtime().spawn(async move {
+        tokio::time::sleep(duration).await;
+        if let Some(path) = dump::stop_expired() {
+            exporter::log_info(&format!("dump to {} finished", path.display()));
+        }
+    });
+    Ok(path.display().to_string())
+}
+
+/// Serializes the span context of the transaction's server span into a compact string
+/// (see `deserialize_context`), or returns nil when the transaction has no span.
+pub fn serialize_context(_lua: &Lua, txn: Txn) -> LuaResult<Option<String>> {
+    let encoded = get_context(&txn)
+        .map(|context| span::encoded_context(context.span().span_context()))
+        .unwrap_or_default();
+    Ok((!encoded.is_empty()).then_some(encoded))
+}
+
+/// Parses a string produced by `serialize_context` into a table with the `trace_id`,
+/// `span_id`, `sampled` and `trace_state` fields, or returns nil when it is invalid.
+pub fn deserialize_context(lua: &Lua, encoded: String) -> LuaResult<Option<LuaTable>> {
+    let span_context = match handoff::decode(&encoded) {
+        Some(span_context) => span_context,
+        None => return Ok(None),
+    };
+    let table = lua.create_table()?;
+    table.set("trace_id", span_context.trace_id().to_string())?;
+    table.set("span_id", span_context.span_id().to_string())?;
+    table.set("sampled", span_context.is_sampled())?;
+    table.set("trace_state", span_context.trace_state().header())?;
+    Ok(Some(table))
+}
+
+/// All `register()` calls made in a Lua state, keyed by registration id
+#[derive(Default)]
+struct Registrations(HashMap<String, Registration>);
+
+struct Registration {
+    options: exporter::Options,
+    pipeline: PipelineState,
+}
+
+/// Initialization state of a registration's pipeline
+enum PipelineState {
+    // Lazily initialized on the first request
+    Uninitialized,
+    Ready(Arc<exporter::Pipeline>),
+    // Not retried, so a broken configuration is reported once instead of per request
+    Failed,
+}
+
+impl Registration {
+    fn pipeline(&mut self) -> Option<Arc<exporter::Pipeline>> {
+        if let PipelineState::Uninitialized = self.pipeline {
+            self.pipeline = match exporter::init(self.options.clone()) {
+                Ok(pipeline) => PipelineState::Ready(pipeline),
+                Err(e) => {
+                    exporter::log_error(&format!("haproxy-otel: lazy init failed: {}", e));
+                    PipelineState::Failed
+                }
+            };
+        }
+        match self.pipeline {
+            PipelineState::Ready(ref pipeline) => Some(pipeline.clone()),
+            _ => None,
+        }
+    }
+}
+
+/// Returns the tracing pipeline of the given registration, initializing it on first use.
+pub(crate) fn pipeline(lua: &Lua, id: &str) -> Option<Arc<exporter::Pipeline>> {
+    let mut registrations = lua.app_data_mut::<Registrations>()?;
+    registrations.0.get_mut(id)?.pipeline()
+}
+
+/// Reads the options of the only registration, `None` before `register()` or with several
+/// registrations, where a frontend's registration is only known per transaction
+pub(crate) fn sole_registration<R>(
+    lua: &Lua,
+    f: impl FnOnce(&exporter::Options) -> R,
+) -> Option<R> {
+    let registrations = lua.app_data_ref::<Registrations>()?;
+    match registrations.0.len() {
+        1 => registrations.0.values().next().map(|r| f(&r.options)),
+        _ => None,
+    }
+}
+
+/// Returns the tracing pipeline of the registration that started the transaction's server span.
+pub(crate) fn txn_pipeline(lua: &Lua, txn: &Txn) -> Option<Arc<exporter::Pipeline>> {
+    let mut registrations = lua.app_data_mut::<Registrations>()?;
+    // Skip the variable lookup in the common single registration setup
+    if registrations.0.len() == 1 {
+        return registrations.0.values_mut().next()?.pipeline();
+    }
+    let registration = (txn.get_var::<Option<String>>("txn.__otel_registration"))
+        .inspect_err(|e| strict::fault("get txn.__otel_registration", e))
+        .unwrap_or_default();
+    let id = registration
+        .as_deref()
+        .unwrap_or(exporter::DEFAULT_REGISTRATION);
+    registrations.0.get_mut(id)?.pipeline()
+}
+
 pub fn register(lua: &Lua, options: LuaTable) -> LuaResult<()> {
     let core = Core::new(lua)?;
+    let compat = compat::Compatibility::detect(&core);
+
+    let config_hash = confighash::config_hash(&LuaValue::Table(options.clone()));
+    let options = config::OptionTable::new(lua, options);
+    let id = options.string("id")?;
+    let service_name = (options.string("name")?).unwrap_or_else(|| "haproxy".to_string());
+    let role = options.string("role")?;
+    if role.as_deref() == Some("") {
+        return Err(config::invalid(
+            "role",
+            "expected a role name, e.g. edge, internal or egress",
+        ));
+    }
+    let sampler = options.choice("sampler", config::SAMPLERS, |s| {
+        config::SAMPLERS.contains(&s)
+    })?;
+    let propagator = options.choice("propagator", config::PROPAGATORS, |p| {
+        config::PROPAGATORS.contains(&p)
+    })?;
+    let mut otlp = options.tables("otlp")?.into_iter();
+    // The first destination is read into the registration options below
+    let primary_otlp = otlp.next().expect("at least one otlp table");
+    let mirror = match options.raw_table("mirror")? {
+        Some(_) => Some(options.table("mirror")?),
+        None => None,
+    };
+    let batch = options.table("batch")?;
+    let flush_interval_ms = batch.unsigned("flush_interval_ms")?;
+    let jitter_ms = batch.unsigned("jitter_ms")?;
+    let max_queue_size = batch.unsigned("max_queue_size")?;
+    let max_export_batch_size = batch.unsigned("max_export_batch_size")?;
+    if max_queue_size == Some(0) || max_export_batch_size == Some(0) {
+        let key = match max_queue_size {
+            Some(0) => "max_queue_size",
+            _ => "max_export_batch_size",
+        };
+        return Err(config::invalid(
+            &batch.path(key),
+            "expected at least 1 span",
+        ));
+    }
+    if let (Some(queue), Some(batch_size)) = (max_queue_size, max_export_batch_size) {
+        if batch_size > queue {
+            return Err(config::invalid(
+                &batch.path("max_export_batch_size"),
+                &format!("larger than batch.max_queue_size ({queue})"),
+            ));
+        }
+    }
+    let overflow = batch.choice("overflow", config::OVERFLOW_POLICIES, |p| {
+        overflow::OverflowPolicy::from_str(p).is_some()
+    })?;
+    let breaker = options.table("breaker")?;
+    let circuit_open_after = breaker.unsigned("open_after")?;
+    let circuit_probe_interval_ms = breaker.unsigned("probe_interval_ms")?;
+    if circuit_probe_interval_ms == Some(0) {
+        return Err(config::invalid(
+            &breaker.path("probe_interval_ms"),
+            "expected a positive number of milliseconds",
+        ));
+    }
+    let exporter = options.choice("exporter", config::EXPORTERS, |e| {
+        exporter::ExporterKind::from_str(e).is_some()
+    })?;
+    let redis = options.table("redis")?;
+    let redis = exporter::RedisOptions {
+        url: redis.string("url")?,
+        key_prefix: redis.string("key_prefix")?,
+        max_len: redis.unsigned("max_len")?,
+        ttl: redis.unsigned("ttl")?,
+    };
+    let file = options.table("file")?;
+    let file = exporter::FileOptions {
+        path: file.string("path")?,
+        max_size_mb: file.unsigned("max_size_mb")?,
+        max_files: file.unsigned("max_files")?,
+    };
+    let disk_queue = options.table("disk_queue")?;
+    let disk_queue = exporter::DiskQueueOptions {
+        path: disk_queue.string("path")?,
+        max_size_mb: disk_queue.unsigned("max_size_mb")?,
+    };
+    let pipe_path = options.table("pipe")?.string("path")?;
+    let zipkin_endpoint = options.table("zipkin")?.string("endpoint")?;
+    let datadog_agent_url = options.table("datadog")?.string("agent_url")?;
+    let tenants = match options.raw_table("tenants")? {
+        Some(tenants) => tenant_policies(lua, tenants)?,
+        None => HashMap::new(),
+    };
+    let attribute_policy = options.choice("attribute_policy", config::ATTRIBUTE_POLICIES, |p| {
+        processor::AttributePolicy::from_str(p).is_some()
+    })?;
+    let always_sample_classes = options.strings("always_sample_classes")?;
+    let normalize_paths = options.boolean("normalize_paths")?;
+    let omit_url_query = options.boolean("omit_url_query")?;
+    let mode = options.choice("mode", config::MODES, |m| config::MODES.contains(&m))?;
+    let inject_upstream = options.boolean("inject_upstream")?;
+    let no_inject_backends = options.strings("no_inject_backends")?;
+    let internal_frontends = options.strings("internal_frontends")?;
+    let internal_sample_ratio = options.ratio("internal_sample_ratio")?;
+    let pipeline_sample_ratio = options.ratio("pipeline_sample_ratio")?;
+    let propagate_sampling_rate = options.boolean("propagate_sampling_rate")?;
+    let diagnostics = options.boolean("diagnostics")?;
+    let payload_events = options.boolean("payload_events")?;
+    let duration_metrics = options.boolean("duration_metrics")?;
+    if duration_metrics == Some(true) && !cfg!(feature = "metrics") {
+        return Err(config::invalid(
+            "duration_metrics",
+            "requires the `metrics` cargo feature",
+        ));
+    }
+    let error_trace_header = options.string("error_trace_header")?;
+    let end_attributes = match options.raw_table("end_attributes")? {
+        Some(end_attributes) => end_attributes_option(end_attributes)?,
+        None => Vec::new(),
+    };
+    let export_vars = options.strings("export_vars")?.unwrap_or_default();
+    for pattern in &export_vars {
+        vars::validate_pattern(pattern).map_err(|e| config::invalid("export_vars", &e))?;
+    }
+    let clock_skew = options.table("clock_skew")?;
+    let default_skew = clock::ClockSkew::default();
+    let clock_skew = clock::ClockSkew {
+        tolerance: (clock_skew.unsigned("tolerance_ms")?)
+            .map_or(default_skew.tolerance, Duration::from_millis),
+        max_age: (clock_skew.unsigned("max_age_ms")?)
+            .map_or(default_skew.max_age, Duration::from_millis),
+    };
+    let sla = match options.raw_table("sla")? {
+        Some(sla) => sla_policies(lua, sla)?,
+        None => HashMap::new(),
+    };
+    let healthcheck_sample_every = options.unsigned("healthcheck_sample_every")?;
+    let strict = options.choice("strict", config::STRICT_MODES, |m| {
+        strict::StrictMode::from_str(m).is_some()
+    })?;
+    let self_test = options.boolean("self_test")?;
+    let id_seed = options.unsigned("id_seed")?;
+    let span_events = options.choice("span_events", config::SPAN_EVENTS, |e| {
+        processor::EventPolicy::from_str(e).is_some()
+    })?;
+    let export_only = options.table("export_only")?;
+    let export_only = processor::ExportFilter {
+        errors: (export_only.boolean("errors")?).unwrap_or_default(),
+        slower_than: (export_only.unsigned("slower_than_ms")?).map(Duration::from_millis),
+    };
+    let no_server_span = options.choice("no_server_span", config::NO_SERVER_SPANS, |s| {
+        config::NO_SERVER_SPANS.contains(&s)
+    })?;
+    let attribute_value_length_limit = options.unsigned("attribute_value_length_limit")?;
+    let span_attributes_size_limit = options.unsigned("span_attributes_size_limit")?;
+    let tracers = match options.raw_table("tracers")? {
+        Some(tracers) => tracer_configs(lua, tracers)?,
+        None => HashMap::new(),
+    };
+    let proxy_protocol_tlv = match options.unsigned("proxy_protocol_tlv")? {
+        Some(tlv) if handoff::PP2_CUSTOM_TLVS.contains(&tlv) => Some(tlv as u8),
+        Some(tlv) => {
+            return Err(config::invalid(
+                "proxy_protocol_tlv",
+                &format!("expected a custom TLV type between 0xE0 and 0xEF, got {tlv:#x}"),
+            ))
+        }
+        None => None,
+    };
+    let context_query_param = options.string("context_query_param")?;
+    if context_query_param.as_deref() == Some("") {
+        return Err(config::invalid(
+            "context_query_param",
+            "expected a query parameter name",
+        ));
+    }
 
-    let service_name = (options.get::<String>("name")).unwrap_or_else(|_| "haproxy".to_string());
-    let sampler = (options.get::<Option<String>>("sampler")).unwrap_or_default();
-    let propagator = (options.get::<Option<String>>("propagator")).unwrap_or_default();
-    let otlp = (options.get::<LuaTable>("otlp")).unwrap_or_else(|_| lua.create_table().unwrap());
-    let endpoint = (otlp.get::<Option<String>>("endpoint")).unwrap_or_default();
-    let protocol = (otlp.get::<Option<String>>("protocol")).unwrap_or_default();
+    let mut options = exporter::Options {
+        id,
+        service_name,
+        role,
+        sampler,
+        propagator,
+        flush_interval: flush_interval_ms.map(Duration::from_millis),
+        flush_jitter: jitter_ms.filter(|ms| *ms > 0).map(Duration::from_millis),
+        max_queue_size: max_queue_size.map(|size| size as usize),
+        max_export_batch_size: max_export_batch_size.map(|size| size as usize),
+        overflow: (overflow.as_deref())
+            .and_then(overflow::OverflowPolicy::from_str)
+            .unwrap_or_default(),
+        circuit_open_after: circuit_open_after.map(|failures| failures.min(u32::MAX as u64) as u32),
+        circuit_probe_interval: circuit_probe_interval_ms.map(Duration::from_millis),
+        exporter,
+        redis,
+        file,
+        disk_queue,
+        pipe_path,
+        zipkin_endpoint,
+        datadog_agent_url,
+        tenants: Arc::new(tenants),
+        attribute_policy,
+        always_sample_classes: always_sample_classes.unwrap_or_default(),
+        normalize_paths: normalize_paths.unwrap_or_default(),
+        omit_url_query: omit_url_query.unwrap_or_default(),
+        egress: mode.as_deref() == Some("egress"),
+        inject_upstream,
+        no_inject_backends: no_inject_backends.unwrap_or_default(),
+        internal_frontends: internal_frontends.unwrap_or_default(),
+        internal_sample_ratio,
+        pipeline_sample_ratio,
+        propagate_sampling_rate: propagate_sampling_rate.unwrap_or_default(),
+        diagnostics: diagnostics.unwrap_or_default(),
+        payload_events: payload_events.unwrap_or_default(),
+        duration_metrics: duration_metrics.unwrap_or_default(),
+        error_trace_header,
+        end_attributes,
+        export_vars: vars::resolve(&export_vars),
+        clock_skew,
+        sla,
+        self_test: self_test.unwrap_or_default(),
+        span_events,
+        export_only,
+        drop_no_server_spans: no_server_span.as_deref() == Some("drop"),
+        attribute_value_length_limit: attribute_value_length_limit.map(|limit| limit as usize),
+        span_attributes_size_limit: span_attributes_size_limit.map(|limit| limit as usize),
+        id_seed,
+        proxy_protocol_tlv,
+        context_query_param,
+        propagation_only: compat.propagation_only,
+        tracers,
+        config_hash,
+        ..Default::default()
+    };
+    otlp_options(&primary_otlp, &mut options)?;
+    // Further destinations export the same spans, with their own OTLP settings
+    for destination in otlp {
+        let mut destination_options = options.secondary();
+        otlp_options(&destination, &mut destination_options)?;
+        if destination_options.endpoint.is_none() && destination_options.endpoints.is_empty() {
+            return Err(config::invalid(
+                &destination.path("endpoint"),
+                "additional otlp destinations require an endpoint",
+            ));
+        }
+        options.destinations.push(destination_options);
+    }
+    // Copies every span to another backend, whatever the exporter
+    if let Some(mirror) = mirror {
+        let mut mirror_options = exporter::Options {
+            disk_queue: Default::default(),
+            ..options.secondary()
+        };
+        otlp_options(&mirror, &mut mirror_options)?;
+        if mirror_options.endpoint.is_none() && mirror_options.endpoints.is_empty() {
+            return Err(config::invalid(
+                &mirror.path("endpoint"),
+                "the mirror requires an endpoint",
+            ));
+        }
+        options.mirror = Some(Box::new(mirror_options));
+    }
+    let id = options.registration_id().to_string();
 
-    let options = exporter::Options {
-        service_name: service_name.clone(),
-        sampler: sampler.clone(),
-        propagator: propagator.clone(),
-        endpoint: endpoint.clone(),
-        protocol: protocol.clone(),
+    if lua.app_data_ref::<Registrations>().is_none() {
+        lua.set_app_data(Registrations::default());
+    }
+    let first_registration = {
+        let mut registrations = lua.app_data_mut::<Registrations>().unwrap();
+        if registrations.0.contains_key(&id) {
+            return Err(LuaError::RuntimeError(format!(
+                "haproxy-otel: registration '{id}' is already registered"
+            )));
+        }
+        exporter::log_summary(&options);
+        if let Some(warning) = compat.warning() {
+            exporter::log_warn(&warning);
+        }
+        registrations.0.insert(
+            id.clone(),
+            Registration {
+                options,
+                pipeline: PipelineState::Uninitialized,
+            },
+        );
+        registrations.0.len() == 1
     };
-    lua.set_app_data(options.clone());
+    if let Some(mode) = strict.as_deref().and_then(strict::StrictMode::from_str) {
+        strict::enable(mode);
+    }
 
     // Lazy initialization happens in start_server_span
 
-    #[rustfmt::skip]
-    core.register_action("start_server_span", &[Action::HttpReq], 0, span::start_server_span)?;
+    // The self-test runs on pipeline initialization, which is done when the scheduler
+    // starts instead of on the first request
+    if self_test.unwrap_or_default() {
+        let id = id.clone();
+        core.register_task(move |lua| {
+            pipeline(lua, &id);
+            Ok(())
+        })?;
+    }
+
+    if let Some(sample_every) = healthcheck_sample_every {
+        healthcheck::subscribe(lua, &core, id.clone(), sample_every)?;
+    }
+
+    // Additional registrations get their own `start_server_span_<id>` action,
+    // the remaining actions and the filter follow the registration that started the span
+    let start_action = match id.as_str() {
+        exporter::DEFAULT_REGISTRATION => "start_server_span".to_string(),
+        id => format!("start_server_span_{id}"),
+    };
+    core.register_action(&start_action, &[Action::HttpReq], 0, move |lua, txn| {
+        strict::run(txn, |txn| span::start_server_span(lua, txn, &id))
+    })?;
+    if !first_registration {
+        return Ok(());
+    }
+
     core.register_action(
         "end_server_span",
         &[Action::HttpRes, Action::HttpAfterRes],
         0,
-        span::end_server_span,
+        |lua, txn| strict::run(txn, |txn| span::end_server_span(lua, txn)),
     )?;
     core.register_action(
         "set_span_attribute_var",
         &[Action::HttpReq, Action::HttpRes, Action::HttpAfterRes],
         2,
-        span::set_span_attribute,
+        |lua, (txn, name, var_name): (Txn, String, String)| {
+            strict::run(txn, |txn| {
+                span::set_span_attribute(lua, (txn, name, var_name))
+            })
+        },
     )?;
-    core.register_filter::<filter::TraceFilter>("opentelemetry-trace")?;
+    core.register_action(
+        "diagnostics_mark",
+        &[Action::HttpReq, Action::HttpRes, Action::HttpAfterRes],
+        1,
+        |lua, (txn, name): (Txn, String)| {
+            strict::run(txn, |txn| span::diagnostics_mark(lua, (txn, name)))
+        },
+    )?;
+    core.register_fetches("otel_trace_id", span::trace_id_fetch)?;
+    core.register_fetches("otel_context", span::context_fetch)?;
+    core.register_fetches("otel_spoe_context", span::context_fetch)?;
+    if compat.filters {
+        filter::register(&core)?;
+    }
+
+    Ok(())
+}
+
+/// Reads the `tenants` option: `{ [tenant] = { sample_ratio, attributes, redact } }`
+fn tenant_policies(
+    lua: &Lua,
+    tenants: LuaTable,
+) -> LuaResult<HashMap<String, tenant::TenantPolicy>> {
+    tenants
+        .pairs::<String, LuaTable>()
+        .map(|pair| {
+            let (tenant, policy) = pair.map_err(|e| config::invalid("tenants", &e.to_string()))?;
+            let policy = config::OptionTable::nested(lua, policy, &format!("tenants.{tenant}"));
+            let policy = tenant::TenantPolicy {
+                sample_ratio: policy.ratio("sample_ratio")?,
+                attributes: policy.strings("attributes")?,
+                redact: (policy.strings("redact")?).unwrap_or_default(),
+            };
+            Ok((tenant::tenant_key(&tenant), policy))
+        })
+        .collect()
+}
+
+/// Reads the `tracers` option: `{ [name] = { scope, attributes = { [key] = value } } }`
+fn tracer_configs(lua: &Lua, tracers: LuaTable) -> LuaResult<tracer::TracerConfigs> {
+    tracers
+        .pairs::<String, LuaTable>()
+        .map(|pair| {
+            let (name, config) = pair.map_err(|e| config::invalid("tracers", &e.to_string()))?;
+            let path = format!("tracers.{name}");
+            let config = config::OptionTable::nested(lua, config, &path);
+            let mut attributes = match config.raw_table("attributes")? {
+                Some(attributes) => attributes
+                    .pairs::<String, String>()
+                    .collect::<LuaResult<Vec<_>>>()
+                    .map_err(|e| config::invalid(&format!("{path}.attributes"), &e.to_string()))?,
+                None => Vec::new(),
+            };
+            // Lua tables have no order, keep the attributes deterministic
+            attributes.sort();
+            let config = tracer::TracerConfig {
+                scope: config.string("scope")?,
+                attributes,
+            };
+            Ok((name, config))
+        })
+        .collect()
+}
 
+/// Reads the settings of an OTLP destination (the `otlp` table or an element of the list)
+fn otlp_options(otlp: &config::OptionTable, options: &mut exporter::Options) -> LuaResult<()> {
+    let endpoint = otlp.string("endpoint")?;
+    let endpoints = otlp.strings("endpoints")?.unwrap_or_default();
+    if endpoint.is_some() && !endpoints.is_empty() {
+        return Err(config::invalid(
+            &otlp.path("endpoints"),
+            "endpoint and endpoints are mutually exclusive",
+        ));
+    }
+    let failover_endpoints = otlp.strings("failover_endpoints")?.unwrap_or_default();
+    if !failover_endpoints.is_empty() && !endpoints.is_empty() {
+        return Err(config::invalid(
+            &otlp.path("failover_endpoints"),
+            "failover_endpoints back otlp.endpoint, not the sharded endpoints",
+        ));
+    }
+    let all_endpoints = endpoint.iter().chain(&endpoints).chain(&failover_endpoints);
+    for endpoint in all_endpoints {
+        let backend = endpoint.strip_prefix(exporter::HAPROXY_SCHEME);
+        if backend.is_some_and(|backend| backend.is_empty() || backend.contains('/')) {
+            return Err(config::invalid(
+                &otlp.path("endpoint"),
+                &format!("expected haproxy://<backend>, got {endpoint}"),
+            ));
+        }
+    }
+    let loopback_socket = otlp.string("loopback_socket")?;
+    let failover_after = otlp.unsigned("failover_after")?;
+    if failover_after == Some(0) {
+        return Err(config::invalid(
+            &otlp.path("failover_after"),
+            "expected at least 1 failed export",
+        ));
+    }
+    let traces_path = otlp.string("traces_path")?;
+    let legacy_traces_endpoint = otlp.boolean("legacy_traces_endpoint")?;
+    let protocol = otlp.choice("protocol", config::PROTOCOLS, |p| {
+        exporter::Protocol::from_str(p).is_some()
+    })?;
+    let http2 = otlp.boolean("http2")?;
+    let processor = otlp.choice("processor", config::PROCESSORS, |p| {
+        config::PROCESSORS.contains(&p)
+    })?;
+    let compression = otlp.choice("compression", config::COMPRESSIONS, |c| {
+        compression::Compression::from_str(c).is_some()
+    })?;
+    let ca_file = otlp.string("ca_file")?;
+    let insecure_skip_verify = otlp.boolean("insecure_skip_verify")?;
+    let proxy_url = otlp.string("proxy_url")?;
+    if let Some(url) = &proxy_url {
+        if !url.starts_with("http://") && !url.starts_with("https://") {
+            return Err(config::invalid(
+                &otlp.path("proxy_url"),
+                "expected an http:// or https:// URL",
+            ));
+        }
+        reqwest::Proxy::all(url.as_str())
+            .map_err(|e| config::invalid(&otlp.path("proxy_url"), &e.to_string()))?;
+    }
+    let headers = match otlp.raw_table("headers")? {
+        Some(headers) => headers_option(headers, &otlp.path("headers"))?,
+        None => Vec::new(),
+    };
+    let bearer_token_file = otlp.string("bearer_token_file")?;
+    let timeout_ms = otlp.unsigned("timeout_ms")?;
+    if timeout_ms == Some(0) {
+        return Err(config::invalid(
+            &otlp.path("timeout_ms"),
+            "expected a positive number of milliseconds",
+        ));
+    }
+    let retry = otlp.table("retry")?;
+    let default_retry = retry::RetryPolicy::default();
+    let retry = retry::RetryPolicy {
+        max_attempts: (retry.unsigned("max_attempts")?).map_or(
+            Ok(default_retry.max_attempts),
+            |attempts| {
+                u32::try_from(attempts)
+                    .ok()
+                    .filter(|attempts| *attempts > 0)
+                    .ok_or_else(|| {
+                        config::invalid(&retry.path("max_attempts"), "expected at least 1 attempt")
+                    })
+            },
+        )?,
+        initial_backoff: (retry.unsigned("initial_backoff_ms")?)
+            .map_or(default_retry.initial_backoff, Duration::from_millis),
+        max_backoff: (retry.unsigned("max_backoff_ms")?)
+            .map_or(default_retry.max_backoff, Duration::from_millis),
+    };
+    let pool = otlp.table("pool")?;
+    let positive_ms = |key| -> LuaResult<Option<Duration>> {
+        match pool.unsigned(key)? {
+            Some(0) => Err(config::invalid(
+                &pool.path(key),
+                "expected a positive number of milliseconds",
+            )),
+            ms => Ok(ms.map(Duration::from_millis)),
+        }
+    };
+    let pool = exporter::PoolOptions {
+        max_idle_per_host: (pool.unsigned("max_idle_per_host")?).map(|idle| idle as usize),
+        idle_timeout: positive_ms("idle_timeout_ms")?,
+        tcp_keepalive: positive_ms("tcp_keepalive_ms")?,
+        http2_keepalive: positive_ms("http2_keepalive_ms")?,
+    };
+    let client_cert = otlp.string("client_cert")?;
+    let client_key = otlp.string("client_key")?;
+    if client_cert.is_some() != client_key.is_some() {
+        return Err(config::invalid(
+            &otlp.path("client_cert"),
+            "client_cert and client_key must be set together",
+        ));
+    }
+    options.endpoint = endpoint;
+    options.endpoints = endpoints;
+    options.failover_endpoints = failover_endpoints;
+    options.failover_after = failover_after.map(|failures| failures.min(u32::MAX as u64) as u32);
+    options.traces_path = traces_path;
+    options.legacy_traces_endpoint = legacy_traces_endpoint.unwrap_or_default();
+    options.loopback_socket = loopback_socket;
+    options.protocol = protocol;
+    options.http2 = http2.unwrap_or_default();
+    options.simple_processor = processor.as_deref() == Some("simple");
+    options.compression = compression;
+    options.ca_file = ca_file;
+    options.insecure_skip_verify = insecure_skip_verify.unwrap_or_default();
+    options.proxy_url = proxy_url;
+    options.client_cert = client_cert;
+    options.client_key = client_key;
+    options.headers = headers;
+    options.bearer_token_file = bearer_token_file;
+    options.timeout = timeout_ms.map(Duration::from_millis);
+    options.retry = retry;
+    options.pool = pool;
     Ok(())
 }
 
+/// Reads the `otlp.headers` option: `{ [header] = "value" }`
+fn headers_option(headers: LuaTable, path: &str) -> LuaResult<Vec<(String, String)>> {
+    let mut headers = headers
+        .pairs::<String, String>()
+        .map(|pair| {
+            let (name, value) = pair.map_err(|e| config::invalid(path, &e.to_string()))?;
+            let path = format!("{path}.{name}");
+            reqwest::header::HeaderName::from_bytes(name.as_bytes())
+                .map_err(|_| config::invalid(&path, "invalid header name"))?;
+            reqwest::header::HeaderValue::from_str(&value)
+                .map_err(|_| config::invalid(&path, "invalid header value"))?;
+            Ok((name.to_ascii_lowercase(), value))
+        })
+        .collect::<LuaResult<Vec<_>>>()?;
+    // Lua tables have no order, keep the headers deterministic
+    headers.sort();
+    Ok(headers)
+}
+
+/// Reads the `sla` option: `{ [backend or "*"] = { queue_ms, connect_ms, total_ms } }`
+fn sla_policies(lua: &Lua, policies: LuaTable) -> LuaResult<sla::SlaPolicies> {
+    policies
+        .pairs::<String, LuaTable>()
+        .map(|pair| {
+            let (backend, thresholds) = pair.map_err(|e| config::invalid("sla", &e.to_string()))?;
+            let thresholds =
+                config::OptionTable::nested(lua, thresholds, &format!("sla.{backend}"));
+            let thresholds = sla::SlaThresholds {
+                queue_ms: thresholds.unsigned("queue_ms")?,
+                connect_ms: thresholds.unsigned("connect_ms")?,
+                total_ms: thresholds.unsigned("total_ms")?,
+            };
+            Ok((backend, thresholds))
+        })
+        .collect()
+}
+
+/// Reads the `end_attributes` option: `{ [attribute] = "fetch expression" }`
+fn end_attributes_option(attributes: LuaTable) -> LuaResult<Vec<fetch::EndAttribute>> {
+    let mut attributes = attributes
+        .pairs::<String, String>()
+        .map(|pair| {
+            let (key, expression) =
+                pair.map_err(|e| config::invalid("end_attributes", &e.to_string()))?;
+            fetch::EndAttribute::parse(&key, &expression)
+                .map_err(|e| config::invalid(&format!("end_attributes.{key}"), &e))
+        })
+        .collect::<LuaResult<Vec<_>>>()?;
+    // Lua tables have no order, keep the attributes deterministic
+    attributes.sort_by(|a, b| a.key.cmp(&b.key));
+    Ok(attributes)
+}
+
 mod cache;
+mod circuit;
+mod clock;
+mod compat;
+mod compression;
+mod config;
+mod confighash;
+mod console;
+mod datadog;
+#[cfg(feature = "direct")]
+mod direct;
+#[cfg(feature = "direct")]
+mod diskqueue;
+mod dump;
 mod exporter;
+mod failover;
+mod fetch;
+#[cfg(feature = "direct")]
+mod file;
 mod filter;
+#[cfg(test)]
+mod fixtures;
+#[cfg(feature = "grpc")]
+mod grpc;
+mod handoff;
+mod headers;
+mod healthcheck;
+mod httpclient;
+mod ids;
+mod json;
+mod metrics;
+mod overflow;
+mod partial;
+#[cfg(feature = "direct")]
+mod pipe;
+mod processor;
+mod propagation;
+mod redis;
+mod retry;
+mod route;
+mod sampler;
+mod selftrace;
+mod shard;
+mod sla;
 mod span;
+mod strict;
+mod tenant;
+mod tls;
+mod token;
+mod tracer;
+mod vars;
+#[cfg(feature = "zipkin")]
+mod zipkin;
@@ -0,0 +1,304 @@
+#[cfg(feature = "metrics")]
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+#[cfg(feature = "metrics")]
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use haproxy_api::Txn;
+use opentelemetry::Context;
+
+/// Histogram bucket upper bounds, in seconds
+#[cfg(feature = "metrics")]
+const BUCKETS: [f64; 12] = [
+    0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Duration histograms derived from every request, independently of sampling
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum DurationMetric {
+    // Server span: total time spent in the proxy
+    Request,
+    // Client span: time spent waiting for the upstream server
+    Upstream,
+}
+
+#[cfg(feature = "metrics")]
+impl DurationMetric {
+    fn name(&self) -> &'static str {
+        match self {
+            DurationMetric::Request => "haproxy_otel_request_duration_seconds",
+            DurationMetric::Upstream => "haproxy_otel_upstream_duration_seconds",
+        }
+    }
+
+    fn help(&self) -> &'static str {
+        match self {
+            DurationMetric::Request => "Total time spent in the proxy (server span duration)",
+            DurationMetric::Upstream => {
+                "Time spent waiting for the upstream (client span duration)"
+            }
+        }
+    }
+}
+
+#[cfg(feature = "metrics")]
+#[derive(Default)]
+struct Histogram {
+    // Cumulative counts are computed at render time
+    buckets: [u64; BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+#[cfg(feature = "metrics")]
+impl Histogram {
+    fn observe(&mut self, secs: f64) {
+        if let Some(i) = BUCKETS.iter().position(|bound| secs <= *bound) {
+            self.buckets[i] += 1;
+        }
+        self.count += 1;
+        self.sum += secs;
+    }
+
+    fn merge(&mut self, other: &Histogram) {
+        for (bucket, count) in self.buckets.iter_mut().zip(other.buckets) {
+            *bucket += count;
+        }
+        self.count += other.count;
+        self.sum += other.sum;
+    }
+}
+
+/// Histograms of a thread by frontend and backend label, indexed by metric
+#[cfg(feature = "metrics")]
+type Histograms = HashMap<String, HashMap<String, [Histogram; 2]>>;
+
+/// Histograms of every thread that observed a duration, merged when rendered
+#[cfg(feature = "metrics")]
+static THREADS: Mutex<Vec<Arc<Mutex<Histograms>>>> = Mutex::new(Vec::new());
+
+#[cfg(feature = "metrics")]
+thread_local! {
+    // Only locked by its thread and the rendering, so observing never waits on other threads
+    static HISTOGRAMS: Arc<Mutex<Histograms>> = {
+        let histograms = Arc::default();
+        let mut threads = THREADS.lock().unwrap_or_else(|e| e.into_inner());
+        threads.push(Arc::clone(&histograms));
+        histograms
+    };
+}
+
+/// Entry of `key`, the key is only copied the first time it is seen
+#[cfg(feature = "metrics")]
+fn entry<'a, V: Default>(map: &'a mut HashMap<String, V>, key: &str) -> &'a mut V {
+    if !map.contains_key(key) {
+        map.insert(key.to_string(), V::default());
+    }
+    map.get_mut(key).expect("inserted above")
+}
+
+/// Records a duration, labeled like the `haproxy.frontend.name`/`haproxy.backend.name` span attributes
+#[cfg(feature = "metrics")]
+pub(crate) fn observe(metric: DurationMetric, frontend: &str, backend: &str, duration: Duration) {
+    HISTOGRAMS.with(|histograms| {
+        let mut histograms = histograms.lock().unwrap_or_else(|e| e.into_inner());
+        let backends = entry(&mut histograms, frontend);
+        entry(backends, backend)[metric as usize].observe(duration.as_secs_f64());
+    });
+}
+
+/// Duration histograms are not compiled in, `duration_metrics` is rejected by `register()`
+#[cfg(not(feature = "metrics"))]
+pub(crate) fn observe(_: DurationMetric, _: &str, _: &str, _: Duration) {}
+
+/// Start of the request, stored in the server span context when duration metrics are enabled
+pub(crate) struct RequestStart(pub(crate) Instant);
+
+/// Records the total proxy time of a request whose context carries a [`RequestStart`]
+pub(crate) fn observe_request(txn: &Txn, context: &Context) {
+    if let Some(RequestStart(start)) = context.get::<RequestStart>() {
+        observe_txn(DurationMetric::Request, txn, start.elapsed());
+    }
+}
+
+/// Records a duration labeled with the frontend and backend of the transaction
+pub(crate) fn observe_txn(metric: DurationMetric, txn: &Txn, duration: Duration) {
+    let name = |fetch| {
+        let name = txn.f.get::<Option<String>>(fetch, ()).ok().flatten();
+        name.unwrap_or_default()
+    };
+    observe(metric, &name("fe_name"), &name("be_name"), duration);
+}
+
+/// Spans a collector rejected in an OTLP partial-success response
+static REJECTED_SPANS: AtomicU64 = AtomicU64::new(0);
+
+pub(crate) fn count_rejected_spans(count: u64) {
+    REJECTED_SPANS.fetch_add(count, Ordering::Relaxed);
+}
+
+/// Spans dropped because the batch queue was full (`batch.overflow`)
+static DROPPED_SPANS: AtomicU64 = AtomicU64::new(0);
+
+pub(crate) fn count_dropped_spans(count: u64) {
+    DROPPED_SPANS.fetch_add(count, Ordering::Relaxed);
+}
+
+/// Failures of the tracing path recorded with `strict`
+static TRACING_FAULTS: AtomicU64 = AtomicU64::new(0);
+
+pub(crate) fn count_tracing_fault() {
+    TRACING_FAULTS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Renders the metrics in the Prometheus text format
+pub(crate) fn render() -> String {
+    let mut out = String::new();
+    render_histograms(&mut out);
+    let counters = [
+        (
+            "haproxy_otel_rejected_spans_total",
+            "Spans rejected by the collector (OTLP partial success)",
+            &REJECTED_SPANS,
+        ),
+        (
+            "haproxy_otel_dropped_spans_total",
+            "Spans dropped because the batch queue was full",
+            &DROPPED_SPANS,
+        ),
+        (
+            "haproxy_otel_tracing_faults_total",
+            "Failures of the tracing path (strict mode)",
+            &TRACING_FAULTS,
+        ),
+    ];
+    for (name, help, counter) in counters {
+        let _ = writeln!(out, "# HELP {name} {help}");
+        let _ = writeln!(out, "# TYPE {name} counter");
+        let _ = writeln!(out, "{name} {}", counter.load(Ordering::Relaxed));
+    }
+    out
+}
+
+#[cfg(feature = "metrics")]
+fn render_histograms(out: &mut String) {
+    // Sorted by metric and labels
+    let mut histograms = BTreeMap::<(DurationMetric, &str, &str), Histogram>::new();
+    let threads = THREADS.lock().unwrap_or_else(|e| e.into_inner());
+    let threads = threads
+        .iter()
+        .map(|thread| thread.lock().unwrap_or_else(|e| e.into_inner()))
+        .collect::<Vec<_>>();
+    for thread in &threads {
+        for (frontend, backends) in thread.iter() {
+            for (backend, metrics) in backends {
+                for (metric, histogram) in [DurationMetric::Request, DurationMetric::Upstream]
+                    .into_iter()
+                    .zip(metrics)
+                {
+                    if histogram.count > 0 {
+                        histograms
+                            .entry((metric, frontend, backend))
+                            .or_default()
+                            .merge(histogram);
+                    }
+                }
+            }
+        }
+    }
+    let mut current = None;
+    for ((metric, frontend, backend), histogram) in histograms.iter() {
+        let name = metric.name();
+        if current != Some(*metric) {
+            let _ = writeln!(out, "# HELP {name} {}", metric.help());
+            let _ = writeln!(out, "# TYPE {name} histogram");
+            current = Some(*metric);
+        }
+        let labels = format!(
+            "frontend=\"{}\",backend=\"{}\"",
+            escape_label(frontend),
+            escape_label(backend)
+        );
+        let mut cumulative = 0;
+        for (bound, count) in BUCKETS.iter().zip(histogram.buckets) {
+            cumulative += count;
+            let _ = writeln!(out, "{name}_bucket{{{labels},le=\"{bound}\"}} {cumulative}");
+        }
+        let _ = writeln!(
+            out,
+            "{name}_bucket{{{labels},le=\"+Inf\"}} {}",
+            histogram.count
+        );
+        let _ = writeln!(out, "{name}_sum{{{labels}}} {}", histogram.sum);
+        let _ = writeln!(out, "{name}_count{{{labels}}} {}", histogram.count);
+    }
+}
+
+#[cfg(not(feature = "metrics"))]
+fn render_histograms(_: &mut String) {}
+
+#[cfg(feature = "metrics")]
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(all(test, feature = "metrics"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram_observe() {
+        let mut histogram = Histogram::default();
+        histogram.observe(0.003);
+        histogram.observe(0.003);
+        histogram.observe(0.2);
+        histogram.observe(60.0);
+        assert_eq!(histogram.buckets[1], 2);
+        assert_eq!(histogram.buckets[6], 1);
+        assert_eq!(histogram.buckets.iter().sum::<u64>(), 3);
+        assert_eq!(histogram.count, 4);
+    }
+
+    #[test]
+    fn test_render() {
+        observe(
+            DurationMetric::Upstream,
+            "fe_test",
+            "be_\"test\"",
+            Duration::from_millis(20),
+        );
+        let out = render();
+        assert!(out.contains("# TYPE haproxy_otel_upstream_duration_seconds histogram"));
+        assert!(out.contains(
+            "haproxy_otel_upstream_duration_seconds_bucket{frontend=\"fe_test\",backend=\"be_\\\"test\\\"\",le=\"0.025\"} 1"
+        ));
+        assert!(out.contains(
+            "haproxy_otel_upstream_duration_seconds_count{frontend=\"fe_test\",backend=\"be_\\\"test\\\"\"} 1"
+        ));
+        assert!(out.contains("# TYPE haproxy_otel_rejected_spans_total counter"));
+        assert!(out.contains("# TYPE haproxy_otel_dropped_spans_total counter"));
+        assert!(out.contains("# TYPE haproxy_otel_tracing_faults_total counter"));
+    }
+
+    #[test]
+    fn test_render_threads() {
+        let observe_request = || {
+            observe(
+                DurationMetric::Request,
+                "fe_threads",
+                "be_threads",
+                Duration::from_millis(20),
+            )
+        };
+        observe_request();
+        std::thread::spawn(observe_request).join().unwrap();
+        assert!(render().contains(
+            "haproxy_otel_request_duration_seconds_count{frontend=\"fe_threads\",backend=\"be_threads\"} 2"
+        ));
+    }
+}
@@ -0,0 +1,382 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+use opentelemetry::Context;
+use opentelemetry_sdk::error::OTelSdkResult;
+use opentelemetry_sdk::trace::{Span, SpanData, SpanExporter, SpanProcessor};
+use opentelemetry_sdk::Resource;
+
+use crate::exporter::{log_info, log_warn};
+
+/// What happens to the spans ending while the batch queue is full (`batch.overflow`)
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub(crate) enum OverflowPolicy {
+    // The spans ending while the queue is full are dropped, like the SDK does
+    #[default]
+    DropNew,
+    // Up to a batch of the spans ending while the queue is full wait in a buffer in front of
+    // it, the oldest buffered ones are dropped. The spans already queued are never evicted.
+    Buffer,
+}
+
+impl OverflowPolicy {
+    /// Parse overflow policy from string ("drop_new" or "buffer")
+    pub(crate) fn from_str(s: &str) -> Option<Self> {
+        match s.to_lowercase().replace('-', "_").as_str() {
+            "drop_new" => Some(OverflowPolicy::DropNew),
+            "buffer" => Some(OverflowPolicy::Buffer),
+            _ => None,
+        }
+    }
+}
+
+/// Spans in the batch queue, shared with the exporter seeing them leave it
+#[derive(Debug)]
+pub(crate) struct QueueState {
+    capacity: usize,
+    // Spans handed to the batch processor whose export didn't start yet, at least as many as
+    // its queue holds
+    queued: AtomicUsize,
+    // Whether spans were dropped since the queue last had room
+    overflowing: AtomicBool,
+    // Spans dropped since the queue last had room
+    dropped: AtomicU64,
+}
+
+impl QueueState {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            queued: AtomicUsize::new(0),
+            overflowing: AtomicBool::new(false),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    /// Takes a place in the queue, false while it is full
+    fn reserve(&self) -> bool {
+        let reserved = (self.queued)
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |queued| {
+                (queued < self.capacity).then_some(queued + 1)
+            })
+            .is_ok();
+        if reserved && self.overflowing.swap(false, Ordering::Relaxed) {
+            let dropped = self.dropped.swap(0, Ordering::Relaxed);
+            log_info(&format!(
+                "batch queue has room again after dropping {dropped} spans"
+            ));
+        }
+        reserved
+    }
+
+    fn drop_spans(&self, count: u64, policy: OverflowPolicy) {
+        crate::metrics::count_dropped_spans(count);
+        self.dropped.fetch_add(count, Ordering::Relaxed);
+        if !self.overflowing.swap(true, Ordering::Relaxed) {
+            let which = match policy {
+                OverflowPolicy::DropNew => "newest",
+                OverflowPolicy::Buffer => "oldest buffered",
+            };
+            log_warn(&format!(
+                "batch queue full ({} spans), dropping the {which} spans",
+                self.capacity
+            ));
+        }
+    }
+}
+
+/// Span exporter releasing the places of the spans it exports in the batch queue
+#[derive(Debug)]
+pub(crate) struct QueueExporter<E> {
+    inner: E,
+    state: Arc<QueueState>,
+}
+
+impl<E: SpanExporter> SpanExporter for QueueExporter<E> {
+    async fn export(&self, batch: Vec<SpanData>) -> OTelSdkResult {
+        // The batch left the queue before being exported
+        let _ = (self.state.queued).fetch_update(Ordering::AcqRel, Ordering::Acquire, |queued| {
+            Some(queued.saturating_sub(batch.len()))
+        });
+        self.inner.export(batch).await
+    }
+
+    fn shutdown_with_timeout(&mut self, timeout: Duration) -> OTelSdkResult {
+        self.inner.shutdown_with_timeout(timeout)
+    }
+
+    fn force_flush(&mut self) -> OTelSdkResult {
+        self.inner.force_flush()
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        self.inner.set_resource(resource);
+    }
+}
+
+/// Wraps the exporter of a batch processor with a queue of `capacity` spans
+pub(crate) fn queue_exporter<E: SpanExporter>(
+    exporter: E,
+    capacity: usize,
+) -> (QueueExporter<E>, Arc<QueueState>) {
+    let state = Arc::new(QueueState::new(capacity));
+    let exporter = QueueExporter {
+        inner: exporter,
+        state: Arc::clone(&state),
+    };
+    (exporter, state)
+}
+
+#[derive(Debug)]
+struct Shared<P> {
+    inner: P,
+    state: Arc<QueueState>,
+    policy: OverflowPolicy,
+    // buffer: the newest spans waiting for room in the queue, oldest first
+    waiting: Mutex<VecDeque<SpanData>>,
+    waiting_capacity: usize,
+    // Whether spans are waiting, read without locking `waiting` at every span
+    has_waiting: AtomicBool,
+    // Interval at which the waiting spans are moved to the queue without new spans ending
+    retry_interval: Duration,
+    // Whether a task moves the waiting spans to the queue
+    retrying: AtomicBool,
+}
+
+impl<P: SpanProcessor> Shared<P> {
+    /// Moves the waiting spans to the queue while it has room, returns whether some are left
+    fn transfer(&self) -> bool {
+        let mut waiting = self.waiting.lock().unwrap_or_else(|e| e.into_inner());
+        while !waiting.is_empty() && self.state.reserve() {
+            if let Some(span) = waiting.pop_front() {
+                self.inner.on_end(span);
+            }
+        }
+        self.has_waiting
+            .store(!waiting.is_empty(), Ordering::Relaxed);
+        !waiting.is_empty()
+    }
+}
+
+/// Span processor in front of a batch processor applying the `batch.overflow` policy when its
+/// queue is full, and counting the dropped spans in `haproxy_otel_dropped_spans_total`.
+///
+/// The SDK queue can't evict spans, so with `buffer` the spans ending while it is full wait in
+/// front of it, up to a batch of them, and the oldest waiting ones are dropped. The waiting
+/// spans are moved to the queue as the exports make room for them.
+#[derive(Debug)]
+pub(crate) struct OverflowProcessor<P> {
+    shared: Arc<Shared<P>>,
+}
+
+impl<P: SpanProcessor + 'static> OverflowProcessor<P> {
+    pub(crate) fn new(
+        inner: P,
+        state: Arc<QueueState>,
+        policy: OverflowPolicy,
+        waiting_capacity: usize,
+        retry_interval: Duration,
+    ) -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                inner,
+                state,
+                policy,
+                waiting: Mutex::new(VecDeque::new()),
+                waiting_capacity: waiting_capacity.max(1),
+                has_waiting: AtomicBool::new(false),
+                retry_interval,
+                retrying: AtomicBool::new(false),
+            }),
+        }
+    }
+
+    /// Queues the span behind the waiting ones, dropping the oldest waiting span when full
+    fn wait(&self, span: SpanData) {
+        let shared = &self.shared;
+        let mut waiting = shared.waiting.lock().unwrap_or_else(|e| e.into_inner());
+        waiting.push_back(span);
+        if waiting.len() > shared.waiting_capacity {
+            waiting.pop_front();
+            shared.state.drop_spans(1, shared.policy);
+        }
+        shared.has_waiting.store(true, Ordering::Relaxed);
+        drop(waiting);
+        if !shared.retrying.swap(true, Ordering::AcqRel) {
+            self.spawn_retry();
+        }
+    }
+
+    /// Moves the waiting spans to the queue until none are left, even if no span ends
+    fn spawn_retry(&self) {
+        let shared: Weak<Shared<P>> = Arc::downgrade(&self.shared);
+        let interval = self.shared.retry_interval;
+        crate::exporter::get_otel_runtime().spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                let Some(shared) = shared.upgrade() else {
+                    break;
+                };
+                if shared.transfer() {
+                    continue;
+                }
+                shared.retrying.store(false, Ordering::Release);
+                // A span may have started waiting since the transfer
+                if !shared.has_waiting.load(Ordering::Relaxed)
+                    || shared.retrying.swap(true, Ordering::AcqRel)
+                {
+                    break;
+                }
+            }
+        });
+    }
+}
+
+impl<P: SpanProcessor + 'static> SpanProcessor for OverflowProcessor<P> {
+    fn on_start(&self, span: &mut Span, cx: &Context) {
+        self.shared.inner.on_start(span, cx);
+    }
+
+    fn on_end(&self, span: SpanData) {
+        let shared = &self.shared;
+        // Behind the waiting spans, if any
+        if shared.has_waiting.load(Ordering::Relaxed) {
+            shared.transfer();
+        }
+        if !shared.has_waiting.load(Ordering::Relaxed) && shared.state.reserve() {
+            shared.inner.on_end(span);
+            return;
+        }
+        match shared.policy {
+            OverflowPolicy::DropNew => shared.state.drop_spans(1, shared.policy),
+            OverflowPolicy::Buffer => self.wait(span),
+        }
+    }
+
+    fn force_flush(&self) -> OTelSdkResult {
+        self.shared.transfer();
+        self.shared.inner.force_flush()
+    }
+
+    fn shutdown_with_timeout(&self, timeout: Duration) -> OTelSdkResult {
+        let shared = &self.shared;
+        shared.transfer();
+        let mut waiting = shared.waiting.lock().unwrap_or_else(|e| e.into_inner());
+        if !waiting.is_empty() {
+            shared.state.drop_spans(waiting.len() as u64, shared.policy);
+            waiting.clear();
+        }
+        drop(waiting);
+        shared.inner.shutdown_with_timeout(timeout)
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        // Set when the provider is built, before any span could start a retry task
+        if let Some(shared) = Arc::get_mut(&mut self.shared) {
+            shared.inner.set_resource(resource);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+
+    use super::*;
+    use crate::fixtures;
+
+    fn span(name: &'static str) -> SpanData {
+        SpanData {
+            name: Cow::Borrowed(name),
+            ..fixtures::span()
+        }
+    }
+
+    /// Batch processor whose queue is never exported, records the queued span names
+    #[derive(Debug, Default)]
+    struct Queue(Mutex<Vec<Cow<'static, str>>>);
+
+    impl SpanProcessor for Queue {
+        fn on_start(&self, _: &mut Span, _: &Context) {}
+
+        fn on_end(&self, span: SpanData) {
+            self.0.lock().unwrap().push(span.name);
+        }
+
+        fn force_flush(&self) -> OTelSdkResult {
+            Ok(())
+        }
+
+        fn shutdown_with_timeout(&self, _: Duration) -> OTelSdkResult {
+            Ok(())
+        }
+    }
+
+    /// Starts the export of `count` queued spans
+    fn export(processor: &OverflowProcessor<Queue>, count: usize) {
+        let state = &processor.shared.state;
+        state.queued.fetch_sub(count, Ordering::AcqRel);
+    }
+
+    fn queued(processor: &OverflowProcessor<Queue>) -> Vec<Cow<'static, str>> {
+        processor.shared.inner.0.lock().unwrap().clone()
+    }
+
+    fn processor(policy: OverflowPolicy) -> OverflowProcessor<Queue> {
+        let state = Arc::new(QueueState::new(2));
+        let interval = Duration::from_secs(3600);
+        OverflowProcessor::new(Queue::default(), state, policy, 2, interval)
+    }
+
+    #[test]
+    fn test_overflow_drop_new() {
+        let processor = processor(OverflowPolicy::DropNew);
+        for name in ["a", "b", "c", "d"] {
+            processor.on_end(span(name));
+        }
+        assert_eq!(queued(&processor), vec!["a", "b"]);
+        assert_eq!(processor.shared.state.dropped.load(Ordering::Relaxed), 2);
+
+        export(&processor, 1);
+        processor.on_end(span("e"));
+        assert_eq!(queued(&processor), vec!["a", "b", "e"]);
+        assert_eq!(processor.shared.state.dropped.load(Ordering::Relaxed), 0);
+        assert!(!processor.shared.state.overflowing.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn test_overflow_buffer() {
+        let _guard = crate::exporter::get_otel_runtime().enter();
+        let processor = processor(OverflowPolicy::Buffer);
+        for name in ["a", "b", "c", "d", "e"] {
+            processor.on_end(span(name));
+        }
+        // c was the oldest of the spans waiting for room
+        assert_eq!(queued(&processor), vec!["a", "b"]);
+        assert_eq!(processor.shared.state.dropped.load(Ordering::Relaxed), 1);
+
+        // The waiting spans go first, in order
+        export(&processor, 2);
+        processor.on_end(span("f"));
+        assert_eq!(queued(&processor), vec!["a", "b", "d", "e"]);
+        export(&processor, 2);
+        processor.force_flush().unwrap();
+        assert_eq!(queued(&processor), vec!["a", "b", "d", "e", "f"]);
+        assert!(!processor.shared.has_waiting.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn test_overflow_policy_from_str() {
+        assert_eq!(
+            OverflowPolicy::from_str("Buffer"),
+            Some(OverflowPolicy::Buffer)
+        );
+        assert_eq!(
+            OverflowPolicy::from_str("drop_new"),
+            Some(OverflowPolicy::DropNew)
+        );
+        assert_eq!(OverflowPolicy::from_str("block"), None);
+    }
+}
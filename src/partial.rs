@@ -0,0 +1,193 @@
+use opentelemetry_http::{Bytes, HttpClient, Request, Response};
+#[cfg(feature = "direct")]
+use opentelemetry_proto::tonic::collector::trace::v1::ExportTraceServiceResponse;
+#[cfg(feature = "direct")]
+use prost::Message as _;
+
+use crate::exporter::SendFuture;
+
+/// Spans rejected by the collector and its explanation, from the `partial_success` field of
+/// an export response
+#[derive(Debug, PartialEq)]
+pub(crate) struct PartialSuccess {
+    pub(crate) rejected_spans: u64,
+    pub(crate) error_message: String,
+}
+
+/// Logs and counts the spans a collector rejected, a partial success without rejected spans
+/// is a warning
+pub(crate) fn report(partial: PartialSuccess) {
+    if partial.rejected_spans == 0 && partial.error_message.is_empty() {
+        return;
+    }
+    match partial.rejected_spans {
+        0 => crate::exporter::log_warn(&format!(
+            "collector accepted the spans with a warning: {}",
+            partial.error_message
+        )),
+        rejected_spans => {
+            crate::metrics::count_rejected_spans(rejected_spans);
+            crate::exporter::log_warn(&format!(
+                "collector rejected {rejected_spans} spans: {}",
+                partial.error_message
+            ));
+        }
+    }
+}
+
+/// Reads the `partial_success` of an export response, protobuf (with the `direct` feature) or
+/// JSON (`http/json`)
+fn parse(response: &Response<Bytes>) -> Option<PartialSuccess> {
+    let body = response.body();
+    if body.is_empty() {
+        return None;
+    }
+    let content_type = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    let partial_success = if content_type.starts_with("application/json") {
+        let response = serde_json::from_slice::<serde_json::Value>(body).ok()?;
+        let partial_success = response.get("partialSuccess")?;
+        // int64 fields are strings in the JSON mapping of protobuf, numbers are accepted too
+        let rejected_spans = match partial_success.get("rejectedSpans") {
+            Some(serde_json::Value::String(count)) => count.parse().ok()?,
+            Some(count) => count.as_u64()?,
+            None => 0,
+        };
+        let error_message = partial_success.get("errorMessage");
+        let error_message = error_message.and_then(|m| m.as_str()).unwrap_or_default();
+        PartialSuccess {
+            rejected_spans,
+            error_message: error_message.to_string(),
+        }
+    } else {
+        parse_protobuf(body)?
+    };
+    match partial_success.rejected_spans > 0 || !partial_success.error_message.is_empty() {
+        true => Some(partial_success),
+        false => None,
+    }
+}
+
+#[cfg(feature = "direct")]
+fn parse_protobuf(body: &[u8]) -> Option<PartialSuccess> {
+    let partial_success = ExportTraceServiceResponse::decode(body)
+        .ok()?
+        .partial_success?;
+    Some(PartialSuccess {
+        rejected_spans: partial_success.rejected_spans.max(0) as u64,
+        error_message: partial_success.error_message,
+    })
+}
+
+/// Protobuf responses are only decoded with the `direct` feature
+#[cfg(not(feature = "direct"))]
+fn parse_protobuf(_: &[u8]) -> Option<PartialSuccess> {
+    None
+}
+
+/// HTTP client of the OTLP HTTP exporters reporting the spans a collector accepted the
+/// request for but rejected (OTLP partial success), which the exporters silently ignore.
+///
+/// Rejected spans are logged and counted in `haproxy_otel_rejected_spans_total`.
+#[derive(Debug)]
+pub(crate) struct PartialSuccessClient<C> {
+    inner: C,
+}
+
+impl<C: HttpClient> PartialSuccessClient<C> {
+    pub(crate) fn new(inner: C) -> Self {
+        Self { inner }
+    }
+}
+
+impl<C: HttpClient> HttpClient for PartialSuccessClient<C> {
+    fn send_bytes<'a, 'b>(&'a self, request: Request<Bytes>) -> SendFuture<'b>
+    where
+        'a: 'b,
+        Self: 'b,
+    {
+        Box::pin(async move {
+            let response = self.inner.send_bytes(request).await?;
+            if !response.status().is_success() {
+                return Ok(response);
+            }
+            if let Some(partial) = parse(&response) {
+                report(partial);
+            }
+            Ok(response)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(feature = "direct")]
+    use opentelemetry_proto::tonic::collector::trace::v1::ExportTracePartialSuccess;
+
+    use super::*;
+
+    fn response(content_type: &str, body: Vec<u8>) -> Response<Bytes> {
+        Response::builder()
+            .status(200)
+            .header("content-type", content_type)
+            .body(Bytes::from(body))
+            .unwrap()
+    }
+
+    #[test]
+    #[cfg(feature = "direct")]
+    fn test_parse_protobuf() {
+        let body = ExportTraceServiceResponse {
+            partial_success: Some(ExportTracePartialSuccess {
+                rejected_spans: 3,
+                error_message: "span too large".to_string(),
+            }),
+        };
+        assert_eq!(
+            parse(&response("application/x-protobuf", body.encode_to_vec())),
+            Some(PartialSuccess {
+                rejected_spans: 3,
+                error_message: "span too large".to_string(),
+            })
+        );
+        // Full success
+        let body = ExportTraceServiceResponse::default();
+        assert_eq!(
+            parse(&response("application/x-protobuf", body.encode_to_vec())),
+            None
+        );
+        assert_eq!(parse(&response("application/x-protobuf", Vec::new())), None);
+    }
+
+    #[test]
+    fn test_parse_json() {
+        let parse_json = |body: &str| parse(&response("application/json", body.into()));
+        assert_eq!(
+            parse_json(r#"{"partialSuccess":{"rejectedSpans":"2","errorMessage":"quota"}}"#),
+            Some(PartialSuccess {
+                rejected_spans: 2,
+                error_message: "quota".to_string(),
+            })
+        );
+        assert_eq!(
+            parse_json(r#"{"partialSuccess":{"rejectedSpans":5}}"#),
+            Some(PartialSuccess {
+                rejected_spans: 5,
+                error_message: String::new(),
+            })
+        );
+        assert_eq!(
+            parse_json(r#"{"partialSuccess":{"errorMessage":"deprecated field"}}"#),
+            Some(PartialSuccess {
+                rejected_spans: 0,
+                error_message: "deprecated field".to_string(),
+            })
+        );
+        assert_eq!(parse_json(r#"{"partialSuccess":{}}"#), None);
+        assert_eq!(parse_json("{}"), None);
+        assert_eq!(parse_json("not json"), None);
+    }
+}
@@ -0,0 +1,177 @@
+use std::path::{Path, PathBuf};
+
+use opentelemetry_proto::tonic::collector::trace::v1::ExportTraceServiceRequest;
+use opentelemetry_proto::transform::common::tonic::ResourceAttributesWithSchema;
+use opentelemetry_proto::transform::trace::tonic::group_spans_by_resource_and_scope;
+use opentelemetry_sdk::error::{OTelSdkError, OTelSdkResult};
+use opentelemetry_sdk::trace::{SpanData, SpanExporter};
+use opentelemetry_sdk::Resource;
+use prost::Message as _;
+use tokio::io::AsyncWriteExt as _;
+use tokio::net::unix::pipe;
+use tokio::sync::Mutex;
+
+/// Span exporter writing the spans to a named pipe read by a node-local collector
+/// (`exporter = "pipe"`), skipping HTTP and its encoding overhead entirely.
+///
+/// Every batch is one frame: the length of an `ExportTraceServiceRequest` as a 4-byte big-endian
+/// integer followed by its protobuf encoding. The pipe is opened at the first export and again
+/// after the reader went away; while no reader has it open the exports fail and the batches are
+/// dropped. A reader that doesn't keep up blocks the exports, and the batch queue fills up.
+#[derive(Debug)]
+pub(crate) struct PipeExporter {
+    path: PathBuf,
+    resource: ResourceAttributesWithSchema,
+    sender: Mutex<Option<pipe::Sender>>,
+}
+
+impl PipeExporter {
+    pub(crate) fn new(path: Option<&str>) -> Result<Self, String> {
+        let path = match path {
+            Some(path) => PathBuf::from(path),
+            None => return Err("exporter 'pipe' requires pipe.path".to_string()),
+        };
+        // The collector may create the pipe later, but a regular file is a mistake
+        if let Ok(metadata) = std::fs::metadata(&path) {
+            use std::os::unix::fs::FileTypeExt as _;
+            if !metadata.file_type().is_fifo() {
+                return Err(format!("{} is not a named pipe", path.display()));
+            }
+        }
+        Ok(PipeExporter {
+            path,
+            resource: ResourceAttributesWithSchema::default(),
+            sender: Mutex::new(None),
+        })
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+/// Length-prefixed frame of the spans
+fn encode_frame(request: &ExportTraceServiceRequest) -> Result<Vec<u8>, String> {
+    let len = request.encoded_len();
+    let prefix = u32::try_from(len).map_err(|_| format!("batch of {len} bytes is too large"))?;
+    let mut frame = Vec::with_capacity(4 + len);
+    frame.extend_from_slice(&prefix.to_be_bytes());
+    request
+        .encode(&mut frame)
+        .map_err(|e| format!("encoding failed: {e}"))?;
+    Ok(frame)
+}
+
+impl SpanExporter for PipeExporter {
+    async fn export(&self, batch: Vec<SpanData>) -> OTelSdkResult {
+        let request = ExportTraceServiceRequest {
+            resource_spans: group_spans_by_resource_and_scope(batch, &self.resource),
+        };
+        let frame = encode_frame(&request).map_err(OTelSdkError::InternalFailure)?;
+
+        let mut sender = self.sender.lock().await;
+        if sender.is_none() {
+            // Fails without a reader rather than waiting for one
+            let opened = pipe::OpenOptions::new()
+                .open_sender(&self.path)
+                .map_err(|e| {
+                    let path = self.path.display();
+                    OTelSdkError::InternalFailure(format!("failed to open {path}: {e}"))
+                })?;
+            *sender = Some(opened);
+        }
+        let result = match sender.as_mut() {
+            Some(pipe) => pipe.write_all(&frame).await,
+            None => Ok(()),
+        };
+        result.map_err(|e| {
+            // The reader is gone, the next export reopens the pipe
+            *sender = None;
+            OTelSdkError::InternalFailure(format!("write to {} failed: {e}", self.path.display()))
+        })
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        self.resource = resource.into();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read as _;
+
+    use super::*;
+    use crate::fixtures::{self, span_context, SPAN_ID, TRACE_ID};
+
+    fn span() -> SpanData {
+        SpanData {
+            span_context: span_context(TRACE_ID, SPAN_ID),
+            ..fixtures::span()
+        }
+    }
+
+    fn fifo(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("haproxy-otel-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let status = std::process::Command::new("mkfifo")
+            .arg(&path)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        path
+    }
+
+    #[test]
+    fn test_pipe_export() {
+        let path = fifo("pipe-export");
+        let mut exporter = PipeExporter::new(path.to_str()).unwrap();
+        exporter.set_resource(&Resource::builder_empty().with_service_name("edge").build());
+        let runtime = crate::exporter::get_otel_runtime();
+
+        // No collector reading the pipe yet
+        assert!(runtime.block_on(exporter.export(vec![span()])).is_err());
+
+        let reader_path = path.clone();
+        let collector = std::thread::spawn(move || {
+            // Blocks until the exporter opens the pipe as well
+            let mut reader = std::fs::File::open(reader_path).unwrap();
+            let mut frames = Vec::new();
+            for _ in 0..2 {
+                let mut len = [0u8; 4];
+                reader.read_exact(&mut len).unwrap();
+                let mut frame = vec![0u8; u32::from_be_bytes(len) as usize];
+                reader.read_exact(&mut frame).unwrap();
+                frames.push(ExportTraceServiceRequest::decode(frame.as_slice()).unwrap());
+            }
+            frames
+        });
+        while runtime.block_on(exporter.export(vec![span()])).is_err() {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        runtime
+            .block_on(exporter.export(vec![span(), span()]))
+            .unwrap();
+        let frames = collector.join().unwrap();
+        let resource_spans = &frames[0].resource_spans[0];
+        let service = &resource_spans.resource.as_ref().unwrap().attributes[0];
+        assert_eq!(service.key, "service.name");
+        let spans = &resource_spans.scope_spans[0].spans;
+        assert_eq!(spans[0].name, "GET example.com");
+        assert_eq!(
+            spans[0].trace_id,
+            0x4bf9_2f35_77b3_4da6_a3ce_929d_0e0e_4736u128.to_be_bytes()
+        );
+        assert_eq!(frames[1].resource_spans[0].scope_spans[0].spans.len(), 2);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_pipe_requires_fifo() {
+        assert!(PipeExporter::new(None).is_err());
+        let file =
+            std::env::temp_dir().join(format!("haproxy-otel-not-a-pipe-{}", std::process::id()));
+        std::fs::write(&file, b"").unwrap();
+        assert!(PipeExporter::new(file.to_str()).is_err());
+        let _ = std::fs::remove_file(file);
+    }
+}
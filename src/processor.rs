@@ -0,0 +1,548 @@
+use std::time::Duration;
+
+use opentelemetry::trace::{SpanKind, Status};
+use opentelemetry::{Array, Context, KeyValue, StringValue, Value};
+use opentelemetry_sdk::error::OTelSdkResult;
+use opentelemetry_sdk::trace::{Span, SpanData, SpanProcessor};
+use opentelemetry_sdk::Resource;
+
+/// Which value wins when the same attribute is set multiple times on a span
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub(crate) enum AttributePolicy {
+    #[default]
+    LastWins,
+    FirstWins,
+}
+
+impl AttributePolicy {
+    /// Parse attribute policy from string ("last_wins" or "first_wins")
+    pub(crate) fn from_str(s: &str) -> Option<Self> {
+        match s.to_lowercase().replace('-', "_").as_str() {
+            "last_wins" => Some(AttributePolicy::LastWins),
+            "first_wins" => Some(AttributePolicy::FirstWins),
+            _ => None,
+        }
+    }
+}
+
+/// Removes duplicated attribute keys, keeping the value selected by the policy.
+///
+/// The SDK appends every `set_attribute` call, so the same key can be set by the
+/// span builder, the filter and user actions. Some backends reject such spans.
+pub(crate) fn dedup_attributes(attributes: &mut Vec<KeyValue>, policy: AttributePolicy) {
+    // Spans carry a handful of attributes, so a quadratic scan beats hashing
+    let len = attributes.len();
+    let has_duplicates =
+        (1..len).any(|i| attributes[..i].iter().any(|kv| kv.key == attributes[i].key));
+    if !has_duplicates {
+        return;
+    }
+
+    let keep = (0..len)
+        .map(|i| {
+            let key = &attributes[i].key;
+            match policy {
+                AttributePolicy::LastWins => !attributes[i + 1..].iter().any(|kv| &kv.key == key),
+                AttributePolicy::FirstWins => !attributes[..i].iter().any(|kv| &kv.key == key),
+            }
+        })
+        .collect::<Vec<_>>();
+    let mut keep = keep.into_iter();
+    attributes.retain(|_| keep.next().unwrap_or(true));
+}
+
+/// Span processor wrapper that de-duplicates attributes of finished spans
+#[derive(Debug)]
+pub(crate) struct DedupAttributes<P> {
+    inner: P,
+    policy: AttributePolicy,
+}
+
+impl<P> DedupAttributes<P> {
+    pub(crate) fn new(inner: P, policy: AttributePolicy) -> Self {
+        Self { inner, policy }
+    }
+}
+
+impl<P: SpanProcessor> SpanProcessor for DedupAttributes<P> {
+    fn on_start(&self, span: &mut Span, cx: &Context) {
+        self.inner.on_start(span, cx);
+    }
+
+    fn on_end(&self, mut span: SpanData) {
+        dedup_attributes(&mut span.attributes, self.policy);
+        self.inner.on_end(span);
+    }
+
+    fn force_flush(&self) -> OTelSdkResult {
+        self.inner.force_flush()
+    }
+
+    fn shutdown_with_timeout(&self, timeout: Duration) -> OTelSdkResult {
+        self.inner.shutdown_with_timeout(timeout)
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        self.inner.set_resource(resource);
+    }
+}
+
+/// Size limits applied to the attributes of finished spans
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub(crate) struct AttributeLimits {
+    // Maximum length of string values in bytes, longer values are truncated
+    pub(crate) value_length: Option<usize>,
+    // Maximum total size of a span's attribute keys and values in bytes
+    pub(crate) span_size: Option<usize>,
+}
+
+impl AttributeLimits {
+    fn is_empty(&self) -> bool {
+        self.value_length.is_none() && self.span_size.is_none()
+    }
+}
+
+/// Truncates oversized string values and, when the span exceeds its size budget, drops
+/// the largest attributes first (typically user agents or long URLs).
+///
+/// Returns the number of dropped attributes.
+pub(crate) fn limit_attributes(attributes: &mut Vec<KeyValue>, limits: AttributeLimits) -> u32 {
+    if let Some(max) = limits.value_length {
+        for kv in attributes.iter_mut() {
+            if let Value::String(ref s) = kv.value {
+                if s.as_str().len() > max {
+                    kv.value = Value::String(truncate(s.as_str(), max));
+                }
+            }
+        }
+    }
+    let budget = match limits.span_size {
+        Some(budget) => budget,
+        None => return 0,
+    };
+    let sizes = attributes.iter().map(attribute_size).collect::<Vec<_>>();
+    let mut total = sizes.iter().sum::<usize>();
+    if total <= budget {
+        return 0;
+    }
+    let mut largest = (0..attributes.len()).collect::<Vec<_>>();
+    largest.sort_by_key(|i| std::cmp::Reverse(sizes[*i]));
+    let mut keep = vec![true; attributes.len()];
+    let mut dropped = 0;
+    for i in largest {
+        if total <= budget {
+            break;
+        }
+        keep[i] = false;
+        total -= sizes[i];
+        dropped += 1;
+    }
+    let mut keep = keep.into_iter();
+    attributes.retain(|_| keep.next().unwrap_or(true));
+    dropped
+}
+
+fn truncate(value: &str, max: usize) -> StringValue {
+    let mut end = max;
+    while !value.is_char_boundary(end) {
+        end -= 1;
+    }
+    value[..end].to_string().into()
+}
+
+fn attribute_size(kv: &KeyValue) -> usize {
+    let value = match kv.value {
+        Value::String(ref s) => s.as_str().len(),
+        Value::Array(Array::String(ref values)) => values.iter().map(|s| s.as_str().len()).sum(),
+        Value::Array(Array::Bool(ref values)) => values.len(),
+        Value::Array(Array::I64(ref values)) => values.len() * 8,
+        Value::Array(Array::F64(ref values)) => values.len() * 8,
+        Value::Bool(_) => 1,
+        _ => 8,
+    };
+    kv.key.as_str().len() + value
+}
+
+/// Span processor wrapper enforcing [`AttributeLimits`], dropped attributes are
+/// reported in the span's dropped attributes count
+#[derive(Debug)]
+pub(crate) struct LimitAttributes<P> {
+    inner: P,
+    limits: AttributeLimits,
+}
+
+impl<P> LimitAttributes<P> {
+    pub(crate) fn new(inner: P, limits: AttributeLimits) -> Self {
+        Self { inner, limits }
+    }
+}
+
+impl<P: SpanProcessor> SpanProcessor for LimitAttributes<P> {
+    fn on_start(&self, span: &mut Span, cx: &Context) {
+        self.inner.on_start(span, cx);
+    }
+
+    fn on_end(&self, mut span: SpanData) {
+        if !self.limits.is_empty() {
+            let dropped = limit_attributes(&mut span.attributes, self.limits);
+            span.dropped_attributes_count += dropped;
+        }
+        self.inner.on_end(span);
+    }
+
+    fn force_flush(&self) -> OTelSdkResult {
+        self.inner.force_flush()
+    }
+
+    fn shutdown_with_timeout(&self, timeout: Duration) -> OTelSdkResult {
+        self.inner.shutdown_with_timeout(timeout)
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        self.inner.set_resource(resource);
+    }
+}
+
+/// Which spans keep their events (`span_events` option)
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub(crate) enum EventPolicy {
+    #[default]
+    All,
+    // Only spans with an error status keep their events
+    Errors,
+}
+
+impl EventPolicy {
+    /// Parse event policy from string ("all" or "errors")
+    pub(crate) fn from_str(s: &str) -> Option<Self> {
+        match s.to_lowercase().as_str() {
+            "all" => Some(EventPolicy::All),
+            "errors" => Some(EventPolicy::Errors),
+            _ => None,
+        }
+    }
+}
+
+/// Removes the events of successful spans under [`EventPolicy::Errors`],
+/// they are reported in the span's dropped events count
+pub(crate) fn filter_events(span: &mut SpanData, policy: EventPolicy) {
+    if policy == EventPolicy::All || matches!(span.status, Status::Error { .. }) {
+        return;
+    }
+    span.events.dropped_count += span.events.events.len() as u32;
+    span.events.events.clear();
+}
+
+/// Span processor wrapper enforcing the [`EventPolicy`]
+#[derive(Debug)]
+pub(crate) struct FilterEvents<P> {
+    inner: P,
+    policy: EventPolicy,
+}
+
+impl<P> FilterEvents<P> {
+    pub(crate) fn new(inner: P, policy: EventPolicy) -> Self {
+        Self { inner, policy }
+    }
+}
+
+impl<P: SpanProcessor> SpanProcessor for FilterEvents<P> {
+    fn on_start(&self, span: &mut Span, cx: &Context) {
+        self.inner.on_start(span, cx);
+    }
+
+    fn on_end(&self, mut span: SpanData) {
+        filter_events(&mut span, self.policy);
+        self.inner.on_end(span);
+    }
+
+    fn force_flush(&self) -> OTelSdkResult {
+        self.inner.force_flush()
+    }
+
+    fn shutdown_with_timeout(&self, timeout: Duration) -> OTelSdkResult {
+        self.inner.shutdown_with_timeout(timeout)
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        self.inner.set_resource(resource);
+    }
+}
+
+/// Spans exported in the export-only mode (`export_only` option), the others are dropped
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub(crate) struct ExportFilter {
+    // Spans with an error status
+    pub(crate) errors: bool,
+    // Spans lasting at least this long
+    pub(crate) slower_than: Option<Duration>,
+}
+
+impl ExportFilter {
+    fn is_empty(&self) -> bool {
+        !self.errors && self.slower_than.is_none()
+    }
+
+    /// Whether a finished span is exported, always true without a filter and for the startup
+    /// self-test span, which only shows that the export path works
+    pub(crate) fn keeps(&self, span: &SpanData) -> bool {
+        let self_test =
+            || (span.attributes.iter()).any(|kv| kv.key.as_str() == crate::sampler::SELF_TEST);
+        if self.is_empty() || self_test() {
+            return true;
+        }
+        let slow = self.slower_than.is_some_and(|threshold| {
+            let duration = span.end_time.duration_since(span.start_time);
+            duration.is_ok_and(|duration| duration >= threshold)
+        });
+        slow || (self.errors && matches!(span.status, Status::Error { .. }))
+    }
+}
+
+/// Whether a finished span is the client span of a request HAProxy found no server for
+fn is_no_server_client_span(span: &SpanData) -> bool {
+    span.span_kind == SpanKind::Client
+        && (span.attributes.iter())
+            .any(|kv| kv.key.as_str() == crate::span::NO_SERVER && kv.value == Value::Bool(true))
+}
+
+/// Span processor wrapper dropping the spans rejected by the [`ExportFilter`], and the client
+/// spans of requests without a server under `no_server_span = "drop"`.
+///
+/// Spans are judged one by one when they end, without buffering their trace: a kept server
+/// span can miss its client span when only the server span failed or was slow.
+#[derive(Debug)]
+pub(crate) struct FilterSpans<P> {
+    inner: P,
+    filter: ExportFilter,
+    drop_no_server: bool,
+}
+
+impl<P> FilterSpans<P> {
+    pub(crate) fn new(inner: P, filter: ExportFilter) -> Self {
+        Self {
+            inner,
+            filter,
+            drop_no_server: false,
+        }
+    }
+
+    pub(crate) fn with_no_server_drop(mut self, drop_no_server: bool) -> Self {
+        self.drop_no_server = drop_no_server;
+        self
+    }
+
+    fn keeps(&self, span: &SpanData) -> bool {
+        self.filter.keeps(span) && !(self.drop_no_server && is_no_server_client_span(span))
+    }
+}
+
+impl<P: SpanProcessor> SpanProcessor for FilterSpans<P> {
+    fn on_start(&self, span: &mut Span, cx: &Context) {
+        self.inner.on_start(span, cx);
+    }
+
+    fn on_end(&self, span: SpanData) {
+        if self.keeps(&span) {
+            self.inner.on_end(span);
+        }
+    }
+
+    fn force_flush(&self) -> OTelSdkResult {
+        self.inner.force_flush()
+    }
+
+    fn shutdown_with_timeout(&self, timeout: Duration) -> OTelSdkResult {
+        self.inner.shutdown_with_timeout(timeout)
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        self.inner.set_resource(resource);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn attrs(pairs: &[(&'static str, &'static str)]) -> Vec<KeyValue> {
+        pairs.iter().map(|(k, v)| KeyValue::new(*k, *v)).collect()
+    }
+
+    #[test]
+    fn test_attribute_policy_from_str() {
+        assert_eq!(
+            AttributePolicy::from_str("last_wins"),
+            Some(AttributePolicy::LastWins)
+        );
+        assert_eq!(
+            AttributePolicy::from_str("First-Wins"),
+            Some(AttributePolicy::FirstWins)
+        );
+        assert_eq!(AttributePolicy::from_str("random"), None);
+        assert_eq!(AttributePolicy::default(), AttributePolicy::LastWins);
+    }
+
+    #[test]
+    fn test_dedup_attributes_last_wins() {
+        let mut attributes = attrs(&[("a", "1"), ("b", "1"), ("a", "2"), ("c", "1"), ("a", "3")]);
+        dedup_attributes(&mut attributes, AttributePolicy::LastWins);
+        assert_eq!(attributes, attrs(&[("b", "1"), ("c", "1"), ("a", "3")]));
+    }
+
+    #[test]
+    fn test_dedup_attributes_first_wins() {
+        let mut attributes = attrs(&[("a", "1"), ("b", "1"), ("a", "2"), ("c", "1"), ("a", "3")]);
+        dedup_attributes(&mut attributes, AttributePolicy::FirstWins);
+        assert_eq!(attributes, attrs(&[("a", "1"), ("b", "1"), ("c", "1")]));
+    }
+
+    #[test]
+    fn test_limit_attributes() {
+        let user_agent = "Mozilla/5.0 (X11; Linux x86_64) ".repeat(10);
+        let mut attributes = vec![
+            KeyValue::new("http.request.method", "GET"),
+            KeyValue::new("user_agent.original", user_agent.clone()),
+            KeyValue::new("http.response.status_code", 200),
+        ];
+        let limits = AttributeLimits {
+            value_length: Some(5),
+            span_size: None,
+        };
+        assert_eq!(limit_attributes(&mut attributes, limits), 0);
+        assert_eq!(attributes[1], KeyValue::new("user_agent.original", "Mozil"));
+
+        let mut attributes = vec![
+            KeyValue::new("http.request.method", "GET"),
+            KeyValue::new("user_agent.original", user_agent),
+            KeyValue::new("http.response.status_code", 200),
+        ];
+        let limits = AttributeLimits {
+            value_length: None,
+            span_size: Some(100),
+        };
+        assert_eq!(limit_attributes(&mut attributes, limits), 1);
+        assert_eq!(
+            attributes,
+            vec![
+                KeyValue::new("http.request.method", "GET"),
+                KeyValue::new("http.response.status_code", 200),
+            ]
+        );
+    }
+
+    fn span(status: Status, duration: Duration) -> SpanData {
+        SpanData {
+            name: "GET /".into(),
+            end_time: std::time::UNIX_EPOCH + duration,
+            status,
+            ..crate::fixtures::span()
+        }
+    }
+
+    #[test]
+    fn test_filter_events() {
+        use std::time::SystemTime;
+
+        use opentelemetry::trace::{Event, SpanContext, SpanId, SpanKind};
+        use opentelemetry::InstrumentationScope;
+        use opentelemetry_sdk::trace::{SpanEvents, SpanLinks};
+
+        let mut events = SpanEvents::default();
+        events.events.push(Event::with_name("response headers"));
+        let mut span = SpanData {
+            span_context: SpanContext::empty_context(),
+            parent_span_id: SpanId::INVALID,
+            span_kind: SpanKind::Server,
+            name: "GET /".into(),
+            start_time: SystemTime::UNIX_EPOCH,
+            end_time: SystemTime::UNIX_EPOCH,
+            attributes: Vec::new(),
+            dropped_attributes_count: 0,
+            events,
+            links: SpanLinks::default(),
+            status: Status::error("5xx status code"),
+            instrumentation_scope: InstrumentationScope::builder("test").build(),
+        };
+        filter_events(&mut span, EventPolicy::Errors);
+        assert_eq!(span.events.len(), 1);
+
+        span.status = Status::Ok;
+        filter_events(&mut span, EventPolicy::All);
+        assert_eq!(span.events.len(), 1);
+        filter_events(&mut span, EventPolicy::Errors);
+        assert_eq!(span.events.len(), 0);
+        assert_eq!(span.events.dropped_count, 1);
+    }
+
+    #[test]
+    fn test_drop_no_server_client_spans() {
+        let mut client = span(Status::error("no server available"), Duration::ZERO);
+        client.span_kind = SpanKind::Client;
+        client
+            .attributes
+            .push(KeyValue::new(crate::span::NO_SERVER, true));
+        let mut server = client.clone();
+        server.span_kind = SpanKind::Server;
+
+        let filter = FilterSpans::new((), ExportFilter::default());
+        assert!(filter.keeps(&client) && filter.keeps(&server));
+        let filter = filter.with_no_server_drop(true);
+        assert!(!filter.keeps(&client));
+        // The server span still tells the request had no server
+        assert!(filter.keeps(&server));
+        client.attributes.clear();
+        assert!(filter.keeps(&client));
+    }
+
+    #[test]
+    fn test_export_filter() {
+        let error = span(Status::error("5xx status code"), Duration::from_millis(10));
+        let slow = span(Status::Unset, Duration::from_secs(2));
+        let fast = span(Status::Ok, Duration::from_millis(10));
+
+        // No filter: everything is exported
+        let filter = ExportFilter::default();
+        assert!(filter.keeps(&error) && filter.keeps(&slow) && filter.keeps(&fast));
+
+        let errors = ExportFilter {
+            errors: true,
+            slower_than: None,
+        };
+        assert!(errors.keeps(&error));
+        assert!(!errors.keeps(&slow) && !errors.keeps(&fast));
+
+        let slow_only = ExportFilter {
+            errors: false,
+            slower_than: Some(Duration::from_secs(1)),
+        };
+        assert!(slow_only.keeps(&slow));
+        assert!(!slow_only.keeps(&error) && !slow_only.keeps(&fast));
+
+        let both = ExportFilter {
+            errors: true,
+            slower_than: Some(Duration::from_secs(2)),
+        };
+        assert!(both.keeps(&error) && both.keeps(&slow));
+        assert!(!both.keeps(&fast));
+
+        // The startup self-test span is always exported
+        let mut self_test = fast.clone();
+        self_test
+            .attributes
+            .push(KeyValue::new(crate::sampler::SELF_TEST, true));
+        assert!(both.keeps(&self_test));
+    }
+
+    #[test]
+    fn test_truncate_char_boundary() {
+        assert_eq!(truncate("héllo", 2).as_str(), "h");
+    }
+
+    #[test]
+    fn test_dedup_attributes_without_duplicates() {
+        let mut attributes = attrs(&[("a", "1"), ("b", "2")]);
+        dedup_attributes(&mut attributes, AttributePolicy::LastWins);
+        assert_eq!(attributes, attrs(&[("a", "1"), ("b", "2")]));
+    }
+}
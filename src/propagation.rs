@@ -0,0 +1,144 @@
+use opentelemetry::trace::TraceState;
+
+/// Maximum number of `tracestate` list members (W3C Trace Context)
+const MAX_TRACESTATE_MEMBERS: usize = 32;
+/// List members longer than this are removed first when truncating
+const LARGE_TRACESTATE_MEMBER: usize = 128;
+
+/// Cleans up an inbound `tracestate` header so that it survives extraction.
+///
+/// The SDK drops the whole header when a single list member fails to parse,
+/// losing foreign vendor entries. Here optional whitespace and empty members are
+/// removed, invalid members are skipped individually and the list is truncated
+/// per spec, keeping the order of the remaining members.
+pub(crate) fn sanitize_tracestate(header: &str) -> String {
+    let mut members = header
+        .split(',')
+        .map(|member| member.trim_matches([' ', '\t']))
+        .filter(|member| is_valid_member(member))
+        .collect::<Vec<_>>();
+
+    // Truncate: remove large members (from the end) first, then the trailing members
+    while members.len() > MAX_TRACESTATE_MEMBERS {
+        match members
+            .iter()
+            .rposition(|member| member.len() > LARGE_TRACESTATE_MEMBER)
+        {
+            Some(i) => {
+                members.remove(i);
+            }
+            None => members.truncate(MAX_TRACESTATE_MEMBERS),
+        }
+    }
+    members.join(",")
+}
+
+fn is_valid_member(member: &str) -> bool {
+    match member.split_once('=') {
+        Some((key, value)) => {
+            !value.ends_with(' ') && TraceState::from_key_value([(key, value)]).is_ok()
+        }
+        None => false,
+    }
+}
+
+/// Header carrying the whole trace context in the format of `propagator`, the one read from
+/// the `context_query_param` query parameter
+pub(crate) fn context_header(propagator: &str) -> &'static str {
+    match propagator {
+        "zipkin" => "b3",
+        "jaeger" => "uber-trace-id",
+        _ => "traceparent",
+    }
+}
+
+/// Percent-decoded value of the first `name` parameter of a query string
+pub(crate) fn query_param(query: &str, name: &str) -> Option<String> {
+    query.split('&').find_map(|param| {
+        let (key, value) = param.split_once('=').unwrap_or((param, ""));
+        let (key, value) = (key.replace('+', " "), value.replace('+', " "));
+        (percent_decode(&key)? == name).then(|| percent_decode(&value))?
+    })
+}
+
+/// Decodes `%XX` escapes, `None` for a malformed escape or invalid UTF-8
+pub(crate) fn percent_decode(value: &str) -> Option<String> {
+    let mut bytes = value.bytes();
+    let mut decoded = Vec::with_capacity(value.len());
+    while let Some(byte) = bytes.next() {
+        decoded.push(match byte {
+            b'%' => {
+                let hex = [bytes.next()?, bytes.next()?];
+                u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?
+            }
+            byte => byte,
+        });
+    }
+    String::from_utf8(decoded).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_query_param() {
+        let traceparent = "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01";
+        let query = format!("token=abc&traceparent={traceparent}&x");
+        assert_eq!(
+            query_param(&query, "traceparent").as_deref(),
+            Some(traceparent)
+        );
+        assert_eq!(query_param(&query, "x").as_deref(), Some(""));
+        assert_eq!(query_param(&query, "tracestate"), None);
+        assert_eq!(query_param("", "traceparent"), None);
+        // First occurrence wins, values are percent-decoded
+        assert_eq!(
+            query_param(
+                "uber-trace-id=a%3Ab%3A0%3A1&uber-trace-id=c",
+                "uber-trace-id"
+            )
+            .as_deref(),
+            Some("a:b:0:1")
+        );
+        assert_eq!(query_param("traceparent=%zz", "traceparent"), None);
+    }
+
+    #[test]
+    fn test_context_header() {
+        assert_eq!(context_header("w3c"), "traceparent");
+        assert_eq!(context_header("zipkin"), "b3");
+        assert_eq!(context_header("jaeger"), "uber-trace-id");
+    }
+
+    #[test]
+    fn test_sanitize_tracestate_keeps_order() {
+        assert_eq!(
+            sanitize_tracestate("congo=t61rcWkgMzE, rojo=00f067aa0ba902b7 ,,dd=s:1;o:rum"),
+            "congo=t61rcWkgMzE,rojo=00f067aa0ba902b7,dd=s:1;o:rum"
+        );
+    }
+
+    #[test]
+    fn test_sanitize_tracestate_skips_invalid_members() {
+        // A single invalid member must not drop the foreign entries
+        assert_eq!(
+            sanitize_tracestate("vendor=a,Invalid Key=b,novalue,tenant@sys=c"),
+            "vendor=a,tenant@sys=c"
+        );
+        assert_eq!(sanitize_tracestate(""), "");
+    }
+
+    #[test]
+    fn test_sanitize_tracestate_truncates() {
+        let large = format!("big={}", "x".repeat(200));
+        let mut members = (0..40).map(|i| format!("k{i}=v")).collect::<Vec<_>>();
+        members.insert(3, large.clone());
+        let sanitized = sanitize_tracestate(&members.join(","));
+        let sanitized = sanitized.split(',').collect::<Vec<_>>();
+        assert_eq!(sanitized.len(), MAX_TRACESTATE_MEMBERS);
+        assert!(!sanitized.contains(&large.as_str()));
+        assert_eq!(sanitized[0], "k0=v");
+        assert_eq!(sanitized[31], "k31=v");
+    }
+}
@@ -0,0 +1,472 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use opentelemetry_http::{Bytes, HttpClient, HttpError, Request, Response};
+use opentelemetry_sdk::error::OTelSdkError;
+
+use crate::exporter::{log_debug, SendFuture};
+
+/// HTTP statuses worth retrying per the OTLP/HTTP specification
+const RETRYABLE_STATUSES: [u16; 4] = [429, 502, 503, 504];
+
+/// Longest `Retry-After` delay honored, collectors asking for more are retried earlier
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(60);
+
+/// Retry policy of failed export requests (`otlp.retry`)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct RetryPolicy {
+    /// Attempts per export request including the first one, 1 disables retries
+    pub(crate) max_attempts: u32,
+    pub(crate) initial_backoff: Duration,
+    pub(crate) max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 1,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(5),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Delay before the given retry (1 for the first one): doubled on every retry up to
+    /// `max_backoff`, then reduced by up to half by `jitter` (0.0 to 1.0) so that the
+    /// exporters of all HAProxy processes don't hit a restarted collector in lockstep
+    fn backoff(&self, retry: u32, jitter: f64) -> Duration {
+        let factor = 2u32.saturating_pow(retry.saturating_sub(1));
+        let backoff = self
+            .initial_backoff
+            .saturating_mul(factor)
+            .min(self.max_backoff);
+        backoff.mul_f64(1.0 - jitter.clamp(0.0, 1.0) / 2.0)
+    }
+
+    /// Waits before the given retry, logging why the previous attempt failed
+    async fn wait(&self, retry: u32, reason: &str) {
+        let delay = self.backoff(retry, jitter());
+        log_debug(&format!(
+            "export failed ({reason}), retry {retry} in {}ms",
+            delay.as_millis()
+        ));
+        tokio::time::sleep(delay).await;
+    }
+}
+
+/// Random number between 0.0 and 1.0, from the randomly seeded keys of the std hasher
+pub(crate) fn jitter() -> f64 {
+    let random = RandomState::new().build_hasher().finish();
+    (random >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// Copy of a request, sent again when the first one failed
+pub(crate) fn clone_request(request: &Request<Bytes>) -> Result<Request<Bytes>, HttpError> {
+    let builder = Request::builder()
+        .method(request.method().clone())
+        .uri(request.uri().clone())
+        .version(request.version());
+    let builder = request
+        .headers()
+        .iter()
+        .fold(builder, |builder, (name, value)| {
+            builder.header(name, value)
+        });
+    Ok(builder.body(request.body().clone())?)
+}
+
+/// Delay requested by the `Retry-After` header of a `429` or `503` response, in seconds or
+/// as an HTTP date
+fn retry_after(response: &Response<Bytes>, now: SystemTime) -> Option<Duration> {
+    if !matches!(response.status().as_u16(), 429 | 503) {
+        return None;
+    }
+    let value = response.headers().get("retry-after")?.to_str().ok()?.trim();
+    let delay = match value.parse::<u64>() {
+        Ok(seconds) => Duration::from_secs(seconds),
+        Err(_) => parse_http_date(value)?
+            .duration_since(now)
+            .unwrap_or_default(),
+    };
+    Some(delay.min(MAX_RETRY_AFTER))
+}
+
+/// Parses an IMF-fixdate such as `Sun, 06 Nov 1994 08:49:37 GMT`
+fn parse_http_date(value: &str) -> Option<SystemTime> {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let parts = value.split_whitespace().collect::<Vec<_>>();
+    let [_, day, month, year, time, "GMT"] = parts[..] else {
+        return None;
+    };
+    let day = day
+        .parse::<u64>()
+        .ok()
+        .filter(|day| (1..=31).contains(day))?;
+    let month = MONTHS.iter().position(|m| *m == month)? as u64 + 1;
+    let year = year.parse::<u64>().ok().filter(|year| *year >= 1970)?;
+    let mut time = time.split(':').map(|part| part.parse::<u64>().ok());
+    let (hours, minutes, seconds) = (time.next()??, time.next()??, time.next()??);
+    if time.next().is_some() || hours > 23 || minutes > 59 || seconds > 60 {
+        return None;
+    }
+    // Days since the epoch of a proleptic Gregorian date (Howard Hinnant's algorithm)
+    let (year, month) = match month {
+        1 | 2 => (year - 1, month + 9),
+        _ => (year, month - 3),
+    };
+    let era = year / 400;
+    let year_of_era = year % 400;
+    let day_of_year = (153 * month + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = (era * 146_097 + day_of_era).checked_sub(719_468)?;
+    let seconds = days * 86_400 + hours * 3600 + minutes * 60 + seconds;
+    Some(UNIX_EPOCH + Duration::from_secs(seconds))
+}
+
+/// HTTP client of the OTLP HTTP exporters retrying failed requests with exponential backoff.
+///
+/// Transport errors (e.g. a restarting collector refusing connections) and the retryable
+/// statuses of the specification are retried, the last response or error is returned.
+/// A `Retry-After` header of a `429` or `503` response replaces the backoff and also delays
+/// the requests of the following batches.
+#[derive(Debug)]
+pub(crate) struct RetryClient<C> {
+    inner: C,
+    policy: RetryPolicy,
+    // No request is sent before this time (`Retry-After`)
+    not_before: Mutex<Option<Instant>>,
+}
+
+impl<C: HttpClient> RetryClient<C> {
+    pub(crate) fn new(inner: C, policy: RetryPolicy) -> Self {
+        Self {
+            inner,
+            policy,
+            not_before: Mutex::new(None),
+        }
+    }
+
+    /// Waits until the delay requested by the collector is over
+    async fn wait_retry_after(&self) {
+        let not_before = self
+            .not_before
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .take_if(|not_before| *not_before > Instant::now());
+        if let Some(not_before) = not_before {
+            tokio::time::sleep_until(not_before.into()).await;
+        }
+    }
+}
+
+impl<C: HttpClient> HttpClient for RetryClient<C> {
+    fn send_bytes<'a, 'b>(&'a self, request: Request<Bytes>) -> SendFuture<'b>
+    where
+        'a: 'b,
+        Self: 'b,
+    {
+        Box::pin(async move {
+            let mut request = request;
+            let mut retry = 0;
+            loop {
+                self.wait_retry_after().await;
+                let next = match retry + 1 < self.policy.max_attempts {
+                    true => Some(clone_request(&request)?),
+                    false => None,
+                };
+                let result = self.inner.send_bytes(request).await;
+                let (reason, retry_after) = match &result {
+                    Ok(response) if RETRYABLE_STATUSES.contains(&response.status().as_u16()) => {
+                        let status = response.status().as_u16();
+                        (
+                            format!("status {status}"),
+                            retry_after(response, SystemTime::now()),
+                        )
+                    }
+                    Ok(_) => return result,
+                    Err(e) => (e.to_string(), None),
+                };
+                if let Some(delay) = retry_after {
+                    log_debug(&format!(
+                        "export failed ({reason}), collector asked to retry after {}ms",
+                        delay.as_millis()
+                    ));
+                    *self.not_before.lock().unwrap_or_else(|e| e.into_inner()) =
+                        Some(Instant::now() + delay);
+                }
+                let Some(next) = next else {
+                    return result;
+                };
+                retry += 1;
+                if retry_after.is_none() {
+                    self.policy.wait(retry, &reason).await;
+                }
+                request = next;
+            }
+        })
+    }
+}
+
+/// Whether a failed export may succeed when sent again: transport errors and the retryable
+/// statuses, not the batches the collector rejected (e.g. `400 Bad Request`)
+#[cfg_attr(not(feature = "direct"), allow(dead_code))]
+pub(crate) fn is_retryable_error(error: &OTelSdkError) -> bool {
+    let OTelSdkError::InternalFailure(error) = error else {
+        return true;
+    };
+    // The HTTP exporter of the SDK swaps the labels of the URL and the status code
+    let status = (error.strip_prefix("OpenTelemetry trace export failed. Url: "))
+        .and_then(|rest| rest.split(',').next()?.parse::<u16>().ok());
+    if let Some(status) = status {
+        return RETRYABLE_STATUSES.contains(&status);
+    }
+    #[cfg(feature = "grpc")]
+    if error.starts_with("status: ") {
+        return is_retryable_grpc_error(error);
+    }
+    true
+}
+
+/// gRPC status codes worth retrying per the OTLP/gRPC specification
+#[cfg(feature = "grpc")]
+const RETRYABLE_CODES: [tonic::Code; 7] = [
+    tonic::Code::Cancelled,
+    tonic::Code::DeadlineExceeded,
+    tonic::Code::ResourceExhausted,
+    tonic::Code::Aborted,
+    tonic::Code::OutOfRange,
+    tonic::Code::Unavailable,
+    tonic::Code::DataLoss,
+];
+
+/// Whether an error of the tonic exporter carries a retryable status.
+///
+/// The exporter only keeps the formatted `tonic::Status` (`status: Unavailable, ...`).
+#[cfg(feature = "grpc")]
+fn is_retryable_grpc_error(error: &str) -> bool {
+    let code = error
+        .strip_prefix("status: ")
+        .and_then(|rest| rest.split(',').next())
+        .unwrap_or_default();
+    RETRYABLE_CODES
+        .iter()
+        .any(|retryable| format!("{retryable:?}") == code)
+}
+
+/// Span exporter retrying the exports of the OTLP gRPC exporter with exponential backoff
+#[cfg(feature = "grpc")]
+#[derive(Debug)]
+pub(crate) struct RetryExporter<E> {
+    inner: E,
+    policy: RetryPolicy,
+}
+
+#[cfg(feature = "grpc")]
+impl<E> RetryExporter<E> {
+    pub(crate) fn new(inner: E, policy: RetryPolicy) -> Self {
+        Self { inner, policy }
+    }
+}
+
+#[cfg(feature = "grpc")]
+impl<E: opentelemetry_sdk::trace::SpanExporter> opentelemetry_sdk::trace::SpanExporter
+    for RetryExporter<E>
+{
+    async fn export(
+        &self,
+        batch: Vec<opentelemetry_sdk::trace::SpanData>,
+    ) -> opentelemetry_sdk::error::OTelSdkResult {
+        let mut batch = batch;
+        let mut retry = 0;
+        loop {
+            let next = (retry + 1 < self.policy.max_attempts).then(|| batch.clone());
+            let result = self.inner.export(batch).await;
+            let reason = match &result {
+                Err(OTelSdkError::InternalFailure(e)) if is_retryable_grpc_error(e) => e.clone(),
+                _ => return result,
+            };
+            let Some(next) = next else {
+                return result;
+            };
+            retry += 1;
+            self.policy.wait(retry, &reason).await;
+            batch = next;
+        }
+    }
+
+    fn shutdown_with_timeout(
+        &mut self,
+        timeout: Duration,
+    ) -> opentelemetry_sdk::error::OTelSdkResult {
+        self.inner.shutdown_with_timeout(timeout)
+    }
+
+    fn force_flush(&mut self) -> opentelemetry_sdk::error::OTelSdkResult {
+        self.inner.force_flush()
+    }
+
+    fn set_resource(&mut self, resource: &opentelemetry_sdk::Resource) {
+        self.inner.set_resource(resource);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::fixtures::{response, HttpCollector};
+
+    #[test]
+    fn test_backoff() {
+        let policy = RetryPolicy {
+            max_attempts: 10,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(1000),
+        };
+        let delays = (1..=6)
+            .map(|retry| policy.backoff(retry, 0.0).as_millis())
+            .collect::<Vec<_>>();
+        assert_eq!(delays, vec![100, 200, 400, 800, 1000, 1000]);
+        assert_eq!(policy.backoff(2, 1.0), Duration::from_millis(100));
+        assert_eq!(policy.backoff(40, 0.0), Duration::from_millis(1000));
+        assert!((0..100).map(|_| jitter()).all(|j| (0.0..1.0).contains(&j)));
+    }
+
+    #[test]
+    fn test_parse_http_date() {
+        assert_eq!(
+            parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"),
+            Some(UNIX_EPOCH + Duration::from_secs(784_111_777))
+        );
+        assert_eq!(
+            parse_http_date("Thu, 29 Feb 2024 00:00:00 GMT"),
+            Some(UNIX_EPOCH + Duration::from_secs(1_709_164_800))
+        );
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 CET"), None);
+        assert_eq!(parse_http_date("Sun, 06 Foo 1994 08:49:37 GMT"), None);
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49 GMT"), None);
+        assert_eq!(parse_http_date("120"), None);
+    }
+
+    #[test]
+    fn test_retry_after() {
+        let now = UNIX_EPOCH + Duration::from_secs(784_111_770);
+        let response = |status: u16, value: &str| {
+            Response::builder()
+                .status(status)
+                .header("retry-after", value)
+                .body(Bytes::new())
+                .unwrap()
+        };
+        assert_eq!(
+            retry_after(&response(429, "5"), now),
+            Some(Duration::from_secs(5))
+        );
+        assert_eq!(
+            retry_after(&response(503, "Sun, 06 Nov 1994 08:49:37 GMT"), now),
+            Some(Duration::from_secs(7))
+        );
+        // In the past
+        assert_eq!(
+            retry_after(&response(503, "Sun, 06 Nov 1994 08:49:00 GMT"), now),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(
+            retry_after(&response(429, "86400"), now),
+            Some(MAX_RETRY_AFTER)
+        );
+        assert_eq!(retry_after(&response(502, "5"), now), None);
+        assert_eq!(retry_after(&response(429, "soon"), now), None);
+    }
+
+    #[test]
+    fn test_retry_client() {
+        let policy = RetryPolicy {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(1),
+        };
+        let send = |statuses: Vec<u16>, policy: RetryPolicy| {
+            let statuses = Arc::new(Mutex::new(statuses));
+            let left = Arc::clone(&statuses);
+            // Answers with the statuses in turn (0 for a connection error), `429` responses
+            // ask to retry right away
+            let collector = HttpCollector::new(move |request| {
+                assert_eq!(request.body().as_ref(), b"spans");
+                match left.lock().unwrap().remove(0) {
+                    0 => Err("connection refused".into()),
+                    429 => Ok(Response::builder()
+                        .status(429)
+                        .header("retry-after", "0")
+                        .body(Bytes::new())?),
+                    status => response(status),
+                }
+            });
+            let client = RetryClient::new(collector, policy);
+            let request = Request::post("http://collector/v1/traces")
+                .body(Bytes::from_static(b"spans"))
+                .unwrap();
+            let result = crate::exporter::get_otel_runtime()
+                .block_on(client.send_bytes(request))
+                .map(|response| response.status().as_u16());
+            let left = statuses.lock().unwrap().len();
+            (result.ok(), left)
+        };
+
+        assert_eq!(send(vec![0, 503, 200, 200], policy), (Some(200), 1));
+        assert_eq!(send(vec![503, 503, 429, 200], policy), (Some(429), 1));
+        assert_eq!(send(vec![0, 0, 0, 200], policy), (None, 1));
+        // Not retryable
+        assert_eq!(send(vec![400, 200], policy), (Some(400), 1));
+
+        // Retry-After replaces the backoff
+        let policy = RetryPolicy {
+            initial_backoff: Duration::from_secs(3600),
+            ..policy
+        };
+        assert_eq!(send(vec![429, 200], policy), (Some(200), 0));
+    }
+
+    #[cfg(feature = "grpc")]
+    #[test]
+    fn test_retryable_grpc_error() {
+        let error = |code| tonic::Status::new(code, "collector restarting").to_string();
+        assert!(is_retryable_grpc_error(&error(tonic::Code::Unavailable)));
+        assert!(is_retryable_grpc_error(&error(
+            tonic::Code::ResourceExhausted
+        )));
+        assert!(!is_retryable_grpc_error(&error(
+            tonic::Code::InvalidArgument
+        )));
+        assert!(!is_retryable_grpc_error(&error(
+            tonic::Code::Unauthenticated
+        )));
+        assert!(!is_retryable_grpc_error("Mutex lock failed"));
+        let error = OTelSdkError::InternalFailure(error(tonic::Code::InvalidArgument));
+        assert!(!is_retryable_error(&error));
+    }
+
+    #[test]
+    fn test_retryable_error() {
+        let http = |status| {
+            OTelSdkError::InternalFailure(format!(
+                "OpenTelemetry trace export failed. Url: {status}, Status Code: \
+                 http://collector:4318/v1/traces, Response: b\"\""
+            ))
+        };
+        assert!(is_retryable_error(&http(503)));
+        assert!(!is_retryable_error(&http(400)));
+        assert!(!is_retryable_error(&http(413)));
+        let connect = OTelSdkError::InternalFailure("connection refused".into());
+        assert!(is_retryable_error(&connect));
+        assert!(is_retryable_error(&OTelSdkError::Timeout(
+            Duration::from_secs(10)
+        )));
+    }
+}
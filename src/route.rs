@@ -0,0 +1,98 @@
+use std::borrow::Cow;
+
+/// Replaces path segments that look like identifiers with placeholders.
+///
+/// UUIDs become `{uuid}`, long hex strings (object ids, hashes) `{hex}` and
+/// integers `{id}`, so `/users/42/orders/5f1d7c...` becomes `/users/{id}/orders/{hex}`.
+pub(crate) fn normalize_path(path: &str) -> Cow<'_, str> {
+    if !path
+        .split('/')
+        .any(|segment| placeholder(segment).is_some())
+    {
+        return Cow::Borrowed(path);
+    }
+    let mut normalized = String::with_capacity(path.len());
+    for (i, segment) in path.split('/').enumerate() {
+        if i > 0 {
+            normalized.push('/');
+        }
+        normalized.push_str(placeholder(segment).unwrap_or(segment));
+    }
+    Cow::Owned(normalized)
+}
+
+/// Splits a `pathq` value into the path and the query, reusing the allocation for the path
+pub(crate) fn split_path_query(mut uri: String) -> (String, String) {
+    match uri.find('?') {
+        Some(i) => {
+            let query = uri[i + 1..].to_string();
+            uri.truncate(i);
+            (uri, query)
+        }
+        None => (uri, String::new()),
+    }
+}
+
+fn placeholder(segment: &str) -> Option<&'static str> {
+    if segment.is_empty() {
+        None
+    } else if segment.bytes().all(|b| b.is_ascii_digit()) {
+        Some("{id}")
+    } else if is_uuid(segment) {
+        Some("{uuid}")
+    } else if segment.len() >= 16
+        && segment.bytes().all(|b| b.is_ascii_hexdigit())
+        && segment.bytes().any(|b| b.is_ascii_digit())
+    {
+        Some("{hex}")
+    } else {
+        None
+    }
+}
+
+fn is_uuid(segment: &str) -> bool {
+    let bytes = segment.as_bytes();
+    bytes.len() == 36
+        && bytes.iter().enumerate().all(|(i, b)| match i {
+            8 | 13 | 18 | 23 => *b == b'-',
+            _ => b.is_ascii_hexdigit(),
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_path_query() {
+        let split = |uri: &str| split_path_query(uri.to_string());
+        assert_eq!(
+            split("/a/b?x=1&y"),
+            ("/a/b".to_string(), "x=1&y".to_string())
+        );
+        assert_eq!(split("/a/b"), ("/a/b".to_string(), String::new()));
+        assert_eq!(split("/?"), ("/".to_string(), String::new()));
+        assert_eq!(split("/a?b?c"), ("/a".to_string(), "b?c".to_string()));
+    }
+
+    #[test]
+    fn test_normalize_path() {
+        assert_eq!(
+            normalize_path("/users/42/orders/5f1d7c3e9b1e8a0012345678"),
+            "/users/{id}/orders/{hex}"
+        );
+        assert_eq!(
+            normalize_path("/items/123e4567-E89B-12d3-a456-426614174000/"),
+            "/items/{uuid}/"
+        );
+        assert_eq!(normalize_path("/v2/api/health"), "/v2/api/health");
+    }
+
+    #[test]
+    fn test_normalize_path_keeps_words() {
+        // Hex-only words and short hex strings are kept
+        assert_eq!(normalize_path("/deadbeefcafebabe"), "/deadbeefcafebabe");
+        assert_eq!(normalize_path("/assets/a1b2c3"), "/assets/a1b2c3");
+        assert!(matches!(normalize_path("/static/app.js"), Cow::Borrowed(_)));
+    }
+}
@@ -0,0 +1,492 @@
+use opentelemetry::trace::{
+    Link, SamplingDecision, SamplingResult, SpanKind, TraceContextExt, TraceId, TraceState,
+};
+use opentelemetry::{Context, KeyValue};
+use opentelemetry_sdk::trace::{Sampler, ShouldSample};
+
+use crate::tenant::{TenantPolicies, TENANT};
+
+/// Attribute recording the request class set via `txn.otel_priority`
+pub(crate) const REQUEST_CLASS: &str = "haproxy.request.class";
+
+/// Attribute recording which rule made the sampling decision of a recorded span:
+/// `parent`, `dump`, `request_class`, `self_test`, `internal`, `pipeline`, `tenant_ratio` or
+/// `sampler`
+pub(crate) const SAMPLING_SOURCE: &str = "haproxy.sampling.source";
+
+/// Attribute marking the synthetic span emitted by the startup self-test
+pub(crate) const SELF_TEST: &str = "haproxy.self_test";
+
+/// Attribute marking the spans of requests to `internal_frontends` (stats, admin)
+pub(crate) const INTERNAL: &str = "haproxy.internal";
+
+/// Attribute marking the spans of the module's own export pipeline (`pipeline_sample_ratio`)
+pub(crate) const PIPELINE: &str = "haproxy.otel.pipeline";
+
+/// Sample ratio of `internal_frontends` requests unless `internal_sample_ratio` is set
+const DEFAULT_INTERNAL_SAMPLE_RATIO: f64 = 0.001;
+
+/// Request header carrying the sampling rate applied to the trace (`propagate_sampling_rate`)
+pub(crate) const SAMPLING_RATE_HEADER: &str = "x-otel-sampling-rate";
+
+/// Randomness values of the OpenTelemetry tracestate `th` threshold, 56 bits
+const THRESHOLD_RANGE: f64 = (1u64 << 56) as f64;
+
+/// Sampler wrapper applying the HAProxy specific sampling rules:
+///
+/// - child spans of local spans (client spans) follow the decision of their parent,
+/// - all spans are sampled while an incident dump is running,
+/// - spans of the configured request classes and the self-test span are always sampled,
+/// - spans of `internal_frontends` requests are sampled at `internal_sample_ratio`,
+/// - spans of the export pipeline are sampled at `pipeline_sample_ratio`,
+/// - spans of tenants with a `sample_ratio` are sampled at that ratio.
+///
+/// Every other decision is delegated to the configured sampler. With `propagate_sampling_rate`
+/// the ratio of the decisions made here is recorded as the `ot=th:` tracestate value, which
+/// child spans inherit.
+#[derive(Debug, Clone)]
+pub(crate) struct HaproxySampler {
+    inner: Sampler,
+    always_sample_classes: Vec<String>,
+    tenants: TenantPolicies,
+    internal_sample_ratio: f64,
+    pipeline_sample_ratio: f64,
+    propagate_rate: bool,
+}
+
+impl HaproxySampler {
+    pub(crate) fn new(
+        inner: Sampler,
+        always_sample_classes: Vec<String>,
+        tenants: TenantPolicies,
+    ) -> Self {
+        Self {
+            inner,
+            always_sample_classes,
+            tenants,
+            internal_sample_ratio: DEFAULT_INTERNAL_SAMPLE_RATIO,
+            pipeline_sample_ratio: 0.0,
+            propagate_rate: false,
+        }
+    }
+
+    pub(crate) fn with_internal_sample_ratio(mut self, ratio: Option<f64>) -> Self {
+        self.internal_sample_ratio = ratio.unwrap_or(DEFAULT_INTERNAL_SAMPLE_RATIO);
+        self
+    }
+
+    pub(crate) fn with_pipeline_sample_ratio(mut self, ratio: Option<f64>) -> Self {
+        self.pipeline_sample_ratio = ratio.unwrap_or_default();
+        self
+    }
+
+    pub(crate) fn with_rate_propagation(mut self, propagate_rate: bool) -> Self {
+        self.propagate_rate = propagate_rate;
+        self
+    }
+
+    fn is_always_sampled(&self, attributes: &[KeyValue]) -> bool {
+        if self.always_sample_classes.is_empty() {
+            return false;
+        }
+        attributes
+            .iter()
+            .find(|kv| kv.key.as_str() == REQUEST_CLASS)
+            .is_some_and(|kv| {
+                let class = kv.value.as_str();
+                self.always_sample_classes.iter().any(|c| *c == class)
+            })
+    }
+
+    /// Ratio applied when no rule decided, and the source recorded for it
+    fn sample_ratio(&self, attributes: &[KeyValue]) -> Option<(f64, &'static str)> {
+        if attributes.iter().any(|kv| kv.key.as_str() == INTERNAL) {
+            return Some((self.internal_sample_ratio, "internal"));
+        }
+        if attributes.iter().any(|kv| kv.key.as_str() == PIPELINE) {
+            return Some((self.pipeline_sample_ratio, "pipeline"));
+        }
+        let ratio = self.tenant_sample_ratio(attributes)?;
+        Some((ratio, "tenant_ratio"))
+    }
+
+    fn tenant_sample_ratio(&self, attributes: &[KeyValue]) -> Option<f64> {
+        if self.tenants.is_empty() {
+            return None;
+        }
+        let tenant = attributes.iter().find(|kv| kv.key.as_str() == TENANT)?;
+        self.tenants
+            .get(tenant.value.as_str().as_ref())?
+            .sample_ratio
+    }
+}
+
+impl ShouldSample for HaproxySampler {
+    fn should_sample(
+        &self,
+        parent_context: Option<&Context>,
+        trace_id: TraceId,
+        name: &str,
+        span_kind: &SpanKind,
+        attributes: &[KeyValue],
+        links: &[Link],
+    ) -> SamplingResult {
+        let parent_span_context = parent_context
+            .filter(|cx| cx.has_active_span())
+            .map(|cx| cx.span().span_context().clone());
+        let trace_state = parent_span_context
+            .as_ref()
+            .map(|sc| sc.trace_state().clone())
+            .unwrap_or_default();
+
+        let (decision, source) = match parent_span_context {
+            Some(ref sc) if sc.is_valid() && !sc.is_remote() => (Some(sc.is_sampled()), "parent"),
+            _ if crate::dump::is_active() => (Some(true), "dump"),
+            _ if self.is_always_sampled(attributes) => (Some(true), "request_class"),
+            _ if attributes.iter().any(|kv| kv.key.as_str() == SELF_TEST) => {
+                (Some(true), "self_test")
+            }
+            _ => (None, ""),
+        };
+        let sample_ratio = self.sample_ratio(attributes);
+        let (mut result, source) = match (decision, sample_ratio) {
+            (Some(sampled), _) => {
+                let result = SamplingResult {
+                    decision: match sampled {
+                        true => SamplingDecision::RecordAndSample,
+                        false => SamplingDecision::Drop,
+                    },
+                    attributes: Vec::new(),
+                    trace_state,
+                };
+                (result, source)
+            }
+            (None, Some((ratio, source))) => {
+                let result = Sampler::TraceIdRatioBased(ratio).should_sample(
+                    parent_context,
+                    trace_id,
+                    name,
+                    span_kind,
+                    attributes,
+                    links,
+                );
+                (result, source)
+            }
+            (None, None) => {
+                let result = self.inner.should_sample(
+                    parent_context,
+                    trace_id,
+                    name,
+                    span_kind,
+                    attributes,
+                    links,
+                );
+                let source = match (&self.inner, parent_span_context) {
+                    (Sampler::ParentBased(_), Some(sc)) if sc.is_valid() => "parent",
+                    _ => "sampler",
+                };
+                (result, source)
+            }
+        };
+        if result.decision != SamplingDecision::Drop {
+            result
+                .attributes
+                .push(KeyValue::new(SAMPLING_SOURCE, source));
+        }
+        // Decisions of the parent keep the parent's rate, if it sent one
+        if self.propagate_rate && result.decision == SamplingDecision::RecordAndSample {
+            let ratio = match (source, sample_ratio) {
+                ("parent", _) => None,
+                ("internal" | "pipeline" | "tenant_ratio", Some((ratio, _))) => Some(ratio),
+                _ => Some(1.0),
+            };
+            if let Some(ratio) = ratio {
+                result.trace_state = with_threshold(&result.trace_state, ratio);
+            }
+        }
+        result
+    }
+}
+
+/// Rejection threshold of a sampling ratio, in the 14 hex digits of the OpenTelemetry
+/// tracestate `th` value without the trailing zeros
+fn threshold(ratio: f64) -> String {
+    let threshold = ((1.0 - ratio) * THRESHOLD_RANGE).round() as u64;
+    let hex = format!("{:014x}", threshold.min((1 << 56) - 1));
+    match hex.trim_end_matches('0') {
+        "" => "0".to_string(),
+        hex => hex.to_string(),
+    }
+}
+
+/// Trace state with the `th` value of the ratio in its `ot` entry, keeping the other values
+fn with_threshold(trace_state: &TraceState, ratio: f64) -> TraceState {
+    let th = format!("th:{}", threshold(ratio));
+    let ot = trace_state.get("ot").unwrap_or_default();
+    let others = ot
+        .split(';')
+        .filter(|v| !v.is_empty() && !v.starts_with("th:"));
+    let ot = std::iter::once(th.as_str()).chain(others);
+    trace_state
+        .insert("ot", ot.collect::<Vec<_>>().join(";"))
+        .unwrap_or_else(|_| trace_state.clone())
+}
+
+/// Sampling rate of the `ot=th:` tracestate value, formatted for `SAMPLING_RATE_HEADER`
+pub(crate) fn sampling_rate(trace_state: &TraceState) -> Option<String> {
+    let ot = trace_state.get("ot")?;
+    let th = ot.split(';').find_map(|v| v.strip_prefix("th:"))?;
+    if th.is_empty() || th.len() > 14 {
+        return None;
+    }
+    let threshold = u64::from_str_radix(&format!("{th:0<14}"), 16).ok()?;
+    let rate = 1.0 - threshold as f64 / THRESHOLD_RANGE;
+    Some(((rate * 1e6).round() / 1e6).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    use opentelemetry::trace::{SpanContext, SpanId, TraceFlags, TraceState};
+
+    use super::*;
+    use crate::tenant::TenantPolicy;
+
+    fn decision(sampler: &HaproxySampler, attributes: &[KeyValue]) -> SamplingDecision {
+        sampler
+            .should_sample(
+                None,
+                TraceId::from(1u128),
+                "GET example.com",
+                &SpanKind::Server,
+                attributes,
+                &[],
+            )
+            .decision
+    }
+
+    #[test]
+    fn test_always_sample_classes() {
+        let sampler = HaproxySampler::new(
+            Sampler::AlwaysOff,
+            vec!["critical".to_string()],
+            TenantPolicies::default(),
+        );
+        assert_eq!(
+            decision(&sampler, &[KeyValue::new(REQUEST_CLASS, "critical")]),
+            SamplingDecision::RecordAndSample
+        );
+        assert_eq!(
+            decision(&sampler, &[KeyValue::new(REQUEST_CLASS, "bulk")]),
+            SamplingDecision::Drop
+        );
+        assert_eq!(decision(&sampler, &[]), SamplingDecision::Drop);
+        assert_eq!(
+            decision(&sampler, &[KeyValue::new(SELF_TEST, true)]),
+            SamplingDecision::RecordAndSample
+        );
+    }
+
+    #[test]
+    fn test_delegates_without_classes() {
+        let sampler = HaproxySampler::new(Sampler::AlwaysOn, Vec::new(), TenantPolicies::default());
+        assert_eq!(
+            decision(&sampler, &[KeyValue::new(REQUEST_CLASS, "critical")]),
+            SamplingDecision::RecordAndSample
+        );
+    }
+
+    #[test]
+    fn test_tenant_sample_ratio() {
+        let tenants = [("none", 0.0), ("all", 1.0)]
+            .into_iter()
+            .map(|(tenant, ratio)| {
+                let policy = TenantPolicy {
+                    sample_ratio: Some(ratio),
+                    ..Default::default()
+                };
+                (tenant.to_string(), policy)
+            })
+            .collect::<HashMap<_, _>>();
+        let sampler = HaproxySampler::new(Sampler::AlwaysOn, Vec::new(), Arc::new(tenants));
+        assert_eq!(
+            decision(&sampler, &[KeyValue::new(TENANT, "none")]),
+            SamplingDecision::Drop
+        );
+        assert_eq!(
+            decision(&sampler, &[KeyValue::new(TENANT, "all")]),
+            SamplingDecision::RecordAndSample
+        );
+        assert_eq!(
+            decision(&sampler, &[KeyValue::new(TENANT, "other")]),
+            SamplingDecision::RecordAndSample
+        );
+    }
+
+    #[test]
+    fn test_internal_sample_ratio() {
+        let internal = [KeyValue::new(INTERNAL, true)];
+        let sampler = HaproxySampler::new(Sampler::AlwaysOn, Vec::new(), TenantPolicies::default());
+        let result = sampler.should_sample(
+            None,
+            TraceId::from(u128::MAX),
+            "GET /stats",
+            &SpanKind::Internal,
+            &internal,
+            &[],
+        );
+        assert_eq!(result.decision, SamplingDecision::Drop);
+        assert_eq!(decision(&sampler, &[]), SamplingDecision::RecordAndSample);
+
+        // Takes precedence over the tenant of the request
+        let policy = TenantPolicy {
+            sample_ratio: Some(1.0),
+            ..Default::default()
+        };
+        let tenants = HashMap::from([("stats".to_string(), policy)]);
+        let sampler = HaproxySampler::new(Sampler::AlwaysOn, Vec::new(), Arc::new(tenants))
+            .with_internal_sample_ratio(Some(0.0));
+        let attributes = [
+            KeyValue::new(INTERNAL, true),
+            KeyValue::new(TENANT, "stats"),
+        ];
+        assert_eq!(decision(&sampler, &attributes), SamplingDecision::Drop);
+        let sampler = sampler.with_internal_sample_ratio(Some(1.0));
+        assert_eq!(
+            decision(&sampler, &internal),
+            SamplingDecision::RecordAndSample
+        );
+    }
+
+    #[test]
+    fn test_sampling_source() {
+        let source = |sampler: &HaproxySampler, parent: Option<&Context>, attributes| {
+            let result = sampler.should_sample(
+                parent,
+                TraceId::from(1u128),
+                "GET example.com",
+                &SpanKind::Server,
+                attributes,
+                &[],
+            );
+            result
+                .attributes
+                .iter()
+                .find(|kv| kv.key.as_str() == SAMPLING_SOURCE)
+                .map(|kv| kv.value.to_string())
+        };
+        let sampler = HaproxySampler::new(
+            Sampler::ParentBased(Box::new(Sampler::AlwaysOn)),
+            vec!["critical".to_string()],
+            TenantPolicies::default(),
+        );
+        let critical = [KeyValue::new(REQUEST_CLASS, "critical")];
+        assert_eq!(
+            source(&sampler, None, &critical),
+            Some("request_class".to_string())
+        );
+        assert_eq!(source(&sampler, None, &[]), Some("sampler".to_string()));
+        let parent = Context::new().with_remote_span_context(SpanContext::new(
+            TraceId::from(1u128),
+            SpanId::from(1u64),
+            TraceFlags::SAMPLED,
+            true,
+            TraceState::default(),
+        ));
+        assert_eq!(
+            source(&sampler, Some(&parent), &[]),
+            Some("parent".to_string())
+        );
+        // Dropped spans are not recorded, no attribute
+        let sampler =
+            HaproxySampler::new(Sampler::AlwaysOff, Vec::new(), TenantPolicies::default());
+        assert_eq!(source(&sampler, None, &[]), None);
+        // Export pipeline spans follow their own ratio, whatever the sampler
+        let pipeline = [KeyValue::new(PIPELINE, true)];
+        assert_eq!(source(&sampler, None, &pipeline), None);
+        let sampler = sampler.with_pipeline_sample_ratio(Some(1.0));
+        assert_eq!(
+            source(&sampler, None, &pipeline),
+            Some("pipeline".to_string())
+        );
+    }
+
+    #[test]
+    fn test_local_parent_decision_is_followed() {
+        let sampler = HaproxySampler::new(Sampler::AlwaysOn, Vec::new(), TenantPolicies::default());
+        let parent = Context::new().with_remote_span_context(SpanContext::new(
+            TraceId::from(1u128),
+            SpanId::from(1u64),
+            TraceFlags::default(),
+            false,
+            TraceState::default(),
+        ));
+        let result = sampler.should_sample(
+            Some(&parent),
+            TraceId::from(1u128),
+            "upstream",
+            &SpanKind::Client,
+            &[],
+            &[],
+        );
+        assert_eq!(result.decision, SamplingDecision::Drop);
+    }
+
+    #[test]
+    fn test_sampling_rate_propagation() {
+        let policy = TenantPolicy {
+            sample_ratio: Some(0.05),
+            ..Default::default()
+        };
+        let tenants = Arc::new(HashMap::from([("bulk".to_string(), policy)]));
+        let sampler = HaproxySampler::new(
+            Sampler::ParentBased(Box::new(Sampler::AlwaysOn)),
+            Vec::new(),
+            tenants,
+        );
+        let trace_state = |sampler: &HaproxySampler, parent, attributes: &[KeyValue]| {
+            sampler
+                .should_sample(
+                    parent,
+                    TraceId::from(1u128),
+                    "GET example.com",
+                    &SpanKind::Server,
+                    attributes,
+                    &[],
+                )
+                .trace_state
+        };
+        let bulk = [KeyValue::new(TENANT, "bulk")];
+        assert_eq!(trace_state(&sampler, None, &bulk), TraceState::default());
+
+        let sampler = sampler.with_rate_propagation(true);
+        let state = trace_state(&sampler, None, &bulk);
+        assert_eq!(state.get("ot"), Some("th:f333333333333"));
+        assert_eq!(sampling_rate(&state).as_deref(), Some("0.05"));
+        let state = trace_state(&sampler, None, &[]);
+        assert_eq!(state.get("ot"), Some("th:0"));
+        assert_eq!(sampling_rate(&state).as_deref(), Some("1"));
+
+        // The rate of a sampled parent is kept, its other values are kept by a new rate
+        let parent = |ot: &str| {
+            let trace_state = TraceState::from_key_value([("ot", ot)]).unwrap();
+            Context::new().with_remote_span_context(SpanContext::new(
+                TraceId::from(1u128),
+                SpanId::from(1u64),
+                TraceFlags::SAMPLED,
+                true,
+                trace_state,
+            ))
+        };
+        let parent = parent("th:8;rv:0123456789abcd");
+        let state = trace_state(&sampler, Some(&parent), &[]);
+        assert_eq!(state.get("ot"), Some("th:8;rv:0123456789abcd"));
+        assert_eq!(sampling_rate(&state).as_deref(), Some("0.5"));
+        let state = trace_state(&sampler, Some(&parent), &bulk);
+        assert_eq!(state.get("ot"), Some("th:f333333333333;rv:0123456789abcd"));
+        let state = TraceState::from_key_value([("ot", "rv:0123456789abcd")]).unwrap();
+        assert_eq!(sampling_rate(&state), None);
+    }
+}
@@ -0,0 +1,300 @@
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+use opentelemetry::context::FutureExt as _;
+use opentelemetry::trace::{Span as _, SpanKind, Status, TraceContextExt as _, Tracer as _};
+use opentelemetry::{Context, KeyValue};
+use opentelemetry_http::{Bytes, HttpClient, Request};
+use opentelemetry_sdk::error::OTelSdkResult;
+use opentelemetry_sdk::trace::{SdkTracer, SpanData, SpanExporter};
+use opentelemetry_sdk::Resource;
+use opentelemetry_semantic_conventions::trace::{
+    HTTP_REQUEST_METHOD, HTTP_RESPONSE_STATUS_CODE, SERVER_ADDRESS, URL_PATH,
+};
+
+use crate::exporter::SendFuture;
+use crate::sampler::PIPELINE;
+
+/// Instrumentation scope of the spans of the module's own export pipeline
+pub(crate) const EXPORT_SCOPE: &str = "haproxy-otel.export";
+
+/// Attributes of the export spans
+const BATCH_SIZE: &str = "haproxy.otel.export.batch_size";
+const ENCODE_MS: &str = "haproxy.otel.export.encode_ms";
+const REQUEST_BODY_SIZE: &str = "http.request.body.size";
+
+/// Export span of the batch being exported, found by the HTTP client in the current context
+#[derive(Debug)]
+struct ExportTiming {
+    tracer: SdkTracer,
+    start: Instant,
+    // Time until the first request was sent: encoding, compression and authentication
+    encoded: OnceLock<Duration>,
+}
+
+/// Span exporter tracing its own exports with `pipeline_sample_ratio`: a
+/// `haproxy-otel export` span per sampled batch with the number of spans and the time spent
+/// encoding the batch, and a child span per HTTP request of the OTLP HTTP exporters
+/// (`TracedClient`).
+///
+/// The spans go through the registration's own pipeline under the `haproxy-otel.export`
+/// scope, the tracer is looked up at every export rather than held, which would keep the
+/// tracer provider alive from its own exporter.
+#[derive(Debug)]
+pub(crate) struct TracedExporter<E> {
+    inner: E,
+    // Registration the spans are traced with, `None` when disabled
+    registration: Option<String>,
+}
+
+impl<E: SpanExporter> TracedExporter<E> {
+    pub(crate) fn new(inner: E, registration: Option<String>) -> Self {
+        Self {
+            inner,
+            registration,
+        }
+    }
+}
+
+/// Whether the span is one of the export spans
+fn is_pipeline_span(span: &SpanData) -> bool {
+    (span.attributes.iter()).any(|kv| kv.key.as_str() == PIPELINE)
+}
+
+/// Exports the batch within a `haproxy-otel export` span of `tracer`. A batch of export spans
+/// only is exported untraced, its span would be exported in another such batch, and so on.
+async fn traced_export<E: SpanExporter>(
+    tracer: &SdkTracer,
+    inner: &E,
+    batch: Vec<SpanData>,
+) -> OTelSdkResult {
+    if batch.iter().all(is_pipeline_span) {
+        return inner.export(batch).await;
+    }
+    let span = tracer
+        .span_builder("haproxy-otel export")
+        .with_kind(SpanKind::Internal)
+        .with_attributes([
+            KeyValue::new(PIPELINE, true),
+            KeyValue::new(BATCH_SIZE, batch.len() as i64),
+        ])
+        .start(tracer);
+    if !span.is_recording() {
+        return inner.export(batch).await;
+    }
+    let timing = ExportTiming {
+        tracer: tracer.clone(),
+        start: Instant::now(),
+        encoded: OnceLock::new(),
+    };
+    let cx = Context::current_with_span(span).with_value(timing);
+    let result = inner.export(batch).with_context(cx.clone()).await;
+
+    let span = cx.span();
+    if let Some(encoded) = cx.get::<ExportTiming>().and_then(|t| t.encoded.get()) {
+        span.set_attribute(KeyValue::new(ENCODE_MS, encoded.as_secs_f64() * 1000.0));
+    }
+    if let Err(e) = &result {
+        span.set_status(Status::error(e.to_string()));
+    }
+    span.end();
+    result
+}
+
+impl<E: SpanExporter> SpanExporter for TracedExporter<E> {
+    async fn export(&self, batch: Vec<SpanData>) -> OTelSdkResult {
+        let tracer = (self.registration.as_deref()).and_then(crate::exporter::export_tracer);
+        match tracer {
+            Some(tracer) => traced_export(&tracer, &self.inner, batch).await,
+            None => self.inner.export(batch).await,
+        }
+    }
+
+    fn shutdown_with_timeout(&mut self, timeout: Duration) -> OTelSdkResult {
+        self.inner.shutdown_with_timeout(timeout)
+    }
+
+    fn force_flush(&mut self) -> OTelSdkResult {
+        self.inner.force_flush()
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        self.inner.set_resource(resource);
+    }
+}
+
+/// HTTP client of the OTLP HTTP exporters adding a `haproxy-otel export request` span per
+/// request (retries included) to the export span of `TracedExporter`, if the batch is traced
+#[derive(Debug)]
+pub(crate) struct TracedClient<C> {
+    inner: C,
+}
+
+impl<C: HttpClient> TracedClient<C> {
+    pub(crate) fn new(inner: C) -> Self {
+        Self { inner }
+    }
+}
+
+impl<C: HttpClient> HttpClient for TracedClient<C> {
+    fn send_bytes<'a, 'b>(&'a self, request: Request<Bytes>) -> SendFuture<'b>
+    where
+        'a: 'b,
+        Self: 'b,
+    {
+        Box::pin(async move {
+            let cx = Context::current();
+            let Some(timing) = cx.get::<ExportTiming>() else {
+                return self.inner.send_bytes(request).await;
+            };
+            let _ = timing.encoded.set(timing.start.elapsed());
+            let mut attributes = vec![
+                KeyValue::new(PIPELINE, true),
+                KeyValue::new(HTTP_REQUEST_METHOD, request.method().to_string()),
+                KeyValue::new(URL_PATH, request.uri().path().to_string()),
+                KeyValue::new(REQUEST_BODY_SIZE, request.body().len() as i64),
+            ];
+            if let Some(host) = request.uri().host() {
+                attributes.push(KeyValue::new(SERVER_ADDRESS, host.to_string()));
+            }
+            let mut span = timing
+                .tracer
+                .span_builder("haproxy-otel export request")
+                .with_kind(SpanKind::Client)
+                .with_attributes(attributes)
+                .start_with_context(&timing.tracer, &cx);
+            let result = self.inner.send_bytes(request).await;
+            match &result {
+                Ok(response) => {
+                    let status = response.status();
+                    span.set_attribute(KeyValue::new(
+                        HTTP_RESPONSE_STATUS_CODE,
+                        status.as_u16() as i64,
+                    ));
+                    if !status.is_success() {
+                        span.set_status(Status::error(status.to_string()));
+                    }
+                }
+                Err(e) => span.set_status(Status::error(e.to_string())),
+            }
+            span.end();
+            result
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use opentelemetry::trace::TracerProvider as _;
+    use opentelemetry_sdk::trace::{SdkTracerProvider, SimpleSpanProcessor};
+
+    use super::*;
+    use crate::fixtures::{span, Collector, HttpCollector};
+
+    /// OTLP HTTP exporter sending every batch with a request of its length
+    #[derive(Debug)]
+    struct HttpExporter(TracedClient<HttpCollector>);
+
+    impl SpanExporter for HttpExporter {
+        async fn export(&self, batch: Vec<SpanData>) -> OTelSdkResult {
+            let request = Request::post("http://collector:4318/v1/traces")
+                .body(Bytes::from(vec![0u8; batch.len()]))
+                .unwrap();
+            let response = self.0.send_bytes(request).await;
+            match response.map(|r| r.status().is_success()) {
+                Ok(true) => Ok(()),
+                _ => Err(opentelemetry_sdk::error::OTelSdkError::InternalFailure(
+                    "export failed".into(),
+                )),
+            }
+        }
+    }
+
+    fn attribute(span: &SpanData, key: &str) -> Option<opentelemetry::Value> {
+        (span.attributes.iter())
+            .find(|kv| kv.key.as_str() == key)
+            .map(|kv| kv.value.clone())
+    }
+
+    #[test]
+    fn test_traced_export() {
+        let recorder = Collector::default();
+        let provider = SdkTracerProvider::builder()
+            .with_span_processor(SimpleSpanProcessor::new(recorder.clone()))
+            .build();
+        let tracer = provider.tracer(EXPORT_SCOPE);
+        let exporter = HttpExporter(TracedClient::new(HttpCollector::status(200)));
+        let runtime = crate::exporter::get_otel_runtime();
+        runtime
+            .block_on(traced_export(&tracer, &exporter, vec![span(), span()]))
+            .unwrap();
+
+        let spans = recorder.spans.lock().unwrap();
+        let (request, export) = (&spans[0], &spans[1]);
+        assert_eq!(export.name, "haproxy-otel export");
+        assert_eq!(export.instrumentation_scope.name(), EXPORT_SCOPE);
+        assert_eq!(attribute(export, BATCH_SIZE), Some(2i64.into()));
+        assert_eq!(attribute(export, PIPELINE), Some(true.into()));
+        assert!(attribute(export, ENCODE_MS).is_some());
+        assert_eq!(request.name, "haproxy-otel export request");
+        assert_eq!(request.span_kind, SpanKind::Client);
+        assert_eq!(request.parent_span_id, export.span_context.span_id());
+        assert_eq!(attribute(request, REQUEST_BODY_SIZE), Some(2i64.into()));
+        assert_eq!(
+            attribute(request, HTTP_RESPONSE_STATUS_CODE),
+            Some(200i64.into())
+        );
+        assert_eq!(attribute(request, SERVER_ADDRESS), Some("collector".into()));
+        drop(spans);
+
+        // Failed exports are recorded as errors
+        let exporter = HttpExporter(TracedClient::new(HttpCollector::status(503)));
+        let result = runtime.block_on(traced_export(&tracer, &exporter, vec![span()]));
+        assert!(result.is_err());
+        let spans = recorder.spans.lock().unwrap();
+        assert!(matches!(spans[2].status, Status::Error { .. }));
+        assert!(matches!(spans[3].status, Status::Error { .. }));
+    }
+
+    #[test]
+    fn test_pipeline_batch() {
+        let recorder = Collector::default();
+        let provider = SdkTracerProvider::builder()
+            .with_span_processor(SimpleSpanProcessor::new(recorder.clone()))
+            .build();
+        let tracer = provider.tracer(EXPORT_SCOPE);
+        let exporter = HttpExporter(TracedClient::new(HttpCollector::status(200)));
+        let runtime = crate::exporter::get_otel_runtime();
+        let pipeline_span = SpanData {
+            attributes: vec![KeyValue::new(PIPELINE, true)],
+            ..span()
+        };
+
+        // The export spans of a previous batch are exported without a span of their own
+        let batch = vec![pipeline_span.clone(), pipeline_span.clone()];
+        runtime
+            .block_on(traced_export(&tracer, &exporter, batch))
+            .unwrap();
+        assert!(recorder.spans.lock().unwrap().is_empty());
+
+        // Unless they are exported with other spans
+        let batch = vec![pipeline_span, span()];
+        runtime
+            .block_on(traced_export(&tracer, &exporter, batch))
+            .unwrap();
+        assert_eq!(recorder.spans.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_untraced_request() {
+        // Outside of a traced export the client only forwards the request
+        let client = TracedClient::new(HttpCollector::status(200));
+        let request = Request::post("http://collector:4318/v1/traces")
+            .body(Bytes::new())
+            .unwrap();
+        let response = crate::exporter::get_otel_runtime()
+            .block_on(client.send_bytes(request))
+            .unwrap();
+        assert_eq!(response.status(), 200);
+    }
+}
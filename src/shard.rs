@@ -0,0 +1,186 @@
+use std::future::{poll_fn, Future};
+use std::task::Poll;
+use std::time::Duration;
+
+use opentelemetry::trace::TraceId;
+use opentelemetry_sdk::error::OTelSdkResult;
+use opentelemetry_sdk::trace::{SpanData, SpanExporter};
+use opentelemetry_sdk::Resource;
+
+/// Collector of a trace among `shards` collectors (jump consistent hash).
+///
+/// Appending a collector to `otlp.endpoints` only moves 1/n of the traces to it, the
+/// others keep their collector.
+pub(crate) fn shard(trace_id: TraceId, shards: usize) -> usize {
+    let id = u128::from_be_bytes(trace_id.to_bytes());
+    let mut key = (id >> 64) as u64 ^ id as u64;
+    let (mut bucket, mut next) = (0u64, 0u64);
+    while next < shards as u64 {
+        bucket = next;
+        key = key.wrapping_mul(2_862_933_555_777_941_757).wrapping_add(1);
+        next = ((bucket + 1) as f64 * ((1u64 << 31) as f64 / ((key >> 33) + 1) as f64)) as u64;
+    }
+    bucket as usize
+}
+
+/// Span exporter spreading the spans over a pool of collectors (`otlp.endpoints`), all the
+/// spans of a trace are sent to the same collector (e.g. for tail sampling).
+///
+/// The shards of a batch are exported concurrently, so a slow collector doesn't delay the
+/// others. A failed shard doesn't prevent the export to the others and its error is returned.
+#[derive(Debug)]
+pub(crate) struct ShardedExporter<E> {
+    shards: Vec<E>,
+}
+
+impl<E: SpanExporter> ShardedExporter<E> {
+    pub(crate) fn new(shards: Vec<E>) -> Self {
+        Self { shards }
+    }
+}
+
+impl<E: SpanExporter> SpanExporter for ShardedExporter<E> {
+    async fn export(&self, batch: Vec<SpanData>) -> OTelSdkResult {
+        let mut batches = self.shards.iter().map(|_| Vec::new()).collect::<Vec<_>>();
+        for span in batch {
+            let shard = shard(span.span_context.trace_id(), self.shards.len());
+            batches[shard].push(span);
+        }
+        let mut exports = self
+            .shards
+            .iter()
+            .zip(batches)
+            .filter(|(_, batch)| !batch.is_empty())
+            .map(|(exporter, batch)| Some(Box::pin(exporter.export(batch))))
+            .collect::<Vec<_>>();
+        let mut result = Ok(());
+        poll_fn(|cx| {
+            let mut pending = false;
+            for export in &mut exports {
+                let Some(future) = export else { continue };
+                match future.as_mut().poll(cx) {
+                    Poll::Ready(shard_result) => {
+                        result = std::mem::replace(&mut result, Ok(())).and(shard_result);
+                        *export = None;
+                    }
+                    Poll::Pending => pending = true,
+                }
+            }
+            match pending {
+                true => Poll::Pending,
+                false => Poll::Ready(()),
+            }
+        })
+        .await;
+        result
+    }
+
+    fn shutdown_with_timeout(&mut self, timeout: Duration) -> OTelSdkResult {
+        let mut result = Ok(());
+        for exporter in &mut self.shards {
+            result = result.and(exporter.shutdown_with_timeout(timeout));
+        }
+        result
+    }
+
+    fn force_flush(&mut self) -> OTelSdkResult {
+        let mut result = Ok(());
+        for exporter in &mut self.shards {
+            result = result.and(exporter.force_flush());
+        }
+        result
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        for exporter in &mut self.shards {
+            exporter.set_resource(resource);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{span_context, Collector};
+
+    fn trace_ids() -> impl Iterator<Item = TraceId> {
+        // Spread like random ids, as the low bits of sequential ids are correlated
+        (1..=10_000u128)
+            .map(|i| TraceId::from(i.wrapping_mul(0x9e37_79b9_7f4a_7c15_f39c_c060_5ced_c835)))
+    }
+
+    #[test]
+    fn test_shard_distribution() {
+        let mut counts = [0usize; 4];
+        for trace_id in trace_ids() {
+            counts[shard(trace_id, 4)] += 1;
+        }
+        assert!(
+            counts.iter().all(|count| (2000..3000).contains(count)),
+            "{counts:?}"
+        );
+        assert!(trace_ids().all(|trace_id| shard(trace_id, 1) == 0));
+    }
+
+    #[test]
+    fn test_shard_consistency() {
+        // A fifth collector takes about a fifth of the traces, from every collector
+        let moved = trace_ids()
+            .filter(|trace_id| {
+                let (before, after) = (shard(*trace_id, 4), shard(*trace_id, 5));
+                assert!(after == before || after == 4);
+                after != before
+            })
+            .count();
+        assert!((1500..2500).contains(&moved), "{moved}");
+    }
+
+    fn span(trace_id: TraceId) -> SpanData {
+        SpanData {
+            span_context: span_context(trace_id, 1u64),
+            ..crate::fixtures::span()
+        }
+    }
+
+    #[test]
+    fn test_sharded_exporter() {
+        let collectors = (0..3).map(|_| Collector::default()).collect::<Vec<_>>();
+        let exporter = ShardedExporter::new(collectors.clone());
+        let batch = trace_ids().take(30).flat_map(|id| [span(id), span(id)]);
+        crate::exporter::get_otel_runtime()
+            .block_on(exporter.export(batch.collect()))
+            .unwrap();
+
+        let mut total = 0;
+        for (index, collector) in collectors.iter().enumerate() {
+            let spans = collector.spans.lock().unwrap();
+            assert!(spans
+                .iter()
+                .all(|span| shard(span.span_context.trace_id(), 3) == index));
+            total += spans.len();
+        }
+        assert_eq!(total, 60);
+    }
+
+    /// Answers after a delay, like a collector under load
+    #[derive(Debug)]
+    struct Slow;
+
+    impl SpanExporter for Slow {
+        async fn export(&self, _batch: Vec<SpanData>) -> OTelSdkResult {
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_concurrent_shards() {
+        let exporter = ShardedExporter::new(vec![Slow, Slow, Slow]);
+        let batch = trace_ids().take(30).map(span).collect();
+        let start = std::time::Instant::now();
+        crate::exporter::get_otel_runtime()
+            .block_on(exporter.export(batch))
+            .unwrap();
+        assert!(start.elapsed() < Duration::from_millis(600));
+    }
+}
@@ -0,0 +1,115 @@
+use std::collections::HashMap;
+
+use opentelemetry::trace::SpanRef;
+use opentelemetry::KeyValue;
+
+use crate::span::BestEffort;
+
+/// Backend key of the thresholds applying to backends without their own
+pub(crate) const DEFAULT_BACKEND: &str = "*";
+
+/// Latency thresholds of a backend, in milliseconds
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct SlaThresholds {
+    // Time spent in queues (`req.timer.queue`, %Tw)
+    pub(crate) queue_ms: Option<u64>,
+    // Time to connect to the server (`bc.timer.connect`, %Tc)
+    pub(crate) connect_ms: Option<u64>,
+    // Active time of the request (`txn.timer.total`, %Ta)
+    pub(crate) total_ms: Option<u64>,
+}
+
+pub(crate) type SlaPolicies = HashMap<String, SlaThresholds>;
+
+/// A breached threshold: name, threshold and measured value in milliseconds
+#[derive(Debug, PartialEq)]
+pub(crate) struct Violation {
+    pub(crate) threshold: &'static str,
+    pub(crate) limit_ms: u64,
+    pub(crate) value_ms: u64,
+}
+
+impl SlaThresholds {
+    /// Compares measured timers (negative when not applicable) with the thresholds
+    pub(crate) fn violations(
+        &self,
+        queue: Option<i64>,
+        connect: Option<i64>,
+        total: Option<i64>,
+    ) -> Vec<Violation> {
+        [
+            ("queue", self.queue_ms, queue),
+            ("connect", self.connect_ms, connect),
+            ("total", self.total_ms, total),
+        ]
+        .into_iter()
+        .filter_map(|(threshold, limit_ms, value)| {
+            let limit_ms = limit_ms?;
+            let value_ms = u64::try_from(value?).ok()?;
+            (value_ms > limit_ms).then_some(Violation {
+                threshold,
+                limit_ms,
+                value_ms,
+            })
+        })
+        .collect()
+    }
+}
+
+/// Adds a `sla.violated` event per threshold of the backend breached by the transaction
+pub(crate) fn record_violations(
+    fetches: &mut BestEffort,
+    span: &SpanRef,
+    policies: &SlaPolicies,
+    backend: Option<&str>,
+) {
+    let thresholds = backend
+        .and_then(|backend| policies.get(backend))
+        .or_else(|| policies.get(DEFAULT_BACKEND));
+    let thresholds = match thresholds {
+        Some(thresholds) => thresholds,
+        None => return,
+    };
+    let mut timer = |enabled: Option<u64>, fetch: &str| match enabled {
+        Some(_) => fetches.get::<i64>(fetch),
+        None => None,
+    };
+    let queue = timer(thresholds.queue_ms, "req_timer_queue");
+    let connect = timer(thresholds.connect_ms, "bc_timer_connect");
+    let total = timer(thresholds.total_ms, "txn_timer_total");
+    for violation in thresholds.violations(queue, connect, total) {
+        span.add_event(
+            "sla.violated",
+            vec![
+                KeyValue::new("sla.threshold", violation.threshold),
+                KeyValue::new("sla.threshold_ms", violation.limit_ms as i64),
+                KeyValue::new("sla.value_ms", violation.value_ms as i64),
+            ],
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_violations() {
+        let thresholds = SlaThresholds {
+            queue_ms: Some(10),
+            connect_ms: Some(50),
+            total_ms: None,
+        };
+        assert_eq!(
+            thresholds.violations(Some(25), Some(50), Some(5000)),
+            vec![Violation {
+                threshold: "queue",
+                limit_ms: 10,
+                value_ms: 25,
+            }]
+        );
+        // Timers are -1 when not applicable (e.g. no connection attempt)
+        assert!(thresholds.violations(Some(-1), Some(-1), None).is_empty());
+        assert!(thresholds.violations(None, None, None).is_empty());
+    }
+}
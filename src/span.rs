@@ -1,54 +1,281 @@
 use std::collections::HashMap;
-use std::time::SystemTime;
+use std::time::{Instant, SystemTime};
 
 use haproxy_api::Txn;
-use mlua::prelude::{Lua, LuaResult, LuaString, LuaTable};
-use opentelemetry::trace::{self, Span, TraceContextExt, Tracer};
-use opentelemetry::KeyValue;
+use mlua::prelude::{FromLua, IntoLuaMulti, Lua, LuaResult, LuaString, LuaTable, LuaValue};
+use mlua::Variadic;
+use opentelemetry::trace::{self, Link, Span, SpanRef, TraceContextExt, Tracer};
+use opentelemetry::{Context, KeyValue, Value};
 use opentelemetry_semantic_conventions::trace::{
-    HTTP_REQUEST_METHOD, NETWORK_PEER_ADDRESS, URL_PATH, URL_QUERY,
+    CLIENT_ADDRESS, HTTP_REQUEST_METHOD, HTTP_RESPONSE_STATUS_CODE, NETWORK_PEER_ADDRESS,
+    SERVER_ADDRESS, SERVER_PORT, URL_PATH, URL_QUERY, URL_TEMPLATE,
 };
 
+use crate::exporter::Options;
+use crate::tracer::TRACER_VAR;
 use crate::{get_context, store_context};
 
+/// Variable set with the reason when the request's spans won't be exported
+const DROPPED_VAR: &str = "txn.otel_dropped";
+
+/// Boolean variable holding the sampling decision of the server span
+const SAMPLED_VAR: &str = "txn.otel_sampled";
+
+/// Attributes set when the server span starts, including the optional ones
+const START_ATTRIBUTES_CAPACITY: usize = 14;
+
+/// Serialized parent context used when the request headers carry none
+const PARENT_CONTEXT_VAR: &str = "txn.otel_parent_context";
+
+/// Private variable marking requests to `internal_frontends`, never propagated upstream
+pub(crate) const INTERNAL_VAR: &str = "txn.__otel_internal";
+
+/// Private variable holding the filter's `omit_url_query` argument, overriding the option
+pub(crate) const OMIT_QUERY_VAR: &str = "txn.__otel_omit_query";
+
+/// Variable naming the routing rule that selected the backend, set by the user's ACLs
+const ROUTING_RULE_VAR: &str = "txn.otel_routing_rule";
+
+/// Variables exposing the ended server span to `http-after-response` rules and log-format
+const DURATION_VAR: &str = "txn.otel_duration_ms";
+const STATUS_VAR: &str = "txn.otel_status";
+
+/// Attribute marking the spans of requests HAProxy answered with a 503 for lack of a server
+pub(crate) const NO_SERVER: &str = "haproxy.no_server";
+
+/// Start time of the server span, carried in its context to expose its duration
+struct SpanStart(SystemTime);
+
 /// Starts a server span for the current transaction.
-pub(crate) fn start_server_span(lua: &Lua, txn: Txn) -> LuaResult<()> {
-    if let Some(options) = lua.app_data_ref::<crate::exporter::Options>() {
-        if let Err(e) = crate::exporter::init(options.clone()) {
-            crate::exporter::log_error(&format!("haproxy-otel: lazy init failed: {}", e));
+pub(crate) fn start_server_span(lua: &Lua, txn: Txn, registration: &str) -> LuaResult<()> {
+    let pipeline = match crate::pipeline(lua, registration) {
+        Some(pipeline) => pipeline,
+        None => {
+            crate::strict::fault("pipeline", format!("{registration} unavailable"));
+            txn.set_var(DROPPED_VAR, "pipeline_unavailable")?;
+            return Ok(());
         }
-    }
-    let tracer = opentelemetry::global::tracer("haproxy-otel");
+    };
+    // Tracer selected by the frontend's filter (`tracer=<name>`)
+    let tracer_name = match pipeline.tracers.is_empty() {
+        true => None,
+        false => (txn.get_var::<Option<String>>(TRACER_VAR))
+            .inspect_err(|e| crate::strict::fault("get txn.__otel_tracer", e))
+            .ok()
+            .flatten(),
+    };
+    let (tracer, default_attributes) = pipeline.named_tracer(tracer_name.as_deref());
     let http = txn.http()?;
 
     // Extract parent context from the request headers
-    let headers = http.req_get_headers().and_then(tracing_headers2map)?;
-    let remote_context = opentelemetry::global::get_text_map_propagator(|p| p.extract(&headers));
+    let mut headers = http.req_get_headers().and_then(tracing_headers2map)?;
+    let mut remote_context = pipeline.propagator.extract(&headers);
+    // Otherwise from the PROXY protocol TLV of the connection (HAProxy 2.9+), or a
+    // context handed over by another Lua module (`serialize_context`)
+    if !remote_context.span().span_context().is_valid() {
+        let tlv = pipeline.options.proxy_protocol_tlv.and_then(|tlv| {
+            let value = txn.f.get::<Option<String>>("fc_pp_tlv", tlv as i64);
+            value.ok().flatten()
+        });
+        let encoded = tlv.or_else(|| {
+            let value = txn.get_var::<Option<String>>(PARENT_CONTEXT_VAR);
+            value.ok().flatten()
+        });
+        if let Some(span_context) = encoded.as_deref().and_then(crate::handoff::decode) {
+            remote_context = Context::new().with_remote_span_context(span_context);
+        }
+    }
+    // Last, from a query parameter of clients that cannot set headers (websockets, beacons)
+    if let Some(ref param) = pipeline.options.context_query_param {
+        if !remote_context.span().span_context().is_valid() {
+            let query = txn.f.get::<Option<String>>("query", ()).ok().flatten();
+            if let Some(value) = query.and_then(|q| crate::propagation::query_param(&q, param)) {
+                let propagator = pipeline.options.propagator.as_deref().unwrap_or("w3c");
+                let header = crate::propagation::context_header(propagator);
+                let carrier = HashMap::from([(header.to_string(), value)]);
+                remote_context = pipeline.propagator.extract(&carrier);
+            }
+        }
+    }
+
+    // Keep the caller's context for logs and the upstream headers, without a span
+    if pipeline.options.propagation_only {
+        let span_context = remote_context.span().span_context().clone();
+        if span_context.is_valid() {
+            // Marked like a server span, so the end of the request removes it from the cache
+            txn.set_var("txn.__otel_server_span", true)?;
+            if registration != crate::exporter::DEFAULT_REGISTRATION {
+                txn.set_var("txn.__otel_registration", registration)?;
+            }
+            store_context(&txn, span_context.trace_id(), remote_context);
+        }
+        return Ok(());
+    }
 
+    // Values are moved into the attributes, only the span name and template are built
     let method = txn.f.get_str("method", ())?;
-    let uri = txn.f.get_str("pathq", ())?;
-    let host = headers.get("host").cloned().unwrap_or_default();
+    let (path, query) = crate::route::split_path_query(txn.f.get_str("pathq", ())?);
+    let host = headers.remove("host").unwrap_or_default();
     let peer_addr = txn.f.get_str("src", ())?;
+    if pipeline.options.id_seed.is_some() {
+        crate::ids::begin_transaction(&[&method, &host, &path, &query]);
+    }
+    // Requests to HAProxy's own stats and admin endpoints
+    let internal = !pipeline.options.internal_frontends.is_empty() && {
+        let fe_name = txn.f.get_str("fe_name", ())?;
+        pipeline.options.internal_frontends.contains(&fe_name)
+    };
+
+    // Forward proxies trace the outbound request to its destination
+    let egress = pipeline.options.egress;
+    let destination = match egress && !internal {
+        true => destination(&txn, &host),
+        false => None,
+    };
 
-    let (path, query) = uri.split_once('?').unwrap_or((&uri, ""));
-    let span_builder = tracer
-        .span_builder(format!("{method} {host}"))
-        .with_kind(trace::SpanKind::Server)
-        .with_start_time(SystemTime::now())
-        .with_attributes([
-            KeyValue::new(HTTP_REQUEST_METHOD, method),
-            KeyValue::new(URL_PATH, path.to_string()),
-            KeyValue::new(URL_QUERY, query.to_string()),
-            KeyValue::new("http.request.header.host", host),
-            KeyValue::new(NETWORK_PEER_ADDRESS, peer_addr),
-        ]);
-    let span = tracer.build_with_context(span_builder, &remote_context);
+    let tenant = match pipeline.options.tenants.is_empty() {
+        true => None,
+        false => resolve_tenant(&txn, &host).filter(|t| pipeline.options.tenants.contains_key(t)),
+    };
+    let template = pipeline
+        .options
+        .normalize_paths
+        .then(|| crate::route::normalize_path(&path).into_owned());
+    let span_name = match template {
+        Some(ref template) => format!("{method} {template}"),
+        None => format!("{method} {host}"),
+    };
+    // Sized for the optional attributes, so pushing them never reallocates
+    let mut attributes = Vec::with_capacity(START_ATTRIBUTES_CAPACITY);
+    attributes.extend([
+        KeyValue::new(HTTP_REQUEST_METHOD, method),
+        KeyValue::new(URL_PATH, path),
+        KeyValue::new("http.request.header.host", host),
+    ]);
+    // Compliance regimes that forbid storing query strings at all
+    if !omits_url_query(&txn, &pipeline.options) {
+        attributes.push(KeyValue::new(URL_QUERY, query));
+    }
+    match destination {
+        Some((address, port)) => {
+            // The peer of a client span is the server, the proxied client is recorded apart
+            attributes.push(KeyValue::new(CLIENT_ADDRESS, peer_addr));
+            attributes.push(KeyValue::new(SERVER_ADDRESS, address));
+            if let Some(port) = port {
+                attributes.push(KeyValue::new(SERVER_PORT, i64::from(port)));
+            }
+        }
+        None => attributes.push(KeyValue::new(NETWORK_PEER_ADDRESS, peer_addr)),
+    }
+    if let Some(template) = template {
+        attributes.push(KeyValue::new(URL_TEMPLATE, template));
+    }
+    // Tenant policies apply to the whole transaction, including the client span
+    if let Some(tenant) = tenant {
+        txn.set_var("txn.__otel_tenant", tenant.as_str())?;
+        attributes.push(KeyValue::new(crate::tenant::TENANT, tenant));
+    }
+    // Request class set by ACLs, also visible to the sampler
+    if let Ok(Some(class)) = txn.get_var::<Option<String>>("txn.otel_priority") {
+        if !class.is_empty() {
+            attributes.push(KeyValue::new(crate::sampler::REQUEST_CLASS, class));
+        }
+    }
+    // Internal requests start their own trace, whatever context the caller sent
+    if internal {
+        txn.set_var(INTERNAL_VAR, true)?;
+        attributes.push(KeyValue::new(crate::sampler::INTERNAL, true));
+        remote_context = Context::new();
+    }
+    // Backends trimming unknown remote parents would otherwise lose the caller
+    let remote_span_context = remote_context.span().span_context().clone();
+    if remote_span_context.is_valid() {
+        let parent_span_id = remote_span_context.span_id().to_string();
+        attributes.push(KeyValue::new("haproxy.parent.span_id", parent_span_id));
+    }
+    let kind = match (internal, egress) {
+        (true, _) => trace::SpanKind::Internal,
+        (false, true) => trace::SpanKind::Client,
+        (false, false) => trace::SpanKind::Server,
+    };
+    let mut span_builder = tracer.span_builder(span_name).with_kind(kind);
+
+    // The start time can be moved back to include time spent before HAProxy,
+    // e.g. queueing in an upstream LB that sets `X-Request-Start`
+    let now = SystemTime::now();
+    let start_time = txn
+        .get_var::<Option<String>>("txn.otel_start_time")
+        .inspect_err(|e| crate::strict::fault("get txn.otel_start_time", e))
+        .ok()
+        .flatten()
+        .and_then(|value| {
+            crate::clock::start_time_override(&value, now, &pipeline.options.clock_skew)
+        });
+    let span_start = SpanStart(start_time.map_or(now, |(start_time, _)| start_time));
+    span_builder = match start_time {
+        Some((start_time, skew)) => {
+            if !skew.is_zero() {
+                let skew_ms = skew.as_millis() as i64;
+                attributes.push(KeyValue::new(crate::clock::CLOCK_SKEW_ATTRIBUTE, skew_ms));
+            }
+            span_builder = span_builder.with_events(vec![trace::Event::new(
+                "request received by haproxy",
+                now,
+                vec![],
+                0,
+            )]);
+            span_builder.with_start_time(start_time)
+        }
+        None => span_builder.with_start_time(now),
+    };
+
+    // Mirrored (shadow) requests are linked to the primary request instead of
+    // being parented, so shadow traffic doesn't distort service latency graphs
+    let parent_context = if var_is_true(&txn, "txn.otel_mirrored") {
+        attributes.push(KeyValue::new("haproxy.mirrored", true));
+        if remote_span_context.is_valid() {
+            span_builder = span_builder.with_links(vec![Link::with_context(remote_span_context)]);
+        }
+        Context::new()
+    } else {
+        remote_context
+    };
+
+    crate::tracer::add_defaults(&mut attributes, default_attributes);
+    let span_builder = span_builder.with_attributes(attributes);
+    let span = tracer.build_with_context(span_builder, &parent_context);
     let trace_id = span.span_context().trace_id();
-    let context = remote_context.with_span(span);
+
+    // Expose the sampling decision to HAProxy rules (e.g. trace-consistent stick counters)
+    txn.set_var(SAMPLED_VAR, span.span_context().is_sampled())?;
+
+    // Tell log lines why this request has no trace
+    if !span.span_context().is_sampled() {
+        let parent_span_context = parent_context.span().span_context().clone();
+        let reason = match parent_span_context.is_valid() && !parent_span_context.is_sampled() {
+            true => "parent_not_sampled",
+            false => "sampler",
+        };
+        txn.set_var(DROPPED_VAR, reason)?;
+    }
+    // Upstream context for backends with `send-proxy-v2`, replaced by the client span's
+    // context when the filter creates one
+    let options = &pipeline.options;
+    if options.proxy_protocol_tlv.is_some() && options.injects_upstream() && !internal {
+        let encoded = crate::handoff::encode(span.span_context());
+        txn.set_var(crate::handoff::PP2_CONTEXT_VAR, encoded)?;
+    }
+    let mut context = parent_context.with_span(span).with_value(span_start);
+    if pipeline.options.duration_metrics {
+        context = context.with_value(crate::metrics::RequestStart(Instant::now()));
+    }
 
     // Mark this session as "main" for finishing the server span
     // This is a private variable to share data with filter
     txn.set_var("txn.__otel_server_span", true)?;
+    if registration != crate::exporter::DEFAULT_REGISTRATION {
+        txn.set_var("txn.__otel_registration", registration)?;
+    }
 
     // Save the context independently of the session
     store_context(&txn, trace_id, context);
@@ -68,9 +295,66 @@ pub(crate) fn set_span_attribute(
     Ok(())
 }
 
+/// Sample fetch `lua.otel_trace_id`, meant for `unique-id-format`.
+///
+/// Returns the trace id of the server span, or of the incoming trace context when the
+/// span has not been started yet, so the unique id and the trace id match either way.
+/// Returns an empty string when neither is available.
+pub(crate) fn trace_id_fetch(lua: &Lua, txn: Txn) -> LuaResult<String> {
+    if let Ok(Some(trace_id)) = txn.get_var::<Option<String>>("txn.otel_trace_id") {
+        return Ok(trace_id);
+    }
+    let pipeline = match crate::txn_pipeline(lua, &txn) {
+        Some(pipeline) => pipeline,
+        None => return Ok(String::new()),
+    };
+    let headers = txn
+        .http()?
+        .req_get_headers()
+        .and_then(tracing_headers2map)?;
+    let remote_context = pipeline.propagator.extract(&headers);
+    let span_context = remote_context.span().span_context().clone();
+    match span_context.is_valid() {
+        true => Ok(span_context.trace_id().to_string()),
+        false => Ok(String::new()),
+    }
+}
+
+/// Sample fetch `lua.otel_context` (and its alias `lua.otel_spoe_context`), the context of the
+/// server span for components that don't see the HTTP headers, e.g. as an SPOE message argument
+/// letting the agent continue the trace as a child of the server span. A fetch rather than a
+/// converter: HAProxy calls Lua converters with their input sample only, without the transaction.
+///
+/// Returns an empty string when the span has not been started.
+pub(crate) fn context_fetch(_lua: &Lua, txn: Txn) -> LuaResult<String> {
+    Ok(get_context(&txn)
+        .map(|context| encoded_context(context.span().span_context()))
+        .unwrap_or_default())
+}
+
+/// The hand-off encoding of the context (`<trace-id>-<span-id>-<flags>[;<tracestate>]`), empty
+/// when it is invalid. Prepending `00-` to the part before `;` gives the `traceparent` header.
+pub(crate) fn encoded_context(span_context: &trace::SpanContext) -> String {
+    if !span_context.is_valid() {
+        return String::new();
+    }
+    crate::handoff::encode(span_context)
+}
+
+/// Records a timing event named after the argument on the server span.
+/// Does nothing unless the `diagnostics` option is enabled.
+pub(crate) fn diagnostics_mark(lua: &Lua, (txn, name): (Txn, String)) -> LuaResult<()> {
+    if let Some(context) = get_context(&txn) {
+        if crate::txn_pipeline(lua, &txn).is_some_and(|pipeline| pipeline.options.diagnostics) {
+            context.span().add_event(name, vec![]);
+        }
+    }
+    Ok(())
+}
+
 /// Ends the server span for the current transaction.
 /// Should be called via http-after-response or http-response action.
-pub(crate) fn end_server_span(_lua: &Lua, txn: Txn) -> LuaResult<()> {
+pub(crate) fn end_server_span(lua: &Lua, txn: Txn) -> LuaResult<()> {
     // Only end if this transaction has a server span
     if !txn
         .get_var::<bool>("txn.__otel_server_span")
@@ -81,40 +365,363 @@ pub(crate) fn end_server_span(_lua: &Lua, txn: Txn) -> LuaResult<()> {
 
     let context = match crate::remove_context(&txn) {
         Some(cx) => cx,
-        None => return Ok(()),
+        None => {
+            // Evicted from the cache, the span is never ended
+            crate::strict::fault("end_server_span", "server span context not found");
+            return Ok(());
+        }
     };
 
     let span = context.span();
+    let pipeline = crate::txn_pipeline(lua, &txn);
+    // HAProxy's own error replies only pass the http-after-response rules, not the filter
+    let header = pipeline
+        .as_ref()
+        .and_then(|p| p.options.error_trace_header.as_ref());
+    if let Some(header) = header {
+        let status = txn.f.get::<Option<i64>>("status", ()).ok().flatten();
+        if let Some(trace_id) = error_trace_id(&context, status) {
+            txn.http()?.res_set_header(header, trace_id)?;
+        }
+    }
+    if pipeline
+        .as_ref()
+        .is_some_and(|p| p.options.propagation_only)
+    {
+        return Ok(());
+    }
+    set_server_span_attributes(&txn, &span, pipeline.as_ref().map(|p| &p.options))?;
+    set_compression_attributes(&txn, &span, false);
+    crate::metrics::observe_request(&txn, &context);
+    end_span(&txn, &context);
+    Ok(())
+}
+
+/// Trace id for the `error_trace_header` of a response, exposed to the client on server errors
+/// of sampled requests only so support can ask for it without leaking it on all traffic
+pub(crate) fn error_trace_id(context: &Context, status: Option<i64>) -> Option<String> {
+    let span = context.span();
+    let span_context = span.span_context();
+    (status.unwrap_or_default() >= 500 && span_context.is_sampled())
+        .then(|| span_context.trace_id().to_string())
+}
+
+/// Ends the server span of `context`, its duration is exposed in `txn.otel_duration_ms`
+pub(crate) fn end_span(txn: &Txn, context: &Context) {
+    let end_time = SystemTime::now();
+    if let Some(SpanStart(start_time)) = context.get::<SpanStart>() {
+        let duration = end_time.duration_since(*start_time).unwrap_or_default();
+        if let Err(e) = txn.set_var(DURATION_VAR, duration.as_millis() as i64) {
+            crate::strict::fault("set txn.otel_duration_ms", e);
+        }
+    }
+    let _guard = crate::exporter::get_otel_runtime().enter();
+    context.span().end_with_timestamp(end_time);
+}
+
+/// Sets the final response and HAProxy-specific attributes on the server span.
+///
+/// `options` of the transaction's pipeline add the `end_attributes` and SLA events.
+pub(crate) fn set_server_span_attributes(
+    txn: &Txn,
+    span: &SpanRef,
+    options: Option<&Options>,
+) -> LuaResult<()> {
+    let mut fetches = BestEffort::new(txn);
 
     // Set response status
-    let status = (txn.f.get::<Option<i64>>("txn_status", ())?).unwrap_or_default();
-    span.set_attribute(KeyValue::new(
-        opentelemetry_semantic_conventions::trace::HTTP_RESPONSE_STATUS_CODE,
-        status,
-    ));
-    if status < 500 {
-        span.set_status(trace::Status::Ok);
-    } else {
-        span.set_status(trace::Status::error("5xx status code"));
+    let status = fetches.get::<i64>("txn_status");
+    let span_status = match status {
+        Some(status) => {
+            span.set_attribute(KeyValue::new(HTTP_RESPONSE_STATUS_CODE, status));
+            if status < 500 {
+                span.set_status(trace::Status::Ok);
+                "ok"
+            } else {
+                span.set_status(trace::Status::error("5xx status code"));
+                "error"
+            }
+        }
+        None => "unset",
+    };
+    if let Err(e) = txn.set_var(STATUS_VAR, span_status) {
+        crate::strict::fault("set txn.otel_status", e);
     }
 
     // Set HAProxy-specific attributes
-    let fe_name = txn.f.get_str("fe_name", ())?;
-    span.set_attribute(KeyValue::new("haproxy.frontend.name", fe_name));
-    let be_name = txn.f.get_str("be_name", ())?;
-    span.set_attribute(KeyValue::new("haproxy.backend.name", be_name));
-    if let Ok(Some(term_state)) = txn.f.get::<Option<String>>("txn_sess_term_state", ()) {
-        span.set_attribute(KeyValue::new("haproxy.termination_state", term_state));
+    if let Some(fe_name) = fetches.get_str("fe_name") {
+        span.set_attribute(KeyValue::new("haproxy.frontend.name", fe_name));
+    }
+    let be_name = fetches.get_str("be_name");
+    if let Some(ref be_name) = be_name {
+        span.set_attribute(KeyValue::new("haproxy.backend.name", be_name.clone()));
+    }
+    // HAProxy doesn't expose which `use_backend` rule matched
+    if let Ok(Some(rule)) = txn.get_var::<Option<String>>(ROUTING_RULE_VAR) {
+        if !rule.is_empty() {
+            span.set_attribute(KeyValue::new("haproxy.routing.rule", rule));
+        }
+    }
+    let term_state = txn.f.get::<Option<String>>("txn_sess_term_state", ());
+    let term_state = term_state.ok().flatten();
+    if let Some(ref term_state) = term_state {
+        span.set_attribute(KeyValue::new(
+            "haproxy.termination_state",
+            term_state.clone(),
+        ));
+    }
+    // Not a partial span when there is no server, local replies have none either
+    let srv_name = txn.f.get::<Option<String>>("srv_name", ()).ok().flatten();
+    if is_no_server(status, srv_name.as_deref(), term_state.as_deref()) {
+        span.set_attribute(KeyValue::new(NO_SERVER, true));
+    }
+    set_connection_error_attributes(txn, span, "fc", "haproxy.frontend.error");
+    if is_cache_hit(txn) {
+        span.set_attribute(KeyValue::new("haproxy.cache.hit", true));
+        if let Ok(Some(cache_name)) = txn.f.get::<Option<String>>("res_cache_name", ()) {
+            span.set_attribute(KeyValue::new("haproxy.cache.name", cache_name));
+        }
+    }
+
+    let options = match options {
+        Some(options) => options,
+        None => {
+            fetches.finish(span);
+            return Ok(());
+        }
+    };
+    if !options.sla.is_empty() {
+        crate::sla::record_violations(&mut fetches, span, &options.sla, be_name.as_deref());
+    }
+
+    // User-defined fetches (`end_attributes` option)
+    for attribute in &options.end_attributes {
+        let args = Variadic::from_iter(attribute.args.iter().map(String::as_str));
+        let value = fetches.get_with_args::<LuaValue>(&attribute.fetch, args);
+        match value.and_then(attribute_value) {
+            Some(value) => span.set_attribute(KeyValue::new(attribute.key.clone(), value)),
+            None => fetches.mark_partial(),
+        }
+    }
+    fetches.finish(span);
+
+    // Variables matching the `export_vars` patterns, unset ones are skipped
+    for name in &options.export_vars {
+        if let Some(value) = txn.get_var::<LuaValue>(name).ok().and_then(attribute_value) {
+            span.set_attribute(KeyValue::new(crate::vars::attribute_key(name), value));
+        }
     }
 
-    let _guard = crate::exporter::get_otel_runtime().enter();
-    span.end();
     Ok(())
 }
 
+/// Converts a fetch result or variable to an attribute value, `None` for nil and empty strings
+fn attribute_value(value: LuaValue) -> Option<Value> {
+    match value {
+        LuaValue::Boolean(b) => Some(Value::from(b)),
+        LuaValue::Integer(i) => Some(Value::from(i)),
+        LuaValue::Number(n) => Some(Value::from(n)),
+        LuaValue::String(s) if !s.as_bytes().is_empty() => Some(Value::from(s.to_string_lossy())),
+        _ => None,
+    }
+}
+
+/// Best effort attribute fetches.
+///
+/// Some fetches fail or return nil in edge cases (aborts, TCP backends). Instead of
+/// recording empty values or failing, such attributes are omitted and the span gets
+/// a single `haproxy.attributes.partial = true` marker.
+pub(crate) struct BestEffort<'a> {
+    txn: &'a Txn,
+    partial: bool,
+}
+
+impl<'a> BestEffort<'a> {
+    pub(crate) fn new(txn: &'a Txn) -> Self {
+        Self {
+            txn,
+            partial: false,
+        }
+    }
+
+    /// Runs a sample fetch, returns `None` for failed or nil results
+    pub(crate) fn get<T: FromLua>(&mut self, fetch: &str) -> Option<T> {
+        self.get_with_args(fetch, ())
+    }
+
+    /// Runs a sample fetch with arguments, returns `None` for failed or nil results
+    pub(crate) fn get_with_args<T: FromLua>(
+        &mut self,
+        fetch: &str,
+        args: impl IntoLuaMulti,
+    ) -> Option<T> {
+        let value = self.txn.f.get::<Option<T>>(fetch, args).ok().flatten();
+        self.partial |= value.is_none();
+        value
+    }
+
+    /// Runs a string sample fetch, empty strings are treated as missing
+    pub(crate) fn get_str(&mut self, fetch: &str) -> Option<String> {
+        let value = self.get::<String>(fetch)?;
+        if value.is_empty() {
+            self.partial = true;
+            return None;
+        }
+        Some(value)
+    }
+
+    /// Marks the attributes as partial, for values obtained without a fetch
+    pub(crate) fn mark_partial(&mut self) {
+        self.partial = true;
+    }
+
+    /// Sets the `haproxy.attributes.partial` marker if any attribute was omitted
+    pub(crate) fn finish(self, span: &SpanRef) {
+        if self.partial {
+            span.set_attribute(KeyValue::new("haproxy.attributes.partial", true));
+        }
+    }
+}
+
+/// Whether HAProxy answered with its own 503 because the backend had no usable server: no
+/// server was assigned and the session ended at the connection setup (`SC`, logged as
+/// `<NOSRV>`). Without the termination state (HAProxy < 2.9) the status and server decide.
+pub(crate) fn is_no_server(
+    status: Option<i64>,
+    srv_name: Option<&str>,
+    term_state: Option<&str>,
+) -> bool {
+    status == Some(503)
+        && srv_name.is_none_or(str::is_empty)
+        && term_state.is_none_or(|state| state.starts_with("SC"))
+}
+
+/// Whether the transaction is a 503 without a server, see [`is_no_server`]
+pub(crate) fn txn_is_no_server(txn: &Txn) -> bool {
+    let status = txn.f.get::<Option<i64>>("txn_status", ()).ok().flatten();
+    let srv_name = txn.f.get::<Option<String>>("srv_name", ()).ok().flatten();
+    let term_state = txn.f.get::<Option<String>>("txn_sess_term_state", ());
+    is_no_server(
+        status,
+        srv_name.as_deref(),
+        term_state.ok().flatten().as_deref(),
+    )
+}
+
+/// Returns true when the response is served by the HAProxy cache (`res.cache_hit`)
+pub(crate) fn is_cache_hit(txn: &Txn) -> bool {
+    matches!(
+        txn.f.get::<Option<bool>>("res_cache_hit", ()),
+        Ok(Some(true))
+    )
+}
+
+/// Records the compression algorithm when HAProxy compressed the response.
+///
+/// The compression ratio is only known once the body has been forwarded, so it is
+/// recorded when `body_forwarded` is set (end of analysis in the filter).
+pub(crate) fn set_compression_attributes(txn: &Txn, span: &SpanRef, body_forwarded: bool) {
+    if !matches!(txn.f.get::<Option<bool>>("res_comp", ()), Ok(Some(true))) {
+        return;
+    }
+    if let Ok(Some(algorithm)) = txn.f.get::<Option<String>>("res_comp_algo", ()) {
+        span.set_attribute(KeyValue::new("haproxy.compression.algorithm", algorithm));
+    }
+    if !body_forwarded {
+        return;
+    }
+    // `res.bytes_in`/`res.bytes_out` require HAProxy >= 3.1
+    let bytes_in = txn.f.get::<Option<i64>>("res_bytes_in", ());
+    let bytes_out = txn.f.get::<Option<i64>>("res_bytes_out", ());
+    if let (Ok(Some(bytes_in)), Ok(Some(bytes_out))) = (bytes_in, bytes_out) {
+        if bytes_in > 0 {
+            let ratio = bytes_out as f64 / bytes_in as f64;
+            span.set_attribute(KeyValue::new("haproxy.compression.ratio", ratio));
+        }
+    }
+}
+
+/// Records a connection error code (`fc_err`/`bc_err`) and its explanation when non-zero.
+///
+/// `side` is the fetch prefix (`fc` or `bc`), `prefix` the attribute namespace.
+pub(crate) fn set_connection_error_attributes(txn: &Txn, span: &SpanRef, side: &str, prefix: &str) {
+    let code = match txn.f.get::<Option<i64>>(&format!("{side}_err"), ()) {
+        Ok(Some(code)) if code != 0 => code,
+        _ => return,
+    };
+    span.set_attribute(KeyValue::new(format!("{prefix}.code"), code));
+    if let Ok(Some(message)) = txn.f.get::<Option<String>>(&format!("{side}_err_str"), ()) {
+        span.set_attribute(KeyValue::new(format!("{prefix}.message"), message));
+    }
+}
+
+/// Selects the tenant key: `txn.otel_tenant` (e.g. set from a map), the Host header or the SNI.
+fn resolve_tenant(txn: &Txn, host: &str) -> Option<String> {
+    if let Ok(Some(tenant)) = txn.get_var::<Option<String>>("txn.otel_tenant") {
+        return Some(crate::tenant::tenant_key(&tenant));
+    }
+    if !host.is_empty() {
+        return Some(crate::tenant::tenant_key(host));
+    }
+    match txn.f.get::<Option<String>>("ssl_fc_sni", ()) {
+        Ok(Some(sni)) if !sni.is_empty() => Some(crate::tenant::tenant_key(&sni)),
+        _ => None,
+    }
+}
+
+/// Whether `url.query` is left out of the request's spans, the filter's argument first
+pub(crate) fn omits_url_query(txn: &Txn, options: &Options) -> bool {
+    match txn.get_var::<Option<bool>>(OMIT_QUERY_VAR) {
+        Ok(Some(omit)) => omit,
+        _ => options.omit_url_query,
+    }
+}
+
+/// Destination of an egress request: the Host header, or the SNI of the client connection
+fn destination(txn: &Txn, host: &str) -> Option<(String, Option<u16>)> {
+    if !host.is_empty() {
+        return Some(split_host_port(host));
+    }
+    match txn.f.get::<Option<String>>("ssl_fc_sni", ()) {
+        Ok(Some(sni)) if !sni.is_empty() => Some((sni, None)),
+        _ => None,
+    }
+}
+
+/// Splits a Host header into `server.address` and `server.port`, IPv6 literals lose their
+/// brackets
+fn split_host_port(host: &str) -> (String, Option<u16>) {
+    let (address, port) = match host.rsplit_once(':') {
+        // Unbracketed IPv6 literals have no port
+        Some((address, port)) if !address.contains(':') || address.ends_with(']') => {
+            match port.parse::<u16>() {
+                Ok(port) => (address, Some(port)),
+                Err(_) => (host, None),
+            }
+        }
+        _ => (host, None),
+    };
+    let unbracketed = (address.strip_prefix('[')).and_then(|a| a.strip_suffix(']'));
+    (unbracketed.unwrap_or(address).to_string(), port)
+}
+
+/// Reads a boolean-like transaction variable.
+///
+/// Accepts Lua booleans, non-zero integers (`tune.lua.bool-sample-conversion pre-3.1-bug`)
+/// and the strings `true`/`1`. Missing variables are treated as false.
+pub(crate) fn var_is_true(txn: &Txn, name: &str) -> bool {
+    match txn.get_var::<LuaValue>(name) {
+        Ok(LuaValue::Boolean(b)) => b,
+        Ok(LuaValue::Integer(i)) => i != 0,
+        Ok(LuaValue::String(s)) => matches!(s.as_bytes().as_ref(), b"true" | b"1"),
+        _ => false,
+    }
+}
+
 /// Convert only specific tracing headers to a map for context extraction
 fn tracing_headers2map(headers: haproxy_api::Headers) -> LuaResult<HashMap<String, String>> {
-    let mut map = HashMap::new();
+    // Host and at most a few tracing headers
+    let mut map = HashMap::with_capacity(4);
     headers.for_each::<LuaString, LuaTable>(|name, value| {
         let nameb = name.as_bytes();
         if nameb == b"host"
@@ -123,6 +730,20 @@ fn tracing_headers2map(headers: haproxy_api::Headers) -> LuaResult<HashMap<Strin
             || nameb.starts_with(b"uber")
         {
             let name = name.to_string_lossy();
+            if nameb == b"tracestate" {
+                // Multiple tracestate headers form a single list (indexes start from 0)
+                let mut values = value
+                    .pairs::<i64, LuaString>()
+                    .filter_map(|pair| pair.ok())
+                    .collect::<Vec<_>>();
+                values.sort_by_key(|(i, _)| *i);
+                let values = values
+                    .into_iter()
+                    .map(|(_, v)| v.to_string_lossy())
+                    .collect::<Vec<_>>();
+                map.insert(name, values.join(","));
+                return Ok(());
+            }
             let value = value.get::<LuaString>(0);
             if let Ok(value) = value.as_ref().map(|v| v.to_string_lossy()) {
                 map.insert(name, value);
@@ -130,5 +751,114 @@ fn tracing_headers2map(headers: haproxy_api::Headers) -> LuaResult<HashMap<Strin
         }
         Ok(())
     })?;
+    if let Some(tracestate) = map.get_mut("tracestate") {
+        *tracestate = crate::propagation::sanitize_tracestate(tracestate);
+    }
     Ok(map)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encoded_context() {
+        let span_context = trace::SpanContext::new(
+            trace::TraceId::from(0x4bf9_2f35_77b3_4da6_a3ce_929d_0e0e_4736u128),
+            trace::SpanId::from(0x00f0_67aa_0ba9_02b7u64),
+            trace::TraceFlags::SAMPLED,
+            false,
+            Default::default(),
+        );
+        assert_eq!(
+            encoded_context(&span_context),
+            "4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"
+        );
+        let unsampled = trace::SpanContext::new(
+            span_context.trace_id(),
+            span_context.span_id(),
+            trace::TraceFlags::default(),
+            false,
+            Default::default(),
+        );
+        assert!(encoded_context(&unsampled).ends_with("-00"));
+        // The sampling threshold reaches the agent
+        let rated = trace::SpanContext::new(
+            span_context.trace_id(),
+            span_context.span_id(),
+            trace::TraceFlags::SAMPLED,
+            false,
+            "ot=th:8".parse().unwrap(),
+        );
+        assert_eq!(
+            encoded_context(&rated),
+            "4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01;ot=th:8"
+        );
+        assert_eq!(encoded_context(&trace::SpanContext::empty_context()), "");
+    }
+
+    #[test]
+    fn test_error_trace_id() {
+        let span_context = |flags| {
+            trace::SpanContext::new(
+                trace::TraceId::from(0x4bf9_2f35_77b3_4da6_a3ce_929d_0e0e_4736u128),
+                trace::SpanId::from(0x00f0_67aa_0ba9_02b7u64),
+                flags,
+                false,
+                Default::default(),
+            )
+        };
+        let sampled =
+            Context::new().with_remote_span_context(span_context(trace::TraceFlags::SAMPLED));
+        assert_eq!(
+            error_trace_id(&sampled, Some(503)).as_deref(),
+            Some("4bf92f3577b34da6a3ce929d0e0e4736")
+        );
+        assert_eq!(error_trace_id(&sampled, Some(404)), None);
+        assert_eq!(error_trace_id(&sampled, None), None);
+        let unsampled =
+            Context::new().with_remote_span_context(span_context(trace::TraceFlags::default()));
+        assert_eq!(error_trace_id(&unsampled, Some(502)), None);
+    }
+
+    #[test]
+    fn test_is_no_server() {
+        assert!(is_no_server(Some(503), None, Some("SC--")));
+        assert!(is_no_server(Some(503), Some(""), None));
+        // Served by a server, or a 503 of the server itself
+        assert!(!is_no_server(Some(503), Some("srv1"), Some("SC--")));
+        assert!(!is_no_server(Some(503), Some("srv1"), Some("----")));
+        // `http-request return status 503` and other local replies
+        assert!(!is_no_server(Some(503), None, Some("LR--")));
+        assert!(!is_no_server(Some(200), None, None));
+        assert!(!is_no_server(None, None, None));
+    }
+
+    #[test]
+    fn test_split_host_port() {
+        assert_eq!(
+            split_host_port("example.com"),
+            ("example.com".to_string(), None)
+        );
+        assert_eq!(
+            split_host_port("example.com:8443"),
+            ("example.com".to_string(), Some(8443))
+        );
+        assert_eq!(
+            split_host_port("[2001:db8::1]:443"),
+            ("2001:db8::1".to_string(), Some(443))
+        );
+        assert_eq!(
+            split_host_port("[2001:db8::1]"),
+            ("2001:db8::1".to_string(), None)
+        );
+        assert_eq!(
+            split_host_port("2001:db8::1"),
+            ("2001:db8::1".to_string(), None)
+        );
+        assert_eq!(
+            split_host_port("example.com:http"),
+            ("example.com:http".to_string(), None)
+        );
+    }
+}
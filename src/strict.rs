@@ -0,0 +1,123 @@
+use std::cell::Cell;
+use std::fmt::Display;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+use haproxy_api::Txn;
+use mlua::prelude::LuaResult;
+
+use crate::exporter::log_warn;
+
+/// Variable naming the first tracing failure of the transaction, for `http-request return` rules
+pub(crate) const FAULT_VAR: &str = "txn.otel_fault";
+
+/// What is done about the failures of the tracing path (`strict`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub(crate) enum StrictMode {
+    // Failures are ignored and the request goes on untraced or partially traced
+    #[default]
+    Off = 0,
+    // Failures are counted in `haproxy_otel_tracing_faults_total` and named in `txn.otel_fault`
+    Count = 1,
+    // Failures are logged as well
+    Log = 2,
+}
+
+impl StrictMode {
+    /// Parse strict mode from string ("count" or "log")
+    pub(crate) fn from_str(s: &str) -> Option<Self> {
+        match s.to_lowercase().as_str() {
+            "count" => Some(StrictMode::Count),
+            "log" => Some(StrictMode::Log),
+            _ => None,
+        }
+    }
+}
+
+/// Strictest mode of the registrations, the tracing path is shared by all of them
+static MODE: AtomicU8 = AtomicU8::new(StrictMode::Off as u8);
+
+thread_local! {
+    // First failure of the action running on the HAProxy thread
+    static FAULT: Cell<Option<&'static str>> = const { Cell::new(None) };
+}
+
+pub(crate) fn enable(mode: StrictMode) {
+    raise(&MODE, mode);
+}
+
+fn mode() -> StrictMode {
+    load(&MODE)
+}
+
+fn raise(current: &AtomicU8, mode: StrictMode) {
+    current.fetch_max(mode as u8, Ordering::Relaxed);
+}
+
+fn load(current: &AtomicU8) -> StrictMode {
+    match current.load(Ordering::Relaxed) {
+        2 => StrictMode::Log,
+        1 => StrictMode::Count,
+        _ => StrictMode::Off,
+    }
+}
+
+/// Records a failure of the tracing path: `operation` failed with `error`
+pub(crate) fn fault(operation: &'static str, error: impl Display) {
+    record(mode(), operation, error);
+}
+
+fn record(mode: StrictMode, operation: &'static str, error: impl Display) {
+    if mode == StrictMode::Off {
+        return;
+    }
+    crate::metrics::count_tracing_fault();
+    FAULT.with(|fault| {
+        if fault.get().is_none() {
+            fault.set(Some(operation));
+        }
+    });
+    if mode == StrictMode::Log {
+        log_warn(&format!("tracing failed: {operation}: {error}"));
+    }
+}
+
+/// Runs an action of the tracing path. With `strict`, its error is recorded as a failure
+/// rather than returned to HAProxy, and the first failure is named in `txn.otel_fault`.
+pub(crate) fn run(txn: Txn, action: impl FnOnce(Txn) -> LuaResult<()>) -> LuaResult<()> {
+    if mode() == StrictMode::Off {
+        return action(txn);
+    }
+    FAULT.with(|fault| fault.set(None));
+    if let Err(e) = action(txn.clone()) {
+        fault("action", e);
+    }
+    if let Some(operation) = FAULT.with(Cell::take) {
+        // Already counted, a failure to set the variable can't be reported anywhere else
+        let _ = txn.set_var(FAULT_VAR, operation);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strict_mode() {
+        assert_eq!(StrictMode::from_str("LOG"), Some(StrictMode::Log));
+        assert_eq!(StrictMode::from_str("count"), Some(StrictMode::Count));
+        assert_eq!(StrictMode::from_str("deny"), None);
+
+        // The strictest registration wins, on a local mode as the global one can't go back down
+        let current = AtomicU8::new(StrictMode::Off as u8);
+        raise(&current, StrictMode::Log);
+        raise(&current, StrictMode::Count);
+        assert_eq!(load(&current), StrictMode::Log);
+
+        record(StrictMode::Off, "set txn.otel_duration", "ignored");
+        assert_eq!(FAULT.with(Cell::get), None);
+        record(StrictMode::Log, "set txn.otel_status", "no transaction");
+        record(StrictMode::Count, "get txn.otel_start_time", "not a string");
+        assert_eq!(FAULT.with(Cell::take), Some("set txn.otel_status"));
+    }
+}
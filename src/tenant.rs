@@ -0,0 +1,132 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use opentelemetry::{Context, KeyValue};
+use opentelemetry_sdk::error::OTelSdkResult;
+use opentelemetry_sdk::trace::{Span, SpanData, SpanProcessor};
+use opentelemetry_sdk::Resource;
+
+/// Attribute recording the tenant whose policy applies to the span
+pub(crate) const TENANT: &str = "haproxy.tenant";
+
+const REDACTED: &str = "[REDACTED]";
+
+/// Observability contract of a tenant, selected by `txn.otel_tenant`, Host or SNI
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct TenantPolicy {
+    // Ratio of traces sampled (0.0 - 1.0), replaces the configured sampler
+    pub(crate) sample_ratio: Option<f64>,
+    // Attribute keys kept on the spans, all attributes are kept when unset
+    pub(crate) attributes: Option<Vec<String>>,
+    // Attribute keys whose values are replaced with `[REDACTED]`
+    pub(crate) redact: Vec<String>,
+}
+
+pub(crate) type TenantPolicies = Arc<HashMap<String, TenantPolicy>>;
+
+/// Normalizes a Host header or SNI value into a tenant key (lowercase, without port)
+pub(crate) fn tenant_key(host: &str) -> String {
+    let host = match host.rsplit_once(':') {
+        // Keep IPv6 literals such as `[::1]` intact
+        Some((name, port)) if !name.ends_with(':') && port.bytes().all(|b| b.is_ascii_digit()) => {
+            name
+        }
+        _ => host,
+    };
+    host.to_ascii_lowercase()
+}
+
+/// Applies the attribute allow list and redaction rules of a policy
+pub(crate) fn apply_policy(attributes: &mut Vec<KeyValue>, policy: &TenantPolicy) {
+    if let Some(ref allowed) = policy.attributes {
+        attributes.retain(|kv| {
+            kv.key.as_str() == TENANT || allowed.iter().any(|key| key == kv.key.as_str())
+        });
+    }
+    for kv in attributes.iter_mut() {
+        if policy.redact.iter().any(|key| key == kv.key.as_str()) {
+            kv.value = REDACTED.into();
+        }
+    }
+}
+
+/// Span processor wrapper enforcing the attribute policies of tenants
+#[derive(Debug)]
+pub(crate) struct TenantProcessor<P> {
+    inner: P,
+    policies: TenantPolicies,
+}
+
+impl<P> TenantProcessor<P> {
+    pub(crate) fn new(inner: P, policies: TenantPolicies) -> Self {
+        Self { inner, policies }
+    }
+}
+
+impl<P: SpanProcessor> SpanProcessor for TenantProcessor<P> {
+    fn on_start(&self, span: &mut Span, cx: &Context) {
+        self.inner.on_start(span, cx);
+    }
+
+    fn on_end(&self, mut span: SpanData) {
+        let policy = span
+            .attributes
+            .iter()
+            .find(|kv| kv.key.as_str() == TENANT)
+            .and_then(|kv| self.policies.get(kv.value.as_str().as_ref()));
+        if let Some(policy) = policy {
+            apply_policy(&mut span.attributes, policy);
+        }
+        self.inner.on_end(span);
+    }
+
+    fn force_flush(&self) -> OTelSdkResult {
+        self.inner.force_flush()
+    }
+
+    fn shutdown_with_timeout(&self, timeout: Duration) -> OTelSdkResult {
+        self.inner.shutdown_with_timeout(timeout)
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        self.inner.set_resource(resource);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tenant_key() {
+        assert_eq!(tenant_key("Shop.Example.com:8443"), "shop.example.com");
+        assert_eq!(tenant_key("example.com"), "example.com");
+        assert_eq!(tenant_key("[::1]:443"), "[::1]");
+        assert_eq!(tenant_key("[::1]"), "[::1]");
+    }
+
+    #[test]
+    fn test_apply_policy() {
+        let policy = TenantPolicy {
+            attributes: Some(vec!["url.path".to_string(), "url.query".to_string()]),
+            redact: vec!["url.query".to_string()],
+            ..Default::default()
+        };
+        let mut attributes = vec![
+            KeyValue::new(TENANT, "acme"),
+            KeyValue::new("url.path", "/login"),
+            KeyValue::new("url.query", "token=secret"),
+            KeyValue::new("network.peer.address", "10.0.0.1"),
+        ];
+        apply_policy(&mut attributes, &policy);
+        assert_eq!(
+            attributes,
+            vec![
+                KeyValue::new(TENANT, "acme"),
+                KeyValue::new("url.path", "/login"),
+                KeyValue::new("url.query", REDACTED),
+            ]
+        );
+    }
+}
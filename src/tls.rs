@@ -0,0 +1,211 @@
+use std::error::Error as StdError;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use opentelemetry_http::{Bytes, HttpClient, Request};
+
+use crate::exporter::{log_info, log_warn, SendFuture};
+
+type BuildClient<T> =
+    dyn Fn(&ClientIdentity) -> Result<T, Box<dyn StdError + Send + Sync>> + Send + Sync;
+
+/// Client certificate and private key (PEM files) for mutual TLS with the collector
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct ClientIdentity {
+    pub(crate) cert_file: String,
+    pub(crate) key_file: String,
+}
+
+impl ClientIdentity {
+    /// Reads the certificate chain and the private key
+    pub(crate) fn read(&self) -> Result<(Vec<u8>, Vec<u8>), String> {
+        let read =
+            |path: &str| std::fs::read(path).map_err(|e| format!("failed to read '{path}': {e}"));
+        Ok((read(&self.cert_file)?, read(&self.key_file)?))
+    }
+
+    /// Identity of the reqwest client (key and certificates in a single PEM buffer)
+    fn reqwest_identity(&self) -> Result<reqwest::Identity, Box<dyn StdError + Send + Sync>> {
+        let (cert, mut pem) = self.read()?;
+        pem.push(b'\n');
+        pem.extend(cert);
+        Ok(reqwest::Identity::from_pem(&pem)?)
+    }
+
+    /// Latest modification time of both files, follows the symlinks of mounted secrets
+    fn modified(&self) -> Option<SystemTime> {
+        let modified = |path: &str| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+        modified(&self.cert_file).max(modified(&self.key_file))
+    }
+}
+
+/// HTTP client of the OTLP HTTP exporters presenting a client certificate, or the gRPC
+/// client of [`GrpcExporter`](crate::grpc::GrpcExporter).
+///
+/// The certificate files are checked before every export, the client is rebuilt when
+/// they change so rotated certificates are picked up without a reload of HAProxy.
+pub(crate) struct ReloadingClient<T = reqwest::Client> {
+    identity: ClientIdentity,
+    build: Box<BuildClient<T>>,
+    // Modification time of the files the client was last built for
+    current: Mutex<(Option<SystemTime>, T)>,
+}
+
+impl ReloadingClient {
+    pub(crate) fn new<F>(
+        identity: ClientIdentity,
+        build: F,
+    ) -> Result<Self, Box<dyn StdError + Send + Sync>>
+    where
+        F: Fn(reqwest::Identity) -> Result<reqwest::Client, Box<dyn StdError + Send + Sync>>
+            + Send
+            + Sync
+            + 'static,
+    {
+        Self::with_identity(identity, move |identity| {
+            build(identity.reqwest_identity()?)
+        })
+    }
+}
+
+impl<T: Clone> ReloadingClient<T> {
+    /// Client built from the certificate files by `build`
+    pub(crate) fn with_identity<F>(
+        identity: ClientIdentity,
+        build: F,
+    ) -> Result<Self, Box<dyn StdError + Send + Sync>>
+    where
+        F: Fn(&ClientIdentity) -> Result<T, Box<dyn StdError + Send + Sync>>
+            + Send
+            + Sync
+            + 'static,
+    {
+        let modified = identity.modified();
+        let client = build(&identity)?;
+        Ok(Self {
+            identity,
+            build: Box::new(build),
+            current: Mutex::new((modified, client)),
+        })
+    }
+
+    /// Client for the next request, rebuilt if the certificate files changed.
+    ///
+    /// A failed rebuild (e.g. only one of the files has been replaced yet) keeps the
+    /// previous client until the files change again.
+    pub(crate) fn client(&self) -> T {
+        let mut current = self.current.lock().unwrap_or_else(|e| e.into_inner());
+        let modified = self.identity.modified();
+        if modified != current.0 {
+            current.0 = modified;
+            match (self.build)(&self.identity) {
+                Ok(client) => {
+                    current.1 = client;
+                    log_info(&format!(
+                        "reloaded client certificate '{}'",
+                        self.identity.cert_file
+                    ));
+                }
+                Err(e) => log_warn(&format!(
+                    "failed to reload client certificate '{}': {e}, keeping the previous one",
+                    self.identity.cert_file
+                )),
+            }
+        }
+        current.1.clone()
+    }
+}
+
+impl<T> fmt::Debug for ReloadingClient<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReloadingClient")
+            .field("identity", &self.identity)
+            .finish_non_exhaustive()
+    }
+}
+
+impl HttpClient for ReloadingClient {
+    fn send_bytes<'a, 'b>(&'a self, request: Request<Bytes>) -> SendFuture<'b>
+    where
+        'a: 'b,
+        Self: 'b,
+    {
+        Box::pin(async move { self.client().send_bytes(request).await })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use super::*;
+
+    const CERT: &[u8] = include_bytes!("testdata/client.pem");
+    const KEY: &[u8] = include_bytes!("testdata/client.key");
+
+    fn touch(path: &str, contents: &[u8], modified: SystemTime) {
+        std::fs::write(path, contents).unwrap();
+        let file = std::fs::File::options().write(true).open(path).unwrap();
+        file.set_modified(modified).unwrap();
+    }
+
+    #[test]
+    fn test_reload_on_change() {
+        let dir = std::env::temp_dir().join(format!("haproxy-otel-tls-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let identity = ClientIdentity {
+            cert_file: dir.join("tls.crt").to_string_lossy().into_owned(),
+            key_file: dir.join("tls.key").to_string_lossy().into_owned(),
+        };
+        let start = SystemTime::now() - Duration::from_secs(60);
+        touch(&identity.cert_file, CERT, start);
+        touch(&identity.key_file, KEY, start);
+
+        let builds = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&builds);
+        let client = ReloadingClient::new(identity.clone(), move |identity| {
+            counter.fetch_add(1, Ordering::Relaxed);
+            Ok(reqwest::Client::builder()
+                .use_rustls_tls()
+                .identity(identity)
+                .build()?)
+        })
+        .unwrap();
+        client.client();
+        assert_eq!(builds.load(Ordering::Relaxed), 1);
+
+        // Rotated certificate
+        touch(&identity.cert_file, CERT, start + Duration::from_secs(10));
+        client.client();
+        client.client();
+        assert_eq!(builds.load(Ordering::Relaxed), 2);
+
+        // An invalid key keeps the previous client, without retrying on every request
+        touch(
+            &identity.key_file,
+            b"garbage",
+            start + Duration::from_secs(20),
+        );
+        client.client();
+        client.client();
+        assert_eq!(builds.load(Ordering::Relaxed), 2);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_read_missing_file() {
+        let identity = ClientIdentity {
+            cert_file: "/nonexistent/tls.crt".to_string(),
+            key_file: "/nonexistent/tls.key".to_string(),
+        };
+        assert!(identity
+            .read()
+            .unwrap_err()
+            .starts_with("failed to read '/nonexistent/tls.crt'"));
+        assert!(ReloadingClient::new(identity, |_| Ok(reqwest::Client::new())).is_err());
+    }
+}
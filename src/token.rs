@@ -0,0 +1,236 @@
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use opentelemetry_http::{Bytes, HttpClient, HttpError, Request, Response};
+use reqwest::header::{HeaderValue, AUTHORIZATION};
+
+use crate::exporter::{log_info, log_warn, SendFuture};
+
+/// Bearer token read from a file that is rotated on disk (`otlp.bearer_token_file`)
+#[derive(Debug)]
+pub(crate) struct TokenFile {
+    path: String,
+    // Modification time of the file when the token was read, and the token
+    cached: Mutex<(Option<SystemTime>, String)>,
+}
+
+impl TokenFile {
+    /// Reads the initial token, an unreadable or empty file is an error
+    pub(crate) fn new(path: &str) -> Result<Self, String> {
+        let modified = modified(path);
+        let token = read_token(path)?;
+        Ok(TokenFile {
+            path: path.to_string(),
+            cached: Mutex::new((modified, token)),
+        })
+    }
+
+    /// Current `Authorization` header value, the file is re-read when it changed
+    pub(crate) fn authorization(&self) -> String {
+        let mut cached = self.cached.lock().unwrap_or_else(|e| e.into_inner());
+        let modified = modified(&self.path);
+        if modified != cached.0 {
+            cached.0 = modified;
+            self.update(&mut cached.1);
+        }
+        format!("Bearer {}", cached.1)
+    }
+
+    /// Re-reads the file after the token was rejected, returns whether the token changed
+    pub(crate) fn reload(&self) -> bool {
+        let mut cached = self.cached.lock().unwrap_or_else(|e| e.into_inner());
+        cached.0 = modified(&self.path);
+        self.update(&mut cached.1)
+    }
+
+    fn update(&self, token: &mut String) -> bool {
+        match read_token(&self.path) {
+            Ok(new_token) if new_token != *token => {
+                *token = new_token;
+                log_info(&format!("reloaded bearer token from '{}'", self.path));
+                true
+            }
+            Ok(_) => false,
+            // Keep the previous token, the file may be in the middle of a rotation
+            Err(e) => {
+                log_warn(&format!("{e}, keeping the previous bearer token"));
+                false
+            }
+        }
+    }
+}
+
+fn modified(path: &str) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+fn read_token(path: &str) -> Result<String, String> {
+    let token = std::fs::read_to_string(path)
+        .map_err(|e| format!("failed to read bearer token file '{path}': {e}"))?;
+    let token = token.trim();
+    if token.is_empty() {
+        return Err(format!("bearer token file '{path}' is empty"));
+    }
+    HeaderValue::from_str(token)
+        .map_err(|_| format!("bearer token file '{path}' holds an invalid header value"))?;
+    Ok(token.to_string())
+}
+
+/// HTTP client of the OTLP HTTP exporters authenticating with a bearer token file.
+///
+/// A request rejected with `401 Unauthorized` is sent once more if re-reading the file
+/// yields a different token.
+#[derive(Debug)]
+pub(crate) struct BearerTokenClient<C> {
+    inner: C,
+    token: Arc<TokenFile>,
+}
+
+impl<C: HttpClient> BearerTokenClient<C> {
+    pub(crate) fn new(inner: C, token: Arc<TokenFile>) -> Self {
+        Self { inner, token }
+    }
+
+    async fn send(
+        &self,
+        mut request: Request<Bytes>,
+        authorization: String,
+    ) -> Result<Response<Bytes>, HttpError> {
+        request
+            .headers_mut()
+            .insert(AUTHORIZATION, HeaderValue::try_from(authorization)?);
+        self.inner.send_bytes(request).await
+    }
+}
+
+impl<C: HttpClient> HttpClient for BearerTokenClient<C> {
+    fn send_bytes<'a, 'b>(&'a self, request: Request<Bytes>) -> SendFuture<'b>
+    where
+        'a: 'b,
+        Self: 'b,
+    {
+        Box::pin(async move {
+            let retry = crate::retry::clone_request(&request)?;
+            let response = self.send(request, self.token.authorization()).await?;
+            if response.status() != reqwest::StatusCode::UNAUTHORIZED || !self.token.reload() {
+                return Ok(response);
+            }
+            self.send(retry, self.token.authorization()).await
+        })
+    }
+}
+
+/// Interceptor of the OTLP gRPC exporter adding the bearer token to every export
+#[cfg(feature = "grpc")]
+#[derive(Clone, Debug)]
+pub(crate) struct BearerTokenInterceptor(pub(crate) Arc<TokenFile>);
+
+#[cfg(feature = "grpc")]
+impl tonic::service::Interceptor for BearerTokenInterceptor {
+    fn call(
+        &mut self,
+        mut request: tonic::Request<()>,
+    ) -> Result<tonic::Request<()>, tonic::Status> {
+        let authorization = self
+            .0
+            .authorization()
+            .parse()
+            .map_err(|_| tonic::Status::unauthenticated("invalid bearer token"))?;
+        request
+            .metadata_mut()
+            .insert("authorization", authorization);
+        Ok(request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::fixtures::{response, HttpCollector};
+
+    #[test]
+    fn test_token_file() {
+        let path = std::env::temp_dir().join(format!("haproxy-otel-token-{}", std::process::id()));
+        let path = path.to_string_lossy().into_owned();
+        let touch = |contents: &str, age: u64| {
+            std::fs::write(&path, contents).unwrap();
+            let file = std::fs::File::options().write(true).open(&path).unwrap();
+            file.set_modified(SystemTime::now() - Duration::from_secs(age))
+                .unwrap();
+        };
+
+        touch("first\n", 60);
+        let token = TokenFile::new(&path).unwrap();
+        assert_eq!(token.authorization(), "Bearer first");
+
+        // Rotated on disk
+        touch("second", 50);
+        assert_eq!(token.authorization(), "Bearer second");
+
+        // An empty file (rotation in progress) keeps the previous token
+        touch("", 40);
+        assert_eq!(token.authorization(), "Bearer second");
+        assert!(!token.reload());
+
+        // A rejected token is re-read even if the modification time did not change
+        let modified = std::fs::metadata(&path).unwrap().modified().unwrap();
+        std::fs::write(&path, "third").unwrap();
+        let file = std::fs::File::options().write(true).open(&path).unwrap();
+        file.set_modified(modified).unwrap();
+        assert!(token.reload());
+        assert_eq!(token.authorization(), "Bearer third");
+        assert!(!token.reload());
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(TokenFile::new(&path).is_err());
+    }
+
+    #[test]
+    fn test_retry_unauthorized() {
+        let path = std::env::temp_dir().join(format!("haproxy-otel-401-{}", std::process::id()));
+        std::fs::write(&path, "expired").unwrap();
+        let token = Arc::new(TokenFile::new(&path.to_string_lossy()).unwrap());
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        // Accepts `Bearer valid` only, records the authorization of every request
+        let authorizations = Arc::clone(&seen);
+        let collector = HttpCollector::new(move |request: &Request<Bytes>| {
+            let authorization = request.headers()[AUTHORIZATION].to_str()?.to_string();
+            let status = match authorization.as_str() {
+                "Bearer valid" => 200,
+                _ => 401,
+            };
+            authorizations.lock().unwrap().push(authorization);
+            response(status)
+        });
+        let client = BearerTokenClient::new(collector, token);
+        let send = || {
+            let request = Request::post("http://collector/v1/traces")
+                .body(Bytes::from_static(b"spans"))
+                .unwrap();
+            crate::exporter::get_otel_runtime()
+                .block_on(client.send_bytes(request))
+                .unwrap()
+                .status()
+        };
+
+        // Unchanged token: no retry
+        assert_eq!(send(), 401);
+        assert_eq!(*seen.lock().unwrap(), vec!["Bearer expired"]);
+
+        // Rotated without a new modification time: the 401 triggers a re-read
+        let modified = std::fs::metadata(&path).unwrap().modified().unwrap();
+        std::fs::write(&path, "valid").unwrap();
+        let file = std::fs::File::options().write(true).open(&path).unwrap();
+        file.set_modified(modified).unwrap();
+        seen.lock().unwrap().clear();
+        assert_eq!(send(), 200);
+        assert_eq!(
+            *seen.lock().unwrap(),
+            vec!["Bearer expired", "Bearer valid"]
+        );
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,86 @@
+use std::collections::HashMap;
+
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry::KeyValue;
+use opentelemetry_sdk::trace::{SdkTracer, SdkTracerProvider};
+
+/// Scope name of the default tracer
+pub(crate) const DEFAULT_SCOPE: &str = "haproxy-otel";
+
+/// Private variable carrying the `tracer=` filter argument to `start_server_span`
+pub(crate) const TRACER_VAR: &str = "txn.__otel_tracer";
+
+/// A tracer declared in the `tracers` option, selected per frontend with the
+/// `tracer=<name>` filter argument
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct TracerConfig {
+    // Instrumentation scope name, defaults to the tracer name
+    pub(crate) scope: Option<String>,
+    // Attributes added to the spans of the tracer unless already set
+    pub(crate) attributes: Vec<(String, String)>,
+}
+
+pub(crate) type TracerConfigs = HashMap<String, TracerConfig>;
+
+/// A named tracer of an initialized pipeline
+#[derive(Debug)]
+pub(crate) struct NamedTracer {
+    pub(crate) tracer: SdkTracer,
+    pub(crate) attributes: Vec<KeyValue>,
+}
+
+/// Creates the named tracers from the pipeline's provider
+pub(crate) fn named_tracers(
+    provider: &SdkTracerProvider,
+    configs: &TracerConfigs,
+) -> HashMap<String, NamedTracer> {
+    configs
+        .iter()
+        .map(|(name, config)| {
+            let scope = config.scope.clone().unwrap_or_else(|| name.clone());
+            let attributes = config
+                .attributes
+                .iter()
+                .map(|(key, value)| KeyValue::new(key.clone(), value.clone()))
+                .collect();
+            let tracer = NamedTracer {
+                tracer: provider.tracer(scope),
+                attributes,
+            };
+            (name.clone(), tracer)
+        })
+        .collect()
+}
+
+/// Adds the default attributes whose keys are not set yet
+pub(crate) fn add_defaults(attributes: &mut Vec<KeyValue>, defaults: &[KeyValue]) {
+    for default in defaults {
+        if !attributes.iter().any(|kv| kv.key == default.key) {
+            attributes.push(default.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_add_defaults() {
+        let mut attributes = vec![KeyValue::new("team", "payments")];
+        add_defaults(
+            &mut attributes,
+            &[
+                KeyValue::new("team", "edge"),
+                KeyValue::new("tier", "public"),
+            ],
+        );
+        assert_eq!(
+            attributes,
+            vec![
+                KeyValue::new("team", "payments"),
+                KeyValue::new("tier", "public")
+            ]
+        );
+    }
+}
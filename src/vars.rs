@@ -0,0 +1,166 @@
+use std::collections::BTreeSet;
+use std::env;
+
+/// Scopes of HAProxy variables
+const SCOPES: [&str; 5] = ["proc", "sess", "txn", "req", "res"];
+
+/// Configuration files loaded by HAProxy, separated by semicolons (set by HAProxy)
+const CFGFILES_ENV: &str = "HAPROXY_CFGFILES";
+
+/// Prefix of the attributes recorded for the `export_vars` option
+const ATTRIBUTE_PREFIX: &str = "haproxy.var.";
+
+/// Checks an `export_vars` pattern, e.g. `txn.rate_*`
+pub(crate) fn validate_pattern(pattern: &str) -> Result<(), String> {
+    match pattern.split_once('.') {
+        Some((scope, name)) if SCOPES.contains(&scope) && !name.is_empty() => Ok(()),
+        _ => Err(format!(
+            "expected '<scope>.<name>' with a scope of {}, got '{pattern}'",
+            SCOPES.join(", ")
+        )),
+    }
+}
+
+/// Resolves `export_vars` patterns to variable names.
+///
+/// HAProxy has no API listing variables, so wildcard patterns are matched against the
+/// variables referenced in the loaded configuration files. Patterns without a wildcard
+/// are always kept, which covers variables set from Lua only.
+pub(crate) fn resolve(patterns: &[String]) -> Vec<String> {
+    let mut names = BTreeSet::new();
+    let mut declared = BTreeSet::new();
+    if patterns.iter().any(|pattern| pattern.contains('*')) {
+        for (_, config) in config_files() {
+            declared.extend(declared_vars(&String::from_utf8_lossy(&config)));
+        }
+    }
+    for pattern in patterns {
+        if !pattern.contains('*') {
+            names.insert(pattern.clone());
+            continue;
+        }
+        let before = names.len();
+        names.extend(
+            declared
+                .iter()
+                .filter(|name| matches(pattern, name))
+                .cloned(),
+        );
+        if names.len() == before {
+            crate::exporter::log_warn(&format!(
+                "export_vars: no variable of the configuration matches '{pattern}'"
+            ));
+        }
+    }
+    names.into_iter().collect()
+}
+
+/// Configuration files loaded by HAProxy and their contents, unreadable files are skipped
+pub(crate) fn config_files() -> Vec<(String, Vec<u8>)> {
+    let files = env::var(CFGFILES_ENV).unwrap_or_default();
+    files
+        .split(';')
+        .filter(|file| !file.is_empty())
+        .filter_map(|file| match std::fs::read(file) {
+            Ok(contents) => Some((file.to_string(), contents)),
+            Err(e) => {
+                crate::exporter::log_debug(&format!(
+                    "failed to read configuration file '{file}': {e}"
+                ));
+                None
+            }
+        })
+        .collect()
+}
+
+/// Attribute key of an exported variable
+pub(crate) fn attribute_key(name: &str) -> String {
+    format!("{ATTRIBUTE_PREFIX}{name}")
+}
+
+/// Names of the variables referenced by `var()`, `set-var()`, `set-var-fmt()` and
+/// `unset-var()` in a configuration
+fn declared_vars(config: &str) -> BTreeSet<String> {
+    let mut names = BTreeSet::new();
+    for line in config.lines() {
+        let line = line.trim_start();
+        if line.starts_with('#') {
+            continue;
+        }
+        for marker in ["var(", "var-fmt("] {
+            for (start, _) in line.match_indices(marker) {
+                let rest = &line[start + marker.len()..];
+                let end = rest
+                    .find(|c: char| c == ')' || c == ',' || c.is_whitespace())
+                    .unwrap_or(rest.len());
+                let name = &rest[..end];
+                if validate_pattern(name).is_ok() && !name.contains('*') {
+                    names.insert(name.to_string());
+                }
+            }
+        }
+    }
+    names
+}
+
+/// Matches a variable name against a pattern where `*` matches any characters
+fn matches(pattern: &str, name: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = name.strip_prefix(first) else {
+        return false;
+    };
+    let mut parts = parts.peekable();
+    while let Some(part) = parts.next() {
+        if parts.peek().is_none() {
+            return rest.len() >= part.len() && rest.ends_with(part);
+        }
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+    rest.is_empty()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matches() {
+        assert!(matches("req.*", "req.backend"));
+        assert!(matches("txn.rate_*", "txn.rate_limit"));
+        assert!(!matches("txn.rate_*", "txn.ratio"));
+        assert!(!matches("req.*", "txn.req"));
+        assert!(matches("txn.*_id", "txn.user_id"));
+        assert!(!matches("txn.*_id", "txn.user_idx"));
+        assert!(matches("txn.a*b*c", "txn.axxbyyc"));
+        assert!(!matches("txn.ab*b", "txn.ab"));
+        assert!(matches("txn.exact", "txn.exact"));
+        assert!(!matches("txn.exact", "txn.exactly"));
+    }
+
+    #[test]
+    fn test_declared_vars() {
+        let config = "\
+            frontend fe\n\
+            \x20   http-request set-var(txn.rate_limit) sc_http_req_rate(0)\n\
+            \x20   http-request set-var-fmt(req.backend,ifnotexists) %[req.hdr(host)]\n\
+            \x20   http-request deny if { var(txn.rate_limit),sub(txn.max) gt 0 }\n\
+            \x20   # http-request set-var(txn.commented) str(x)\n\
+            \x20   http-request unset-var(sess.user)\n\
+            \x20   http-request set-header x-user %[var(sess.user)] if { var(nope) -m found }\n";
+        let names = declared_vars(config).into_iter().collect::<Vec<_>>();
+        assert_eq!(names, vec!["req.backend", "sess.user", "txn.rate_limit"]);
+    }
+
+    #[test]
+    fn test_validate_pattern() {
+        assert!(validate_pattern("req.*").is_ok());
+        assert!(validate_pattern("proc.version").is_ok());
+        assert!(validate_pattern("req.").is_err());
+        assert!(validate_pattern("rate_*").is_err());
+        assert!(validate_pattern("conn.*").is_err());
+    }
+}
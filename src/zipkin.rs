@@ -0,0 +1,90 @@
+use opentelemetry_http::{Bytes, HttpClient, Request};
+use serde_json::{Map, Value};
+
+use crate::exporter::SendFuture;
+
+/// Zipkin collector of `exporter = "zipkin"` without `zipkin.endpoint` and the
+/// `OTEL_EXPORTER_ZIPKIN_ENDPOINT` variable
+pub(crate) const DEFAULT_ENDPOINT: &str = "http://127.0.0.1:9411/api/v2/spans";
+
+/// Parent id the Zipkin exporter sends for root spans
+const NO_PARENT: &str = "0000000000000000";
+
+/// Adds the service name to the spans of a Zipkin v2 JSON body and drops the parent id of
+/// root spans. `None` when the body is not a list of spans.
+fn add_service_name(body: &[u8], service_name: &str) -> Option<Vec<u8>> {
+    let mut spans = serde_json::from_slice::<Vec<Map<String, Value>>>(body).ok()?;
+    for span in &mut spans {
+        let endpoint = span
+            .entry("localEndpoint")
+            .or_insert_with(|| Value::Object(Map::new()));
+        if let Value::Object(endpoint) = endpoint {
+            endpoint.insert("serviceName".to_string(), service_name.into());
+        }
+        if span.get("parentId").and_then(Value::as_str) == Some(NO_PARENT) {
+            span.remove("parentId");
+        }
+    }
+    serde_json::to_vec(&spans).ok()
+}
+
+/// HTTP client of the Zipkin exporter setting `localEndpoint.serviceName`, which the
+/// `opentelemetry-zipkin` exporter leaves out: Zipkin would otherwise list every span
+/// under an unknown service.
+#[derive(Debug)]
+pub(crate) struct ServiceNameClient<C> {
+    inner: C,
+    service_name: String,
+}
+
+impl<C: HttpClient> ServiceNameClient<C> {
+    pub(crate) fn new(inner: C, service_name: &str) -> Self {
+        Self {
+            inner,
+            service_name: service_name.to_string(),
+        }
+    }
+}
+
+impl<C: HttpClient> HttpClient for ServiceNameClient<C> {
+    fn send_bytes<'a, 'b>(&'a self, request: Request<Bytes>) -> SendFuture<'b>
+    where
+        'a: 'b,
+        Self: 'b,
+    {
+        Box::pin(async move {
+            let (parts, body) = request.into_parts();
+            let body = match add_service_name(&body, &self.service_name) {
+                Some(body) => Bytes::from(body),
+                None => body,
+            };
+            self.inner
+                .send_bytes(Request::from_parts(parts, body))
+                .await
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_add_service_name() {
+        let body = br#"[
+            {"traceId":"4bf92f3577b34da6a3ce929d0e0e4736","id":"00f067aa0ba902b7",
+             "parentId":"0000000000000000","name":"GET example.com","localEndpoint":{}},
+            {"traceId":"4bf92f3577b34da6a3ce929d0e0e4736","id":"53995c3f42cd8ad8",
+             "parentId":"00f067aa0ba902b7","name":"upstream"}
+        ]"#;
+        let body = add_service_name(body, "haproxy-ingress").unwrap();
+        let spans = serde_json::from_slice::<Vec<Value>>(&body).unwrap();
+        assert_eq!(spans[0]["localEndpoint"]["serviceName"], "haproxy-ingress");
+        assert_eq!(spans[0].get("parentId"), None);
+        assert_eq!(spans[0]["name"], "GET example.com");
+        assert_eq!(spans[1]["localEndpoint"]["serviceName"], "haproxy-ingress");
+        assert_eq!(spans[1]["parentId"], "00f067aa0ba902b7");
+
+        assert_eq!(add_service_name(b"not json", "haproxy-ingress"), None);
+    }
+}
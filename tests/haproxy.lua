@@ -6,6 +6,19 @@ opentelemetry.register({
 		endpoint = "http://127.0.0.1:4317",
 		protocol = "http/json",
 	},
-	sampler = "AlwaysOn",
-	propagator = "zipkin",
+	-- Set by the propagator/sampler matrix of the integration tests
+	sampler = os.getenv("HAPROXY_OTEL_TEST_SAMPLER") or "AlwaysOn",
+	propagator = os.getenv("HAPROXY_OTEL_TEST_PROPAGATOR") or "zipkin",
+	-- Set by the deterministic ids test
+	id_seed = tonumber(os.getenv("HAPROXY_OTEL_TEST_ID_SEED") or ""),
 })
+
+-- Trace context cache size, read by the soak test
+core.register_service("otel_metrics", "http", function(applet)
+	local response = "haproxy_otel_cache_size " .. tostring(opentelemetry.cache_size()) .. "\n"
+	applet:set_status(200)
+	applet:add_header("content-length", string.len(response))
+	applet:add_header("content-type", "text/plain")
+	applet:start_response()
+	applet:send(response)
+end)
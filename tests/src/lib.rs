@@ -1,13 +1,16 @@
 #![cfg(test)]
 
+use std::collections::{HashMap, HashSet};
 use std::net::TcpListener;
 
 use serde_json::{json, Value as JsonValue};
 use std::time::Duration;
 use tokio::time::timeout;
-use wiremock::matchers::{method, path};
+use wiremock::matchers::{method, path, path_regex};
 use wiremock::{Mock, MockGuard, MockServer, ResponseTemplate};
 
+mod soak;
+
 /// Check if HAProxy has Lua support
 fn haproxy_has_lua_support() -> bool {
     let output = std::process::Command::new("haproxy")
@@ -34,32 +37,84 @@ async fn integration_tests() {
         return;
     }
 
-    // Compile haproxy-otel-module
+    build_module().await;
+
+    // Start the mock server on port 4317
+    let listener = TcpListener::bind("127.0.0.1:4317").unwrap();
+    let mock_server = MockServer::builder().listener(listener).start().await;
+
+    // Default configuration of haproxy.lua
+    let haproxy = start_haproxy(&[]).await;
+    run_tests(&mock_server).await.expect("Tests failed");
+    run_h2_streams_test(&mock_server).await;
+    run_no_server_test(&mock_server).await;
+    stop_haproxy(haproxy).await;
+
+    // Every propagator with every sampler
+    for propagator in Propagator::ALL {
+        for sampler in SAMPLERS {
+            println!("Matrix case: propagator={propagator:?} sampler={sampler}");
+            let haproxy = start_haproxy(&[
+                ("HAPROXY_OTEL_TEST_PROPAGATOR", propagator.option()),
+                ("HAPROXY_OTEL_TEST_SAMPLER", sampler),
+            ])
+            .await;
+            run_matrix_case(&mock_server, propagator, sampler).await;
+            stop_haproxy(haproxy).await;
+        }
+    }
+
+    // Same ids for the same requests, whatever thread handles them and in which order
+    let mut runs = Vec::new();
+    for reverse in [false, true] {
+        let haproxy = start_haproxy(&[("HAPROXY_OTEL_TEST_ID_SEED", "42")]).await;
+        runs.push(seeded_trace_ids(&mock_server, reverse).await);
+        stop_haproxy(haproxy).await;
+    }
+    assert_eq!(runs[0], runs[1], "Seeded trace ids differ between runs");
+}
+
+/// Compiles haproxy-otel-module, loaded by haproxy.cfg from the release target directory
+async fn build_module() {
     tokio::process::Command::new("cargo")
         .args(&["build", "--release", "-p", "haproxy-otel-module"])
         .current_dir("..")
         .status()
         .await
         .expect("Failed to compile haproxy-otel-module");
+}
 
-    // Start the mock server on port 4317
-    let listener = TcpListener::bind("127.0.0.1:4317").unwrap();
-    let mock_server = MockServer::builder().listener(listener).start().await;
-
-    // Spawn haproxy and wait
-    let mut haproxy = tokio::process::Command::new("haproxy")
+/// Spawns haproxy with the given environment and waits until it accepts requests
+async fn start_haproxy(env: &[(&str, &str)]) -> tokio::process::Child {
+    let haproxy = tokio::process::Command::new("haproxy")
         .args(&["-f", "haproxy.cfg"])
         .env("RUST_LOG", "debug")
         .env("OTEL_LOG_LEVEL", "debug")
+        .envs(env.iter().copied())
         .kill_on_drop(true)
         .spawn()
         .expect("Failed to start haproxy");
-    tokio::time::sleep(Duration::from_secs(1)).await;
-
-    // Run the tests
-    run_tests(&mock_server).await.expect("Tests failed");
+    wait_for_frontend(true).await;
+    haproxy
+}
 
+/// Stops haproxy and waits until its frontend port is released for the next case
+async fn stop_haproxy(mut haproxy: tokio::process::Child) {
     haproxy.kill().await.expect("Failed to stop haproxy");
+    wait_for_frontend(false).await;
+}
+
+async fn wait_for_frontend(listening: bool) {
+    for _ in 0..50 {
+        let connected = tokio::net::TcpStream::connect("127.0.0.1:8082")
+            .await
+            .is_ok();
+        if connected == listening {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    panic!("haproxy frontend still listening={}", !listening);
 }
 
 /// Set up the scoped mock for regular HTTP requests (for testing propagation)
@@ -254,9 +309,339 @@ async fn run_tests(server: &MockServer) -> Result<(), Box<dyn std::error::Error>
     Ok(())
 }
 
+/// Streams multiplexed on one HTTP/2 connection
+const H2_STREAMS: usize = 8;
+
+/// Sends concurrent streams on one h2c connection, all with the same `traceparent` like a
+/// buggy client would, and checks that every request gets its own spans
+async fn run_h2_streams_test(server: &MockServer) {
+    // The delay keeps the streams in flight together
+    let _http_mock = Mock::given(method("GET"))
+        .and(path_regex("^/stream/[0-9]+$"))
+        .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_millis(100)))
+        .expect(H2_STREAMS as u64)
+        .named("HTTP/2 streams mock")
+        .mount_as_scoped(server)
+        .await;
+    let otlp_mock = Mock::given(method("POST"))
+        .and(path("/v1/traces"))
+        .respond_with(ResponseTemplate::new(200))
+        .named("OTLP Mock")
+        .mount_as_scoped(server)
+        .await;
+
+    let client = reqwest::Client::builder()
+        .http2_prior_knowledge()
+        .build()
+        .unwrap();
+    let traceparent = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+    let requests = (0..H2_STREAMS).map(|i| {
+        let request = client
+            .get(format!("http://127.0.0.1:8082/stream/{i}"))
+            .header("traceparent", traceparent);
+        tokio::spawn(async move {
+            let response = request.send().await.unwrap();
+            assert_eq!(response.version(), reqwest::Version::HTTP_2);
+            assert_eq!(response.status(), 200);
+            let header = |name: &str| response.headers()[name].to_str().unwrap().to_string();
+            (format!("/stream/{i}"), header("x-span-id"))
+        })
+    });
+    let mut streams = Vec::new();
+    for request in requests.collect::<Vec<_>>() {
+        streams.push(request.await.unwrap());
+    }
+
+    // Every stream has its own server span, the one returned in its response
+    let span_ids = streams.iter().map(|(_, id)| id).collect::<HashSet<_>>();
+    assert_eq!(span_ids.len(), H2_STREAMS, "Streams share a span id");
+
+    let mut spans = Vec::new();
+    for _ in 0..100 {
+        spans = (otlp_mock.received_requests().await.iter())
+            .flat_map(|request| {
+                let body = request.body_json::<JsonValue>().unwrap();
+                let spans = body.pointer("/resourceSpans/0/scopeSpans/0/spans").cloned();
+                spans
+                    .and_then(|s| s.as_array().cloned())
+                    .unwrap_or_default()
+            })
+            .collect::<Vec<_>>();
+        if spans.len() >= 2 * H2_STREAMS {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert_eq!(spans.len(), 2 * H2_STREAMS, "Missing or extra spans");
+
+    for (url_path, span_id) in &streams {
+        let server_span = (spans.iter())
+            .find(|span| span["kind"].as_i64() == Some(2) && span["spanId"] == span_id.as_str())
+            .expect("Server span of the stream not exported");
+        assert_eq!(
+            find_attribute(&server_span["attributes"], "url.path"),
+            Some(url_path.as_str())
+        );
+        assert_eq!(server_span["parentSpanId"], "00f067aa0ba902b7");
+        // The client span of the stream is the child of its own server span
+        let client_span = (spans.iter())
+            .find(|span| {
+                span["kind"].as_i64() == Some(3) && span["parentSpanId"] == span_id.as_str()
+            })
+            .expect("Client span of the stream not exported");
+        assert_eq!(
+            find_attribute(&client_span["attributes"], "url.path"),
+            Some(url_path.as_str())
+        );
+    }
+}
+
+/// Requests sent concurrently with `id_seed`, each on its own connection
+const SEEDED_REQUESTS: usize = 8;
+
+/// Sends the `id_seed` test requests, returns the trace id of each path
+async fn seeded_trace_ids(server: &MockServer, reverse: bool) -> HashMap<String, String> {
+    let _http_mock = Mock::given(method("GET"))
+        .and(path_regex("^/seed/[0-9]+$"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(SEEDED_REQUESTS as u64)
+        .named("Seeded ids mock")
+        .mount_as_scoped(server)
+        .await;
+    let _otlp_mock = Mock::given(method("POST"))
+        .and(path("/v1/traces"))
+        .respond_with(ResponseTemplate::new(200))
+        .named("OTLP Mock")
+        .mount_as_scoped(server)
+        .await;
+
+    let mut paths = (0..SEEDED_REQUESTS)
+        .map(|i| format!("/seed/{i}"))
+        .collect::<Vec<_>>();
+    if reverse {
+        paths.reverse();
+    }
+    let requests = paths.into_iter().map(|url_path| {
+        tokio::spawn(async move {
+            let response = reqwest::get(format!("http://127.0.0.1:8082{url_path}"))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), 200);
+            let trace_id = response.headers()["x-trace-id"]
+                .to_str()
+                .unwrap()
+                .to_string();
+            (url_path, trace_id)
+        })
+    });
+    let mut trace_ids = HashMap::new();
+    for request in requests.collect::<Vec<_>>() {
+        let (url_path, trace_id) = request.await.unwrap();
+        trace_ids.insert(url_path, trace_id);
+    }
+    let distinct = trace_ids.values().collect::<HashSet<_>>();
+    assert_eq!(distinct.len(), SEEDED_REQUESTS, "Requests share a trace id");
+    trace_ids
+}
+
+/// A backend without servers: HAProxy's own 503 marks both spans with `haproxy.no_server`
+async fn run_no_server_test(server: &MockServer) {
+    let otlp_mock = mount_otlp_mock(server).await;
+    let response = reqwest::get("http://127.0.0.1:8082/no-server")
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 503);
+
+    timeout(Duration::from_secs(10), otlp_mock.wait_until_satisfied())
+        .await
+        .unwrap();
+    let otlp_request = otlp_mock.received_requests().await.pop().unwrap();
+    let spans = otlp_request.body_json::<JsonValue>().unwrap();
+    let spans_array = spans
+        .pointer("/resourceSpans/0/scopeSpans/0/spans")
+        .and_then(|s| s.as_array())
+        .expect("Could not find spans array");
+    assert_eq!(spans_array.len(), 2);
+    let no_server = |span: &JsonValue| {
+        (span["attributes"].as_array().unwrap().iter())
+            .any(|attr| attr["key"] == "haproxy.no_server" && attr["value"]["boolValue"] == true)
+    };
+    let client_span = (spans_array.iter())
+        .find(|span| span["kind"].as_i64() == Some(3))
+        .expect("Client span (kind=3) not found");
+    assert!(no_server(client_span));
+    assert_eq!(client_span["status"]["code"], 2);
+    assert_eq!(client_span["status"]["message"], "no server available");
+    let server_span = (spans_array.iter())
+        .find(|span| span["kind"].as_i64() == Some(2))
+        .expect("Server span (kind=2) not found");
+    assert!(no_server(server_span));
+    assert_eq!(
+        client_span["parentSpanId"], server_span["spanId"],
+        "Client span's parent ID should match server span's ID"
+    );
+}
+
 fn find_attribute<'a>(attributes: &'a JsonValue, key: &str) -> Option<&'a str> {
     (attributes.as_array()?)
         .iter()
         .find(|attr| attr["key"] == key)
         .and_then(|attr| attr.pointer("/value/stringValue").and_then(|v| v.as_str()))
 }
+
+/// `sampler` values of the matrix (the ratio samplers are per tenant, not global)
+const SAMPLERS: [&str; 3] = ["AlwaysOn", "AlwaysOff", "ParentBased"];
+
+/// Trace context formats of the `propagator` option
+#[derive(Clone, Copy, Debug)]
+enum Propagator {
+    W3c,
+    Zipkin,
+    Jaeger,
+}
+
+/// Trace id, span id and sampled flag of a trace context
+type TraceContext = (String, String, bool);
+
+impl Propagator {
+    const ALL: [Propagator; 3] = [Propagator::W3c, Propagator::Zipkin, Propagator::Jaeger];
+
+    fn option(self) -> &'static str {
+        match self {
+            Propagator::W3c => "w3c",
+            Propagator::Zipkin => "zipkin",
+            Propagator::Jaeger => "jaeger",
+        }
+    }
+
+    /// Request headers of a remote parent in this format
+    fn parent_headers(self, (trace_id, span_id, sampled): &TraceContext) -> Vec<(&str, String)> {
+        let flag = if *sampled { "1" } else { "0" };
+        match self {
+            Propagator::W3c => vec![("traceparent", format!("00-{trace_id}-{span_id}-0{flag}"))],
+            Propagator::Zipkin => vec![
+                ("x-b3-traceid", trace_id.clone()),
+                ("x-b3-spanid", span_id.clone()),
+                ("x-b3-sampled", flag.to_string()),
+            ],
+            Propagator::Jaeger => vec![("uber-trace-id", format!("{trace_id}:{span_id}:0:{flag}"))],
+        }
+    }
+
+    /// Trace context injected into a request received by the upstream server
+    fn injected(self, request: &wiremock::Request) -> Option<TraceContext> {
+        let header = |name: &str| {
+            let (_, values) = (request.headers.iter())
+                .find(|(header, _)| header.as_str().eq_ignore_ascii_case(name))?;
+            Some(values.last().as_str().to_string())
+        };
+        match self {
+            Propagator::W3c => {
+                let traceparent = header("traceparent")?;
+                let parts = traceparent.split('-').collect::<Vec<_>>();
+                let flags = u8::from_str_radix(parts.get(3)?, 16).ok()?;
+                Some((parts[1].to_string(), parts[2].to_string(), flags & 1 == 1))
+            }
+            Propagator::Zipkin => Some((
+                header("x-b3-traceid")?,
+                header("x-b3-spanid")?,
+                header("x-b3-sampled").as_deref() == Some("1"),
+            )),
+            Propagator::Jaeger => {
+                let uber_trace_id = header("uber-trace-id")?;
+                let parts = uber_trace_id.split(':').collect::<Vec<_>>();
+                let flags = parts.get(3)?.parse::<u8>().ok()?;
+                let trace_id = format!("{:0>32}", parts[0]);
+                Some((trace_id, parts[1].to_string(), flags & 1 == 1))
+            }
+        }
+    }
+}
+
+/// Sends requests with and without a remote parent and checks the injected headers and
+/// the exported spans against the sampler's decision
+async fn run_matrix_case(server: &MockServer, propagator: Propagator, sampler: &str) {
+    let sampled_parent = (
+        "4bf92f3577b34da6a3ce929d0e0e4736".to_string(),
+        "00f067aa0ba902b7".to_string(),
+        true,
+    );
+    let unsampled_parent = (sampled_parent.0.clone(), sampled_parent.1.clone(), false);
+    let mut requests = vec![(None, sampler != "AlwaysOff")];
+    if sampler == "ParentBased" {
+        requests.push((Some(sampled_parent), true));
+        requests.push((Some(unsampled_parent), false));
+    }
+
+    for (parent, sampled) in requests {
+        let case = format!("propagator={propagator:?} sampler={sampler} parent={parent:?}");
+        let http_mock = mount_http_mock(server).await;
+        let otlp_mock = Mock::given(method("POST"))
+            .and(path("/v1/traces"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({})))
+            .expect(u64::from(sampled))
+            .named("OTLP Mock")
+            .mount_as_scoped(server)
+            .await;
+
+        let mut request = reqwest::Client::new().get("http://127.0.0.1:8082/test");
+        for (name, value) in parent
+            .as_ref()
+            .map_or(Vec::new(), |p| propagator.parent_headers(p))
+        {
+            request = request.header(name, value);
+        }
+        let response = request.send().await.expect("Request to haproxy failed");
+        assert_eq!(response.status(), 200, "{case}");
+
+        // The upstream receives the client span's context in the configured format
+        let upstream = (http_mock.received_requests().await)
+            .pop()
+            .expect("No HTTP test requests were received");
+        let (trace_id, span_id, injected_sampled) = propagator
+            .injected(&upstream)
+            .unwrap_or_else(|| panic!("{case}: no trace context injected"));
+        assert_eq!(injected_sampled, sampled, "{case}: injected sampled flag");
+        if let Some((parent_trace_id, parent_span_id, _)) = &parent {
+            assert_eq!(&trace_id, parent_trace_id, "{case}: trace id of the parent");
+            assert_ne!(
+                &span_id, parent_span_id,
+                "{case}: span id of the client span"
+            );
+        }
+
+        if !sampled {
+            // Give the batch processor a chance to export what it shouldn't
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            assert!(
+                otlp_mock.received_requests().await.is_empty(),
+                "{case}: unsampled spans were exported"
+            );
+            continue;
+        }
+        timeout(Duration::from_secs(10), otlp_mock.wait_until_satisfied())
+            .await
+            .unwrap_or_else(|_| panic!("{case}: no spans exported"));
+        let otlp_request = otlp_mock.received_requests().await.pop().unwrap();
+        let spans = otlp_request.body_json::<JsonValue>().unwrap();
+        let spans = spans
+            .pointer("/resourceSpans/0/scopeSpans/0/spans")
+            .and_then(|s| s.as_array())
+            .expect("Could not find spans array");
+        let client_span = (spans.iter())
+            .find(|span| span["kind"].as_i64() == Some(3))
+            .unwrap_or_else(|| panic!("{case}: client span not found"));
+        let server_span = (spans.iter())
+            .find(|span| span["kind"].as_i64() == Some(2))
+            .unwrap_or_else(|| panic!("{case}: server span not found"));
+
+        // The injected context is the client span's, in the server span's trace
+        assert_eq!(client_span["traceId"], trace_id.as_str(), "{case}");
+        assert_eq!(client_span["spanId"], span_id.as_str(), "{case}");
+        assert_eq!(client_span["parentSpanId"], server_span["spanId"], "{case}");
+        let parent_span_id = parent
+            .as_ref()
+            .map_or("", |(_, span_id, _)| span_id.as_str());
+        assert_eq!(server_span["parentSpanId"], parent_span_id, "{case}");
+    }
+}
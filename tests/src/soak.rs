@@ -0,0 +1,251 @@
+//! Soak test: drives HAProxy with tens of thousands of requests, checks that no span is lost
+//! and that the trace context cache and the memory of HAProxy stay bounded, and reports the
+//! per-request overhead of the module against a frontend without it.
+//!
+//! Ignored by default (it takes a while and binds the same ports as `integration_tests`):
+//!
+//! ```sh
+//! cargo test -p haproxy-otel-tests --release -- --ignored soak --nocapture
+//! ```
+//!
+//! `SOAK_REQUESTS` (20000), `SOAK_CONCURRENCY` (32) and `SOAK_MAX_RSS_GROWTH_MB` (64) tune
+//! the run.
+
+use std::net::TcpListener;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use serde_json::{json, Value as JsonValue};
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, Request, Respond, ResponseTemplate};
+
+/// Frontend with the module (`http-in`)
+const TRACED_URL: &str = "http://127.0.0.1:8082/test";
+/// Frontend without the module, same backend (`baseline`)
+const BASELINE_URL: &str = "http://127.0.0.1:8083/test";
+/// `otel_metrics` service of tests/haproxy.lua
+const METRICS_URL: &str = "http://127.0.0.1:8084/";
+
+fn env_or(name: &str, default: u64) -> u64 {
+    match std::env::var(name) {
+        Ok(value) => value
+            .parse()
+            .unwrap_or_else(|_| panic!("{name} must be a number, got '{value}'")),
+        Err(_) => default,
+    }
+}
+
+/// OTLP collector counting the exported spans, without recording the requests
+struct SpanCounter(Arc<AtomicUsize>);
+
+impl Respond for SpanCounter {
+    fn respond(&self, request: &Request) -> ResponseTemplate {
+        let spans = (request.body_json::<JsonValue>())
+            .map(|body| count_spans(&body))
+            .unwrap_or_default();
+        self.0.fetch_add(spans, Ordering::Relaxed);
+        ResponseTemplate::new(200).set_body_json(json!({}))
+    }
+}
+
+fn count_spans(body: &JsonValue) -> usize {
+    let resource_spans = body["resourceSpans"].as_array().into_iter().flatten();
+    resource_spans
+        .flat_map(|resource| resource["scopeSpans"].as_array().into_iter().flatten())
+        .map(|scope| scope["spans"].as_array().map_or(0, Vec::len))
+        .sum()
+}
+
+/// Sends `requests` requests to `url` from `concurrency` clients, returns the sorted latencies
+async fn drive(url: &'static str, requests: u64, concurrency: u64) -> Vec<Duration> {
+    let client = reqwest::Client::new();
+    let workers = (0..concurrency)
+        .map(|worker| {
+            let client = client.clone();
+            let count = requests / concurrency + u64::from(worker < requests % concurrency);
+            tokio::spawn(async move {
+                let mut latencies = Vec::with_capacity(count as usize);
+                for _ in 0..count {
+                    let start = Instant::now();
+                    let response = client.get(url).send().await.expect("Request failed");
+                    assert_eq!(response.status(), 200, "{url}");
+                    response.bytes().await.expect("Response body failed");
+                    latencies.push(start.elapsed());
+                }
+                latencies
+            })
+        })
+        .collect::<Vec<_>>();
+    let mut latencies = Vec::with_capacity(requests as usize);
+    for worker in workers {
+        latencies.extend(worker.await.expect("Load worker panicked"));
+    }
+    latencies.sort();
+    latencies
+}
+
+/// Mean, median and 99th percentile of sorted latencies
+fn summarize(latencies: &[Duration]) -> (Duration, Duration, Duration) {
+    let mean = latencies.iter().sum::<Duration>() / latencies.len().max(1) as u32;
+    let percentile = |p: usize| latencies[(latencies.len() * p / 100).min(latencies.len() - 1)];
+    (mean, percentile(50), percentile(99))
+}
+
+/// Entries of the trace context cache (`haproxy_otel_cache_size`)
+async fn cache_size() -> u64 {
+    let metrics = reqwest::get(METRICS_URL)
+        .await
+        .expect("Metrics request failed")
+        .text()
+        .await
+        .expect("Metrics body failed");
+    metrics
+        .lines()
+        .find_map(|line| line.strip_prefix("haproxy_otel_cache_size "))
+        .and_then(|size| size.trim().parse().ok())
+        .expect("No haproxy_otel_cache_size in the metrics")
+}
+
+/// Resident memory in KiB of the haproxy master and its workers, `None` without `/proc`
+fn rss_kib(master: u32) -> Option<u64> {
+    let mut pids = vec![master];
+    for entry in std::fs::read_dir("/proc").ok()?.flatten() {
+        let Ok(stat) = std::fs::read_to_string(entry.path().join("stat")) else {
+            continue;
+        };
+        // Fields after the command name: state, ppid, ...
+        let ppid = (stat.rsplit_once(") "))
+            .and_then(|(_, fields)| fields.split(' ').nth(1))
+            .and_then(|ppid| ppid.parse::<u32>().ok());
+        let pid = entry.file_name().to_str().and_then(|pid| pid.parse().ok());
+        if let (Some(ppid), Some(pid)) = (ppid, pid) {
+            if ppid == master {
+                pids.push(pid);
+            }
+        }
+    }
+    pids.iter()
+        .map(|pid| {
+            let status = std::fs::read_to_string(format!("/proc/{pid}/status")).ok()?;
+            let rss = status
+                .lines()
+                .find_map(|line| line.strip_prefix("VmRSS:"))?;
+            rss.trim().trim_end_matches("kB").trim().parse::<u64>().ok()
+        })
+        .sum()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore = "soak test, run with --ignored soak"]
+async fn soak() {
+    if !crate::haproxy_has_lua_support() {
+        eprintln!("SKIPPED: HAProxy not found or lacks Lua support.");
+        return;
+    }
+    crate::build_module().await;
+
+    let requests = env_or("SOAK_REQUESTS", 20_000);
+    let concurrency = env_or("SOAK_CONCURRENCY", 32).max(1);
+    let max_rss_growth = env_or("SOAK_MAX_RSS_GROWTH_MB", 64) * 1024;
+
+    let listener = TcpListener::bind("127.0.0.1:4317").unwrap();
+    let server = MockServer::builder()
+        .listener(listener)
+        .disable_request_recording()
+        .start()
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/test"))
+        .respond_with(ResponseTemplate::new(200).set_body_string("ok"))
+        .mount(&server)
+        .await;
+    let spans = Arc::new(AtomicUsize::new(0));
+    Mock::given(method("POST"))
+        .and(path("/v1/traces"))
+        .respond_with(SpanCounter(Arc::clone(&spans)))
+        .mount(&server)
+        .await;
+
+    let haproxy = crate::start_haproxy(&[]).await;
+    let pid = haproxy.id().expect("haproxy exited");
+
+    // Warm up connections, allocator and export batches before the reference points
+    let warmup = (requests / 10).max(concurrency);
+    drive(TRACED_URL, warmup, concurrency).await;
+    drive(BASELINE_URL, warmup, concurrency).await;
+    let rss_before = rss_kib(pid);
+
+    let started = Instant::now();
+    let baseline = drive(BASELINE_URL, requests, concurrency).await;
+    let traced = drive(TRACED_URL, requests, concurrency).await;
+    let elapsed = started.elapsed();
+
+    // Every traced request has a server and a client span
+    let expected = 2 * (warmup + requests) as usize;
+    let deadline = Instant::now() + Duration::from_secs(30);
+    while spans.load(Ordering::Relaxed) < expected && Instant::now() < deadline {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    let exported = spans.load(Ordering::Relaxed);
+    let cache = cache_size().await;
+    let rss_after = rss_kib(pid);
+    crate::stop_haproxy(haproxy).await;
+
+    let (baseline_mean, baseline_p50, baseline_p99) = summarize(&baseline);
+    let (traced_mean, traced_p50, traced_p99) = summarize(&traced);
+    println!("soak: {requests} requests per frontend, concurrency {concurrency}, {elapsed:?}");
+    println!("  baseline: mean {baseline_mean:?} p50 {baseline_p50:?} p99 {baseline_p99:?}");
+    println!("  traced:   mean {traced_mean:?} p50 {traced_p50:?} p99 {traced_p99:?}");
+    println!(
+        "  overhead: mean {:?} p50 {:?} p99 {:?} per request",
+        traced_mean.saturating_sub(baseline_mean),
+        traced_p50.saturating_sub(baseline_p50),
+        traced_p99.saturating_sub(baseline_p99),
+    );
+    println!("  spans: {exported} exported of {expected}, cache size after the load: {cache}");
+    match (rss_before, rss_after) {
+        (Some(before), Some(after)) => println!("  rss: {before} KiB -> {after} KiB"),
+        _ => println!("  rss: unavailable (no /proc)"),
+    }
+
+    assert_eq!(exported, expected, "spans were lost or duplicated");
+    // Contexts are removed when their transaction ends, nothing is in flight anymore
+    assert!(
+        cache <= concurrency,
+        "trace context cache holds {cache} entries after the load"
+    );
+    if let (Some(before), Some(after)) = (rss_before, rss_after) {
+        assert!(
+            after.saturating_sub(before) <= max_rss_growth,
+            "haproxy memory grew from {before} KiB to {after} KiB"
+        );
+    }
+}
+
+#[test]
+fn test_count_spans() {
+    let body = json!({
+        "resourceSpans": [
+            { "scopeSpans": [{ "spans": [{}, {}] }, { "spans": [{}] }] },
+            { "scopeSpans": [{ "spans": [{}] }] },
+        ]
+    });
+    assert_eq!(count_spans(&body), 4);
+    assert_eq!(count_spans(&json!({})), 0);
+}
+
+#[test]
+fn test_summarize() {
+    let latencies = (1..=100).map(Duration::from_millis).collect::<Vec<_>>();
+    let (mean, p50, p99) = summarize(&latencies);
+    assert_eq!(mean, Duration::from_micros(50_500));
+    assert_eq!(p50, Duration::from_millis(51));
+    assert_eq!(p99, Duration::from_millis(100));
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_rss_kib() {
+    assert!(rss_kib(std::process::id()).is_some_and(|rss| rss > 0));
+}